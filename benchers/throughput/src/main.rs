@@ -117,6 +117,22 @@ fn main() {
                 "Address and port of the server that comes after this one in it's replication chain.")
             (@arg workers: -w --workers +takes_value
                 "Number of worker threads this server should use (default is <number of cores> - 2).")
+            (@arg ordering_threads: -o --ordering_threads +takes_value
+                "Number of ordering threads the server's chains are split between (default is 1).")
+        )
+
+        (@subcommand shards =>
+            (about: "ordering thread scaling: runs the uwr workload against a local server with 1, 2, 4, ... ordering threads.")
+            (@arg max_ordering_threads: -o --max_ordering_threads +takes_value
+                "Most ordering threads to try (default is <number of cores> / 2).")
+            (@arg port: -p --port +takes_value "First port the servers should listen on.")
+            (@arg workers: -w --workers +takes_value
+                "Number of worker threads each server should use (default is <number of cores> / 2).")
+            (@arg clients: -n --num_clients +takes_value "Number of clients to run.")
+            (@arg jobsize: -j --jobsize  +takes_value "Number of bytes per entry.")
+            (@arg num_writes: -r --num_writes +takes_value "Number writes to perform.")
+            (@arg write_window: -i --write_window +takes_value
+                "Window size for writes.")
         )
     );
 
@@ -251,6 +267,7 @@ fn main() {
             let port = value_t!(args, "port", u16).unwrap_or_else(|e| e.exit());
             //FIXME max 1
             value_or!(let workers; args, usize, num_cpus::get() - 2);
+            value_or!(let ordering_threads; args, usize, 1);
             value_if!(let upstream; args, SocketAddr);
             value_if!(let downstream; args, IpAddr);
             let group = args.value_of("group").map(|a| {
@@ -264,7 +281,36 @@ fn main() {
                 (server_num, group_size)
             });
 
-            servers::run(port, workers, upstream, downstream, group)
+            servers::run(port, workers, ordering_threads, upstream, downstream, group)
+        }
+
+        ///////////////////////////////////////
+
+        "shards" => {
+            value_or!(let max_ordering_threads; args, usize, ::std::cmp::max(num_cpus::get() / 2, 1));
+            value_or!(let port; args, u16, 13490);
+            value_or!(let workers; args, usize, ::std::cmp::max(num_cpus::get() / 2, 1));
+            value_or!(let clients; args, usize, 4 * max_ordering_threads);
+            value_or!(let jobsize; args, usize, 1);
+            value_or!(let num_writes; args, u32, 100_000);
+            value_or!(let write_window; args, u32, num_writes);
+            drop(help);
+            let ordering_threads = (0..).map(|i| 1 << i)
+                .take_while(|&threads| threads <= max_ordering_threads);
+            for (i, threads) in ordering_threads.enumerate() {
+                println!("# {} ordering threads", threads);
+                let server = servers::spawn_local(port + i as u16, workers, threads);
+                // each client writes to random colors so every shard gets work
+                workloads::run_unreplicated_write_read(
+                    vec![server].into_boxed_slice(),
+                    clients,
+                    clients,
+                    jobsize,
+                    num_writes,
+                    write_window,
+                    Some([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]),
+                )
+            }
         }
 
         ///////////////////////////////////////
@@ -1,6 +1,7 @@
 
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
 
 use mio;
 
@@ -9,23 +10,38 @@ use fuzzy_log::servers2;
 pub fn run(
     port: u16,
     workers: usize,
+    ordering_threads: usize,
     upstream: Option<SocketAddr>,
     downstream: Option<IpAddr>,
     group: Option<(u32, u32)>,
 ) -> ! {
     let a = AtomicUsize::new(0);
     let (server_num, group_size) = group.unwrap_or((0, 1));
-    let replicated = upstream.is_some() || downstream.is_some();
     let ip_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
     let addr = SocketAddr::new(ip_addr, port);
     let acceptor = mio::tcp::TcpListener::bind(&addr);
     match acceptor {
-        Ok(accept) => if replicated {
-            servers2::tcp::run_with_replication(accept, server_num, group_size,
-                upstream, downstream, workers, &a)
-        } else {
-            servers2::tcp::run(accept, server_num, group_size, workers, &a)
-        },
+        Ok(accept) => servers2::tcp::run_sharded(accept, server_num, group_size,
+            upstream, downstream, workers, ordering_threads, &a),
         Err(e) => panic!("Could not start server due to {}.", e),
     }
 }
+
+/// Run an unreplicated server on `port` in the background,
+/// returns once it accepts connections.
+pub fn spawn_local(port: u16, workers: usize, ordering_threads: usize) -> SocketAddr {
+    static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+    let started = SERVERS_READY.load(Ordering::Acquire);
+    let ip_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+    let addr = SocketAddr::new(ip_addr, port);
+    let acceptor = mio::tcp::TcpListener::bind(&addr)
+        .unwrap_or_else(|e| panic!("Could not start server due to {}.", e));
+    thread::spawn(move ||
+        servers2::tcp::run_sharded(acceptor, 0, 1, None, None,
+            workers, ordering_threads, &SERVERS_READY)
+    );
+    while SERVERS_READY.load(Ordering::Acquire) == started {
+        thread::yield_now()
+    }
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+}
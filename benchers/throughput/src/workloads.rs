@@ -100,6 +100,9 @@ fn write_read(
     let clients_to_run = clients.len();
     static WRITERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
     static READERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+    // the workload may be run several times in one process
+    let writers_ready = WRITERS_READY.load(Ordering::SeqCst) + clients_to_run;
+    let readers_ready = READERS_READY.load(Ordering::SeqCst) + clients_to_run;
 
     let start = Instant::now();
     let joins: Vec<_> = clients.drain(..).enumerate().map(|(client_num, handle)| {
@@ -133,7 +136,7 @@ fn write_read(
             }
 
             WRITERS_READY.fetch_add(1, Ordering::SeqCst);
-            while WRITERS_READY.load(Ordering::SeqCst) < clients_to_run {
+            while WRITERS_READY.load(Ordering::SeqCst) < writers_ready {
                 thread::yield_now()
             }
 
@@ -206,7 +209,7 @@ fn write_read(
             }
 
             READERS_READY.fetch_add(1, Ordering::SeqCst);
-            while READERS_READY.load(Ordering::SeqCst) < clients_to_run {
+            while READERS_READY.load(Ordering::SeqCst) < readers_ready {
                 thread::yield_now()
             }
 
@@ -158,7 +158,8 @@ fn main() {
                     }
                     Err(GetRes::NothingReady) => continue 'recv,
                     Err(GetRes::Done) => break 'recv,
-                    e @ Err(GetRes::IoErr(..)) | e @ Err(GetRes::AlreadyGCd(..)) =>
+                    e @ Err(GetRes::IoErr(..)) | e @ Err(GetRes::AlreadyGCd(..))
+                    | e @ Err(GetRes::Corrupted(..)) | e @ Err(GetRes::PermissionDenied(..)) =>
                         panic!("{:?}", e),
                 }
             }
@@ -174,7 +175,8 @@ fn main() {
                     Err(GetRes::NothingReady) => break 'poll,
                     Err(GetRes::Done) => break 'recv,
 
-                    e @ Err(GetRes::IoErr(..)) | e @ Err(GetRes::AlreadyGCd(..)) =>
+                    e @ Err(GetRes::IoErr(..)) | e @ Err(GetRes::AlreadyGCd(..))
+                    | e @ Err(GetRes::Corrupted(..)) | e @ Err(GetRes::PermissionDenied(..)) =>
                         panic!("{:?}", e),
                 }
                 count += 1;
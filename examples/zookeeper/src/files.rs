@@ -1,11 +1,13 @@
 use std::cmp::{Eq, PartialEq};
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::ffi::OsString;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use message::*;
+use session::Sessions;
 use super::WhichPath;
 
 use order;//
@@ -13,12 +15,14 @@ use order;//
 #[derive(Debug)]
 pub struct FileSystem {
     //FIXME PathBuf isn't cross platform
-    // watches
     my_root: OsString,
     num_entries: u64,
     files: HashMap<Arc<Path>, FileNode>,
     roots: HashMap<OsString, order>,
     empty_path: Arc<Path>,
+    watches: Arc<Mutex<Watches>>,
+    sessions: Sessions,
+    ephemerals: HashMap<ClientId, HashSet<Arc<Path>>>,
     // seen_ids: HashSet<Id>,
 }
 
@@ -29,7 +33,6 @@ struct FileNode {
     data: Arc<[u8]>,
     ephemeral: bool,
     children: HashSet<Arc<Path>>,
-    sequential_counters: HashMap<Arc<Path>, i64>,
     pending_rename: Option<Box<PendingRename>>,
 }
 
@@ -52,6 +55,96 @@ impl Eq for FileNode {}
 
 /////////////////
 
+/// The one-shot watches a client has set. These are shared by all of the
+/// client's partitions, since the parent of a node may live in another one.
+pub struct Watches {
+    data: HashSet<PathBuf>,
+    children: HashSet<PathBuf>,
+    watcher: Option<Box<FnMut(WatchedEvent) + Send>>,
+}
+
+impl Watches {
+    pub fn new(watcher: Box<FnMut(WatchedEvent) + Send>) -> Self {
+        Watches {
+            data: Default::default(),
+            children: Default::default(),
+            watcher: Some(watcher),
+        }
+    }
+
+    fn watch_data(&mut self, path: &Path) {
+        self.data.insert(path.to_path_buf());
+    }
+
+    fn watch_children(&mut self, path: &Path) {
+        self.children.insert(path.to_path_buf());
+    }
+
+    fn created(&mut self, path: &Path) {
+        if self.data.remove(path) {
+            self.fire(WatchedEvent::NodeCreated(path.to_path_buf()))
+        }
+        self.children_changed(path);
+    }
+
+    fn deleted(&mut self, path: &Path) {
+        let data = self.data.remove(path);
+        let children = self.children.remove(path);
+        if data || children {
+            self.fire(WatchedEvent::NodeDeleted(path.to_path_buf()))
+        }
+        self.children_changed(path);
+    }
+
+    fn data_changed(&mut self, path: &Path) {
+        if self.data.remove(path) {
+            self.fire(WatchedEvent::NodeDataChanged(path.to_path_buf()))
+        }
+    }
+
+    fn children_changed(&mut self, child: &Path) {
+        if let Some(parent) = child.parent() {
+            if self.children.remove(parent) {
+                self.fire(WatchedEvent::NodeChildrenChanged(parent.to_path_buf()))
+            }
+        }
+    }
+
+    /// Our session is over, so are all of our watches.
+    pub fn session_expired(&mut self) {
+        self.data.clear();
+        self.children.clear();
+        self.fire(WatchedEvent::SessionExpired)
+    }
+
+    fn fire(&mut self, event: WatchedEvent) {
+        if let Some(ref mut watcher) = self.watcher {
+            watcher(event)
+        }
+    }
+}
+
+impl Default for Watches {
+    fn default() -> Self {
+        Watches {
+            data: Default::default(),
+            children: Default::default(),
+            watcher: None,
+        }
+    }
+}
+
+impl fmt::Debug for Watches {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Watches")
+            .field("data", &self.data)
+            .field("children", &self.children)
+            .finish()
+    }
+}
+
+/////////////////
+
 #[derive(Debug)]
 struct PendingRename {
     id: Id,
@@ -103,13 +196,13 @@ impl PendingRename {
 
 #[derive(Debug)]
 enum Operation {
-    Mut(Arc<Mutation>, WhichPath),
+    Mut(Arc<Mutation>, u64, WhichPath),
     Obs(Observation),
 }
 
-impl From<(Mutation, WhichPath)> for Operation {
-    fn from((mutation, which_path): (Mutation, WhichPath)) -> Self {
-        Operation::Mut(Arc::new(mutation), which_path)
+impl From<(Mutation, u64, WhichPath)> for Operation {
+    fn from((mutation, position, which_path): (Mutation, u64, WhichPath)) -> Self {
+        Operation::Mut(Arc::new(mutation), position, which_path)
     }
 }
 
@@ -129,6 +222,14 @@ enum Next {
 
 impl FileSystem {
     pub fn new(my_root: OsString, roots: HashMap<OsString, order>) -> Self {
+        Self::with_watches(my_root, roots, Default::default())
+    }
+
+    pub fn with_watches(
+        my_root: OsString,
+        roots: HashMap<OsString, order>,
+        watches: Arc<Mutex<Watches>>,
+    ) -> Self {
         let empty_path = PathBuf::new().into_boxed_path().into();
         let my_root_path: Arc<Path> = PathBuf::from(&*my_root).into_boxed_path().into();
         let mut system = FileSystem {
@@ -137,6 +238,9 @@ impl FileSystem {
             files: Default::default(),
             num_entries: 0,
             empty_path,
+            watches,
+            sessions: Sessions::new(),
+            ephemerals: Default::default(),
             // seen_ids: Default::default(),
         };
         let root_path: &Path = "/".as_ref();
@@ -157,6 +261,21 @@ impl FileSystem {
         &mut self,
         mutation: Mutation,
         which_path: WhichPath,
+        mutation_callback: CB,
+    ) where
+        CB: FnMut(Id, Result<(&Arc<Path>, &Stat), u32>, Option<Mutation>, Option<Mutation>),
+    {
+        let position = self.num_entries;
+        self.apply_logged_mutation(mutation, position, which_path, mutation_callback)
+    }
+
+    /// Apply a mutation found at `position` in the log,
+    /// sequential nodes it creates are numbered by that position.
+    pub fn apply_logged_mutation<CB>(
+        &mut self,
+        mutation: Mutation,
+        position: u64,
+        which_path: WhichPath,
         mut mutation_callback: CB,
     ) where
         CB: FnMut(Id, Result<(&Arc<Path>, &Stat), u32>, Option<Mutation>, Option<Mutation>),
@@ -167,8 +286,26 @@ impl FileSystem {
             version: 0,
             create_time: 0,
             mutate_time: 0,
+            ephemeral_owner: 0,
         };
 
+        if mutation.is_session_op() {
+            if let Some(session) = self.sessions.apply(&mutation) {
+                self.end_session(session)
+            }
+            return;
+        }
+
+        // an expired session is fenced off, nothing it does takes effect
+        match mutation {
+            Create { id, .. } | Delete { id, .. } | Set { id, .. } | RenamePart1 { id, .. }
+                if self.sessions.is_expired(id.client) =>
+            {
+                return mutation_callback(id, Err(line!()), None, None)
+            }
+            _ => {}
+        }
+
         enum RenamePart2 {
             OldExists {
                 id: Id,
@@ -242,7 +379,7 @@ impl FileSystem {
                         }),
 
                         op => {
-                            rename.pending_ops.push_back((op, which_path).into());
+                            rename.pending_ops.push_back((op, position, which_path).into());
                             return;
                         }
                     },
@@ -258,9 +395,8 @@ impl FileSystem {
                 path,
                 data,
             }) => {
-                let res = self.create(create_mode, path, data);
+                let res = self.create(id.client, create_mode, path, data, position);
                 let res = res.map(|path| (path, EMPTY_STAT));
-                assert!(res.is_ok());
                 mutation_callback(id, res, None, None)
             }
 
@@ -367,6 +503,165 @@ impl FileSystem {
                 let (res, msg0, msg1) = self.rename_part1(id, old_path, new_path, which_path, check_new);
                 mutation_callback(id, res.map(|p| (p, EMPTY_STAT)), msg0, msg1)
             }
+
+            // session ops were applied above,
+            // and multis are applied with check_multi and apply_multi
+            Ok(Heartbeat { .. })
+            | Ok(ExpireSession { .. })
+            | Ok(CloseSession { .. })
+            | Ok(Multi { .. }) => unreachable!(),
+        }
+    }
+
+    /// Check, without changing anything, whether the ops of a multi which
+    /// are `mine` would all succeed if applied in order at `position`,
+    /// returning the index of the first that wouldn't.
+    /// The other partitions check the rest of the ops.
+    pub fn check_multi(
+        &self,
+        client: ClientId,
+        ops: &[MultiOp],
+        mine: &[bool],
+        position: u64,
+    ) -> Result<(), (usize, MultiError)> {
+        use MultiOp::*;
+        // what the earlier ops did to each path, `None` if they deleted it
+        let mut changed = HashMap::new();
+        for (i, op) in ops.iter().enumerate().filter(|&(i, _)| mine[i]) {
+            if self.sessions.is_expired(client) {
+                return Err((i, MultiError::SessionExpired));
+            }
+            if !op.path().starts_with(&self.my_root) {
+                return Err((i, MultiError::NoNode));
+            }
+            match self.files.get(&**op.path()) {
+                Some(file) if file.pending_rename.is_some() => {
+                    return Err((i, MultiError::RenamePending))
+                }
+                _ => {}
+            }
+            match op {
+                &Create {
+                    create_mode,
+                    ref path,
+                    ..
+                } => {
+                    let path = if create_mode.is_sequential() {
+                        sequential_path(path, position)
+                    } else {
+                        path.clone()
+                    };
+                    if self.multi_lookup(&changed, &path).is_some() {
+                        return Err((i, MultiError::NodeExists));
+                    }
+                    match path.parent().and_then(|p| self.multi_lookup(&changed, p)) {
+                        None => return Err((i, MultiError::NoNode)),
+                        Some((_, true)) => return Err((i, MultiError::NoChildrenForEphemerals)),
+                        Some(..) => {}
+                    }
+                    changed.insert(path, Some((0, create_mode.is_ephemeral())));
+                }
+
+                &Delete { ref path, version } => {
+                    self.multi_version(&changed, path, version)
+                        .map_err(|e| (i, e))?;
+                    let has_children = self.files.get(&**path).map_or(false, |file| {
+                        file.children.iter().any(|c| changed.get(&**c) != Some(&None))
+                    }) || changed.iter().any(|(p, c)| c.is_some() && p.parent() == Some(&**path));
+                    if has_children {
+                        return Err((i, MultiError::NotEmpty));
+                    }
+                    changed.insert(path.clone(), None);
+                }
+
+                &Set {
+                    ref path, version, ..
+                } => {
+                    let (version, ephemeral) = self.multi_version(&changed, path, version)
+                        .map_err(|e| (i, e))?;
+                    changed.insert(path.clone(), Some((version + 1, ephemeral)));
+                }
+
+                &Check { ref path, version } => {
+                    self.multi_version(&changed, path, version)
+                        .map_err(|e| (i, e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the ops of a multi which are `mine`,
+    /// once `check_multi` passed them in every partition.
+    pub fn apply_multi(
+        &mut self,
+        client: ClientId,
+        ops: &[MultiOp],
+        mine: &[bool],
+        position: u64,
+    ) -> Vec<(usize, MultiResult)> {
+        use MultiOp::*;
+        let mut results = vec![];
+        for (i, op) in ops.iter().enumerate().filter(|&(i, _)| mine[i]) {
+            let result = match op {
+                &Create {
+                    create_mode,
+                    ref path,
+                    ref data,
+                } => {
+                    let created = self.create(client, create_mode, path.clone(), data.clone(), position)
+                        .expect("checked create failed")
+                        .to_path_buf();
+                    MultiResult::Created(created)
+                }
+                &Delete { ref path, version } => {
+                    self.delete(path.clone(), version).expect("checked delete failed");
+                    MultiResult::Deleted
+                }
+                &Set {
+                    ref path,
+                    ref data,
+                    version,
+                } => {
+                    let stat = *self.set(path.clone(), version, data.clone().into_boxed_slice())
+                        .expect("checked set failed")
+                        .1;
+                    MultiResult::Set(stat)
+                }
+                &Check { .. } => MultiResult::Checked,
+            };
+            results.push((i, result));
+        }
+        results
+    }
+
+    /// The version of `path` and whether it's ephemeral,
+    /// after the earlier ops of a multi made the `changed`s.
+    fn multi_lookup(
+        &self,
+        changed: &HashMap<PathBuf, Option<(Version, bool)>>,
+        path: &Path,
+    ) -> Option<(Version, bool)> {
+        match changed.get(path) {
+            Some(&changed) => changed,
+            None => self.files
+                .get(path)
+                .map(|file| (file.stat.version, file.ephemeral)),
+        }
+    }
+
+    fn multi_version(
+        &self,
+        changed: &HashMap<PathBuf, Option<(Version, bool)>>,
+        path: &Path,
+        version: Version,
+    ) -> Result<(Version, bool), MultiError> {
+        match self.multi_lookup(changed, path) {
+            None => Err(MultiError::NoNode),
+            Some((current, _)) if version != -1 && current != version => {
+                Err(MultiError::BadVersion)
+            }
+            Some(found) => Ok(found),
         }
     }
 
@@ -383,12 +678,12 @@ impl FileSystem {
         use self::Operation::*;
         for op in ops.drain(..) {
             match op {
-                Mut(mutation, which_path) => {
+                Mut(mutation, position, which_path) => {
                     let mutation = match Arc::try_unwrap(mutation) {
                         Ok(mutation) => mutation,
                         Err(arc) => (&*arc).clone(),
                     };
-                    self.apply_mutation(mutation, which_path, &mut *mutation_callback)
+                    self.apply_logged_mutation(mutation, position, which_path, &mut *mutation_callback)
                 }
                 Obs(observation) => self.handle_observation(observation),
             }
@@ -399,9 +694,11 @@ impl FileSystem {
 
     pub fn create(
         &mut self,
+        owner: ClientId,
         create_mode: CreateMode,
         path: PathBuf,
         data: Vec<u8>,
+        position: u64,
     ) -> Result<&Arc<Path>, u32> {
         //TODO Err type
         match self.files.get_mut(&*path) {
@@ -409,36 +706,40 @@ impl FileSystem {
             None => (),
         }
 
-        let path: Arc<Path> = Arc::from(path.into_boxed_path());
+        let path: Arc<Path> = if create_mode.is_sequential() {
+            let path: Arc<Path> = Arc::from(sequential_path(&path, position).into_boxed_path());
+            if self.files.contains_key(&path) {
+                return Err(line!());
+            }
+            path
+        } else {
+            Arc::from(path.into_boxed_path())
+        };
         //TODO unwrap
-        let path: Arc<Path> = match self.files.get_mut(path.parent().unwrap()) {
+        match self.files.get_mut(path.parent().unwrap()) {
             None => return Err(line!()),
             Some(parent) => {
-                let actual_path: Arc<Path> = if create_mode.is_sequential() {
-                    let counter = parent.sequential_counters.entry(path.clone()).or_insert(0);
-                    let count = *counter;
-                    *counter += 1;
-                    //FIXME this isn't a great way to do multiversion
-                    //      doesn't work with version checks in delete / set
-                    let real_path = format!("{}{}", path.to_string_lossy(), count);
-                    //FIXME why doesn't From work directly?
-                    Arc::from(Box::<Path>::from(Path::new(&real_path)))
-                } else {
-                    path.clone()
-                };
-                parent.children.insert(actual_path.clone());
-                actual_path
+                // ephemeral nodes cannot have children
+                if parent.ephemeral {
+                    return Err(line!());
+                }
+                parent.children.insert(path.clone());
             }
-        };
+        }
 
-        let new_node = FileNode::new(
+        let mut new_node = FileNode::new(
             path.clone(),
             data.into_boxed_slice().into(),
             self.num_entries,
         );
         self.num_entries += 1;
+        if create_mode.is_ephemeral() {
+            new_node.ephemeral = true;
+            new_node.stat.ephemeral_owner = owner;
+            self.ephemerals.entry(owner).or_insert_with(Default::default).insert(path.clone());
+        }
 
-        //TODO self.triggerwatches
+        self.watches.lock().unwrap().created(&path);
         let path = &self.files.entry(path).or_insert(new_node.into()).path;
         return Ok(path);
     }
@@ -459,11 +760,12 @@ impl FileSystem {
         {
             let parent = self.files.get_mut(path.parent().unwrap());
             parent.unwrap().children.remove(path.as_path());
-            //TODO triggerwatches
         }
 
-        let path = self.files.remove(path.as_path()).unwrap().path;
-        Ok(path)
+        let removed = self.files.remove(path.as_path()).unwrap();
+        self.forget_ephemeral(&removed);
+        self.watches.lock().unwrap().deleted(&removed.path);
+        Ok(removed.path)
     }
 
     fn set(
@@ -479,9 +781,10 @@ impl FileSystem {
                     return Err(line!());
                 }
                 file.data = data.into();
+                file.stat.version += 1;
                 file.stat.mutate_time = self.num_entries;
                 self.num_entries += 1;
-                //TODO triggerwatches
+                self.watches.lock().unwrap().data_changed(&file.path);
                 return Ok((&file.path, &file.stat));
             }
         }
@@ -879,10 +1182,11 @@ impl FileSystem {
             None => unreachable!(),
             Some(parent) => {
                 parent.children.remove(&*old_path);
-                //TODO trigger watches
             }
         }
         let mut old = self.files.remove(&*old_path).unwrap();
+        self.forget_ephemeral(&old);
+        self.watches.lock().unwrap().deleted(&old_path);
         old.pending_rename.take().unwrap().pending_ops
     }
 
@@ -893,7 +1197,6 @@ impl FileSystem {
             None => unreachable!(),
             Some(new_node) => {
                 new_node.stat.create_time = self.num_entries;
-                //TODO trigger watches
                 let mut rename = new_node.pending_rename.take().unwrap();
                 let new_data = ::std::mem::replace(&mut rename.data, vec![]);
                 let flush = rename.pending_ops;
@@ -906,12 +1209,40 @@ impl FileSystem {
             None => unreachable!(),
             Some(parent) => {
                 parent.children.insert(path);
-                //TODO trigger watches
             }
         }
+        self.watches.lock().unwrap().created(&new_path);
         flush
     }
 
+    /// Delete all of the ephemeral nodes of a session which has ended.
+    fn end_session(&mut self, session: ClientId) {
+        let ephemerals = match self.ephemerals.remove(&session) {
+            Some(ephemerals) => ephemerals,
+            None => return,
+        };
+        for path in ephemerals {
+            let _ = self.delete(path.to_path_buf(), -1);
+        }
+    }
+
+    fn forget_ephemeral(&mut self, node: &FileNode) {
+        if !node.ephemeral {
+            return;
+        }
+        let owner = node.stat.ephemeral_owner;
+        let now_empty = match self.ephemerals.get_mut(&owner) {
+            Some(ephemerals) => {
+                ephemerals.remove(&node.path);
+                ephemerals.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            self.ephemerals.remove(&owner);
+        }
+    }
+
     ////////////
 
     pub fn observe(&mut self, observation: Observation) {
@@ -942,13 +1273,14 @@ impl FileSystem {
                 watch,
                 mut callback,
             } => {
+                if watch {
+                    // exists watches are set even on nodes which don't exist yet
+                    self.watches.lock().unwrap().watch_data(&path);
+                }
                 match self.files.get_mut(&*path) {
-                    None => {
-                        //TODO watches
-                        callback(Err(line!()))
-                    }
+                    None => callback(Err(line!())),
                     Some(file) => {
-                        //TODO watches //TODO callback thread
+                        //TODO callback thread
                         callback(Ok((&*file.path, &file.stat)))
                     }
                 }
@@ -961,12 +1293,12 @@ impl FileSystem {
                 mut callback,
             } => {
                 match self.files.get_mut(&*path) {
-                    None => {
-                        //TODO watches
-                        callback(Err(line!()))
-                    }
+                    None => callback(Err(line!())),
                     Some(file) => {
-                        //TODO watches //TODO callback thread
+                        if watch {
+                            self.watches.lock().unwrap().watch_data(&path);
+                        }
+                        //TODO callback thread
                         callback(Ok((&*file.path, &file.data, &file.stat)))
                     }
                 }
@@ -979,12 +1311,12 @@ impl FileSystem {
                 mut callback,
             } => {
                 match self.files.get_mut(&*path) {
-                    None => {
-                        //TODO watches
-                        callback(Err(line!()))
-                    }
+                    None => callback(Err(line!())),
                     Some(file) => {
-                        //TODO watches //TODO callback thread
+                        if watch {
+                            self.watches.lock().unwrap().watch_children(&path);
+                        }
+                        //TODO callback thread
                         callback(Ok((&*file.path, &mut file.children.iter().map(|p| &**p))))
                     }
                 }
@@ -999,14 +1331,20 @@ impl FileNode {
             path,
             stat: Stat::new(create_time),
             data,
-            ephemeral: false, //TODO
+            ephemeral: false,
             children: Default::default(),
-            sequential_counters: Default::default(),
             pending_rename: None,
         }
     }
 }
 
+/// The path of a sequential node created at `position`,
+/// every entry has a unique position in our chain,
+/// and later creates have later positions.
+fn sequential_path(path: &Path, position: u64) -> PathBuf {
+    format!("{}{:010}", path.to_string_lossy(), position).into()
+}
+
 fn box_path(path: &Path) -> Box<Path> {
     path.to_path_buf().into_boxed_path()
 }
@@ -21,14 +21,14 @@ extern crate fuzzy_log_server;
 #[macro_use]
 extern crate matches;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::RandomState;
 use std::ffi::OsString;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub use fuzzy_log_client::fuzzy_log::log_handle::{entry, order, AtomicWriteHandle, GetRes,
                                                   LogHandle, OrderIndex, ReadHandle, TryWaitRes,
@@ -43,10 +43,16 @@ pub use files::*;
 
 pub use message::CreateMode;
 
+use session::Sessions;
+
 pub mod message;
 pub mod files;
+pub mod session;
 // pub mod parrallel_files;
 
+/// How long a client can go without heartbeating before others expire its session.
+pub const DEFAULT_SESSION_TIMEOUT_MS: u64 = 10_000;
+
 pub struct Client {
     to_materializer: Sender<MessageFromClient>,
 
@@ -65,6 +71,7 @@ enum MessageFromClient {
     Obs(Observation),
     EndOfSnapshot,
     EarlyMut(Id, Result<(Arc<Path>, Stat), u32>),
+    EarlyMulti(Id, MultiOutcome),
 }
 
 impl MessageFromClient {
@@ -90,6 +97,28 @@ impl Client {
         color: order,
         my_root: OsString,
         roots: HashMap<OsString, order>,
+    ) -> Self {
+        Self::with_session(
+            reader,
+            writer,
+            color,
+            my_root,
+            roots,
+            Duration::from_millis(DEFAULT_SESSION_TIMEOUT_MS),
+            Box::new(|_| {}),
+        )
+    }
+
+    /// `watcher` receives the events for the watches set by this client's
+    /// observations, and is told if this client's session expires.
+    pub fn with_session(
+        reader: ReadHandle<[u8]>,
+        writer: AtomicWriteHandle<[u8]>,
+        color: order,
+        my_root: OsString,
+        roots: HashMap<OsString, order>,
+        session_timeout: Duration,
+        watcher: Box<FnMut(WatchedEvent) + Send>,
     ) -> Self {
         use std::thread::spawn;
         let handle = reader;
@@ -99,6 +128,7 @@ impl Client {
         let to_server = writer.clone();
         let my_root1 = my_root.clone();
         let roots1 = roots.clone();
+        let watches = Arc::new(Mutex::new(Watches::new(watcher)));
         spawn(move || {
             Materializer::new(
                 to_server,
@@ -108,6 +138,8 @@ impl Client {
                 color,
                 my_root1,
                 roots1,
+                session_timeout,
+                watches,
             ).run()
         });
 
@@ -204,6 +236,26 @@ impl Client {
         }
     }
 
+    /// Apply `ops` all together or not at all, they're appended as one entry
+    /// and each is checked against the state the ones before it leave.
+    /// Every path must be in our root. Two sequential creates of the same
+    /// path in one multi would get the same number, so the second fails.
+    pub fn multi(&mut self, ops: Vec<MultiOp>, callback: Box<FnMut(MultiOutcome) + Send>) {
+        let id = Id::new();
+        let msg = Mutation::Multi { id, ops };
+        self.send_msg(id, msg, MutationCallback::Multi(callback));
+    }
+
+    /// End our session now rather than waiting for it to expire,
+    /// our ephemeral nodes are deleted and our later mutations will fail.
+    pub fn close_session(&mut self) {
+        let msg = Mutation::CloseSession { id: Id::new() };
+        serialize_into(&mut self.serialize_cache, &msg, Infinite).expect("cannot serialize");
+        self.to_server
+            .async_append(self.color, &*self.serialize_cache, &[]);
+        self.serialize_cache.clear();
+    }
+
     fn send_msg(&mut self, id: Id, msg: Mutation, callback: MutationCallback) {
         self.to_materializer
             .send(MessageFromClient::Mut(id, callback))
@@ -358,6 +410,7 @@ struct Materializer {
     my_root: OsString,
     //TODO (Id, index) and multiversion?
     early_mutations: HashMap<Id, Result<(Arc<Path>, Stat), u32>>,
+    early_multis: HashMap<Id, MultiOutcome>,
     waiting_mutations: HashMap<Id, MutationCallback>,
     waiting_observations: VecDeque<Observation>,
 
@@ -368,6 +421,15 @@ struct Materializer {
     color: order,
 
     serialize_cache: Vec<u8>,
+
+    sessions: Sessions,
+    session_timeout: Duration,
+    last_heartbeat_sent: Option<Instant>,
+    /// When we last saw a heartbeat from each live session.
+    heard_from: HashMap<ClientId, Instant>,
+    /// Sessions we have already tried to expire since their last heartbeat.
+    expiring: HashSet<ClientId>,
+    watches: Arc<Mutex<Watches>>,
 }
 
 enum Op {
@@ -377,9 +439,27 @@ enum Op {
         Option<MutationCallback>,
         WhichPath,
     ),
+    Multi(Arc<MultiApply>),
     Obs(Observation),
 }
 
+/// A multi is sent to every file partition, each of which checks and applies
+/// the ops on the paths it owns. So that a multi is all or nothing, the
+/// partitions all check their ops before any of them applies theirs.
+struct MultiApply {
+    id: Id,
+    ops: Vec<MultiOp>,
+    /// The partition which owns the path of each op.
+    owners: Vec<usize>,
+    position: u64,
+    callback: Mutex<Option<MutationCallback>>,
+    /// Each partition waits here once it's checked its ops, then again once
+    /// it's applied them.
+    votes: Barrier,
+    failed: Mutex<Option<(usize, MultiError)>>,
+    results: Mutex<Vec<Option<MultiResult>>>,
+}
+
 impl Materializer {
     fn new(
         to_server: AtomicWriteHandle<[u8]>,
@@ -389,12 +469,15 @@ impl Materializer {
         color: order,
         my_root: OsString,
         roots: HashMap<OsString, order>,
+        session_timeout: Duration,
+        watches: Arc<Mutex<Watches>>,
     ) -> Self {
         let balancer = RandomState::new();
         let to_files = (0..2)
             .map(|me| {
                 let balancer = balancer.clone();
-                let mut files = FileSystem::new(my_root.clone(), roots.clone());
+                let mut files =
+                    FileSystem::with_watches(my_root.clone(), roots.clone(), watches.clone());
                 let my_root = my_root.clone().into_string().expect("root not valid");
                 // for i in 0..2_000_000 {
                 //     let mut hasher = balancer.build_hasher();
@@ -421,7 +504,8 @@ impl Materializer {
                         match op {
                             Op::Mut(m, locs, mut cb, which_path) => {
                                 // let od = PrintOnDrop(&*locs);
-                                files.apply_mutation(m, which_path, |id, result, msg1, msg2| {
+                                let position = u64::from(locs[0].1);
+                                files.apply_logged_mutation(m, position, which_path, |id, result, msg1, msg2| {
                                     for ref new_msg in msg1.into_iter().chain(msg2) {
                                         let &id = new_msg.id();
                                         serialize_into(&mut serialize_cache, &new_msg, Infinite)
@@ -476,6 +560,45 @@ impl Materializer {
                                 });
                                 // ::std::mem::forget(od)
                             }
+                            Op::Multi(multi) => {
+                                let mine: Vec<_> = multi.owners.iter().map(|&o| o == me).collect();
+                                let (client, position) = (multi.id.client, multi.position);
+                                if let Err(failed) =
+                                    files.check_multi(client, &multi.ops, &mine, position)
+                                {
+                                    let mut first = multi.failed.lock().unwrap();
+                                    if first.map_or(true, |(i, _)| failed.0 < i) {
+                                        *first = Some(failed)
+                                    }
+                                }
+                                multi.votes.wait();
+                                let failed = *multi.failed.lock().unwrap();
+                                if failed.is_none() {
+                                    let applied =
+                                        files.apply_multi(client, &multi.ops, &mine, position);
+                                    let mut results = multi.results.lock().unwrap();
+                                    for (i, result) in applied {
+                                        results[i] = Some(result)
+                                    }
+                                }
+                                // the last partition to finish answers the client
+                                if !multi.votes.wait().is_leader() || client != client_id() {
+                                    continue;
+                                }
+                                let outcome = match failed {
+                                    Some(failed) => Err(failed),
+                                    None => Ok(multi.results.lock().unwrap()
+                                        .drain(..)
+                                        .map(|r| r.expect("multi op not applied"))
+                                        .collect()),
+                                };
+                                match multi.callback.lock().unwrap().take() {
+                                    Some(mut callback) => do_multi_callback(&mut callback, outcome),
+                                    None => loopback
+                                        .send(MessageFromClient::EarlyMulti(multi.id, outcome))
+                                        .unwrap(),
+                                }
+                            }
                             Op::Obs(o) => files.observe(o),
                         }
                     }
@@ -491,6 +614,7 @@ impl Materializer {
             loopback,
             my_root,
             early_mutations: Default::default(),
+            early_multis: Default::default(),
             waiting_mutations: Default::default(),
             waiting_observations: Default::default(),
             balancer,
@@ -498,11 +622,18 @@ impl Materializer {
             handle,
             color,
             serialize_cache: Default::default(),
+            sessions: Sessions::new(),
+            session_timeout,
+            last_heartbeat_sent: None,
+            heard_from: Default::default(),
+            expiring: Default::default(),
+            watches,
         }
     }
 
     pub fn run(&mut self) -> ! {
         loop {
+            self.maintain_sessions();
             //sleep, waiting for work to be needed
             let msg = self.from_client.recv_timeout(Duration::from_millis(1)).ok();
             self.handle_ops(msg);
@@ -524,16 +655,80 @@ impl Materializer {
         self.handle.snapshot(self.color);
     }
 
+    /// Heartbeat for our session, and try to expire any session we haven't
+    /// heard from in too long.
+    fn maintain_sessions(&mut self) {
+        let now = Instant::now();
+        let me = client_id();
+        let heartbeat_due = match self.last_heartbeat_sent {
+            None => true,
+            Some(sent) => now.duration_since(sent) >= self.session_timeout / 3,
+        };
+        if heartbeat_due && !self.sessions.is_expired(me) {
+            self.last_heartbeat_sent = Some(now);
+            self.append(&Mutation::Heartbeat { id: Id::new() });
+        }
+
+        let session_timeout = self.session_timeout;
+        let silent: Vec<_> = self.heard_from
+            .iter()
+            .filter(|&(&session, &heard)| {
+                session != me && now.duration_since(heard) >= session_timeout
+            })
+            .map(|(&session, _)| session)
+            .collect();
+        for session in silent {
+            if !self.expiring.insert(session) {
+                continue;
+            }
+            let last_heartbeat = self.sessions.last_heartbeat(session);
+            self.append(&Mutation::ExpireSession {
+                id: Id::new(),
+                session,
+                last_heartbeat,
+            });
+        }
+    }
+
+    fn observe_session_op(&mut self, msg: &Mutation) {
+        let ended = self.sessions.apply(msg);
+        if let &Mutation::Heartbeat { id } = msg {
+            if !self.sessions.is_expired(id.client) {
+                self.heard_from.insert(id.client, Instant::now());
+                self.expiring.remove(&id.client);
+            }
+        }
+        if let Some(session) = ended {
+            self.heard_from.remove(&session);
+            self.expiring.remove(&session);
+            if session == client_id() {
+                self.watches.lock().unwrap().session_expired()
+            }
+        }
+    }
+
+    fn append(&mut self, msg: &Mutation) {
+        serialize_into(&mut self.serialize_cache, msg, Infinite).expect("cannot serialize");
+        self.to_server
+            .async_append(self.color, &*self.serialize_cache, &[]);
+        self.serialize_cache.clear();
+    }
+
     fn drain_pending_ops(&mut self, first_op: Option<MessageFromClient>) {
         let waiting_observations = &mut self.waiting_observations;
         let waiting_mutations = &mut self.waiting_mutations;
         let early_mutations = &mut self.early_mutations;
+        let early_multis = &mut self.early_multis;
         let mut handle_message = |msg: MessageFromClient| {
             use MessageFromClient::*;
             match msg {
                 EndOfSnapshot => return true,
                 Obs(observation) => waiting_observations.push_back(observation),
                 Mut(mutation_id, mut callback) => {
+                    if let Some(outcome) = early_multis.remove(&mutation_id) {
+                        do_multi_callback(&mut callback, outcome);
+                        return false;
+                    }
                     let early = early_mutations.remove(&mutation_id);
                     match early {
                         Some(res) => {
@@ -559,6 +754,12 @@ impl Materializer {
                         early_mutations.insert(id, result);
                     }
                 },
+                EarlyMulti(id, outcome) => match waiting_mutations.remove(&id) {
+                    Some(mut callback) => do_multi_callback(&mut callback, outcome),
+                    None => {
+                        early_multis.insert(id, outcome);
+                    }
+                },
             }
             return false;
         };
@@ -574,12 +775,46 @@ impl Materializer {
     }
 
     fn play_log(&mut self) {
+        let mut session_ops = vec![];
         'play: loop {
             match self.handle.get_next() {
                 Err(GetRes::Done) => break 'play,
                 Err(e) => panic!(e),
                 Ok((bytes, locs)) => {
                     let msg: Mutation = deserialize(bytes).expect("bad msg");
+                    if let Mutation::Multi { id, ops } = msg {
+                        let num_file_threads = self.to_files.len();
+                        let owners = ops.iter()
+                            .map(|op| {
+                                let mut hasher = self.balancer.build_hasher();
+                                op.path().hash(&mut hasher);
+                                hasher.finish() as usize % num_file_threads
+                            })
+                            .collect();
+                        let multi = Arc::new(MultiApply {
+                            id,
+                            owners,
+                            position: u64::from(locs[0].1),
+                            callback: Mutex::new(self.waiting_mutations.remove(&id)),
+                            votes: Barrier::new(num_file_threads),
+                            failed: Mutex::new(None),
+                            results: Mutex::new(vec![None; ops.len()]),
+                            ops,
+                        });
+                        for to_files in &self.to_files {
+                            to_files.send(Op::Multi(multi.clone())).unwrap();
+                        }
+                        continue 'play;
+                    }
+                    if msg.is_session_op() {
+                        for to_files in &self.to_files {
+                            to_files
+                                .send(Op::Mut(msg.clone(), locs.to_vec(), None, WhichPath::Both))
+                                .unwrap();
+                        }
+                        session_ops.push(msg);
+                        continue 'play;
+                    }
                     //FIXME rename across partitions?
                     //FIXME clean into match
                     let my_root = &self.my_root;
@@ -676,6 +911,9 @@ impl Materializer {
                 }
             }
         }
+        for msg in session_ops {
+            self.observe_session_op(&msg);
+        }
     }
 
     fn handle_observations(&mut self) {
@@ -702,10 +940,19 @@ fn do_callback(callback: &mut MutationCallback, result: Result<(&Arc<Path>, &Sta
         &mut Stat(ref mut callback) => callback(result.map(|(p, s)| (&**p, s))),
         &mut Path(ref mut callback) => callback(result.map(|(p, _)| (&**p, &**p))),
         &mut Void(ref mut callback) => callback(result.map(|(p, _)| &**p)),
+        &mut Multi(..) => unreachable!("multis are answered by do_multi_callback"),
         &mut None => {}
     }
 }
 
+fn do_multi_callback(callback: &mut MutationCallback, outcome: MultiOutcome) {
+    match callback {
+        &mut MutationCallback::Multi(ref mut callback) => callback(outcome),
+        &mut MutationCallback::None => {}
+        other => unreachable!("{:?} for a multi", other),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -878,4 +1125,90 @@ mod tests {
         }
         println!("test done.");
     }
+
+    #[test]
+    fn sessions_and_watches() {
+        let (send_event, events) = channel();
+        let watches = Watches::new(Box::new(move |event| send_event.send(event).unwrap()));
+        let mut roots = HashMap::new();
+        roots.insert("abcd".into(), order::from(102));
+        let mut files = FileSystem::with_watches("/abcd/".into(), roots, Arc::new(Mutex::new(watches)));
+
+        let session = 7;
+        let id = |count| Id { client: session, count };
+        let apply = |files: &mut FileSystem, mutation, position| {
+            let mut result = None;
+            files.apply_logged_mutation(mutation, position, WhichPath::Path1, |_, res, _, _| {
+                result = Some(res.map(|(p, _)| p.to_path_buf()))
+            });
+            result
+        };
+        let exists = |files: &mut FileSystem, path: &str, watch| {
+            let (finished, done) = channel();
+            files.observe(Observation::Exists {
+                id: Id { client: 1, count: 0 },
+                path: path.into(),
+                watch,
+                callback: Box::new(move |res| finished.send(res.is_ok()).unwrap()),
+            });
+            done.recv().unwrap()
+        };
+
+        apply(&mut files, Mutation::Heartbeat { id: id(1) }, 1);
+        let created = apply(&mut files, Mutation::Create {
+            id: id(2),
+            create_mode: CreateMode::ephemeral(),
+            path: "/abcd/e".into(),
+            data: vec![],
+        }, 2);
+        assert_eq!(created, Some(Ok("/abcd/e".into())));
+
+        // sequential nodes are numbered by their position in the log
+        let created = apply(&mut files, Mutation::Create {
+            id: id(3),
+            create_mode: CreateMode::persistent_sequential(),
+            path: "/abcd/lock-".into(),
+            data: vec![],
+        }, 42);
+        assert_eq!(created, Some(Ok("/abcd/lock-0000000042".into())));
+
+        assert!(exists(&mut files, "/abcd/e", true));
+        files.observe(Observation::GetChildren {
+            id: Id { client: 1, count: 1 },
+            path: "/abcd".into(),
+            watch: true,
+            callback: Box::new(|res| assert!(res.is_ok())),
+        });
+
+        // a heartbeat came after the one this expiry saw, so it's ignored
+        apply(&mut files, Mutation::ExpireSession {
+            id: Id { client: 1, count: 2 },
+            session,
+            last_heartbeat: None,
+        }, 4);
+        assert!(exists(&mut files, "/abcd/e", false));
+        assert!(events.try_recv().is_err());
+
+        apply(&mut files, Mutation::ExpireSession {
+            id: Id { client: 1, count: 3 },
+            session,
+            last_heartbeat: Some(1),
+        }, 5);
+        assert!(!exists(&mut files, "/abcd/e", false));
+        assert_eq!(events.try_recv(), Ok(WatchedEvent::NodeDeleted("/abcd/e".into())));
+        assert_eq!(events.try_recv(), Ok(WatchedEvent::NodeChildrenChanged("/abcd".into())));
+        // watches are one-shot
+        assert!(events.try_recv().is_err());
+
+        // and the session is fenced off
+        apply(&mut files, Mutation::Heartbeat { id: id(4) }, 6);
+        let created = apply(&mut files, Mutation::Create {
+            id: id(5),
+            create_mode: CreateMode::persistent(),
+            path: "/abcd/f".into(),
+            data: vec![],
+        }, 7);
+        assert!(matches!(created, Some(Err(..))));
+        assert!(!exists(&mut files, "/abcd/f", false));
+    }
 }
@@ -7,6 +7,7 @@ pub enum MutationCallback {
     Stat(Box<for<'a, 'b> FnMut(Result<(&'a Path, &'b Stat), u32>) + Send>),
     Path(Box<for<'a, 'b> FnMut(Result<(&'a Path, &'b Path), u32>) + Send>),
     Void(Box<for<'a> FnMut(Result<&'a Path, u32>) + Send>),
+    Multi(Box<FnMut(MultiOutcome) + Send>),
     None,
 }
 
@@ -17,6 +18,7 @@ impl fmt::Debug for MutationCallback {
             &Stat(..) => f.debug_tuple("Stat").finish(),
             &Path(..) => f.debug_tuple("Path").finish(),
             &Void(..) => f.debug_tuple("Void").finish(),
+            &Multi(..) => f.debug_tuple("Multi").finish(),
             &None => f.debug_tuple("None").finish(),
         }
     }
@@ -108,8 +110,97 @@ pub enum Mutation {
         new_path: PathBuf,
         due_to_old: bool,
     },
+
+    /// Keeps the session of `id.client` alive, see `session`.
+    Heartbeat {
+        id: Id,
+    },
+
+    /// Ends `session` if `last_heartbeat` is the last heartbeat it sent before this.
+    ExpireSession {
+        id: Id,
+        session: ClientId,
+        last_heartbeat: Option<Count>,
+    },
+
+    CloseSession {
+        id: Id,
+    },
+
+    /// Ops which take effect all together or not at all, see `Client::multi`.
+    Multi {
+        id: Id,
+        ops: Vec<MultiOp>,
+    },
+}
+
+/// One op of a `Mutation::Multi`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MultiOp {
+    Create {
+        create_mode: CreateMode,
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+
+    Delete {
+        path: PathBuf,
+        version: Version,
+    },
+
+    Set {
+        path: PathBuf,
+        data: Vec<u8>,
+        version: Version,
+    },
+
+    /// Changes nothing, but fails the multi unless `path` is at `version`.
+    Check {
+        path: PathBuf,
+        version: Version,
+    },
+}
+
+impl MultiOp {
+    pub fn path(&self) -> &PathBuf {
+        use self::MultiOp::*;
+        match self {
+            &Create { ref path, .. }
+            | &Delete { ref path, .. }
+            | &Set { ref path, .. }
+            | &Check { ref path, .. } => path,
+        }
+    }
 }
 
+/// What one op of a multi which succeeded did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultiResult {
+    /// The path of the new node, which differs from the requested one for
+    /// sequential nodes.
+    Created(PathBuf),
+    Deleted,
+    Set(Stat),
+    Checked,
+}
+
+/// Why an op of a multi failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MultiError {
+    NoNode,
+    NodeExists,
+    BadVersion,
+    NotEmpty,
+    NoChildrenForEphemerals,
+    SessionExpired,
+    /// The node is in the middle of a rename, so we can't tell what the op would do.
+    RenamePending,
+}
+
+/// What every op of a multi did, or the index of the first op which failed
+/// and why, in which case none of them took effect.
+pub type MultiOutcome = Result<Vec<MultiResult>, (usize, MultiError)>;
+
 impl Mutation {
     pub fn id(&self) -> &Id {
         use self::Mutation::*;
@@ -120,7 +211,11 @@ impl Mutation {
             | &RenamePart1 { ref id, .. }
             | &RenameOldExists { ref id, .. }
             | &RenameNewEmpty { ref id, .. }
-            | &RenameNack { ref id, .. } => id,
+            | &RenameNack { ref id, .. }
+            | &Heartbeat { ref id }
+            | &ExpireSession { ref id, .. }
+            | &CloseSession { ref id }
+            | &Multi { ref id, .. } => id,
         }
     }
 
@@ -142,6 +237,10 @@ impl Mutation {
             | &RenameOldExists { ref old_path, .. }
             | &RenameNewEmpty { ref old_path, .. }
             | &RenameNack { ref old_path, .. } => &old_path,
+            &Heartbeat { .. } | &ExpireSession { .. } | &CloseSession { .. } => {
+                panic!("session mutations have no path {:?}", self)
+            }
+            &Multi { .. } => panic!("each op of a multi has its own path {:?}", self),
         }
     }
 
@@ -156,6 +255,16 @@ impl Mutation {
         }
     }
 
+    /// Session mutations go to every partition of the file system rather than
+    /// the one which owns a path.
+    pub fn is_session_op(&self) -> bool {
+        use self::Mutation::*;
+        match self {
+            &Heartbeat { .. } | &ExpireSession { .. } | &CloseSession { .. } => true,
+            _ => false,
+        }
+    }
+
     pub fn is_nack(&self) -> bool {
         match self {
             &Mutation::RenameNack { .. } => true,
@@ -197,6 +306,8 @@ pub struct Stat {
     pub version: Version,
     pub create_time: u64,
     pub mutate_time: u64,
+    /// The session which owns this node if it is ephemeral, 0 otherwise.
+    pub ephemeral_owner: ClientId,
 }
 
 impl Stat {
@@ -205,10 +316,25 @@ impl Stat {
             create_time,
             version: 0,
             mutate_time: 0,
+            ephemeral_owner: 0,
         }
     }
 }
 
+/// Delivered to a client's watcher when something it watched changes.
+/// Like ZooKeeper's, watches are one-shot: to keep watching a node the watcher
+/// must set a new watch after each event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchedEvent {
+    NodeCreated(PathBuf),
+    NodeDeleted(PathBuf),
+    NodeDataChanged(PathBuf),
+    NodeChildrenChanged(PathBuf),
+    /// Our session expired, our ephemeral nodes are gone and all our further
+    /// mutations will fail.
+    SessionExpired,
+}
+
 pub enum Observation {
     Exists {
         id: Id,
@@ -0,0 +1,63 @@
+//! Client sessions, kept alive by heartbeats in the log.
+//!
+//! Every client appends a heartbeat to its chain a few times per session timeout.
+//! A client which sees no heartbeat from a session for a whole timeout appends an
+//! `ExpireSession` naming the last heartbeat it did see. That only takes effect
+//! if no newer heartbeat came before it in the log, so a slow client and an
+//! impatient peer can't both think they won: either the session is fenced off,
+//! and all its later mutations fail, or the expiry is ignored.
+//! Since the decision only depends on the order of the log, every materializer
+//! makes the same one.
+
+use std::collections::{HashMap, HashSet};
+
+use message::{ClientId, Count, Mutation};
+
+#[derive(Debug, Default)]
+pub struct Sessions {
+    last_heartbeat: HashMap<ClientId, Count>,
+    expired: HashSet<ClientId>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Apply a session mutation, returns the session it ended, if any.
+    pub fn apply(&mut self, mutation: &Mutation) -> Option<ClientId> {
+        use message::Mutation::*;
+        match mutation {
+            &Heartbeat { id } => {
+                if !self.expired.contains(&id.client) {
+                    self.last_heartbeat.insert(id.client, id.count);
+                }
+                None
+            }
+            &ExpireSession { session, last_heartbeat, .. } => {
+                if self.last_heartbeat.get(&session).cloned() != last_heartbeat {
+                    return None
+                }
+                self.end(session)
+            }
+            &CloseSession { id } => self.end(id.client),
+            _ => None,
+        }
+    }
+
+    pub fn is_expired(&self, session: ClientId) -> bool {
+        self.expired.contains(&session)
+    }
+
+    pub fn last_heartbeat(&self, session: ClientId) -> Option<Count> {
+        self.last_heartbeat.get(&session).cloned()
+    }
+
+    fn end(&mut self, session: ClientId) -> Option<ClientId> {
+        if !self.expired.insert(session) {
+            return None
+        }
+        self.last_heartbeat.remove(&session);
+        Some(session)
+    }
+}
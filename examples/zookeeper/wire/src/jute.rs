@@ -0,0 +1,401 @@
+//! The jute encoding ZooKeeper uses on the wire, and the records we speak.
+//!
+//! Everything is big-endian. Buffers and strings are an i32 length followed by
+//! that many bytes, with -1 meaning null; vectors are an i32 count followed by
+//! their elements. Every packet in either direction is framed by an i32 length.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Anything longer than this is a corrupt frame, ZooKeeper's own default `jute.maxbuffer`.
+pub const MAX_FRAME: usize = 0xfffff;
+
+pub mod op {
+    pub const NOTIFICATION: i32 = 0;
+    pub const CREATE: i32 = 1;
+    pub const DELETE: i32 = 2;
+    pub const EXISTS: i32 = 3;
+    pub const GET_DATA: i32 = 4;
+    pub const SET_DATA: i32 = 5;
+    pub const GET_CHILDREN: i32 = 8;
+    pub const SYNC: i32 = 9;
+    pub const PING: i32 = 11;
+    pub const GET_CHILDREN2: i32 = 12;
+    pub const CHECK: i32 = 13;
+    pub const MULTI: i32 = 14;
+    pub const CREATE2: i32 = 15;
+    pub const CREATE_CONTAINER: i32 = 19;
+    pub const SET_WATCHES: i32 = 101;
+    pub const CLOSE_SESSION: i32 = -11;
+    pub const ERROR: i32 = -1;
+}
+
+pub mod error {
+    pub const OK: i32 = 0;
+    pub const SYSTEM_ERROR: i32 = -1;
+    pub const RUNTIME_INCONSISTENCY: i32 = -2;
+    pub const MARSHALLING_ERROR: i32 = -5;
+    pub const UNIMPLEMENTED: i32 = -6;
+    pub const BAD_ARGUMENTS: i32 = -8;
+    pub const NO_NODE: i32 = -101;
+    pub const BAD_VERSION: i32 = -103;
+    pub const NO_CHILDREN_FOR_EPHEMERALS: i32 = -108;
+    pub const NODE_EXISTS: i32 = -110;
+    pub const NOT_EMPTY: i32 = -111;
+    pub const SESSION_EXPIRED: i32 = -112;
+}
+
+/// The xids of messages which aren't replies to a request.
+pub mod xid {
+    pub const NOTIFICATION: i32 = -1;
+    pub const PING: i32 = -2;
+}
+
+pub mod event {
+    pub const NONE: i32 = -1;
+    pub const NODE_CREATED: i32 = 1;
+    pub const NODE_DELETED: i32 = 2;
+    pub const NODE_DATA_CHANGED: i32 = 3;
+    pub const NODE_CHILDREN_CHANGED: i32 = 4;
+}
+
+pub mod state {
+    pub const SYNC_CONNECTED: i32 = 3;
+    pub const EXPIRED: i32 = -112;
+}
+
+/// Read one length prefixed frame, `None` if the stream ended cleanly before it.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_i32::<BigEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len < 0 || len as usize > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad frame length {}", len)))
+    }
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn int(&mut self) -> io::Result<i32> {
+        self.bytes.read_i32::<BigEndian>()
+    }
+
+    pub fn long(&mut self) -> io::Result<i64> {
+        self.bytes.read_i64::<BigEndian>()
+    }
+
+    pub fn boolean(&mut self) -> io::Result<bool> {
+        Ok(self.bytes.read_u8()? != 0)
+    }
+
+    pub fn buffer(&mut self) -> io::Result<Option<&'a [u8]>> {
+        let len = self.int()?;
+        if len < 0 {
+            return Ok(None)
+        }
+        let len = len as usize;
+        if self.bytes.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "buffer past end of frame"))
+        }
+        let (buffer, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(Some(buffer))
+    }
+
+    /// A null string reads as empty.
+    pub fn string(&mut self) -> io::Result<String> {
+        match self.buffer()? {
+            None => Ok(String::new()),
+            Some(bytes) => String::from_utf8(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    pub fn strings(&mut self) -> io::Result<Vec<String>> {
+        let count = self.int()?;
+        (0..count.max(0)).map(|_| self.string()).collect()
+    }
+}
+
+/// Builds one frame.
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { bytes: vec![0; 4] }
+    }
+
+    pub fn int(&mut self, i: i32) -> &mut Self {
+        let _ = self.bytes.write_i32::<BigEndian>(i);
+        self
+    }
+
+    pub fn long(&mut self, l: i64) -> &mut Self {
+        let _ = self.bytes.write_i64::<BigEndian>(l);
+        self
+    }
+
+    pub fn boolean(&mut self, b: bool) -> &mut Self {
+        self.bytes.push(b as u8);
+        self
+    }
+
+    pub fn buffer(&mut self, buffer: &[u8]) -> &mut Self {
+        self.int(buffer.len() as i32);
+        self.bytes.extend_from_slice(buffer);
+        self
+    }
+
+    pub fn string(&mut self, s: &str) -> &mut Self {
+        self.buffer(s.as_bytes())
+    }
+
+    pub fn strings<S: AsRef<str>>(&mut self, strings: &[S]) -> &mut Self {
+        self.int(strings.len() as i32);
+        for s in strings {
+            self.string(s.as_ref());
+        }
+        self
+    }
+
+    pub fn reply_header(&mut self, xid: i32, zxid: i64, err: i32) -> &mut Self {
+        self.int(xid).long(zxid).int(err)
+    }
+
+    pub fn stat(&mut self, stat: &Stat) -> &mut Self {
+        self.long(stat.czxid).long(stat.mzxid).long(stat.ctime).long(stat.mtime)
+            .int(stat.version).int(stat.cversion).int(stat.aversion)
+            .long(stat.ephemeral_owner).int(stat.data_length).int(stat.num_children)
+            .long(stat.pzxid)
+    }
+
+    /// The frame, with its length filled in.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let len = (self.bytes.len() - 4) as i32;
+        (&mut self.bytes[..4]).write_i32::<BigEndian>(len).unwrap();
+        ::std::mem::replace(&mut self.bytes, vec![0; 4])
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_all(frame)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stat {
+    pub czxid: i64,
+    pub mzxid: i64,
+    pub ctime: i64,
+    pub mtime: i64,
+    pub version: i32,
+    pub cversion: i32,
+    pub aversion: i32,
+    pub ephemeral_owner: i64,
+    pub data_length: i32,
+    pub num_children: i32,
+    pub pzxid: i64,
+}
+
+impl Stat {
+    pub fn decode(d: &mut Decoder) -> io::Result<Self> {
+        Ok(Stat {
+            czxid: d.long()?,
+            mzxid: d.long()?,
+            ctime: d.long()?,
+            mtime: d.long()?,
+            version: d.int()?,
+            cversion: d.int()?,
+            aversion: d.int()?,
+            ephemeral_owner: d.long()?,
+            data_length: d.int()?,
+            num_children: d.int()?,
+            pzxid: d.long()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+    pub protocol_version: i32,
+    pub last_zxid_seen: i64,
+    pub timeout: i32,
+    pub session_id: i64,
+    pub passwd: Vec<u8>,
+    /// Older clients don't send this at all, and don't expect it back.
+    pub read_only: Option<bool>,
+}
+
+impl ConnectRequest {
+    pub fn decode(d: &mut Decoder) -> io::Result<Self> {
+        Ok(ConnectRequest {
+            protocol_version: d.int()?,
+            last_zxid_seen: d.long()?,
+            timeout: d.int()?,
+            session_id: d.long()?,
+            passwd: d.buffer()?.unwrap_or(&[]).to_vec(),
+            read_only: if d.is_empty() { None } else { Some(d.boolean()?) },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// `with_stat` for create2, which also returns the stat of the new node.
+    Create { path: String, data: Vec<u8>, flags: i32, with_stat: bool },
+    Delete { path: String, version: i32 },
+    Exists { path: String, watch: bool },
+    GetData { path: String, watch: bool },
+    SetData { path: String, data: Vec<u8>, version: i32 },
+    GetChildren { path: String, watch: bool, with_stat: bool },
+    Sync { path: String },
+    Check { path: String, version: i32 },
+    Multi(Vec<Request>),
+    SetWatches { data: Vec<String>, exist: Vec<String>, child: Vec<String> },
+    Ping,
+    Close,
+    Unimplemented(i32),
+}
+
+impl Request {
+    pub fn decode(op: i32, d: &mut Decoder) -> io::Result<Self> {
+        use self::Request::*;
+        let request = match op {
+            op::CREATE | op::CREATE2 | op::CREATE_CONTAINER => {
+                let path = d.string()?;
+                let data = d.buffer()?.unwrap_or(&[]).to_vec();
+                // we don't do ACLs, everything is world readable and writable
+                let acls = d.int()?;
+                for _ in 0..acls.max(0) {
+                    let _perms = d.int()?;
+                    let _scheme = d.string()?;
+                    let _id = d.string()?;
+                }
+                let flags = d.int()?;
+                Create { path, data, flags, with_stat: op != op::CREATE }
+            },
+            op::DELETE => Delete { path: d.string()?, version: d.int()? },
+            op::EXISTS => Exists { path: d.string()?, watch: d.boolean()? },
+            op::GET_DATA => GetData { path: d.string()?, watch: d.boolean()? },
+            op::SET_DATA => SetData {
+                path: d.string()?,
+                data: d.buffer()?.unwrap_or(&[]).to_vec(),
+                version: d.int()?,
+            },
+            op::GET_CHILDREN | op::GET_CHILDREN2 => GetChildren {
+                path: d.string()?,
+                watch: d.boolean()?,
+                with_stat: op == op::GET_CHILDREN2,
+            },
+            op::SYNC => Sync { path: d.string()? },
+            op::CHECK => Check { path: d.string()?, version: d.int()? },
+            op::MULTI => {
+                let mut ops = vec![];
+                loop {
+                    let (op, done, _err) = (d.int()?, d.boolean()?, d.int()?);
+                    if done {
+                        break
+                    }
+                    ops.push(Request::decode(op, d)?);
+                }
+                Multi(ops)
+            },
+            op::SET_WATCHES => {
+                let _relative_zxid = d.long()?;
+                SetWatches { data: d.strings()?, exist: d.strings()?, child: d.strings()? }
+            },
+            op::PING => Ping,
+            op::CLOSE_SESSION => Close,
+            other => Unimplemented(other),
+        };
+        Ok(request)
+    }
+
+    /// The op code of this request, as it appears in a multi response.
+    pub fn op(&self) -> i32 {
+        use self::Request::*;
+        match *self {
+            Create { with_stat: false, .. } => op::CREATE,
+            Create { with_stat: true, .. } => op::CREATE2,
+            Delete { .. } => op::DELETE,
+            Exists { .. } => op::EXISTS,
+            GetData { .. } => op::GET_DATA,
+            SetData { .. } => op::SET_DATA,
+            GetChildren { with_stat: false, .. } => op::GET_CHILDREN,
+            GetChildren { with_stat: true, .. } => op::GET_CHILDREN2,
+            Sync { .. } => op::SYNC,
+            Check { .. } => op::CHECK,
+            Multi(..) => op::MULTI,
+            SetWatches { .. } => op::SET_WATCHES,
+            Ping => op::PING,
+            Close => op::CLOSE_SESSION,
+            Unimplemented(op) => op,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_request() {
+        let mut e = Encoder::new();
+        e.string("/foo/a").buffer(&[1, 2]).int(1).int(31).string("world").string("anyone").int(2);
+        let frame = e.finish();
+        assert_eq!(&frame[..4], &[0, 0, 0, frame.len() as u8 - 4]);
+        let request = Request::decode(op::CREATE2, &mut Decoder::new(&frame[4..])).unwrap();
+        assert_eq!(request, Request::Create {
+            path: "/foo/a".to_owned(), data: vec![1, 2], flags: 2, with_stat: true,
+        });
+    }
+
+    #[test]
+    fn multi_request() {
+        let mut e = Encoder::new();
+        e.int(op::CHECK).boolean(false).int(-1).string("/foo").int(3);
+        e.int(op::DELETE).boolean(false).int(-1).string("/foo/a").int(-1);
+        e.int(-1).boolean(true).int(-1);
+        let frame = e.finish();
+        let request = Request::decode(op::MULTI, &mut Decoder::new(&frame[4..])).unwrap();
+        assert_eq!(request, Request::Multi(vec![
+            Request::Check { path: "/foo".to_owned(), version: 3 },
+            Request::Delete { path: "/foo/a".to_owned(), version: -1 },
+        ]));
+    }
+
+    #[test]
+    fn stat_round_trip() {
+        let stat = Stat { czxid: 1, mzxid: 2, version: 3, ephemeral_owner: -4, num_children: 5, ..Stat::default() };
+        let frame = Encoder::new().stat(&stat).finish();
+        assert_eq!(frame.len(), 4 + 68);
+        assert_eq!(Stat::decode(&mut Decoder::new(&frame[4..])).unwrap(), stat);
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Encoder::new().reply_header(7, 8, 0).finish();
+        let mut reader = &frame[..];
+        let read = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(read, &frame[4..]);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+}
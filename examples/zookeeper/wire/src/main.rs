@@ -0,0 +1,322 @@
+//! Serve the ZooKeeper wire protocol from the FuzzyLog-backed zookeeper example,
+//! so existing ZooKeeper clients can run against it unmodified.
+
+extern crate byteorder;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+extern crate zookeeper;
+
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+use zookeeper::LogHandle;
+
+use server::Server;
+
+pub mod jute;
+pub mod server;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "zk_wire", about = "A ZooKeeper wire-protocol server backed by the FuzzyLog.")]
+struct Args {
+    #[structopt(help = "FuzzyLog servers to run against.")]
+    servers: ServerAddrs,
+
+    #[structopt(short = "p", long = "port", help = "port to serve ZooKeeper clients on.", default_value = "2181")]
+    port: u16,
+
+    #[structopt(long = "client-num", help = "id of this server among the FuzzyLog's clients.", default_value = "1")]
+    client_num: usize,
+
+    #[structopt(long = "root", help = "the subtree this server owns.", default_value = "/foo")]
+    root: String,
+
+    #[structopt(long = "chain", help = "the chain the subtree is stored on.", default_value = "3")]
+    chain: u64,
+}
+
+fn main() {
+    let _ = env_logger::init();
+    let args: Args = StructOpt::from_args();
+
+    zookeeper::message::set_client_id(args.client_num as u32);
+    let listener = TcpListener::bind(&SocketAddr::new([0, 0, 0, 0].into(), args.port))
+        .expect("cannot bind");
+    serve(listener, args.servers.0, args.chain, args.root)
+}
+
+fn serve(listener: TcpListener, servers: Vec<(SocketAddr, SocketAddr)>, chain: u64, root: String) -> ! {
+    let color = chain.into();
+    let replicated = servers[0].0 != servers[0].1;
+    let (reader, writer) = if replicated {
+        LogHandle::<[u8]>::replicated_with_servers(&servers[..])
+    } else {
+        LogHandle::<[u8]>::unreplicated_with_servers(servers.iter().map(|&(a, _)| a))
+    }.chains(&[color])
+        .reads_my_writes()
+        .build_handles();
+    let roots = Some((root.clone(), color)).into_iter().collect();
+    Server::new(reader, writer, color, root, roots).run(listener)
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerAddrs(Vec<(SocketAddr, SocketAddr)>);
+
+impl FromStr for ServerAddrs {
+    type Err = std::string::ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ServerAddrs(
+            s.split('^').map(|t|{
+                let mut addrs = t.split('#').map(|s| {
+                    match SocketAddr::from_str(s) {
+                        Ok(addr) => addr,
+                        Err(e) => panic!("head parse err {} @ {}", e, s),
+                    }
+                });
+                let head = addrs.next().expect("no head");
+                let tail = if let Some(addr) = addrs.next() {
+                    addr
+                } else {
+                    head
+                };
+                assert!(addrs.next().is_none());
+                (head, tail)
+            }).collect()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate fuzzy_log_server;
+
+    use super::*;
+    use self::fuzzy_log_server::tcp::run_server;
+    use jute::{self, error, op, xid, Decoder, Encoder};
+
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::thread;
+
+    fn acl(e: &mut Encoder) -> &mut Encoder {
+        e.int(1).int(31).string("world").string("anyone")
+    }
+
+    fn send(stream: &mut TcpStream, e: &mut Encoder) {
+        stream.write_all(&e.finish()).unwrap();
+    }
+
+    fn recv(stream: &mut TcpStream) -> Vec<u8> {
+        jute::read_frame(stream).unwrap().expect("disconnected")
+    }
+
+    /// Start a log server on `log_port` and serve `/foo` from it on `zk_port`,
+    /// returns a connected ZooKeeper session.
+    fn start(log_port: u16, zk_port: u16, started: &'static AtomicUsize) -> TcpStream {
+        thread::spawn(move || {
+            run_server(
+                SocketAddr::new([0, 0, 0, 0].into(), log_port),
+                0,
+                1,
+                None,
+                None,
+                2,
+                started,
+            )
+        });
+
+        while started.load(Ordering::Relaxed) == 0 {
+            ::std::thread::yield_now()
+        }
+
+        let listener = TcpListener::bind(("127.0.0.1", zk_port)).unwrap();
+        thread::spawn(move || {
+            // the client id is global, so every test uses the same one
+            zookeeper::message::set_client_id(17);
+            let addr = SocketAddr::new([127, 0, 0, 1].into(), log_port);
+            serve(listener, vec![(addr, addr)], 3, "/foo".to_owned())
+        });
+
+        let mut stream = TcpStream::connect(("127.0.0.1", zk_port)).unwrap();
+
+        send(&mut stream, Encoder::new().int(0).long(0).int(10_000).long(0).buffer(&[0; 16]));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!(d.int().unwrap(), 0);
+        assert_eq!(d.int().unwrap(), 10_000);
+        assert!(d.long().unwrap() != 0);
+        stream
+    }
+
+    #[test]
+    fn zookeeper_client() {
+        static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+        let mut stream = start(14007, 13337, &STARTED);
+
+        let create = |stream: &mut TcpStream, xid, path: &str, data: &[u8]| {
+            let mut e = Encoder::new();
+            e.int(xid).int(op::CREATE).string(path).buffer(data);
+            acl(&mut e).int(0);
+            send(stream, &mut e);
+        };
+        create(&mut stream, 1, "/foo/a", &[1, 2, 3]);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!(d.int().unwrap(), 1);
+        d.long().unwrap();
+        assert_eq!(d.int().unwrap(), error::OK);
+        assert_eq!(d.string().unwrap(), "/foo/a");
+
+        create(&mut stream, 2, "/foo/a", &[]);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (2, true, error::NODE_EXISTS));
+
+        send(&mut stream, Encoder::new().int(3).int(op::GET_DATA).string("/foo/a").boolean(false));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (3, true, error::OK));
+        assert_eq!(d.buffer().unwrap(), Some(&[1, 2, 3][..]));
+        let stat = jute::Stat::decode(&mut d).unwrap();
+        assert_eq!((stat.version, stat.data_length), (0, 3));
+
+        send(&mut stream, Encoder::new().int(4).int(op::EXISTS).string("/foo/a").boolean(true));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (4, true, error::OK));
+
+        send(&mut stream, Encoder::new().int(5).int(op::SET_DATA).string("/foo/a").buffer(&[4]).int(0));
+        let (mut replied, mut notified) = (false, false);
+        while !(replied && notified) {
+            let frame = recv(&mut stream);
+            let mut d = Decoder::new(&frame);
+            match d.int().unwrap() {
+                xid::NOTIFICATION => {
+                    assert_eq!((d.long().unwrap(), d.int().unwrap()), (-1, error::OK));
+                    assert_eq!(d.int().unwrap(), jute::event::NODE_DATA_CHANGED);
+                    assert_eq!(d.int().unwrap(), jute::state::SYNC_CONNECTED);
+                    assert_eq!(d.string().unwrap(), "/foo/a");
+                    notified = true;
+                },
+                5 => {
+                    assert_eq!((d.long().is_ok(), d.int().unwrap()), (true, error::OK));
+                    assert_eq!(jute::Stat::decode(&mut d).unwrap().version, 1);
+                    replied = true;
+                },
+                other => panic!("unexpected xid {}", other),
+            }
+        }
+
+        // the check fails, so /foo/b must not be created
+        let mut e = Encoder::new();
+        e.int(6).int(op::MULTI)
+            .int(op::CHECK).boolean(false).int(-1).string("/foo/a").int(0)
+            .int(op::CREATE).boolean(false).int(-1).string("/foo/b").buffer(&[]);
+        acl(&mut e).int(0)
+            .int(-1).boolean(true).int(-1);
+        send(&mut stream, &mut e);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (6, true, error::OK));
+        for &err in &[error::BAD_VERSION, error::RUNTIME_INCONSISTENCY] {
+            assert_eq!((d.int().unwrap(), d.boolean().unwrap(), d.int().unwrap()), (op::ERROR, false, err));
+            assert_eq!(d.int().unwrap(), err);
+        }
+        assert_eq!((d.int().unwrap(), d.boolean().unwrap(), d.int().unwrap()), (-1, true, -1));
+
+        send(&mut stream, Encoder::new().int(7).int(op::EXISTS).string("/foo/b").boolean(false));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (7, true, error::NO_NODE));
+
+        send(&mut stream, Encoder::new().int(8).int(op::GET_CHILDREN).string("/foo").boolean(false));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (8, true, error::OK));
+        assert_eq!(d.strings().unwrap(), vec!["a".to_owned()]);
+    }
+
+    #[test]
+    fn multi() {
+        static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+        let mut stream = start(14008, 13338, &STARTED);
+
+        fn op_header(e: &mut Encoder, op: i32) -> &mut Encoder {
+            e.int(op).boolean(false).int(-1)
+        }
+        fn create(e: &mut Encoder, path: &str) {
+            op_header(e, op::CREATE).string(path).buffer(&[]);
+            acl(e).int(0);
+        }
+        fn result(d: &mut Decoder) -> (i32, bool, i32) {
+            (d.int().unwrap(), d.boolean().unwrap(), d.int().unwrap())
+        }
+
+        let mut e = Encoder::new();
+        e.int(1).int(op::MULTI);
+        create(&mut e, "/foo/a");
+        op_header(&mut e, op::SET_DATA).string("/foo/a").buffer(&[7]).int(0);
+        create(&mut e, "/foo/b");
+        e.int(-1).boolean(true).int(-1);
+        send(&mut stream, &mut e);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (1, true, error::OK));
+        assert_eq!(result(&mut d), (op::CREATE, false, error::OK));
+        assert_eq!(d.string().unwrap(), "/foo/a");
+        assert_eq!(result(&mut d), (op::SET_DATA, false, error::OK));
+        assert_eq!(jute::Stat::decode(&mut d).unwrap().version, 1);
+        assert_eq!(result(&mut d), (op::CREATE, false, error::OK));
+        assert_eq!(d.string().unwrap(), "/foo/b");
+        assert_eq!(result(&mut d), (-1, true, -1));
+
+        // /foo/a already exists, so /foo/b must not be deleted
+        let mut e = Encoder::new();
+        e.int(2).int(op::MULTI);
+        op_header(&mut e, op::DELETE).string("/foo/b").int(-1);
+        create(&mut e, "/foo/a");
+        e.int(-1).boolean(true).int(-1);
+        send(&mut stream, &mut e);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (2, true, error::OK));
+        for &err in &[error::OK, error::NODE_EXISTS] {
+            assert_eq!(result(&mut d), (op::ERROR, false, err));
+            assert_eq!(d.int().unwrap(), err);
+        }
+        assert_eq!(result(&mut d), (-1, true, -1));
+
+        send(&mut stream, Encoder::new().int(3).int(op::EXISTS).string("/foo/b").boolean(false));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (3, true, error::OK));
+
+        let mut e = Encoder::new();
+        e.int(4).int(op::MULTI);
+        op_header(&mut e, op::CHECK).string("/foo/a").int(1);
+        op_header(&mut e, op::DELETE).string("/foo/a").int(1);
+        op_header(&mut e, op::DELETE).string("/foo/b").int(0);
+        e.int(-1).boolean(true).int(-1);
+        send(&mut stream, &mut e);
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (4, true, error::OK));
+        for &kind in &[op::CHECK, op::DELETE, op::DELETE] {
+            assert_eq!(result(&mut d), (kind, false, error::OK));
+        }
+        assert_eq!(result(&mut d), (-1, true, -1));
+
+        send(&mut stream, Encoder::new().int(5).int(op::GET_CHILDREN).string("/foo").boolean(false));
+        let frame = recv(&mut stream);
+        let mut d = Decoder::new(&frame);
+        assert_eq!((d.int().unwrap(), d.long().is_ok(), d.int().unwrap()), (5, true, error::OK));
+        assert!(d.strings().unwrap().is_empty());
+    }
+}
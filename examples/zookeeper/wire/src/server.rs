@@ -0,0 +1,772 @@
+//! ZooKeeper sessions served from a single FuzzyLog-backed `zookeeper::Client`.
+//!
+//! Every ZooKeeper session shares the client's FuzzyLog session. We track the
+//! ZooKeeper sessions ourselves: their ephemeral nodes are ephemeral in the log
+//! too, so they go away if this server does, and we delete them when the
+//! ZooKeeper session closes or times out. Watches are likewise kept per
+//! ZooKeeper session here and set on the client, whose single watcher routes
+//! each event to every session watching the node it's about.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zookeeper::{self, AtomicWriteHandle, Client, CreateMode, MultiError, MultiOp, MultiResult,
+                ReadHandle, WatchedEvent, order};
+
+use jute::{self, error, event, op, state, xid, ConnectRequest, Decoder, Encoder, Request};
+
+pub type SessionId = i64;
+
+/// The bounds ZooKeeper puts on session timeouts with its default tick of 2s.
+const MIN_SESSION_TIMEOUT_MS: i32 = 4_000;
+const MAX_SESSION_TIMEOUT_MS: i32 = 40_000;
+
+const PROTOCOL_VERSION: i32 = 0;
+
+pub struct Server {
+    client: Mutex<Client>,
+    my_root: PathBuf,
+    state: Arc<Mutex<State>>,
+    next_session: AtomicUsize,
+    zxid: AtomicUsize,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<SessionId, Session>,
+    /// The session which owns each ephemeral node we created.
+    owners: HashMap<PathBuf, SessionId>,
+    data_watches: HashMap<PathBuf, HashSet<SessionId>>,
+    exist_watches: HashMap<PathBuf, HashSet<SessionId>>,
+    child_watches: HashMap<PathBuf, HashSet<SessionId>>,
+}
+
+struct Session {
+    timeout: Duration,
+    last_heard: Instant,
+    /// Where to send notifications, `None` while the session is disconnected.
+    outbox: Option<Sender<Vec<u8>>>,
+    ephemerals: HashSet<PathBuf>,
+    expired: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WatchKind {
+    Data,
+    Exist,
+    Child,
+}
+
+impl Server {
+    pub fn new(
+        reader: ReadHandle<[u8]>,
+        writer: AtomicWriteHandle<[u8]>,
+        color: order,
+        my_root: String,
+        roots: HashMap<String, order>,
+    ) -> Arc<Self> {
+        let state: Arc<Mutex<State>> = Default::default();
+        let watcher_state = state.clone();
+        let watcher = Box::new(move |event| watcher_state.lock().unwrap().on_event(event));
+        let roots = roots.into_iter().map(|(k, v)| (k.into(), v)).collect();
+        let client = Client::with_session(
+            reader,
+            writer,
+            color,
+            my_root.clone().into(),
+            roots,
+            Duration::from_millis(zookeeper::DEFAULT_SESSION_TIMEOUT_MS),
+            watcher,
+        );
+        let server = Arc::new(Server {
+            client: Mutex::new(client),
+            my_root: my_root.into(),
+            state,
+            next_session: AtomicUsize::new(1),
+            zxid: AtomicUsize::new(1),
+        });
+        let reaper = server.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(500));
+            reaper.expire_idle_sessions();
+        });
+        server
+    }
+
+    pub fn run(self: Arc<Self>, listener: TcpListener) -> ! {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("accept error {}", e);
+                    continue
+                },
+            };
+            let server = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr();
+                match server.serve(stream) {
+                    Ok(()) => trace!("{:?} disconnected", peer),
+                    Err(e) => error!("{:?} disconnected with {}", peer, e),
+                }
+            });
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let _ = stream.set_nodelay(true);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (outbox, to_send) = channel();
+        let writer = BufWriter::new(stream);
+        thread::spawn(move || write_frames(writer, to_send));
+
+        let connect = match jute::read_frame(&mut reader)? {
+            None => return Ok(()),
+            Some(frame) => ConnectRequest::decode(&mut Decoder::new(&frame))?,
+        };
+        let session = match self.connect(&connect, outbox.clone()) {
+            Some(session) => session,
+            None => {
+                // a timeout of 0 tells the client its session expired
+                let _ = outbox.send(connect_response(&connect, 0, 0));
+                return Ok(())
+            },
+        };
+        let timeout = self.state.lock().unwrap().sessions[&session].timeout;
+        let timeout = timeout.as_secs() as i32 * 1000 + timeout.subsec_nanos() as i32 / 1_000_000;
+        let _ = outbox.send(connect_response(&connect, timeout, session));
+        debug!("session {:#x} connected", session);
+
+        let result = self.handle_requests(session, &mut reader, &outbox);
+        if let Some(session) = self.state.lock().unwrap().sessions.get_mut(&session) {
+            session.outbox = None;
+            session.last_heard = Instant::now();
+        }
+        result
+    }
+
+    fn handle_requests<R: io::Read>(
+        &self, session: SessionId, reader: &mut R, outbox: &Sender<Vec<u8>>
+    ) -> io::Result<()> {
+        let mut reply = Encoder::new();
+        loop {
+            let frame = match jute::read_frame(reader)? {
+                None => return Ok(()),
+                Some(frame) => frame,
+            };
+            let mut d = Decoder::new(&frame);
+            let (xid, op) = (d.int()?, d.int()?);
+            let expired = match self.state.lock().unwrap().sessions.get_mut(&session) {
+                Some(ref session) if session.expired => true,
+                Some(session) => {
+                    session.last_heard = Instant::now();
+                    false
+                },
+                None => true,
+            };
+            if expired {
+                reply.reply_header(xid, self.zxid(), error::SESSION_EXPIRED);
+                let _ = outbox.send(reply.finish());
+                return Ok(())
+            }
+
+            let request = match Request::decode(op, &mut d) {
+                Ok(request) => request,
+                Err(e) => {
+                    reply.reply_header(xid, self.zxid(), error::MARSHALLING_ERROR);
+                    let _ = outbox.send(reply.finish());
+                    return Err(e)
+                },
+            };
+            let close = request == Request::Close;
+            self.handle_request(session, xid, request, &mut reply);
+            let _ = outbox.send(reply.finish());
+            if close {
+                return Ok(())
+            }
+        }
+    }
+
+    fn handle_request(&self, session: SessionId, xid: i32, request: Request, reply: &mut Encoder) {
+        use jute::Request::*;
+        trace!("session {:#x} {:?}", session, request);
+        match request {
+            Ping => { reply.reply_header(xid::PING, self.zxid(), error::OK); },
+
+            Close => {
+                self.end_session(session);
+                reply.reply_header(xid, self.bump_zxid(), error::OK);
+            },
+
+            Sync { path } => { reply.reply_header(xid, self.zxid(), error::OK).string(&path); },
+
+            SetWatches { data, exist, child } => {
+                for path in data {
+                    self.set_watch(session, WatchKind::Data, &path);
+                }
+                for path in exist {
+                    self.set_watch(session, WatchKind::Exist, &path);
+                }
+                for path in child {
+                    self.set_watch(session, WatchKind::Child, &path);
+                }
+                reply.reply_header(xid, self.zxid(), error::OK);
+            },
+
+            Exists { path, watch } => {
+                if watch {
+                    self.set_watch(session, WatchKind::Exist, &path);
+                }
+                match self.stat(&path) {
+                    Some(stat) => { reply.reply_header(xid, self.zxid(), error::OK).stat(&stat); },
+                    None => { reply.reply_header(xid, self.zxid(), error::NO_NODE); },
+                }
+            },
+
+            GetData { path, watch } => {
+                if watch {
+                    self.set_watch(session, WatchKind::Data, &path);
+                }
+                match self.get_data(&path) {
+                    Some((data, stat)) => {
+                        reply.reply_header(xid, self.zxid(), error::OK).buffer(&data).stat(&stat);
+                    },
+                    None => {
+                        self.clear_watch(session, WatchKind::Data, &path);
+                        reply.reply_header(xid, self.zxid(), error::NO_NODE);
+                    },
+                }
+            },
+
+            GetChildren { path, watch, with_stat } => {
+                if watch {
+                    self.set_watch(session, WatchKind::Child, &path);
+                }
+                match self.get_children(&path) {
+                    Some(children) => {
+                        reply.reply_header(xid, self.zxid(), error::OK).strings(&children);
+                        if with_stat {
+                            let stat = self.stat(&path).unwrap_or_default();
+                            reply.stat(&stat);
+                        }
+                    },
+                    None => {
+                        self.clear_watch(session, WatchKind::Child, &path);
+                        reply.reply_header(xid, self.zxid(), error::NO_NODE);
+                    },
+                }
+            },
+
+            Multi(ops) => {
+                let results = self.multi(session, ops);
+                reply.reply_header(xid, self.bump_zxid(), error::OK);
+                for result in results {
+                    match result {
+                        (op, Ok(ref body)) => {
+                            reply.int(op).boolean(false).int(error::OK);
+                            body.encode(reply);
+                        },
+                        (_, Err(err)) => {
+                            reply.int(op::ERROR).boolean(false).int(err).int(err);
+                        },
+                    }
+                }
+                reply.int(-1).boolean(true).int(-1);
+            },
+
+            Unimplemented(op) => {
+                debug!("unimplemented op {}", op);
+                reply.reply_header(xid, self.zxid(), error::UNIMPLEMENTED);
+            },
+
+            mutation => {
+                match self.mutate(session, mutation) {
+                    Ok(body) => {
+                        reply.reply_header(xid, self.bump_zxid(), error::OK);
+                        body.encode(reply);
+                    },
+                    Err(err) => { reply.reply_header(xid, self.zxid(), err); },
+                }
+            },
+        }
+    }
+
+    ////////////////////
+
+    /// Start or resume a session, `None` if the client asked to resume one which expired.
+    fn connect(&self, connect: &ConnectRequest, outbox: Sender<Vec<u8>>) -> Option<SessionId> {
+        let mut state = self.state.lock().unwrap();
+        if connect.session_id != 0 {
+            return match state.sessions.get_mut(&connect.session_id) {
+                Some(ref session) if session.expired => None,
+                Some(session) => {
+                    session.outbox = Some(outbox);
+                    session.last_heard = Instant::now();
+                    Some(connect.session_id)
+                },
+                None => None,
+            }
+        }
+        let timeout = connect.timeout.max(MIN_SESSION_TIMEOUT_MS).min(MAX_SESSION_TIMEOUT_MS);
+        let count = self.next_session.fetch_add(1, Ordering::Relaxed) as i64;
+        let id = ((zookeeper::client_id() as i64) << 32) | count;
+        state.sessions.insert(id, Session {
+            timeout: Duration::from_millis(timeout as u64),
+            last_heard: Instant::now(),
+            outbox: Some(outbox),
+            ephemerals: Default::default(),
+            expired: false,
+        });
+        Some(id)
+    }
+
+    fn expire_idle_sessions(&self) {
+        let now = Instant::now();
+        let idle: Vec<_> = self.state.lock().unwrap().sessions.iter()
+            .filter(|&(_, s)| !s.expired && now.duration_since(s.last_heard) > s.timeout)
+            .map(|(&id, _)| id)
+            .collect();
+        for session in idle {
+            debug!("session {:#x} expired", session);
+            self.end_session(session)
+        }
+    }
+
+    /// Delete the ephemeral nodes of a session, and forget about it.
+    fn end_session(&self, session: SessionId) {
+        let ephemerals = {
+            let mut state = self.state.lock().unwrap();
+            state.forget_watches(session);
+            match state.sessions.remove(&session) {
+                Some(session) => session.ephemerals,
+                None => return,
+            }
+        };
+        for path in ephemerals {
+            let _ = self.delete(&path, -1);
+            self.state.lock().unwrap().owners.remove(&path);
+        }
+    }
+
+    fn set_watch(&self, session: SessionId, kind: WatchKind, path: &str) {
+        self.state.lock().unwrap().watches(kind)
+            .entry(path.into()).or_insert_with(Default::default).insert(session);
+        // the client keeps its own watches, which trigger ours
+        let (done, wait) = channel();
+        {
+            let mut client = self.client.lock().unwrap();
+            match kind {
+                WatchKind::Data | WatchKind::Exist => client.exists(path.into(), true,
+                    Box::new(move |_| { let _ = done.send(()); })),
+                WatchKind::Child => client.get_children(path.into(), true,
+                    Box::new(move |_| { let _ = done.send(()); })),
+            }
+        }
+        let _ = wait.recv();
+    }
+
+    fn clear_watch(&self, session: SessionId, kind: WatchKind, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        let now_empty = match state.watches(kind).get_mut(Path::new(path)) {
+            Some(sessions) => {
+                sessions.remove(&session);
+                sessions.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            state.watches(kind).remove(Path::new(path));
+        }
+    }
+
+    ////////////////////
+
+    fn mutate(&self, session: SessionId, request: Request) -> Result<Body, i32> {
+        use jute::Request::*;
+        match request {
+            Create { path, data, flags, with_stat } => {
+                let create_mode = create_mode(flags)?;
+                let created = self.create(&path, data, create_mode)?;
+                Ok(self.created(session, created, create_mode, with_stat))
+            },
+
+            Delete { path, version } => {
+                self.delete(Path::new(&path), version)?;
+                self.deleted(Path::new(&path));
+                Ok(Body::Empty)
+            },
+
+            SetData { path, data, version } => {
+                self.set_data(&path, data, version)?;
+                Ok(Body::Stat(self.stat(&path).unwrap_or_default()))
+            },
+
+            Check { path, version } => match self.stat(&path) {
+                None => Err(error::NO_NODE),
+                Some(ref stat) if version != -1 && stat.version != version => Err(error::BAD_VERSION),
+                Some(..) => Ok(Body::Empty),
+            },
+
+            _ => Err(error::BAD_ARGUMENTS),
+        }
+    }
+
+    /// The ops of a multi are appended as one entry, and take effect all
+    /// together or not at all.
+    fn multi(&self, session: SessionId, ops: Vec<Request>) -> Vec<(i32, Result<Body, i32>)> {
+        use jute::Request::*;
+        let mut multi_ops = Vec::with_capacity(ops.len());
+        for (i, request) in ops.iter().enumerate() {
+            let op = match *request {
+                Create { ref path, ref data, flags, .. } => match create_mode(flags) {
+                    Ok(create_mode) => MultiOp::Create {
+                        create_mode,
+                        path: path.into(),
+                        data: data.clone(),
+                    },
+                    Err(err) => return failed_multi(&ops, i, err),
+                },
+                Delete { ref path, version } =>
+                    MultiOp::Delete { path: path.into(), version: version as i64 },
+                SetData { ref path, ref data, version } =>
+                    MultiOp::Set { path: path.into(), data: data.clone(), version: version as i64 },
+                Check { ref path, version } =>
+                    MultiOp::Check { path: path.into(), version: version as i64 },
+                _ => return failed_multi(&ops, i, error::BAD_ARGUMENTS),
+            };
+            multi_ops.push(op);
+        }
+
+        let (done, wait) = channel();
+        self.client.lock().unwrap().multi(multi_ops, Box::new(move |outcome| {
+            let _ = done.send(outcome);
+        }));
+        let results = match wait.recv() {
+            Ok(Ok(results)) => results,
+            Ok(Err((failed_at, err))) => return failed_multi(&ops, failed_at, multi_error(err)),
+            Err(..) => return failed_multi(&ops, 0, error::SYSTEM_ERROR),
+        };
+
+        ops.into_iter().zip(results).map(|(request, result)| {
+            let op = request.op();
+            let body = match (request, result) {
+                (Create { flags, with_stat, .. }, MultiResult::Created(created)) => {
+                    let create_mode = create_mode(flags).expect("checked create mode");
+                    let created = created.to_string_lossy().into_owned();
+                    self.created(session, created, create_mode, with_stat)
+                },
+                (Delete { path, .. }, MultiResult::Deleted) => {
+                    self.deleted(Path::new(&path));
+                    Body::Empty
+                },
+                (SetData { path, .. }, MultiResult::Set(..)) =>
+                    Body::Stat(self.stat(&path).unwrap_or_default()),
+                (Check { .. }, MultiResult::Checked) => Body::Empty,
+                (request, result) => panic!("{:?} for {:?}", result, request),
+            };
+            (op, Ok(body))
+        }).collect()
+    }
+
+    /// Track who owns a node we created, and build the reply for it.
+    fn created(&self, session: SessionId, created: String, create_mode: CreateMode, with_stat: bool)
+    -> Body {
+        if create_mode.is_ephemeral() {
+            let mut state = self.state.lock().unwrap();
+            state.owners.insert(created.clone().into(), session);
+            match state.sessions.get_mut(&session) {
+                Some(session) => { session.ephemerals.insert(created.clone().into()); },
+                None => {},
+            }
+        }
+        let stat = if with_stat { Some(self.stat(&created).unwrap_or_default()) } else { None };
+        Body::Create(created, stat)
+    }
+
+    fn deleted(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(owner) = state.owners.remove(path) {
+            if let Some(session) = state.sessions.get_mut(&owner) {
+                session.ephemerals.remove(path);
+            }
+        }
+    }
+
+    ////////////////////
+    // blocking wrappers around the client
+
+    fn in_my_root(&self, path: &Path) -> bool {
+        path.starts_with(&self.my_root)
+    }
+
+    fn create(&self, path: &str, data: Vec<u8>, create_mode: CreateMode) -> Result<String, i32> {
+        if !self.in_my_root(Path::new(path)) || path == self.my_root.to_string_lossy() {
+            return Err(error::NO_NODE)
+        }
+        let (done, wait) = channel();
+        self.client.lock().unwrap().create(path.into(), data, create_mode, Box::new(move |res| {
+            let _ = done.send(res.map(|(p, _)| p.to_string_lossy().into_owned()));
+        }));
+        match wait.recv() {
+            Ok(Ok(created)) => Ok(created),
+            _ => {
+                // the state machine doesn't tell us why, so we look
+                if !create_mode.is_sequential() && self.stat(path).is_some() {
+                    return Err(error::NODE_EXISTS)
+                }
+                let parent = Path::new(path).parent().map(|p| p.to_string_lossy().into_owned());
+                match parent.and_then(|p| self.stat(&p)) {
+                    None => Err(error::NO_NODE),
+                    Some(ref parent) if parent.ephemeral_owner != 0 =>
+                        Err(error::NO_CHILDREN_FOR_EPHEMERALS),
+                    Some(..) => Err(error::SYSTEM_ERROR),
+                }
+            },
+        }
+    }
+
+    fn delete(&self, path: &Path, version: i32) -> Result<(), i32> {
+        if !self.in_my_root(path) {
+            return Err(error::NO_NODE)
+        }
+        let (done, wait) = channel();
+        self.client.lock().unwrap().delete(path.into(), version as i64, Box::new(move |res| {
+            let _ = done.send(res.is_ok());
+        }));
+        if wait.recv().unwrap_or(false) {
+            return Ok(())
+        }
+        let path = path.to_string_lossy();
+        match self.stat(&path) {
+            None => Err(error::NO_NODE),
+            Some(ref stat) if stat.num_children > 0 => Err(error::NOT_EMPTY),
+            Some(..) => Err(error::BAD_VERSION),
+        }
+    }
+
+    fn set_data(&self, path: &str, data: Vec<u8>, version: i32) -> Result<(), i32> {
+        if !self.in_my_root(Path::new(path)) {
+            return Err(error::NO_NODE)
+        }
+        let (done, wait) = channel();
+        self.client.lock().unwrap().set_data(path.into(), data, version as i64, Box::new(move |res| {
+            let _ = done.send(res.is_ok());
+        }));
+        if wait.recv().unwrap_or(false) {
+            return Ok(())
+        }
+        match self.stat(path) {
+            None => Err(error::NO_NODE),
+            Some(..) => Err(error::BAD_VERSION),
+        }
+    }
+
+    fn get_data(&self, path: &str) -> Option<(Vec<u8>, jute::Stat)> {
+        let (done, wait) = channel();
+        self.client.lock().unwrap().get_data(path.into(), false, Box::new(move |res| {
+            let _ = done.send(res.map(|(_, data, &stat)| (data.to_vec(), stat)).ok());
+        }));
+        let (data, stat) = match wait.recv() {
+            Ok(Some(found)) => found,
+            _ => return None,
+        };
+        let num_children = self.get_children(path).map(|c| c.len()).unwrap_or(0);
+        let stat = self.jute_stat(path, &stat, data.len(), num_children);
+        Some((data, stat))
+    }
+
+    /// The names of the children of `path`.
+    fn get_children(&self, path: &str) -> Option<Vec<String>> {
+        let (done, wait) = channel();
+        self.client.lock().unwrap().get_children(path.into(), false, Box::new(move |res| {
+            let children = res.map(|(_, children)| {
+                children.filter_map(|c| c.file_name()).map(|c| c.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+            });
+            let _ = done.send(children.ok());
+        }));
+        wait.recv().ok().and_then(|r| r)
+    }
+
+    fn stat(&self, path: &str) -> Option<jute::Stat> {
+        self.get_data(path).map(|(_, stat)| stat)
+    }
+
+    fn jute_stat(&self, path: &str, stat: &zookeeper::Stat, data_len: usize, num_children: usize)
+    -> jute::Stat {
+        let ephemeral_owner = self.state.lock().unwrap().owners.get(Path::new(path)).cloned()
+            .unwrap_or(stat.ephemeral_owner as i64);
+        jute::Stat {
+            czxid: stat.create_time as i64,
+            mzxid: stat.mutate_time as i64,
+            version: stat.version as i32,
+            ephemeral_owner,
+            data_length: data_len as i32,
+            num_children: num_children as i32,
+            ..jute::Stat::default()
+        }
+    }
+
+    fn zxid(&self) -> i64 {
+        self.zxid.load(Ordering::Relaxed) as i64
+    }
+
+    fn bump_zxid(&self) -> i64 {
+        self.zxid.fetch_add(1, Ordering::Relaxed) as i64 + 1
+    }
+}
+
+impl State {
+    fn watches(&mut self, kind: WatchKind) -> &mut HashMap<PathBuf, HashSet<SessionId>> {
+        match kind {
+            WatchKind::Data => &mut self.data_watches,
+            WatchKind::Exist => &mut self.exist_watches,
+            WatchKind::Child => &mut self.child_watches,
+        }
+    }
+
+    fn on_event(&mut self, event: WatchedEvent) {
+        use self::WatchKind::*;
+        let (event_type, path, kinds): (_, _, &[_]) = match event {
+            WatchedEvent::NodeCreated(path) => (event::NODE_CREATED, path, &[Exist]),
+            WatchedEvent::NodeDeleted(path) => (event::NODE_DELETED, path, &[Data, Exist, Child]),
+            WatchedEvent::NodeDataChanged(path) => (event::NODE_DATA_CHANGED, path, &[Data, Exist]),
+            WatchedEvent::NodeChildrenChanged(path) => (event::NODE_CHILDREN_CHANGED, path, &[Child]),
+            WatchedEvent::SessionExpired => {
+                // our session in the log is gone, and with it every session we serve
+                for (_, session) in &mut self.sessions {
+                    session.expired = true;
+                    if let Some(ref outbox) = session.outbox {
+                        let _ = outbox.send(notification(event::NONE, state::EXPIRED, ""));
+                    }
+                }
+                return
+            },
+        };
+        let mut to_notify = HashSet::new();
+        for &kind in kinds {
+            if let Some(sessions) = self.watches(kind).remove(&path) {
+                to_notify.extend(sessions)
+            }
+        }
+        let path = path.to_string_lossy();
+        for session in to_notify {
+            let outbox = self.sessions.get(&session).and_then(|s| s.outbox.as_ref());
+            if let Some(outbox) = outbox {
+                let _ = outbox.send(notification(event_type, state::SYNC_CONNECTED, &path));
+            }
+        }
+    }
+
+    fn forget_watches(&mut self, session: SessionId) {
+        for &kind in &[WatchKind::Data, WatchKind::Exist, WatchKind::Child] {
+            let watches = self.watches(kind);
+            for sessions in watches.values_mut() {
+                sessions.remove(&session);
+            }
+            watches.retain(|_, sessions| !sessions.is_empty());
+        }
+    }
+}
+
+/// The body of a successful mutation's reply.
+enum Body {
+    Create(String, Option<jute::Stat>),
+    Stat(jute::Stat),
+    Empty,
+}
+
+impl Body {
+    fn encode(&self, reply: &mut Encoder) {
+        match *self {
+            Body::Create(ref path, ref stat) => {
+                reply.string(path);
+                if let Some(ref stat) = *stat {
+                    reply.stat(stat);
+                }
+            },
+            Body::Stat(ref stat) => { reply.stat(stat); },
+            Body::Empty => {},
+        }
+    }
+}
+
+fn create_mode(flags: i32) -> Result<CreateMode, i32> {
+    match flags {
+        0 => Ok(CreateMode::persistent()),
+        1 => Ok(CreateMode::ephemeral()),
+        2 => Ok(CreateMode::persistent_sequential()),
+        3 => Ok(CreateMode::ephemeral_sequential()),
+        // we don't clean up containers, so they're just persistent nodes
+        4 => Ok(CreateMode::persistent()),
+        _ => Err(error::BAD_ARGUMENTS),
+    }
+}
+
+fn multi_error(err: MultiError) -> i32 {
+    match err {
+        MultiError::NoNode => error::NO_NODE,
+        MultiError::NodeExists => error::NODE_EXISTS,
+        MultiError::BadVersion => error::BAD_VERSION,
+        MultiError::NotEmpty => error::NOT_EMPTY,
+        MultiError::NoChildrenForEphemerals => error::NO_CHILDREN_FOR_EPHEMERALS,
+        MultiError::SessionExpired => error::SESSION_EXPIRED,
+        MultiError::RenamePending => error::SYSTEM_ERROR,
+    }
+}
+
+/// The results of a multi whose op at `failed_at` failed with `err`,
+/// like ZooKeeper we report the ops before it as having been fine.
+fn failed_multi(ops: &[Request], failed_at: usize, err: i32) -> Vec<(i32, Result<Body, i32>)> {
+    ops.iter().enumerate().map(|(i, request)| {
+        let err = if i < failed_at {
+            error::OK
+        } else if i == failed_at {
+            err
+        } else {
+            error::RUNTIME_INCONSISTENCY
+        };
+        (request.op(), Err(err))
+    }).collect()
+}
+
+fn connect_response(connect: &ConnectRequest, timeout: i32, session: SessionId) -> Vec<u8> {
+    let mut response = Encoder::new();
+    // we don't authenticate, so the password is always empty
+    response.int(PROTOCOL_VERSION).int(timeout).long(session).buffer(&[0; 16]);
+    if connect.read_only.is_some() {
+        response.boolean(false);
+    }
+    response.finish()
+}
+
+fn notification(event_type: i32, state: i32, path: &str) -> Vec<u8> {
+    Encoder::new()
+        .reply_header(xid::NOTIFICATION, -1, error::OK)
+        .int(event_type).int(state).string(path)
+        .finish()
+}
+
+fn write_frames(mut writer: BufWriter<TcpStream>, frames: Receiver<Vec<u8>>) {
+    for frame in frames.iter() {
+        if jute::write_frame(&mut writer, &frame).is_err() {
+            return
+        }
+        // batch up whatever else is ready before flushing
+        for frame in frames.try_iter() {
+            if jute::write_frame(&mut writer, &frame).is_err() {
+                return
+            }
+        }
+        if writer.flush().is_err() {
+            return
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! A catalog of named colors, stored in the log itself.
+//!
+//! Creating or deleting a name appends a record to a reserved system color,
+//! and every client replays those records to build the same catalog.
+//! Colors are assigned in log order from a range reserved for named colors,
+//! and are never reused, so a deleted name's entries can't be mistaken for
+//! those of a later color.
+//!
+//! Names are `/` separated paths, the components before the last form its
+//! namespace, eg. `billing/invoices` is in the namespace `billing`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use fuzzy_log::log_handle::{order, GetRes, LogHandle, TryWaitRes, Uuid};
+
+/// The color the catalog is stored in.
+pub const CATALOG_COLOR: u64 = 0x7fff_ffff;
+
+/// Named colors are assigned from here up to `CATALOG_COLOR`,
+/// colors below are free for applications to number themselves.
+pub const FIRST_NAMED_COLOR: u64 = 0x4000_0000;
+
+pub fn catalog_color() -> order {
+    order::from(CATALOG_COLOR)
+}
+
+/// The name of a color in the catalog, for use where an `order` is expected,
+/// eg. in `LogBuilder::chains`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ColorName(pub String);
+
+impl<'a> From<&'a str> for ColorName {
+    fn from(name: &'a str) -> Self {
+        ColorName(name.to_owned())
+    }
+}
+
+impl From<String> for ColorName {
+    fn from(name: String) -> Self {
+        ColorName(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Chain {
+    Color(order),
+    Named(String),
+}
+
+/// Either a color or the name of one.
+/// Names are only taken by value, a `&ColorName` would make `&[1.into()]` ambiguous.
+pub trait IntoChain {
+    fn into_chain(self) -> Chain;
+}
+
+impl<O: Borrow<order>> IntoChain for O {
+    fn into_chain(self) -> Chain {
+        Chain::Color(*self.borrow())
+    }
+}
+
+impl IntoChain for ColorName {
+    fn into_chain(self) -> Chain {
+        Chain::Named(self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Retention {
+    Forever,
+    /// Keep the latest `n` entries.
+    Entries(u64),
+    /// Keep entries for this many seconds after they were appended.
+    Seconds(u64),
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention::Forever
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ColorMeta {
+    pub owner: String,
+    /// How many servers should store the color, 0 for the log's default.
+    pub replicas: u32,
+    pub retention: Retention,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ColorInfo {
+    pub name: String,
+    pub color: order,
+    pub meta: ColorMeta,
+    created_by: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CatalogError {
+    /// The name was already taken, by this color.
+    Exists(ColorInfo),
+    InvalidName,
+    NotFound,
+    /// Every color in the named range has been assigned.
+    Exhausted,
+    Read(GetRes),
+    Write(TryWaitRes),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CatalogError::Exists(ref info) =>
+                write!(f, "{} already names color {:?}", info.name, info.color),
+            CatalogError::InvalidName => write!(f, "invalid color name"),
+            CatalogError::NotFound => write!(f, "no such color"),
+            CatalogError::Exhausted => write!(f, "no more colors to name"),
+            CatalogError::Read(ref e) => write!(f, "could not read the catalog: {:?}", e),
+            CatalogError::Write(ref e) => write!(f, "could not write the catalog: {:?}", e),
+        }
+    }
+}
+
+/// A client of the catalog.
+///
+/// The catalog reads with its own handle, which should not be used for
+/// anything else, so that other colors' entries are not mixed in with it.
+pub struct Catalog {
+    handle: LogHandle<[u8]>,
+    registry: Registry,
+    buffer: Vec<u8>,
+}
+
+impl Catalog {
+    pub fn new(handle: LogHandle<[u8]>) -> Self {
+        Catalog {
+            handle,
+            registry: Registry::new(),
+            buffer: vec![],
+        }
+    }
+
+    /// Catch up with every record appended before this call.
+    pub fn sync(&mut self) -> Result<(), CatalogError> {
+        self.handle.snapshot(catalog_color());
+        loop {
+            match self.handle.get_next2() {
+                Ok((bytes, _, &id)) => match Record::decode(bytes) {
+                    Some(record) => self.registry.apply(record, id),
+                    None => warn!("skipping corrupt catalog record {:?}", id),
+                },
+                Err(GetRes::Done) => return Ok(()),
+                Err(e) => return Err(CatalogError::Read(e)),
+            }
+        }
+    }
+
+    /// Name a new color.
+    pub fn create(&mut self, name: &str, meta: ColorMeta) -> Result<ColorInfo, CatalogError> {
+        if !valid_name(name) {
+            return Err(CatalogError::InvalidName)
+        }
+        let id = self.append(&Record::Create { name: name.to_owned(), meta })?;
+        self.sync()?;
+        match self.registry.by_name.get(name) {
+            Some(info) if info.created_by == id => Ok(info.clone()),
+            Some(info) => Err(CatalogError::Exists(info.clone())),
+            None if self.registry.exhausted() => Err(CatalogError::Exhausted),
+            // someone deleted it right after we created it
+            None => Err(CatalogError::NotFound),
+        }
+    }
+
+    /// Remove a name, its color is not reused.
+    pub fn delete(&mut self, name: &str) -> Result<(), CatalogError> {
+        if !valid_name(name) {
+            return Err(CatalogError::InvalidName)
+        }
+        self.append(&Record::Delete { name: name.to_owned() })?;
+        self.sync()
+    }
+
+    /// Look up a name as of the last sync.
+    pub fn lookup(&self, name: &str) -> Option<&ColorInfo> {
+        self.registry.by_name.get(name)
+    }
+
+    /// Sync, then look up a name.
+    pub fn lookup_fresh(&mut self, name: &str) -> Result<ColorInfo, CatalogError> {
+        self.sync()?;
+        self.lookup(name).cloned().ok_or(CatalogError::NotFound)
+    }
+
+    /// The colors in `namespace` and the namespaces within it, as of the last sync,
+    /// ordered by name. The empty namespace lists everything.
+    pub fn list(&self, namespace: &str) -> Vec<&ColorInfo> {
+        self.registry.list(namespace)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<Uuid, CatalogError> {
+        self.buffer.clear();
+        record.encode(&mut self.buffer);
+        let id = self.handle.async_append(catalog_color(), &self.buffer[..], &[]);
+        self.handle.wait_for_a_specific_append(id).map_err(CatalogError::Write)?;
+        Ok(id)
+    }
+}
+
+/// The state built from the catalog's records.
+#[derive(Debug)]
+struct Registry {
+    by_name: BTreeMap<String, ColorInfo>,
+    next_color: u64,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Registry {
+            by_name: BTreeMap::new(),
+            next_color: FIRST_NAMED_COLOR,
+        }
+    }
+
+    fn apply(&mut self, record: Record, id: Uuid) {
+        match record {
+            Record::Create { name, meta } => {
+                if self.by_name.contains_key(&name) || self.exhausted() || !valid_name(&name) {
+                    return
+                }
+                let color = order::from(self.next_color);
+                self.next_color += 1;
+                self.by_name.insert(name.clone(), ColorInfo { name, color, meta, created_by: id });
+            },
+            Record::Delete { name } => {
+                self.by_name.remove(&name);
+            },
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.next_color >= CATALOG_COLOR
+    }
+
+    fn list(&self, namespace: &str) -> Vec<&ColorInfo> {
+        let namespace = namespace.trim_right_matches('/');
+        if namespace.is_empty() {
+            return self.by_name.values().collect()
+        }
+        let prefix = format!("{}/", namespace);
+        self.by_name.range(prefix.clone()..)
+            .take_while(|&(name, _)| name.starts_with(&prefix))
+            .map(|(_, info)| info)
+            .collect()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.split('/').all(|component| !component.is_empty())
+}
+
+////////////////////
+// The records are encoded by hand since the client doesn't depend on serde.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Create { name: String, meta: ColorMeta },
+    Delete { name: String },
+}
+
+const CREATE: u8 = 1;
+const DELETE: u8 = 2;
+
+const FOREVER: u8 = 0;
+const ENTRIES: u8 = 1;
+const SECONDS: u8 = 2;
+
+impl Record {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            Record::Create { ref name, ref meta } => {
+                buffer.push(CREATE);
+                put_str(buffer, name);
+                put_str(buffer, &meta.owner);
+                put_u64(buffer, meta.replicas as u64);
+                match meta.retention {
+                    Retention::Forever => { buffer.push(FOREVER); put_u64(buffer, 0) },
+                    Retention::Entries(n) => { buffer.push(ENTRIES); put_u64(buffer, n) },
+                    Retention::Seconds(s) => { buffer.push(SECONDS); put_u64(buffer, s) },
+                }
+            },
+            Record::Delete { ref name } => {
+                buffer.push(DELETE);
+                put_str(buffer, name);
+            },
+        }
+    }
+
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let bytes = &mut bytes;
+        match take_u8(bytes)? {
+            CREATE => {
+                let name = take_str(bytes)?;
+                let owner = take_str(bytes)?;
+                let replicas = take_u64(bytes)? as u32;
+                let retention = match (take_u8(bytes)?, take_u64(bytes)?) {
+                    (FOREVER, _) => Retention::Forever,
+                    (ENTRIES, n) => Retention::Entries(n),
+                    (SECONDS, s) => Retention::Seconds(s),
+                    _ => return None,
+                };
+                Some(Record::Create { name, meta: ColorMeta { owner, replicas, retention } })
+            },
+            DELETE => Some(Record::Delete { name: take_str(bytes)? }),
+            _ => None,
+        }
+    }
+}
+
+fn put_u64(buffer: &mut Vec<u8>, n: u64) {
+    for i in 0..8 {
+        buffer.push((n >> (i * 8)) as u8)
+    }
+}
+
+fn put_str(buffer: &mut Vec<u8>, s: &str) {
+    put_u64(buffer, s.len() as u64);
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn take_u8(bytes: &mut &[u8]) -> Option<u8> {
+    let (&b, rest) = bytes.split_first()?;
+    *bytes = rest;
+    Some(b)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    if bytes.len() < 8 {
+        return None
+    }
+    let n = bytes[..8].iter().rev().fold(0, |n, &b| (n << 8) | b as u64);
+    *bytes = &bytes[8..];
+    Some(n)
+}
+
+fn take_str(bytes: &mut &[u8]) -> Option<String> {
+    let len = take_u64(bytes)? as usize;
+    if bytes.len() < len {
+        return None
+    }
+    let s = String::from_utf8(bytes[..len].to_vec()).ok()?;
+    *bytes = &bytes[len..];
+    Some(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create(name: &str) -> Record {
+        Record::Create { name: name.to_owned(), meta: ColorMeta::default() }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = vec![
+            create("a/b"),
+            Record::Create {
+                name: "logs".to_owned(),
+                meta: ColorMeta { owner: "ops".to_owned(), replicas: 3, retention: Retention::Seconds(60) },
+            },
+            Record::Delete { name: "a/b".to_owned() },
+        ];
+        for record in records {
+            let mut bytes = vec![];
+            record.encode(&mut bytes);
+            assert_eq!(Record::decode(&bytes), Some(record));
+            assert_eq!(Record::decode(&bytes[..bytes.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn names_are_assigned_in_log_order() {
+        let mut registry = Registry::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        registry.apply(create("app/events"), first);
+        registry.apply(create("app/events"), second);
+        registry.apply(create("app/users"), second);
+        registry.apply(create("other"), second);
+        registry.apply(create("app//bad"), second);
+        assert_eq!(registry.by_name["app/events"].created_by, first);
+        assert_eq!(registry.by_name["app/events"].color, order::from(FIRST_NAMED_COLOR));
+        assert_eq!(registry.by_name["app/users"].color, order::from(FIRST_NAMED_COLOR + 1));
+
+        let names: Vec<_> = registry.list("app/").iter().map(|i| &*i.name).collect();
+        assert_eq!(names, vec!["app/events", "app/users"]);
+        assert_eq!(registry.list("").len(), 3);
+        assert!(registry.list("ap").is_empty());
+
+        registry.apply(Record::Delete { name: "app/events".to_owned() }, first);
+        registry.apply(create("app/events"), second);
+        assert_eq!(registry.by_name["app/events"].color, order::from(FIRST_NAMED_COLOR + 3));
+    }
+}
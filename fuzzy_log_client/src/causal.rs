@@ -0,0 +1,196 @@
+//! Blocking queries over the causal graph formed by entries' dependencies.
+//!
+//! An entry's causal past is found by following the `deps` stored in the
+//! entries themselves, while the entries which depend on an entry are found
+//! using the reverse index each server maintains for the chains it stores.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use packets::*;
+use packets::Packet::WrapErr;
+use packets::hello::{self, Hello, Rejection};
+
+use hash::HashSet;
+
+use fuzzy_log_util::socket_addr::Ipv4SocketAddr;
+
+pub struct CausalQueries {
+    id: Ipv4SocketAddr,
+    //the write servers followed by the read servers if the log is replicated
+    servers: Vec<TcpStream>,
+    num_chain_servers: usize,
+    is_unreplicated: bool,
+    buffer: Vec<u8>,
+}
+
+impl CausalQueries {
+    pub fn unreplicated<I>(chain_servers: I) -> io::Result<Self>
+    where I: IntoIterator<Item=SocketAddr> {
+        let servers: Vec<_> = chain_servers.into_iter()
+            .map(TcpStream::connect)
+            .collect::<Result<_, _>>()?;
+        let num_chain_servers = servers.len();
+        Self::build(servers, num_chain_servers, true)
+    }
+
+    pub fn replicated<I>(chain_servers: I) -> io::Result<Self>
+    where I: IntoIterator<Item=(SocketAddr, SocketAddr)> {
+        let (write_servers, read_servers): (Vec<_>, Vec<_>) =
+            chain_servers.into_iter().unzip();
+        let num_chain_servers = write_servers.len();
+        let servers = write_servers.into_iter()
+            .chain(read_servers.into_iter())
+            .map(TcpStream::connect)
+            .collect::<Result<_, _>>()?;
+        Self::build(servers, num_chain_servers, false)
+    }
+
+    fn build(mut servers: Vec<TcpStream>, num_chain_servers: usize, is_unreplicated: bool)
+    -> io::Result<Self> {
+        assert!(num_chain_servers > 0);
+        let id = Ipv4SocketAddr::random();
+        let ours = Hello::current(0);
+        let mut versioned = vec![false; servers.len()];
+        for (stream, versioned) in servers.iter_mut().zip(versioned.iter_mut()) {
+            let _ = stream.set_nodelay(true);
+            let mut server_first = [0];
+            stream.read_exact(&mut server_first)?;
+            let (bytes, sends_reply) = hello::peer_hello_bytes(server_first[0], 2, &ours)
+                .map_err(rejected)?;
+            *versioned = sends_reply;
+            stream.write_all(&bytes)?;
+            stream.write_all(id.bytes())?;
+        }
+        let mut ack = [0; 16];
+        let mut reply = [0; hello::HELLO_REPLY_SIZE];
+        for (stream, &versioned) in servers.iter_mut().zip(versioned.iter()) {
+            if versioned {
+                stream.read_exact(&mut reply[..])?;
+                hello::check_reply(&ours, &reply).map_err(rejected)?;
+            }
+            stream.read_exact(&mut ack[..])?;
+            if Ipv4SocketAddr::from_bytes(ack) != id {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad handshake"))
+            }
+        }
+        Ok(CausalQueries {
+            id, servers, num_chain_servers, is_unreplicated, buffer: Vec::new(),
+        })
+    }
+
+    /// The dependencies of the entry at `loc`,
+    /// `None` if there is no entry there yet.
+    pub fn dependencies_of(&mut self, loc: OrderIndex) -> io::Result<Option<Vec<OrderIndex>>> {
+        let write_server = u64::from(loc.0) as usize % self.num_chain_servers;
+        let mut read = Vec::new();
+        EntryContents::Read{
+            id: &Uuid::nil(),
+            flags: &EntryFlag::Nothing,
+            data_bytes: &0,
+            dependency_bytes: &0,
+            loc: &loc,
+            horizon: &OrderIndex(0.into(), 0.into()),
+            min: &OrderIndex(0.into(), 0.into()),
+        }.fill_vec(&mut read);
+        let server = self.read_server(write_server);
+        self.send_to(server, &read)?;
+        self.recv_from(server)?;
+        let contents = self.contents();
+        if !contents.flag().contains(EntryFlag::ReadSuccess) {
+            return Ok(None)
+        }
+        Ok(Some(contents.dependencies().to_vec()))
+    }
+
+    /// The entries `loc` transitively depends on, nearest first,
+    /// following at most `max_depth` levels of dependencies.
+    pub fn causal_past(&mut self, loc: OrderIndex, max_depth: usize)
+    -> io::Result<Vec<OrderIndex>> {
+        let mut past = Vec::new();
+        let mut seen = HashSet::default();
+        let mut to_visit = VecDeque::new();
+        seen.insert(loc);
+        to_visit.push_back((loc, 0));
+        while let Some((loc, depth)) = to_visit.pop_front() {
+            if depth >= max_depth {
+                continue
+            }
+            let deps = match self.dependencies_of(loc)? {
+                Some(deps) => deps,
+                None => continue,
+            };
+            for dep in deps {
+                //a dependency on entry 0 is a read of an empty color
+                if dep.1 == entry::from(0) || !seen.insert(dep) {
+                    continue
+                }
+                past.push(dep);
+                to_visit.push_back((dep, depth + 1));
+            }
+        }
+        Ok(past)
+    }
+
+    /// The entries which list `loc` among their dependencies, sorted.
+    pub fn cited_by(&mut self, loc: OrderIndex) -> io::Result<Vec<OrderIndex>> {
+        let mut query = Vec::new();
+        EntryContents::CitedBy{
+            id: &Uuid::new_v4(),
+            flags: &EntryFlag::Nothing,
+            loc: &loc,
+            citations: &[],
+        }.fill_vec(&mut query);
+        //entries on any server may cite `loc`, so every server must be asked
+        let mut citations = Vec::new();
+        for server in 0..self.num_chain_servers {
+            self.send_to(server, &query)?;
+            let read_server = self.read_server(server);
+            self.recv_from(read_server)?;
+            citations.extend_from_slice(self.contents().citations());
+        }
+        citations.sort();
+        citations.dedup();
+        Ok(citations)
+    }
+
+    fn read_server(&self, write_server: usize) -> usize {
+        if self.is_unreplicated {
+            write_server
+        } else {
+            write_server + self.num_chain_servers
+        }
+    }
+
+    fn send_to(&mut self, server: usize, packet: &[u8]) -> io::Result<()> {
+        let stream = &mut self.servers[server];
+        stream.write_all(packet)?;
+        stream.write_all(self.id.bytes())
+    }
+
+    fn recv_from(&mut self, server: usize) -> io::Result<()> {
+        let stream = &mut self.servers[server];
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        loop {
+            let size = match unsafe { EntryContents::try_ref(&buffer[..]) } {
+                Ok(..) => return Ok(()),
+                Err(WrapErr::NotEnoughBytes(needs)) => needs,
+                Err(err) => return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))),
+            };
+            let read = buffer.len();
+            buffer.resize(size, 0);
+            stream.read_exact(&mut buffer[read..])?;
+        }
+    }
+
+    fn contents(&self) -> EntryContents {
+        unsafe { EntryContents::try_ref(&self.buffer[..]).unwrap().0 }
+    }
+}
+
+fn rejected(rejection: Rejection) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad handshake: {}", rejection))
+}
@@ -0,0 +1,238 @@
+//! A cache of entries shared by the handles in a process, so that handles
+//! reading the same colors fetch each entry from the servers once.
+//!
+//! Entries never change once they are written, so a cached entry is never stale;
+//! the only thing the cache can miss is a server trimming a color.
+//! Every read which finds no entry, snapshots included, carries the first entry
+//! the server still stores, and the handle which gets it trims the cache to it,
+//! so trimmed entries are returned at most until one of the handles sharing the
+//! cache next takes a snapshot of the color.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::u64;
+
+use packets::{bytes_as_entry, entry, order, EntryFlag, EntryLayout, OrderIndex};
+
+/// Entries keyed by their location, at most `max_bytes` of them,
+/// evicting the least recently used first.
+/// Share one between handles with `LogBuilder::entry_cache`.
+pub struct EntryCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: BTreeMap<OrderIndex, Cached>,
+    /// Each entry by when it was last used, oldest first.
+    by_use: BTreeMap<u64, OrderIndex>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct Cached {
+    bytes: Vec<u8>,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    /// Entries removed by `EntryCache::trim`.
+    pub trimmed: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl EntryCache {
+    pub fn new(max_bytes: usize) -> Self {
+        EntryCache {
+            max_bytes,
+            inner: Mutex::new(Inner {
+                entries: BTreeMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn get(&self, loc: OrderIndex) -> Option<Vec<u8>> {
+        let mut buffer = vec![];
+        if self.get_into(loc, &mut buffer) {
+            Some(buffer)
+        } else {
+            None
+        }
+    }
+
+    /// Copy the entry read from `loc` into `buffer`, if it is cached.
+    pub fn get_into(&self, loc: OrderIndex, buffer: &mut Vec<u8>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        inner.clock += 1;
+        match inner.entries.get_mut(&loc) {
+            Some(cached) => {
+                inner.by_use.remove(&cached.last_used);
+                cached.last_used = inner.clock;
+                inner.by_use.insert(inner.clock, loc);
+                buffer.clear();
+                buffer.extend_from_slice(&cached.bytes);
+                inner.stats.hits += 1;
+                true
+            },
+            None => {
+                inner.stats.misses += 1;
+                false
+            },
+        }
+    }
+
+    /// Cache a copy of `packet`, the reply to a read of `loc`,
+    /// if it holds an entry rather than a snapshot or an empty read.
+    pub fn insert(&self, loc: OrderIndex, packet: &[u8]) {
+        if !is_cacheable(loc, packet) || packet.len() > self.max_bytes {
+            return
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.contains_key(&loc) {
+            return
+        }
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.entries.insert(loc, Cached { bytes: packet.to_vec(), last_used });
+        inner.by_use.insert(last_used, loc);
+        inner.stats.inserts += 1;
+        inner.stats.entries += 1;
+        inner.stats.bytes += packet.len();
+        while inner.stats.bytes > self.max_bytes {
+            let oldest = match inner.by_use.values().next() {
+                Some(&loc) => loc,
+                None => break,
+            };
+            inner.remove(oldest);
+            inner.stats.evictions += 1;
+        }
+    }
+
+    /// Forget the entries of `chain` before `first_kept`,
+    /// which the servers have trimmed.
+    pub fn trim(&self, chain: order, first_kept: entry) {
+        let mut inner = self.inner.lock().unwrap();
+        let trimmed: Vec<_> = inner.entries
+            .range(OrderIndex(chain, 0.into())..OrderIndex(chain, first_kept))
+            .map(|(&loc, _)| loc)
+            .collect();
+        for loc in trimmed {
+            inner.remove(loc);
+            inner.stats.trimmed += 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, loc: OrderIndex) {
+        if let Some(cached) = self.entries.remove(&loc) {
+            self.by_use.remove(&cached.last_used);
+            self.stats.entries -= 1;
+            self.stats.bytes -= cached.bytes.len();
+        }
+    }
+}
+
+impl fmt::Debug for EntryCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EntryCache")
+            .field("max_bytes", &self.max_bytes)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+fn is_cacheable(OrderIndex(_, index): OrderIndex, packet: &[u8]) -> bool {
+    if index == entry::from(0) || index == entry::from(u64::MAX) {
+        return false
+    }
+    let e = bytes_as_entry(packet);
+    if !e.flag().contains(EntryFlag::ReadSuccess) {
+        return false
+    }
+    match e.kind().layout() {
+        EntryLayout::Data | EntryLayout::Multiput | EntryLayout::Sentinel => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use packets::{bytes_as_entry_mut, EntryContents, Uuid};
+
+    fn entry_at(loc: OrderIndex, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![];
+        EntryContents::Single {
+            id: &Uuid::new_v4(),
+            flags: &EntryFlag::ReadSuccess,
+            loc: &loc,
+            deps: &[],
+            data,
+            timestamp: &0,
+        }.fill_vec(&mut packet);
+        packet
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let (a, b, c) = (OrderIndex(1.into(), 1.into()), OrderIndex(1.into(), 2.into()),
+            OrderIndex(2.into(), 1.into()));
+        let (pa, pb, pc) = (entry_at(a, &[1; 8]), entry_at(b, &[2; 8]), entry_at(c, &[3; 8]));
+        let cache = EntryCache::new(pa.len() * 2);
+        cache.insert(a, &pa);
+        cache.insert(b, &pb);
+        assert_eq!(cache.get(a), Some(pa.clone()));
+        cache.insert(c, &pc);
+        assert_eq!(cache.get(b), None);
+        assert_eq!(cache.get(a), Some(pa.clone()));
+        assert_eq!(cache.get(c), Some(pc.clone()));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.inserts, stats.evictions), (3, 1, 3, 1));
+        assert_eq!((stats.entries, stats.bytes), (2, pa.len() + pc.len()));
+    }
+
+    #[test]
+    fn trim() {
+        let cache = EntryCache::new(1 << 20);
+        for i in 1..6u64 {
+            let loc = OrderIndex(7.into(), i.into());
+            cache.insert(loc, &entry_at(loc, &[i as u8]));
+        }
+        cache.trim(7.into(), 4.into());
+        assert_eq!(cache.stats().trimmed, 3);
+        assert_eq!(cache.get(OrderIndex(7.into(), 3.into())), None);
+        assert!(cache.get(OrderIndex(7.into(), 4.into())).is_some());
+    }
+
+    #[test]
+    fn only_entries() {
+        let cache = EntryCache::new(1 << 20);
+        let loc = OrderIndex(1.into(), 1.into());
+        let mut unread = entry_at(loc, &[1]);
+        bytes_as_entry_mut(&mut unread).flag_mut().remove(EntryFlag::ReadSuccess);
+        cache.insert(loc, &unread);
+        cache.insert(OrderIndex(1.into(), u64::MAX.into()), &entry_at(loc, &[1]));
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -1,18 +1,22 @@
 
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
 use std::net::SocketAddr;
 use std::thread;
 use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
 
 pub use hash::HashMap;
 use hash::HashSet;
 
 use fuzzy_log::{
     self,
+    CursorId,
     Message,
+    Refusal,
     ThreadLog,
     FinshedReadQueue,
     FinshedReadRecv,
@@ -21,7 +25,10 @@ use fuzzy_log::{
 };
 use fuzzy_log_util::socket_addr::Ipv4SocketAddr;
 use store;
+use catalog::{Catalog, CatalogError, Chain, IntoChain};
 use fuzzy_log::FromClient::*;
+pub use fuzzy_log::entry_cache::{CacheStats, EntryCache};
+pub use fuzzy_log::session::SessionToken;
 pub use packets::{
     order,
     entry,
@@ -37,6 +44,8 @@ use packets::{
     slice_to_data,
     EntryFlag,
 };
+use packets::checksum;
+use packets::hello::Credentials;
 
 pub struct LogHandle<V: ?Sized> {
     read_handle: ReadHandle<V>,
@@ -57,6 +66,9 @@ pub struct ReadHandle<V: ?Sized> {
     curr_entry: Vec<u8>,
     num_errors: u64,
     last_dropped: Arc<()>,
+    session: SessionToken,
+    /// Set if this handle reads through a cursor of another handle's log.
+    cursor: Option<(CursorId, String)>,
 }
 
 pub struct WriteHandle<V: ?Sized> {
@@ -64,16 +76,21 @@ pub struct WriteHandle<V: ?Sized> {
     finished_writes: FinshedWriteRecv,
     num_async_writes: Option<usize>,
     num_errors: u64,
+    session: SessionToken,
 }
 
 pub struct AtomicWriteHandle<V: ?Sized> {
     _pd: PhantomData<Box<V>>,
     to_log: mpsc::Sender<Message>,
     last_dropped: Arc<()>,
+    checksums: bool,
 }
 
 impl<V: ?Sized> Drop for ReadHandle<V> {
     fn drop(&mut self) {
+        if let Some((id, _)) = self.cursor.take() {
+            let _ = self.to_log.send(Message::FromClient(CloseCursor(id)));
+        }
         if let Some(..) = Arc::get_mut(&mut self.last_dropped) {
             let _ = self.to_log.send(Message::FromClient(Shutdown));
         }
@@ -90,11 +107,12 @@ impl<V: ?Sized> Drop for AtomicWriteHandle<V> {
 
 impl<V: ?Sized> Clone for AtomicWriteHandle<V> {
     fn clone(&self) -> Self {
-        let &AtomicWriteHandle{ref _pd, ref to_log, ref last_dropped} = self;
+        let &AtomicWriteHandle{ref _pd, ref to_log, ref last_dropped, checksums} = self;
         AtomicWriteHandle {
             _pd: _pd.clone(),
             to_log: to_log.clone(),
-            last_dropped:last_dropped.clone()
+            last_dropped:last_dropped.clone(),
+            checksums,
         }
     }
 }
@@ -105,12 +123,31 @@ pub enum GetRes {
     Done,
     IoErr(io::ErrorKind, usize),
     AlreadyGCd(order, entry),
+    /// The entry at this location failed its checksum, see `LogBuilder::checksum_appends`.
+    Corrupted(OrderIndex),
+    /// A server refused to let us read this color, see `LogBuilder::credentials`.
+    /// Reads of the color will not make progress on this handle.
+    PermissionDenied(order),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TryWaitRes {
     NothingReady,
     IoErr(io::ErrorKind, usize),
+    /// A server refused an append to this color, see `LogBuilder::credentials`.
+    PermissionDenied(order),
+    /// A server refused an append because it would exceed the quota this color
+    /// is charged to, see `fuzzy_log_server::quota`. The append was not stored.
+    QuotaExceeded(order),
+    /// The append with this id was damaged on its way to a server,
+    /// see `LogBuilder::checksum_appends`. It was not stored,
+    /// or was stored flagged as `Corrupted` and is returned by reads as `GetRes::Corrupted`.
+    Corrupted(Uuid),
+    /// A transaction read a color which was appended to before it could commit,
+    /// contains the first location after the read, see `Transaction`.
+    /// If instead it wrote a color read by a transaction which had yet to commit
+    /// it contains that color, at entry 0.
+    Aborted(OrderIndex),
 }
 
 pub struct Event<'e, V: 'e + ?Sized> {
@@ -120,6 +157,34 @@ pub struct Event<'e, V: 'e + ?Sized> {
     pub happens_after: &'e [OrderIndex],
 }
 
+/// A multiappend which only commits if nothing else was appended to the
+/// colors it read from since it observed them.
+///
+/// The transaction is appended to its write colors only, flagged as a `Transaction`
+/// with its read set as its dependencies. The servers storing the read colors check
+/// the read set during the skeens round, before the transaction is given a location,
+/// so an aborted transaction is never seen by readers.
+/// Between its skeens rounds the read colors are locked,
+/// appends to them wait for it to finish, and transactions writing them abort.
+pub struct Transaction<'h, V: 'h + ?Sized> {
+    handle: &'h mut LogHandle<V>,
+    read_set: Vec<OrderIndex>,
+    write_set: Vec<order>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TransactionRes {
+    /// Another entry was appended to a read color first;
+    /// contains the earliest such entry.
+    /// Or a written color was read by another transaction which had yet to commit;
+    /// contains that color, at entry 0.
+    Aborted(OrderIndex),
+    IoErr(io::ErrorKind, usize),
+    PermissionDenied(order),
+    QuotaExceeded(order),
+    Corrupted(Uuid),
+}
+
 impl<V> LogHandle<[V]>
 where V: Storeable {
 
@@ -199,15 +264,21 @@ where V: Storeable {
 pub struct LogBuilder<V: ?Sized> {
     servers: Servers,
     chains: Vec<order>,
+    named_chains: Vec<String>,
     reads_my_writes: bool,
     fetch_boring_multis: bool,
     id: Option<Ipv4SocketAddr>,
     ack_writes: bool,
     my_colors_chains: Option<Vec<order>>,
+    recovery_timeout: Option<Duration>,
+    retry_backoff: Option<(Duration, Duration)>,
+    checksums: bool,
+    credentials: Option<Credentials>,
+    entry_cache: Option<Arc<EntryCache>>,
     _pd: PhantomData<Box<V>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Servers {
     Unreplicated(Vec<SocketAddr>),
     Replicated(Vec<(SocketAddr, SocketAddr)>),
@@ -220,21 +291,36 @@ where V: Storeable {
         LogBuilder {
             servers: servers,
             chains: vec![],
+            named_chains: vec![],
             reads_my_writes: false,
             fetch_boring_multis: false,
             id: None,
             ack_writes: true,
             my_colors_chains: None,
+            recovery_timeout: None,
+            retry_backoff: None,
+            checksums: false,
+            credentials: None,
+            entry_cache: None,
             _pd: PhantomData,
         }
     }
 
+    /// The chains to read, either as colors or as `catalog::ColorName`s.
+    /// Names are looked up in the catalog given to `try_build`,
+    /// a builder with names cannot be `build`.
     pub fn chains<C, O>(self, chains: C) -> Self
     where
         C: IntoIterator<Item=O>,
-        O: Borrow<order>, {
-        let chains = chains.into_iter().map(|o| o.borrow().clone()).collect();
-        LogBuilder{ chains: chains, .. self}
+        O: IntoChain, {
+        let (mut colors, mut names) = (vec![], vec![]);
+        for chain in chains {
+            match chain.into_chain() {
+                Chain::Color(color) => colors.push(color),
+                Chain::Named(name) => names.push(name),
+            }
+        }
+        LogBuilder{ chains: colors, named_chains: names, .. self}
     }
 
     pub fn reads_my_writes(self) -> Self {
@@ -263,10 +349,65 @@ where V: Storeable {
         LogBuilder{ack_writes: false, ..self}
     }
 
+    /// Finish multiappends that have blocked our writes for longer than `timeout`,
+    /// eg. because the client which started them crashed between skeens rounds.
+    pub fn recover_stalled_multiappends(self, timeout: Duration) -> Self {
+        LogBuilder{ recovery_timeout: Some(timeout), .. self }
+    }
+
+    /// Wait `initial` before resending an append a busy server asked us to retry later,
+    /// doubling the wait each time the same append is refused, up to `max`.
+    /// By default 1ms, up to 1s.
+    pub fn retry_backoff(self, initial: Duration, max: Duration) -> Self {
+        LogBuilder{ retry_backoff: Some((initial, max)), .. self }
+    }
+
+    /// Append entries with a CRC32C of their contents, which servers check
+    /// before storing them. Entries are always checked when they are read,
+    /// whether or not this handle writes checksums.
+    pub fn checksum_appends(self) -> Self {
+        LogBuilder{ checksums: true, .. self }
+    }
+
+    /// Authenticate to the servers as `principal`, rather than anonymously.
+    /// Servers which do not know the principal, or which do not support
+    /// authentication, refuse the connection and `build` panics.
+    /// What the principal may do to each color is up to the servers,
+    /// requests they refuse return `PermissionDenied`.
+    pub fn credentials(self, principal: &str, secret: &[u8]) -> Self {
+        LogBuilder{ credentials: Some(Credentials::new(principal, secret)), .. self }
+    }
+
+    /// Read entries from, and add the entries read to, `cache`.
+    /// Handles built with the same cache fetch each entry from the servers
+    /// once between them, for as long as it stays in the cache.
+    pub fn entry_cache(self, cache: Arc<EntryCache>) -> Self {
+        LogBuilder{ entry_cache: Some(cache), .. self }
+    }
+
+    /// Look up the chains named in `chains` in `catalog`, after syncing it,
+    /// and build the handle. Fails with `CatalogError::NotFound` if a name does not exist.
+    pub fn try_build(mut self, catalog: &mut Catalog) -> Result<LogHandle<V>, CatalogError> {
+        if !self.named_chains.is_empty() {
+            catalog.sync()?;
+            for name in mem::replace(&mut self.named_chains, vec![]) {
+                match catalog.lookup(&name) {
+                    Some(info) => self.chains.push(info.color),
+                    None => return Err(CatalogError::NotFound),
+                }
+            }
+        }
+        Ok(self.build())
+    }
+
     pub fn build(self) -> LogHandle<V> {
         let LogBuilder {
-            servers, chains, reads_my_writes, fetch_boring_multis, ack_writes, id, my_colors_chains, _pd,
+            servers, chains, named_chains, reads_my_writes, fetch_boring_multis, ack_writes, id,
+            my_colors_chains, recovery_timeout, retry_backoff, checksums, credentials, entry_cache,
+            _pd,
         } = self;
+        assert!(named_chains.is_empty(), "chains named {:?} need a catalog, see try_build",
+            named_chains);
 
         let make_store = |client| {
             let to_store_m = Arc::new(Mutex::new(None));
@@ -275,24 +416,34 @@ where V: Storeable {
                 match servers {
                     Servers::Unreplicated(servers) => {
                         let (mut store, to_store) =
-                            ::store::AsyncTcpStore::new_tcp(
+                            ::store::AsyncTcpStore::authenticated_new_tcp(
                                 id.unwrap_or_else(Ipv4SocketAddr::random),
+                                credentials,
                                 servers.into_iter(),
                                 client,
                             ).expect("could not start store.");
                         *tsm.lock().unwrap() = Some(to_store);
                         store.set_reads_my_writes(reads_my_writes);
+                        store.set_recovery_timeout(recovery_timeout);
+                        if let Some((initial, max)) = retry_backoff {
+                            store.set_retry_backoff(initial, max)
+                        }
                         store.run();
                     },
                     Servers::Replicated(servers) => {
                         let (mut store, to_store) =
-                            ::store::AsyncTcpStore::replicated_new_tcp(
+                            ::store::AsyncTcpStore::authenticated_replicated_new_tcp(
                                 id.unwrap_or_else(Ipv4SocketAddr::random),
+                                credentials,
                                 servers.into_iter(),
                                 client,
                             ).expect("could not start store.");
                         *tsm.lock().unwrap() = Some(to_store);
                         store.set_reads_my_writes(reads_my_writes);
+                        store.set_recovery_timeout(recovery_timeout);
+                        if let Some((initial, max)) = retry_backoff {
+                            store.set_retry_backoff(initial, max)
+                        }
                         store.run();
                     },
                 }
@@ -308,13 +459,16 @@ where V: Storeable {
             to_store
         };
 
-        LogHandle::build_with_store(
+        let mut handle = LogHandle::build_with_store_and_cache(
             chains,
             fetch_boring_multis,
             ack_writes,
             my_colors_chains,
+            entry_cache,
             make_store
-        )
+        );
+        handle.write_handle.handle.checksums = checksums;
+        handle
     }
 
     pub fn build_handles(self) -> (ReadHandle<V>, AtomicWriteHandle<V>) {
@@ -397,6 +551,26 @@ where V: Storeable {
         my_colors_chains: Option<Vec<order>>,
         store_builder: F,
     ) -> Self
+    where C: IntoIterator<Item=order>,
+          F: FnOnce(mpsc::Sender<Message>) -> store::ToSelf {
+        Self::build_with_store_and_cache(
+            interesting_chains,
+            fetch_boring_multis,
+            ack_writes,
+            my_colors_chains,
+            None,
+            store_builder,
+        )
+    }
+
+    pub fn build_with_store_and_cache<C, F>(
+        interesting_chains: C,
+        fetch_boring_multis: bool,
+        ack_writes: bool,
+        my_colors_chains: Option<Vec<order>>,
+        entry_cache: Option<Arc<EntryCache>>,
+        store_builder: F,
+    ) -> Self
     where C: IntoIterator<Item=order>,
           F: FnOnce(mpsc::Sender<Message>) -> store::ToSelf {
         let (to_log, from_outside) = mpsc::channel();
@@ -415,6 +589,10 @@ where V: Storeable {
                 Some(my_colors_chains) => builder.my_colors_chains(my_colors_chains),
                 None => builder,
             };
+            let builder = match entry_cache {
+                Some(entry_cache) => builder.entry_cache(entry_cache),
+                None => builder,
+            };
             match ack_writes {
                 true => builder.ack_writes(finished_writes_s).build().run(),
                 false => builder.build().run(),
@@ -653,6 +831,26 @@ where V: Storeable {
         self.read_handle.take_snapshot()
     }
 
+    /// Take a snapshot of the colors in `token` which is guaranteed to
+    /// contain every entry the token's session has observed,
+    /// and start prefetching.
+    pub fn snapshot_at_least(&mut self, token: &SessionToken) {
+        self.read_handle.snapshot_at_least(token)
+    }
+
+    /// The horizons of every entry this handle has read or had acknowledged as written.
+    pub fn session_token(&self) -> SessionToken {
+        let mut token = self.read_handle.session_token();
+        token.merge(&self.write_handle.session_token());
+        token
+    }
+
+    /// Open a cursor on `colors` with its own horizons,
+    /// which reads through this handle's log, see `ReadHandle::cursor`.
+    pub fn cursor(&self, name: &str, colors: &[order]) -> ReadHandle<V> {
+        self.read_handle.cursor(name, colors)
+    }
+
     /// Wait until an event is ready, then returns the contents.
     pub fn get_next(&mut self) -> Result<(&V, &[OrderIndex]), GetRes>
     where V: UnStoreable {
@@ -958,9 +1156,82 @@ impl<V: ?Sized> LogHandle<V> {
         self.read_handle.fastforward(loc)
     }
 
+    /// Never read the entries of `loc.0` up to and including `loc.1`,
+    /// entries at them and at a later location in another color are skipped too.
+    /// Must be called before the color is first read.
+    pub fn start_after(&mut self, loc: OrderIndex) {
+        self.read_handle.start_after(loc)
+    }
+
     pub fn rewind(&mut self, loc: OrderIndex) {
         self.read_handle.rewind(loc)
     }
+
+    /// Start a transaction, see `Transaction`.
+    pub fn transaction(&mut self) -> Transaction<V> {
+        Transaction {
+            handle: self,
+            read_set: Vec::new(),
+            write_set: Vec::new(),
+        }
+    }
+}
+
+impl<'h, V: ?Sized> Transaction<'h, V> {
+
+    /// Add `color` to the read set as of the last entry in it this handle has read.
+    pub fn read(&mut self, color: order) -> &mut Self {
+        let horizon = self.handle.read_handle.session.horizon_for(color)
+            .unwrap_or(0.into());
+        self.read_at(color, horizon)
+    }
+
+    /// Add `color` to the read set as of `horizon`.
+    pub fn read_at(&mut self, color: order, horizon: entry) -> &mut Self {
+        assert!(color != order::from(0), "color 0 cannot be read transactionally");
+        match self.read_set.binary_search_by_key(&color, |oi| oi.0) {
+            // if a color was read at multiple points the earliest one is what counts
+            Ok(i) => if horizon < self.read_set[i].1 {
+                self.read_set[i].1 = horizon
+            },
+            Err(i) => self.read_set.insert(i, OrderIndex(color, horizon)),
+        }
+        self
+    }
+
+    /// Add `color` to the write set.
+    pub fn write(&mut self, color: order) -> &mut Self {
+        assert!(color != order::from(0), "color 0 should not be used;it is special cased for legacy reasons.");
+        self.write_set.push(color);
+        self
+    }
+
+    pub fn read_set(&self) -> &[OrderIndex] {
+        &self.read_set
+    }
+
+    /// Append `data` to every color in the write set and wait for the outcome.
+    /// On commit returns the locations the transaction was written to.
+    pub fn commit(self, data: &V) -> Result<Vec<OrderIndex>, TransactionRes>
+    where V: Storeable {
+        let Transaction{handle, read_set, mut write_set} = self;
+        write_set.sort();
+        write_set.dedup();
+        assert!(!write_set.is_empty(), "a transaction must write at least one color");
+        trace!("HANDLE transaction reads {:?} writes {:?}", read_set, write_set);
+        let id = handle.write_handle.async_transaction(&write_set, data, &read_set);
+        match handle.wait_for_a_specific_append(id) {
+            Ok(locs) => Ok(locs),
+            Err(TryWaitRes::Aborted(stale)) => Err(TransactionRes::Aborted(stale)),
+            Err(TryWaitRes::IoErr(kind, server)) => Err(TransactionRes::IoErr(kind, server)),
+            Err(TryWaitRes::PermissionDenied(color)) =>
+                Err(TransactionRes::PermissionDenied(color)),
+            Err(TryWaitRes::QuotaExceeded(color)) => Err(TransactionRes::QuotaExceeded(color)),
+            Err(TryWaitRes::Corrupted(id)) => Err(TransactionRes::Corrupted(id)),
+            Err(TryWaitRes::NothingReady) =>
+                panic!("cannot commit a transaction on a handle which does not ack writes"),
+        }
+    }
 }
 
 impl<V: ?Sized> ReadHandle<V> {
@@ -978,13 +1249,48 @@ impl<V: ?Sized> ReadHandle<V> {
             num_snapshots: 0,
             num_errors: 0,
             last_dropped,
+            session: Default::default(),
+            cursor: None,
+        }
+    }
+
+    /// Open a cursor named `name` on `colors`: a handle whose reads have their
+    /// own horizon in each color, independent of this handle's and of other cursors',
+    /// but which shares this handle's connections to the servers and read buffers.
+    /// A follower and a lagging reader can thus be served by one log.
+    ///
+    /// The cursor only reads, and only while the log is running;
+    /// if this is part of a `LogHandle`, dropping that stops the log.
+    pub fn cursor(&self, name: &str, colors: &[order]) -> ReadHandle<V> {
+        static NEXT_CURSOR: AtomicUsize = ATOMIC_USIZE_INIT;
+        let id = NEXT_CURSOR.fetch_add(1, Ordering::Relaxed) as CursorId;
+        let (ready_reads_s, ready_reads_r) = mpsc::channel();
+        let open = OpenCursor(id, name.to_string(), colors.to_vec(), ready_reads_s);
+        self.to_log.send(Message::FromClient(open)).unwrap();
+        let mut cursor = ReadHandle::new(
+            self.to_log.clone(), ready_reads_r, self.last_dropped.clone()
+        );
+        cursor.cursor = Some((id, name.to_string()));
+        cursor
+    }
+
+    /// The name of this handle's cursor, if it is one.
+    pub fn cursor_name(&self) -> Option<&str> {
+        self.cursor.as_ref().map(|&(_, ref name)| &**name)
+    }
+
+    /// Address a read request to this handle's frontier.
+    fn request(&self, msg: fuzzy_log::FromClient) -> Message {
+        match self.cursor {
+            Some((id, _)) => Message::FromClient(ForCursor(id, Box::new(msg))),
+            None => Message::FromClient(msg),
         }
     }
 
     /// Take a snapshot of a supplied interesting color and start prefetching.
     pub fn snapshot(&mut self, chain: order) {
         self.num_snapshots = self.num_snapshots.saturating_add(1);
-        self.to_log.send(Message::FromClient(SnapshotAndPrefetch(chain)))
+        self.to_log.send(self.request(SnapshotAndPrefetch(chain)))
             .unwrap();
     }
 
@@ -993,7 +1299,7 @@ impl<V: ?Sized> ReadHandle<V> {
         trace!("HANDLE send snap {:?}.", colors);
         let colors = colors.to_vec();
         self.num_snapshots = self.num_snapshots.saturating_add(1);
-        self.to_log.send(Message::FromClient(MultiSnapshotAndPrefetch(colors))).unwrap();
+        self.to_log.send(self.request(MultiSnapshotAndPrefetch(colors))).unwrap();
     }
 
     /// Take a linearizable snapshot of a set of interesting colors and start prefetching.
@@ -1002,17 +1308,39 @@ impl<V: ?Sized> ReadHandle<V> {
         let mut c = Vec::with_capacity(colors.len());
         c.extend(colors.into_iter().map(|&o| OrderIndex(o, entry::from(0))));
         self.num_snapshots = self.num_snapshots.saturating_add(1);
-        self.to_log.send(Message::FromClient(StrongSnapshotAndPrefetch(c))).unwrap();
+        self.to_log.send(self.request(StrongSnapshotAndPrefetch(c))).unwrap();
     }
 
     /// Take a snapshot of all interesting colors and start prefetching.
     pub fn take_snapshot(&mut self) {
         trace!("HANDLE send all snap.");
         self.num_snapshots = self.num_snapshots.saturating_add(1);
-        self.to_log.send(Message::FromClient(SnapshotAndPrefetch(0.into())))
+        self.to_log.send(self.request(SnapshotAndPrefetch(0.into())))
             .unwrap();
     }
 
+    /// Take a snapshot of the colors in `token` which is guaranteed to
+    /// contain every entry the token's session has observed,
+    /// and start prefetching.
+    /// An empty token snapshots all interesting colors.
+    pub fn snapshot_at_least(&mut self, token: &SessionToken) {
+        if token.is_empty() {
+            return self.take_snapshot()
+        }
+        trace!("HANDLE send snap at least {:?}.", token);
+        // reads are monotonic, so anything read after this must also
+        // be ordered after the token
+        self.session.merge(token);
+        self.num_snapshots = self.num_snapshots.saturating_add(1);
+        let horizons = token.horizons().to_vec();
+        self.to_log.send(self.request(SnapshotAtLeastAndPrefetch(horizons))).unwrap();
+    }
+
+    /// The horizons of every entry this handle has read.
+    pub fn session_token(&self) -> SessionToken {
+        self.session.clone()
+    }
+
     pub fn sync<F>(&mut self, mut per_event: F)
     -> Result<HashMap<order, entry>, GetRes>
     where V: UnStoreable, F: FnMut(&V, &[OrderIndex], &Uuid) {
@@ -1110,9 +1438,22 @@ impl<V: ?Sized> ReadHandle<V> {
 
         trace!("HANDLE got val.");
         let e = bytes_as_entry(&self.curr_entry);
+        let payload = match e.verify_checksum().and_then(|()| e.payload()) {
+            // the server found the entry corrupt, even if we could not
+            Ok(..) if e.flag().contains(EntryFlag::Corrupted) => {
+                error!("HANDLE server reports entry at {:?} is corrupt", e.locs()[0]);
+                return Err(GetRes::Corrupted(e.locs()[0]))
+            },
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("HANDLE entry at {:?} is corrupt: {}", e.locs()[0], err);
+                return Err(GetRes::Corrupted(e.locs()[0]))
+            },
+        };
+        self.session.observe(e.locs());
         Ok(Event{
             id: e.id(),
-            data: slice_to_data(e.data()),
+            data: slice_to_data(payload),
             inhabits: e.locs(),
             happens_after: e.dependencies(),
         })
@@ -1164,36 +1505,57 @@ impl<V: ?Sized> ReadHandle<V> {
 
         trace!("HANDLE got val.");
         let e = bytes_as_entry(&self.curr_entry);
+        let payload = match e.verify_checksum().and_then(|()| e.payload()) {
+            // the server found the entry corrupt, even if we could not
+            Ok(..) if e.flag().contains(EntryFlag::Corrupted) => {
+                error!("HANDLE server reports entry at {:?} is corrupt", e.locs()[0]);
+                return Err(GetRes::Corrupted(e.locs()[0]))
+            },
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("HANDLE entry at {:?} is corrupt: {}", e.locs()[0], err);
+                return Err(GetRes::Corrupted(e.locs()[0]))
+            },
+        };
+        self.session.observe(e.locs());
         Ok(Event{
             id: e.id(),
-            data: slice_to_data(e.data()),
+            data: slice_to_data(payload),
             inhabits: e.locs(),
             happens_after: e.dependencies(),
         })
     }
 
-    fn make_read_error(&mut self, fuzzy_log::Error{server, error_num, error}: fuzzy_log::Error)
-    -> Option<GetRes> {
+    fn make_read_error(
+        &mut self, fuzzy_log::Error{server, error_num, error, refused}: fuzzy_log::Error
+    ) -> Option<GetRes> {
         if self.num_errors < error_num {
             assert!(self.num_errors + 1 == error_num);
             self.num_errors += 1;
-            Some(GetRes::IoErr(error, server))
+            match refused {
+                Some(Refusal::Denied(color)) => Some(GetRes::PermissionDenied(color)),
+                _ => Some(GetRes::IoErr(error, server)),
+            }
         } else {
             None
         }
     }
 
     pub fn read_until(&mut self, loc: OrderIndex) {
-        self.to_log.send(Message::FromClient(ReadUntil(loc))).unwrap();
+        self.to_log.send(self.request(ReadUntil(loc))).unwrap();
         self.num_snapshots = self.num_snapshots.saturating_add(1);
     }
 
     pub fn fastforward(&mut self, loc: OrderIndex) {
-        self.to_log.send(Message::FromClient(Fastforward(loc))).unwrap();
+        self.to_log.send(self.request(Fastforward(loc))).unwrap();
+    }
+
+    pub fn start_after(&mut self, loc: OrderIndex) {
+        self.to_log.send(self.request(StartAfter(loc))).unwrap();
     }
 
     pub fn rewind(&mut self, loc: OrderIndex) {
-        self.to_log.send(Message::FromClient(Rewind(loc))).unwrap();
+        self.to_log.send(self.request(Rewind(loc))).unwrap();
     }
 }
 
@@ -1244,6 +1606,13 @@ where V: Storeable {
         id
     }
 
+    fn async_transaction(&mut self, chains: &[order], data: &V, read_set: &[OrderIndex])
+    -> Uuid {
+        let id = self.handle.async_transaction(chains, data, read_set);
+        self.num_async_writes.as_mut().map(|n| *n += 1);
+        id
+    }
+
     // A multiappend which does not induce a read dependency on the foreign chain
     pub fn no_remote_multiappend(&mut self, chains: &[order], data: &V, deps: &[OrderIndex])
     -> Vec<OrderIndex> {
@@ -1297,9 +1666,15 @@ impl<V: ?Sized> WriteHandle<V> {
             finished_writes,
             num_async_writes: if ack_writes { Some(0) } else { None },
             num_errors: 0,
+            session: Default::default(),
         }
     }
 
+    /// The horizons of every append this handle has seen acknowledged.
+    pub fn session_token(&self) -> SessionToken {
+        self.session.clone()
+    }
+
     fn atomize(self) -> AtomicWriteHandle<V> {
         self.handle
     }
@@ -1339,6 +1714,7 @@ impl<V: ?Sized> WriteHandle<V> {
                     match res {
                         Ok(write) => {
                             self.num_async_writes.as_mut().map(|n| *n -= 1);
+                            self.session.observe(&write.1);
                             return Ok(write)
                         },
                         Err(err) => if let Some(err) = self.to_wait_error(err) {
@@ -1363,8 +1739,9 @@ impl<V: ?Sized> WriteHandle<V> {
                             None => TryWaitRes::NothingReady,
                         }
                     ));
-                if ret.is_ok() {
+                if let Ok((_, ref locs)) = ret {
                     self.num_async_writes.as_mut().map(|n| *n -= 1);
+                    self.session.observe(locs);
                 }
                 //TODO return buffers here and cache them?
                 ret
@@ -1377,14 +1754,16 @@ impl<V: ?Sized> WriteHandle<V> {
             Some(0) => return Ok(0),
             _ => {
                 let num_errors = &mut self.num_errors;
+                let session = &mut self.session;
                 let mut flushed = 0;
                 for res in self.finished_writes.try_iter() {
                     match res {
-                        Ok(..) => {
+                        Ok((_, locs)) => {
+                            session.observe(&locs);
                             flushed += 1;
                             self.num_async_writes.as_mut().map(|n| *n -= 1);
                         },
-                        Err(fuzzy_log::Error{server, error_num, error}) =>
+                        Err(fuzzy_log::Error{server, error_num, error, ..}) =>
                             //TODO return incremental count
                             if *num_errors < error_num {
                                 assert!(*num_errors + 1 == error_num);
@@ -1398,12 +1777,20 @@ impl<V: ?Sized> WriteHandle<V> {
         }
     }
 
-    fn to_wait_error(&mut self, fuzzy_log::Error{server, error_num, error}: fuzzy_log::Error)
-    -> Option<TryWaitRes> {
+    fn to_wait_error(
+        &mut self,
+        fuzzy_log::Error{server, error_num, error, refused}: fuzzy_log::Error
+    ) -> Option<TryWaitRes> {
         if self.num_errors < error_num {
             assert!(self.num_errors + 1 == error_num);
             self.num_errors += 1;
-            Some(TryWaitRes::IoErr(error, server))
+            match refused {
+                Some(Refusal::Denied(color)) => Some(TryWaitRes::PermissionDenied(color)),
+                Some(Refusal::OverQuota(color)) => Some(TryWaitRes::QuotaExceeded(color)),
+                Some(Refusal::Corrupted(id)) => Some(TryWaitRes::Corrupted(id)),
+                Some(Refusal::Aborted(stale)) => Some(TryWaitRes::Aborted(stale)),
+                None => Some(TryWaitRes::IoErr(error, server)),
+            }
         } else {
             None
         }
@@ -1419,7 +1806,18 @@ impl<V: ?Sized> WriteHandle<V> {
 
 impl<V: ?Sized> AtomicWriteHandle<V> {
     fn new(to_log: mpsc::Sender<Message>, last_dropped: Arc<()>) -> Self {
-        Self { to_log, last_dropped, _pd: Default::default() }
+        Self { to_log, last_dropped, checksums: false, _pd: Default::default() }
+    }
+
+    /// The flags and data of an entry, with room for a checksum if this handle writes them.
+    /// The log thread seals the checksum once the entry's dependencies are final.
+    fn entry_data<'d>(&self, flags: EntryFlag::Flag, data: &'d [u8])
+    -> (EntryFlag::Flag, Cow<'d, [u8]>) {
+        if self.checksums {
+            (flags | EntryFlag::Checksummed, checksum::with_room_for_checksum(data).into())
+        } else {
+            (flags, data.into())
+        }
     }
 }
 
@@ -1440,13 +1838,14 @@ where V: Storeable {
     pub fn async_append(&self, chain: order, data: &V, deps: &[OrderIndex]) -> Uuid {
         //TODO no-alloc?
         let id = Uuid::new_v4();
+        let (flags, data) = self.entry_data(EntryFlag::Nothing, data_to_slice(data));
         let mut buffer = Vec::new();
         EntryContents::Single {
             id: &id,
-            flags: &EntryFlag::Nothing,
+            flags: &flags,
             loc: &OrderIndex(chain, 0.into()),
             deps: deps,
-            data: data_to_slice(data),
+            data: &data,
             timestamp: &0, //TODO
         }.fill_vec(&mut buffer);
         self.to_log.send(Message::FromClient(PerformAppend(buffer))).unwrap();
@@ -1465,14 +1864,37 @@ where V: Storeable {
             return self.async_append(locs[0].0, data, deps)
         }
         let id = Uuid::new_v4();
+        let (flags, data) = self.entry_data(EntryFlag::Nothing, data_to_slice(data));
         let mut buffer = Vec::new();
         EntryContents::Multi {
             id: &id,
-            flags: &EntryFlag::Nothing,
+            flags: &flags,
             lock: &0,
             locs: &locs,
             deps: deps,
-            data: data_to_slice(data),
+            data: &data,
+        }.fill_vec(&mut buffer);
+        self.to_log.send(Message::FromClient(PerformAppend(buffer))).unwrap();
+        id
+    }
+
+    /// A transaction is always sent as a multiappend, even to a single color,
+    /// since that is the path on which the servers check its read set, see `Transaction`.
+    fn async_transaction(&self, chains: &[order], data: &V, read_set: &[OrderIndex])
+    -> Uuid {
+        let mut locs: Vec<_> = chains.into_iter().map(|&o| OrderIndex(o, 0.into())).collect();
+        locs.sort();
+        locs.dedup();
+        let id = Uuid::new_v4();
+        let (flags, data) = self.entry_data(EntryFlag::Transaction, data_to_slice(data));
+        let mut buffer = Vec::new();
+        EntryContents::Multi {
+            id: &id,
+            flags: &flags,
+            lock: &0,
+            locs: &locs,
+            deps: read_set,
+            data: &data,
         }.fill_vec(&mut buffer);
         self.to_log.send(Message::FromClient(PerformAppend(buffer))).unwrap();
         id
@@ -1490,14 +1912,15 @@ where V: Storeable {
             return self.async_append(locs[0].0, data, deps)
         }
         let id = Uuid::new_v4();
+        let (flags, data) = self.entry_data(EntryFlag::NoRemote, data_to_slice(data));
         let mut buffer = Vec::new();
         EntryContents::Multi {
             id: &id,
-            flags: &EntryFlag::NoRemote,
+            flags: &flags,
             lock: &0,
             locs: &locs,
             deps: deps,
-            data: data_to_slice(data),
+            data: &data,
         }.fill_vec(&mut buffer);
         self.to_log.send(Message::FromClient(PerformAppend(buffer))).unwrap();
         id
@@ -1529,14 +1952,15 @@ where V: Storeable {
         debug_assert!(mchains[(chains.len() + 1)..]
             .iter().all(|&OrderIndex(o, _)| depends_on.contains(&o)));
         let id = Uuid::new_v4();
+        let (flags, data) = self.entry_data(EntryFlag::Nothing, data_to_slice(data));
         let mut buffer = Vec::new();
         EntryContents::Multi {
             id: &id,
-            flags: &EntryFlag::Nothing,
+            flags: &flags,
             lock: &0,
             locs: &mchains,
             deps: deps,
-            data: data_to_slice(data),
+            data: &data,
         }.fill_vec(&mut buffer);
         self.to_log.send(Message::FromClient(PerformAppend(buffer))).unwrap();
         id
@@ -4,7 +4,7 @@ use std::{self, iter, mem};
 use std::collections::VecDeque;
 use std::collections::hash_map;
 use std::io;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::rc::Rc;
 use std::u64;
 
@@ -15,11 +15,14 @@ use self::FromClient::*;
 
 use hash::{HashMap, HashSet, UuidHashMap};
 
+use self::entry_cache::EntryCache;
 use self::per_color::{PerColor, IsRead, ReadHandle, NextToFetch};
 
 use store;
 
+pub mod entry_cache;
 pub mod log_handle;
+pub mod session;
 mod per_color;
 mod range_tree;
 
@@ -62,6 +65,70 @@ pub struct ThreadLog<FinshedReadQueue, FinshedWriteQueue> {
 
     last_seen_entries: HashMap<order, entry>,
     my_colors_chains: HashSet<order>,
+
+    /// The frontiers other than the one above, see `ReadHandle::cursor`.
+    /// While a cursor is being served its frontier is swapped with the
+    /// handle's, and the cursor is moved to `active_cursor`.
+    cursors: HashMap<CursorId, Cursor>,
+    active_cursor: Option<Cursor>,
+    /// Who asked for each outstanding snapshot of a chain, `None` being the handle.
+    /// A server answers the reads of a chain in order.
+    snapshot_readers: HashMap<order, VecDeque<Option<CursorId>>>,
+
+    entry_cache: Option<Arc<EntryCache>>,
+    /// Reads answered by `entry_cache`, handled once the current message is.
+    cached_reads: VecDeque<(OrderIndex, Vec<u8>)>,
+}
+
+pub type CursorId = u64;
+
+/// A read frontier with its own colors and horizons,
+/// which shares its log's connections and buffers.
+struct Cursor {
+    id: CursorId,
+    name: String,
+    ready_reads: FinshedReadQueue,
+    num_errors: u64,
+    frontier: Frontier,
+}
+
+/// The parts of a `ThreadLog` which track how far a reader has read.
+struct Frontier {
+    per_chains: HashMap<order, PerColor>,
+    blockers: HashMap<OrderIndex, Vec<ChainEntry>>,
+    blocked_multiappends: UuidHashMap<MultiSearchState>,
+    no_longer_blocked: Vec<OrderIndex>,
+    chains_currently_being_read: IsRead,
+    num_snapshots: usize,
+    prefetch: u32,
+}
+
+impl Cursor {
+    fn new(id: CursorId, name: String, chains: Vec<order>, ready_reads: FinshedReadQueue) -> Self {
+        Cursor {
+            id,
+            name,
+            ready_reads,
+            num_errors: 0,
+            frontier: Frontier {
+                per_chains: chains.into_iter().map(|c| (c, PerColor::interesting(c))).collect(),
+                blockers: HashMap::default(),
+                blocked_multiappends: Default::default(),
+                no_longer_blocked: Default::default(),
+                chains_currently_being_read: Rc::new(ReadHandle),
+                num_snapshots: 0,
+                prefetch: 1,
+            },
+        }
+    }
+
+    /// Errors are numbered per reader, see `log_handle::ReadHandle::make_read_error`.
+    fn send_error(&mut self, err: &Error) {
+        let error_num = self.num_errors;
+        self.num_errors += 1;
+        // a cursor whose handle is gone is removed by its CloseCursor
+        let _ = self.ready_reads.send(Err(Error{error_num, ..err.clone()}));
+    }
 }
 
 pub struct ThreadLogBuilder<FinshedReadQueue, FinshedWriteQueue=()> {
@@ -77,6 +144,7 @@ pub struct ThreadLogBuilder<FinshedReadQueue, FinshedWriteQueue=()> {
     no_remote_style: NoRemoteStyle,
 
     my_colors_chains: Option<HashSet<order>>,
+    entry_cache: Option<Arc<EntryCache>>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -107,6 +175,7 @@ impl<FinshedReadQueue> ThreadLogBuilder<FinshedReadQueue, ()> {
             no_remote_style: NoRemoteStyle::NoConnection,
 
             my_colors_chains: None,
+            entry_cache: None,
         }
     }
 }
@@ -130,6 +199,7 @@ impl<FinshedReadQueue, FinshedWriteQueue> ThreadLogBuilder<FinshedReadQueue, Fin
             fetch_boring_multis,
             no_remote_style,
             my_colors_chains,
+            entry_cache,
         } = self;
         ThreadLogBuilder{
             to_store,
@@ -142,6 +212,7 @@ impl<FinshedReadQueue, FinshedWriteQueue> ThreadLogBuilder<FinshedReadQueue, Fin
             ack_writes: true,
             finished_writes: to,
             my_colors_chains,
+            entry_cache,
         }
     }
 
@@ -177,6 +248,11 @@ impl<FinshedReadQueue, FinshedWriteQueue> ThreadLogBuilder<FinshedReadQueue, Fin
         ThreadLogBuilder{ my_colors_chains: Some(chains), .. builder }
     }
 
+    /// Answer reads from `cache` when it can, and add what is read to it.
+    pub fn entry_cache(self, cache: Arc<EntryCache>) -> Self {
+        ThreadLogBuilder{ entry_cache: Some(cache), .. self}
+    }
+
     pub fn build(self) -> ThreadLog<FinshedReadQueue, FinshedWriteQueue>
     where
         FinshedReadQueue: OnRead,
@@ -192,6 +268,7 @@ impl<FinshedReadQueue, FinshedWriteQueue> ThreadLogBuilder<FinshedReadQueue, Fin
             fetch_boring_multis,
             no_remote_style,
             my_colors_chains,
+            entry_cache,
         } = self;
         ThreadLog {
             to_store,
@@ -216,6 +293,11 @@ impl<FinshedReadQueue, FinshedWriteQueue> ThreadLogBuilder<FinshedReadQueue, Fin
             prefetch: 1,
             last_seen_entries: Default::default(),
             my_colors_chains: my_colors_chains.unwrap_or_default(),
+            cursors: HashMap::default(),
+            active_cursor: None,
+            snapshot_readers: HashMap::default(),
+            entry_cache,
+            cached_reads: VecDeque::new(),
         }
     }
 }
@@ -231,6 +313,21 @@ pub struct Error {
     error_num: u64,
     server: usize,
     error: io::ErrorKind,
+    /// Why a server refused a request, if it did.
+    refused: Option<Refusal>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Refusal {
+    /// The color a server refused us, see `fuzzy_log_server::access`.
+    Denied(order),
+    /// The color whose quota an append would exceed, see `fuzzy_log_server::quota`.
+    OverQuota(order),
+    /// The append which failed its checksum, see `packets::checksum`.
+    Corrupted(Uuid),
+    /// The first location after an aborted transaction's stale read,
+    /// see `log_handle::Transaction`.
+    Aborted(OrderIndex),
 }
 
 counters!{
@@ -259,6 +356,10 @@ pub enum FromStore {
     WriteComplete(Uuid, Vec<OrderIndex>), //TODO
     ReadComplete(OrderIndex, Vec<u8>),
     IoError(io::ErrorKind, usize),
+    PermissionDenied(order, usize),
+    QuotaExceeded(order, usize),
+    Corrupted(Uuid, usize),
+    Aborted(OrderIndex, usize),
 }
 
 pub enum FromClient {
@@ -266,12 +367,20 @@ pub enum FromClient {
     SnapshotAndPrefetch(order),
     MultiSnapshotAndPrefetch(Vec<order>),
     StrongSnapshotAndPrefetch(Vec<OrderIndex>),
+    SnapshotAtLeastAndPrefetch(Vec<OrderIndex>),
     PerformAppend(Vec<u8>),
     ReturnBuffer(Vec<u8>),
     ReadUntil(OrderIndex),
     Fastforward(OrderIndex),
+    /// Skip the entries of a chain up to the index without reading them.
+    StartAfter(OrderIndex),
     Rewind(OrderIndex),
     StopAckingWrites,
+    /// Start a cursor over the chains, whose reads are sent to the queue.
+    OpenCursor(CursorId, String, Vec<order>, FinshedReadQueue),
+    /// A read request for a cursor instead of the handle.
+    ForCursor(CursorId, Box<FromClient>),
+    CloseCursor(CursorId),
     Shutdown,
 }
 
@@ -341,7 +450,7 @@ where
             else {
                 #[cfg(feature = "print_stats")]
                 {
-                    println!("no log activity for 10s, {:?}",
+                    println!("no log activity for 3s, {:?}",
                         self.print_data);
                 }
             }
@@ -349,10 +458,18 @@ where
     }
 
     fn handle_message(&mut self, msg: Message) -> bool {
-        match msg {
+        let keep_running = match msg {
             Message::FromClient(msg) => self.handle_from_client(msg),
             Message::FromStore(msg) => self.handle_from_store(msg),
+        };
+        // handling a cached read can fetch more, which may also be cached
+        while keep_running && !self.finished {
+            match self.cached_reads.pop_front() {
+                Some((loc, msg)) => self.route_completed_read(loc, msg),
+                None => break,
+            }
         }
+        keep_running
     }
 
     fn handle_from_client(&mut self, msg: FromClient) -> bool {
@@ -397,10 +514,33 @@ where
                 }
                 true
             },
+            SnapshotAtLeastAndPrefetch(horizons) => {
+                self.print_data.snap(1);
+                self.num_snapshots = self.num_snapshots.saturating_add(1);
+                trace!("FUZZY snapshot at least {:?}: {:?}", horizons, self.num_snapshots);
+                for OrderIndex(chain, index) in horizons {
+                    // The session has already seen everything up to index,
+                    // so we read at least that far even if the server we ask
+                    // for a snapshot is lagging behind the one that was written to.
+                    let unblocked = self.per_chains.entry(chain)
+                        .or_insert_with(|| PerColor::new(chain))
+                        .update_horizon(index);
+                    if let Some(val) = unblocked {
+                        let locs = self.return_entry(val);
+                        if let Some(locs) = locs { self.stop_blocking_on(locs) }
+                    }
+                    self.fetch_snapshot(chain);
+                    self.prefetch(chain);
+                }
+                true
+            },
             PerformAppend(mut msg) => {
                 self.print_data.append(1);
 
-                if !self.my_colors_chains.is_empty() {
+                //a transaction's deps are its read set, the servers check them as they are
+                let is_transaction =
+                    bytes_as_entry(&msg).flag().contains(EntryFlag::Transaction);
+                if !self.my_colors_chains.is_empty() && !is_transaction {
                     msg = {
 
                         let contents = bytes_as_entry(&msg);
@@ -421,6 +561,8 @@ where
                     let layout = bytes_as_entry(&msg).layout();
                     assert!(layout == EntryLayout::Data || layout == EntryLayout::Multiput);
                 }
+                // the deps are final, so the checksum can cover them
+                bytes_as_entry_mut(&mut msg).seal_checksum();
                 self.to_store.send(msg).expect("store hung up");
                 true
             }
@@ -451,6 +593,12 @@ where
                 pc.give_new_snapshot(loc.1);
                 true
             }
+            StartAfter(loc) => {
+                self.per_chains.entry(loc.0)
+                    .or_insert_with(|| PerColor::new(loc.0))
+                    .skip_to(loc.1);
+                true
+            }
             Rewind(loc) => {
                 let pc = self.per_chains.entry(loc.0)
                     .or_insert_with(|| PerColor::new(loc.0));
@@ -462,6 +610,26 @@ where
                 self.ack_writes = false;
                 true
             }
+            OpenCursor(id, name, chains, ready_reads) => {
+                trace!("FUZZY open cursor {:?} {:?} on {:?}", id, name, chains);
+                self.cursors.insert(id, Cursor::new(id, name, chains, ready_reads));
+                true
+            }
+            ForCursor(id, msg) => {
+                if !self.enter_cursor(id) {
+                    trace!("FUZZY message for closed cursor {:?}", id);
+                    return true
+                }
+                let keep_running = self.handle_from_client(*msg);
+                self.leave_cursor();
+                keep_running
+            }
+            CloseCursor(id) => {
+                if let Some(cursor) = self.cursors.remove(&id) {
+                    trace!("FUZZY close cursor {:?} {:?}", id, cursor.name);
+                }
+                true
+            }
             Shutdown => {
                 self.print_data.shut(1);
                 self.finished = true;
@@ -488,38 +656,178 @@ where
             },
             ReadComplete(loc, msg) => {
                 self.print_data.read_done(1);
-                self.handle_completed_read(loc, msg)
+                if let Some(ref cache) = self.entry_cache {
+                    cache.insert(loc, &msg);
+                    // reads which find no entry tell us where the server trimmed the chain to
+                    let e = bytes_as_entry(&msg);
+                    if e.kind().layout() == EntryLayout::Read {
+                        let OrderIndex(chain, first_kept) = e.min();
+                        if first_kept > entry::from(0) {
+                            cache.trim(chain, first_kept)
+                        }
+                    }
+                }
+                self.route_completed_read(loc, msg)
             },
             IoError(kind, server) => {
-                let err = self.make_error(kind, server);
-                let e1 = if self.ack_writes {
-                    self.finished_writes.send(Err(err.clone()))
-                } else {
-                    Ok(())
-                };
-                let e2 = self.ready_reads.send(Err(err));
-                if e1.is_err() || e2.is_err() {
-                    self.finished = true;
-                }
-            }
+                let err = self.make_error(kind, server, None);
+                self.send_error(err)
+            },
+            PermissionDenied(color, server) => {
+                let err = self.make_error(io::ErrorKind::PermissionDenied, server, Some(Refusal::Denied(color)));
+                self.send_error(err)
+            },
+            QuotaExceeded(color, server) => {
+                let err = self.make_error(io::ErrorKind::Other, server, Some(Refusal::OverQuota(color)));
+                self.send_error(err)
+            },
+            Corrupted(id, server) => {
+                let err = self.make_error(io::ErrorKind::InvalidData, server, Some(Refusal::Corrupted(id)));
+                self.send_error(err)
+            },
+            Aborted(stale, server) => {
+                let err = self.make_error(io::ErrorKind::Other, server, Some(Refusal::Aborted(stale)));
+                self.send_error(err)
+            },
         }
         true
     }
 
-    fn make_error(&mut self, error: io::ErrorKind, server: usize) -> Error {
+    fn send_error(&mut self, err: Error) {
+        let e1 = if self.ack_writes {
+            self.finished_writes.send(Err(err.clone()))
+        } else {
+            Ok(())
+        };
+        for cursor in self.cursors.values_mut() {
+            cursor.send_error(&err)
+        }
+        let e2 = self.ready_reads.send(Err(err));
+        if e1.is_err() || e2.is_err() {
+            self.finished = true;
+        }
+    }
+
+    /// Send a read to whoever is being served, the handle or a cursor.
+    fn send_read(&mut self, read: Result<Vec<u8>, Error>) -> Result<(), ()> {
+        match self.active_cursor {
+            // a cursor whose handle is gone is removed by its CloseCursor
+            Some(ref mut cursor) => {
+                let _ = cursor.ready_reads.send(read);
+                Ok(())
+            },
+            None => self.ready_reads.send(read).map_err(|_| ()),
+        }
+    }
+
+    fn enter_cursor(&mut self, id: CursorId) -> bool {
+        debug_assert!(self.active_cursor.is_none());
+        match self.cursors.remove(&id) {
+            Some(mut cursor) => {
+                self.swap_frontier(&mut cursor.frontier);
+                self.active_cursor = Some(cursor);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn leave_cursor(&mut self) {
+        if let Some(mut cursor) = self.active_cursor.take() {
+            self.swap_frontier(&mut cursor.frontier);
+            self.cursors.insert(cursor.id, cursor);
+        }
+    }
+
+    fn swap_frontier(&mut self, frontier: &mut Frontier) {
+        mem::swap(&mut self.per_chains, &mut frontier.per_chains);
+        mem::swap(&mut self.blockers, &mut frontier.blockers);
+        mem::swap(&mut self.blocked_multiappends, &mut frontier.blocked_multiappends);
+        mem::swap(&mut self.no_longer_blocked, &mut frontier.no_longer_blocked);
+        mem::swap(&mut self.chains_currently_being_read, &mut frontier.chains_currently_being_read);
+        mem::swap(&mut self.num_snapshots, &mut frontier.num_snapshots);
+        mem::swap(&mut self.prefetch, &mut frontier.prefetch);
+    }
+
+    fn note_snapshot_reader(&mut self, chain: order) {
+        let reader = self.active_cursor.as_ref().map(|c| c.id);
+        self.snapshot_readers.entry(chain).or_insert_with(VecDeque::new).push_back(reader)
+    }
+
+    /// Hand a read to the frontiers which asked for it.
+    /// Snapshots go to whoever took them, in the order they were taken.
+    /// Other reads go to every frontier waiting on that location,
+    /// so a read wanted by several cursors is fetched once if they ask at once.
+    /// A read no frontier is waiting on is dropped: when several frontiers
+    /// fetched the same location the first answer satisfies all of them,
+    /// and a cursor may be closed while its reads are in flight.
+    fn route_completed_read(&mut self, loc: OrderIndex, msg: Vec<u8>) {
+        let is_snapshot = match bytes_as_entry(&msg).kind().layout() {
+            EntryLayout::Snapshot => true,
+            EntryLayout::Read => loc.1 == u64::MAX.into(),
+            _ => false,
+        };
+        if is_snapshot {
+            let reader = self.snapshot_readers.get_mut(&loc.0)
+                .and_then(|readers| readers.pop_front())
+                .unwrap_or(None);
+            return self.completed_read_for(reader, loc, msg)
+        }
+        let mut readers: Vec<_> = self.cursors.values()
+            .filter(|c| c.frontier.per_chains.get(&loc.0).map_or(false, |pc| pc.is_fetching(loc.1)))
+            .map(|c| Some(c.id))
+            .collect();
+        let handle_is_fetching = self.per_chains.get(&loc.0)
+            .map_or(false, |pc| pc.is_fetching(loc.1));
+        if handle_is_fetching {
+            readers.push(None)
+        }
+        let last = match readers.pop() {
+            Some(last) => last,
+            None => {
+                trace!("FUZZY no one is fetching {:?}", loc);
+                return
+            },
+        };
+        for reader in readers {
+            self.completed_read_for(reader, loc, msg.clone())
+        }
+        self.completed_read_for(last, loc, msg)
+    }
+
+    fn completed_read_for(&mut self, reader: Option<CursorId>, loc: OrderIndex, msg: Vec<u8>) {
+        match reader {
+            None => self.handle_completed_read(loc, msg),
+            Some(id) => if self.enter_cursor(id) {
+                self.handle_completed_read(loc, msg);
+                self.leave_cursor();
+            },
+        }
+    }
+
+    fn make_error(
+        &mut self,
+        error: io::ErrorKind,
+        server: usize,
+        refused: Option<Refusal>,
+    ) -> Error {
         let error_num = self.num_errors;
         self.num_errors += 1;
-        Error {error_num, error, server,}
+        Error {error_num, error, server, refused,}
     }
 
     fn fetch_snapshot(&mut self, chain: order) {
         //XXX outstanding_snapshots is incremented in prefetch
+        self.note_snapshot_reader(chain);
         let packet = self.make_read_packet(chain, u64::MAX.into());
         self.to_store.send(packet).expect("store hung up")
     }
 
     fn fetch_strong_snapshot(&mut self, chains: &[OrderIndex]) {
         //XXX outstanding_snapshots is incremented in prefetch
+        for &OrderIndex(chain, _) in chains {
+            self.note_snapshot_reader(chain)
+        }
         let packet = {
             let mut buffer = self.cache.alloc();
             EntryContents::Snapshot {
@@ -606,7 +914,7 @@ where
                     }
                 }
                 if self.return_snapshots {
-                    self.ready_reads.send(Ok(msg)).expect("client gone");
+                    self.send_read(Ok(msg)).expect("client gone");
                 }
             }
             EntryLayout::Read => {
@@ -646,7 +954,7 @@ where
                         if let Some(locs) = locs { self.stop_blocking_on(locs) }
                     }
                     if self.return_snapshots {
-                        self.ready_reads.send(Ok(msg)).expect("client gone");
+                        self.send_read(Ok(msg)).expect("client gone");
                     }
                 }
             }
@@ -763,7 +1071,7 @@ where
                 //FIXME add is_snapshoting to PerColor so this doesn't race?
                 trace!("FUZZY finished reading {:?} snaps", num_completeds);
                 for _ in 0..num_completeds {
-                    if self.ready_reads.send(Ok(vec![])).is_err() {
+                    if self.send_read(Ok(vec![])).is_err() {
                         self.finished = true;
                     }
                 }
@@ -1325,7 +1633,7 @@ where
         trace!("FUZZY returning read @ {:?}", loc);
         if is_interesting {
             //FIXME first_buffered?
-            if self.ready_reads.send(Ok(val)).is_err() {
+            if self.send_read(Ok(val)).is_err() {
                 self.finished = true;
             }
         }
@@ -1422,7 +1730,7 @@ where
         trace!("FUZZY returning read @ {:?}", locs);
         if is_interesting {
             //FIXME first_buffered?
-            if self.ready_reads.send(Ok(val)).is_err() {
+            if self.send_read(Ok(val)).is_err() {
                 self.finished = true;
             }
         }
@@ -1441,6 +1749,11 @@ where
                 &self.chains_currently_being_read)
         };
         for next in low..high+1 {
+            let loc = OrderIndex(chain, next.into());
+            if let Some(cached) = self.cached_read(loc) {
+                self.cached_reads.push_back((loc, cached));
+                continue
+            }
             let packet = self.make_read_packet(chain, next.into());
             if self.to_store.send(packet).is_err() {
                 self.finished = true;
@@ -1448,6 +1761,19 @@ where
         }
     }
 
+    fn cached_read(&mut self, loc: OrderIndex) -> Option<Vec<u8>> {
+        let cache = match self.entry_cache {
+            Some(ref cache) => cache,
+            None => return None,
+        };
+        let mut buffer = self.cache.alloc();
+        if cache.get_into(loc, &mut buffer) {
+            return Some(buffer)
+        }
+        self.cache.cache_buffer(buffer);
+        None
+    }
+
     fn make_read_packet(&mut self, chain: order, index: entry) -> Vec<u8> {
         let mut buffer = self.cache.alloc();
         EntryContents::Read{
@@ -1580,6 +1906,26 @@ impl AsyncStoreClient for mpsc::Sender<Message> {
         self.send(Message::FromStore(IoError(err.kind(), server)))
             .map(|_| ()).map_err(|_| ())
     }
+
+    fn on_permission_denied(&mut self, color: order, server: usize) -> Result<(), ()> {
+        self.send(Message::FromStore(PermissionDenied(color, server)))
+            .map(|_| ()).map_err(|_| ())
+    }
+
+    fn on_quota_exceeded(&mut self, color: order, server: usize) -> Result<(), ()> {
+        self.send(Message::FromStore(QuotaExceeded(color, server)))
+            .map(|_| ()).map_err(|_| ())
+    }
+
+    fn on_corrupted(&mut self, id: Uuid, server: usize) -> Result<(), ()> {
+        self.send(Message::FromStore(Corrupted(id, server)))
+            .map(|_| ()).map_err(|_| ())
+    }
+
+    fn on_aborted(&mut self, _id: Uuid, stale: OrderIndex, server: usize) -> Result<(), ()> {
+        self.send(Message::FromStore(Aborted(stale, server)))
+            .map(|_| ()).map_err(|_| ())
+    }
 }
 
 pub trait OnRead {
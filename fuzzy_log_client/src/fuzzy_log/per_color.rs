@@ -195,6 +195,11 @@ impl PerColor {
         //index > self.last_returned_to_client
     }
 
+    /// Whether a read of `index` has been sent and not yet answered.
+    pub fn is_fetching(&self, index: entry) -> bool {
+        self.read_status.is_outstanding(index)
+    }
+
     pub fn has_read_state(&self) -> bool {
         self.is_being_read.is_some()
     }
@@ -246,6 +251,17 @@ impl PerColor {
         self.update_horizon(new_horizon)
     }
 
+    /// Treat everything up to `index` as already returned, without reading it.
+    pub fn skip_to(&mut self, index: entry) {
+        assert!(!self.has_read_state(), "skipping {:?} while it is being read", self.chain);
+        if index == entry::from(0) {
+            return
+        }
+        self.read_status.set_below_as_returned(index);
+        let _unblocked = self.update_horizon(index);
+        debug_assert!(_unblocked.is_none());
+    }
+
     pub fn rewind_to(&mut self, index: entry) {
         self.read_status.set_above_as_none(index)
     }
@@ -182,6 +182,20 @@ impl RangeTree {
         debug_assert!(self.tree_invariant(), "invariant failed @ {:#?}", self);
     }
 
+    /// Mark every point up to `high` as returned, none of them may have been read yet.
+    pub fn set_below_as_returned(&mut self, high: entry) {
+        debug_assert!(self.tree_invariant(), "invariant failed @ {:#?}", self);
+        let (old_range, old_kind) = remove_from_map(&mut self.inner, Range::point(high));
+        assert_eq!(old_kind, Kind::None);
+        assert_eq!(old_range.first(), 1.into(), "already read below {:?}", high);
+        remove_from_map(&mut self.inner, Range::point(0.into()));
+        self.inner.insert(Range::new(0.into(), high), Kind::ReturnedToClient);
+        if high < old_range.last() {
+            self.inner.insert(Range::new(high + 1, old_range.last()), Kind::None);
+        }
+        debug_assert!(self.tree_invariant(), "invariant failed @ {:#?}", self);
+    }
+
     pub fn set_above_as_none(&mut self, low: entry) {
         debug_assert!(self.tree_invariant(), "invariant failed @ {:#?}", self);
         let new_range = Range::new(low, u64::MAX.into());
@@ -205,6 +219,13 @@ impl RangeTree {
         }
     }
 
+    pub fn is_outstanding(&self, point: entry) -> bool {
+        match self.inner.get(&Range::point(point)) {
+            Some(&Kind::SentToServer) => true,
+            _ => false,
+        }
+    }
+
     pub fn next_return_is(&self, point: entry) -> bool {
         match (self.inner.get(&Range::point(point - 1)), self.inner.get(&Range::point(point))) {
             (_, Some(&Kind::ReturnedToClient)) => false,
@@ -510,4 +531,13 @@ mod test {
         println!("{:?}", tree);
         assert!(false);
     }
+
+    #[test]
+    fn start_after() {
+        let mut tree = RangeTree::new();
+        tree.set_below_as_returned(7.into());
+        assert!(tree.is_returned(7.into()));
+        assert!(tree.next_return_is(8.into()));
+        assert_eq!(tree.min_range_to_fetch(), (8, u64::MAX));
+    }
 }
@@ -0,0 +1,98 @@
+use std::{mem, ptr};
+
+use packets::{data_to_slice, entry, order, OrderIndex};
+
+/// The set of horizons a session has observed,
+/// one `OrderIndex` per color the session has read from or written to.
+///
+/// A token can be exported from one handle and passed, possibly via another
+/// process, to `snapshot_at_least` on a different handle; that handle's next
+/// snapshot will then include every entry the first session saw.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SessionToken {
+    //sorted by color, at most one entry per color
+    horizons: Vec<OrderIndex>,
+}
+
+impl SessionToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The furthest entry the session has observed in each color.
+    pub fn horizons(&self) -> &[OrderIndex] {
+        &self.horizons
+    }
+
+    pub fn horizon_for(&self, color: order) -> Option<entry> {
+        self.horizons.binary_search_by_key(&color, |oi| oi.0).ok()
+            .map(|i| self.horizons[i].1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.horizons.is_empty()
+    }
+
+    /// Record that the session has seen the entries at `locs`.
+    pub fn observe(&mut self, locs: &[OrderIndex]) {
+        for &OrderIndex(o, i) in locs {
+            //the locations of unreached colors are not real entries
+            if o == order::from(0) || i == entry::from(0) {
+                continue
+            }
+            match self.horizons.binary_search_by_key(&o, |oi| oi.0) {
+                Ok(pos) => if self.horizons[pos].1 < i {
+                    self.horizons[pos].1 = i
+                },
+                Err(pos) => self.horizons.insert(pos, OrderIndex(o, i)),
+            }
+        }
+    }
+
+    /// Combine the horizons of two sessions, keeping the later entry for each color.
+    pub fn merge(&mut self, other: &SessionToken) {
+        self.observe(&other.horizons)
+    }
+
+    /// The token in the same in-memory format the log uses on the wire.
+    pub fn as_bytes(&self) -> &[u8] {
+        data_to_slice(&self.horizons[..])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let size = mem::size_of::<OrderIndex>();
+        if bytes.len() % size != 0 {
+            return None
+        }
+        let mut token = SessionToken::new();
+        let locs: Vec<OrderIndex> = bytes.chunks(size).map(|loc| unsafe {
+            ptr::read_unaligned(loc.as_ptr() as *const OrderIndex)
+        }).collect();
+        token.observe(&locs);
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn observe_keeps_max() {
+        let mut token = SessionToken::new();
+        token.observe(&[(3, 4).into(), (1, 7).into()]);
+        token.observe(&[(3, 2).into(), (1, 9).into(), (2, 0).into()]);
+        assert_eq!(token.horizons(), &[OrderIndex::from((1, 9)), OrderIndex::from((3, 4))][..]);
+        assert_eq!(token.horizon_for(3.into()), Some(4.into()));
+        assert_eq!(token.horizon_for(2.into()), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut token = SessionToken::new();
+        token.observe(&[(5, 1).into(), (2, 11).into()]);
+        let bytes = token.as_bytes().to_vec();
+        assert_eq!(SessionToken::from_bytes(&bytes), Some(token));
+        assert_eq!(SessionToken::from_bytes(&bytes[1..]), None);
+    }
+}
@@ -15,3 +15,6 @@ pub mod fuzzy_log;
 pub mod colors;
 pub mod store;
 pub mod replicator;
+pub mod causal;
+pub mod state_machine;
+pub mod catalog;
@@ -0,0 +1,327 @@
+//! Replicated state machines materialized from the log.
+//!
+//! A service implements `StateMachine`: how its operations are encoded, how
+//! they change its state, and how that state is saved and restored.
+//! A `Replica` drives it: operations are appended to the service's colors,
+//! the log is replayed into the state, observations wait on a read barrier,
+//! and checkpoints let a new replica start from a saved state instead of
+//! replaying the whole log.
+//!
+//! A replica has local colors, which it always reads and by default appends
+//! to, and remote colors, which it only reads on a full barrier. Appends which
+//! include a remote color do not make readers of the local colors wait for the
+//! remote one, as with `LogHandle::no_remote_multiappend`.
+
+use fuzzy_log::log_handle::{
+    entry, order, GetRes, LogHandle, OrderIndex, SessionToken, TryWaitRes, Uuid,
+};
+
+pub trait StateMachine {
+    type Op;
+
+    fn encode(op: &Self::Op, buffer: &mut Vec<u8>);
+
+    /// `None` if the bytes are not an op of this state machine,
+    /// such entries are skipped.
+    fn decode(bytes: &[u8]) -> Option<Self::Op>;
+
+    /// Apply an op read from the log at `locs`, `id` is unique to its entry.
+    /// Every replica applies the ops in a color in the same order.
+    fn apply(&mut self, op: Self::Op, locs: &[OrderIndex], id: &Uuid);
+
+    /// Save the state, to be given to `restore` in another replica.
+    fn snapshot(&self, buffer: &mut Vec<u8>);
+
+    fn restore(&mut self, bytes: &[u8]);
+}
+
+/// Why a barrier failed: either an outstanding append or a read did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaError {
+    Append(TryWaitRes),
+    Read(GetRes),
+}
+
+impl From<TryWaitRes> for ReplicaError {
+    fn from(e: TryWaitRes) -> Self {
+        ReplicaError::Append(e)
+    }
+}
+
+impl From<GetRes> for ReplicaError {
+    fn from(e: GetRes) -> Self {
+        ReplicaError::Read(e)
+    }
+}
+
+/// A saved state, and the last entry in each color which it includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub state: Vec<u8>,
+    pub horizon: SessionToken,
+}
+
+pub struct Replica<S: StateMachine> {
+    handle: LogHandle<[u8]>,
+    state: S,
+    local: Vec<order>,
+    remote: Vec<order>,
+    applied: SessionToken,
+    /// Entries at or before this were included in the checkpoint we started from.
+    restored: SessionToken,
+
+    buffer: Vec<u8>,
+    batch_size: usize,
+    unacked_appends: usize,
+
+    checkpoint_every: usize,
+    applied_since_checkpoint: usize,
+    on_checkpoint: Option<Box<FnMut(&Checkpoint) + Send>>,
+}
+
+impl<S: StateMachine> Replica<S> {
+    /// `local_colors` must not be empty, the first is the one `append` writes to.
+    pub fn new(handle: LogHandle<[u8]>, state: S, local_colors: Vec<order>) -> Self {
+        assert!(!local_colors.is_empty(), "a replica needs a color to append to");
+        Replica {
+            handle,
+            state,
+            local: local_colors,
+            remote: vec![],
+            applied: SessionToken::new(),
+            restored: SessionToken::new(),
+            buffer: vec![],
+            batch_size: 1,
+            unacked_appends: 0,
+            checkpoint_every: 0,
+            applied_since_checkpoint: 0,
+            on_checkpoint: None,
+        }
+    }
+
+    /// Start from a checkpoint; the handle skips the entries it includes,
+    /// so it must not have read any of the checkpoint's colors yet.
+    pub fn from_checkpoint(
+        mut handle: LogHandle<[u8]>,
+        mut state: S,
+        local_colors: Vec<order>,
+        checkpoint: &Checkpoint,
+    ) -> Self {
+        state.restore(&checkpoint.state);
+        for &loc in checkpoint.horizon.horizons() {
+            handle.start_after(loc);
+        }
+        let mut replica = Self::new(handle, state, local_colors);
+        replica.applied = checkpoint.horizon.clone();
+        replica.restored = checkpoint.horizon.clone();
+        replica
+    }
+
+    /// Colors which are only read on a `full_barrier`.
+    pub fn remote_colors(mut self, colors: Vec<order>) -> Self {
+        self.remote = colors;
+        self
+    }
+
+    /// Let up to `size` appends be in flight before waiting for them.
+    /// Barriers always wait for every outstanding append first.
+    pub fn batch_appends(mut self, size: usize) -> Self {
+        assert!(size > 0);
+        self.batch_size = size;
+        self
+    }
+
+    /// Take a checkpoint after every `ops` applied ops and hand it to `on_checkpoint`.
+    pub fn checkpoint_every<F>(mut self, ops: usize, on_checkpoint: F) -> Self
+    where F: FnMut(&Checkpoint) + Send + 'static {
+        assert!(ops > 0);
+        self.checkpoint_every = ops;
+        self.on_checkpoint = Some(Box::new(on_checkpoint));
+        self
+    }
+
+    /// Append an op to this replica's own color.
+    pub fn append(&mut self, op: &S::Op) -> Result<(), TryWaitRes> {
+        let color = self.local[0];
+        self.append_to(op, &[color])
+    }
+
+    pub fn append_to(&mut self, op: &S::Op, colors: &[order]) -> Result<(), TryWaitRes> {
+        assert!(!colors.is_empty());
+        self.buffer.clear();
+        S::encode(op, &mut self.buffer);
+        if colors.len() == 1 {
+            self.handle.async_append(colors[0], &self.buffer[..], &[]);
+        } else if colors.iter().any(|c| self.remote.contains(c)) {
+            self.handle.async_no_remote_multiappend(colors, &self.buffer[..], &[]);
+        } else {
+            self.handle.async_multiappend(colors, &self.buffer[..], &[]);
+        }
+        self.unacked_appends += 1;
+        if self.unacked_appends >= self.batch_size {
+            return self.flush()
+        }
+        Ok(())
+    }
+
+    /// Append an op to this replica's own color which every replica applies after
+    /// what this one has applied from the other colors,
+    /// see `LogHandle::causal_color_append`.
+    pub fn causal_append(&mut self, op: &S::Op) -> Result<Uuid, TryWaitRes> {
+        self.buffer.clear();
+        S::encode(op, &mut self.buffer);
+        let color = self.local[0];
+        let mut happens_after: Vec<_> = self.applied.horizons().iter()
+            .filter(|oi| oi.0 != color)
+            .cloned()
+            .collect();
+        let id = self.handle.causal_color_append(
+            &self.buffer[..], &mut [color], &mut [], &mut happens_after[..]
+        );
+        self.unacked_appends += 1;
+        if self.unacked_appends >= self.batch_size {
+            self.flush()?
+        }
+        Ok(id)
+    }
+
+    /// Wait for every outstanding append.
+    pub fn flush(&mut self) -> Result<(), TryWaitRes> {
+        if self.unacked_appends == 0 {
+            return Ok(())
+        }
+        self.unacked_appends = 0;
+        self.handle.wait_for_all_appends()
+    }
+
+    /// Apply everything in the local colors which was appended before this call,
+    /// including our own appends.
+    pub fn barrier(&mut self) -> Result<(), ReplicaError> {
+        self.flush()?;
+        self.handle.snapshot_colors(&self.local);
+        self.play()
+    }
+
+    /// `barrier` over the remote colors too.
+    pub fn full_barrier(&mut self) -> Result<(), ReplicaError> {
+        self.flush()?;
+        let mut colors = self.local.clone();
+        colors.extend_from_slice(&self.remote);
+        self.handle.snapshot_colors(&colors);
+        self.play()
+    }
+
+    /// Apply everything at least as new as what `token`'s session observed.
+    pub fn barrier_at_least(&mut self, token: &SessionToken) -> Result<(), ReplicaError> {
+        self.flush()?;
+        self.handle.snapshot_at_least(token);
+        self.play()
+    }
+
+    /// A linearizable observation of the local colors.
+    pub fn observe<F, R>(&mut self, observation: F) -> Result<R, ReplicaError>
+    where F: FnOnce(&S) -> R {
+        self.barrier()?;
+        Ok(observation(&self.state))
+    }
+
+    /// The state as of the last barrier, which may be stale.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// The colors this replica always reads, it appends to the first.
+    pub fn local_colors(&self) -> &[order] {
+        &self.local
+    }
+
+    /// The last entry applied in each color.
+    pub fn applied(&self) -> &SessionToken {
+        &self.applied
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut state = vec![];
+        self.state.snapshot(&mut state);
+        Checkpoint { state, horizon: self.applied.clone() }
+    }
+
+    pub fn handle(&mut self) -> &mut LogHandle<[u8]> {
+        &mut self.handle
+    }
+
+    pub fn into_inner(self) -> (LogHandle<[u8]>, S) {
+        (self.handle, self.state)
+    }
+
+    fn play(&mut self) -> Result<(), ReplicaError> {
+        loop {
+            match self.handle.get_next2() {
+                Ok((bytes, locs, id)) => {
+                    if already_applied(&self.restored, locs) {
+                        continue
+                    }
+                    self.applied.observe(locs);
+                    match S::decode(bytes) {
+                        Some(op) => self.state.apply(op, locs, id),
+                        None => {
+                            warn!("skipping undecodable entry at {:?}", locs);
+                            continue
+                        },
+                    }
+                },
+                Err(GetRes::Done) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            self.applied_since_checkpoint += 1;
+            if self.checkpoint_every > 0
+                && self.applied_since_checkpoint >= self.checkpoint_every {
+                self.applied_since_checkpoint = 0;
+                let checkpoint = self.checkpoint();
+                if let Some(ref mut on_checkpoint) = self.on_checkpoint {
+                    on_checkpoint(&checkpoint)
+                }
+            }
+        }
+    }
+}
+
+/// Was the entry at `locs` included in a checkpoint taken at `horizon`?
+/// Since a checkpoint includes every color of each entry it applied,
+/// an entry is new as soon as one of its locations is past the horizon.
+fn already_applied(horizon: &SessionToken, locs: &[OrderIndex]) -> bool {
+    let mut included = false;
+    for &OrderIndex(o, i) in locs {
+        if o == order::from(0) || i == entry::from(0) {
+            continue
+        }
+        match horizon.horizon_for(o) {
+            Some(h) if i <= h => included = true,
+            Some(..) => return false,
+            None => {},
+        }
+    }
+    included
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_what_the_checkpoint_includes() {
+        let mut horizon = SessionToken::new();
+        horizon.observe(&[(3, 4).into(), (5, 2).into()]);
+        assert!(already_applied(&horizon, &[(3, 4).into()]));
+        assert!(already_applied(&horizon, &[(3, 1).into(), (5, 2).into()]));
+        assert!(already_applied(&horizon, &[(3, 2).into(), (7, 9).into()]));
+        assert!(!already_applied(&horizon, &[(3, 5).into()]));
+        assert!(!already_applied(&horizon, &[(3, 3).into(), (5, 3).into()]));
+        assert!(!already_applied(&horizon, &[(7, 1).into()]));
+        assert!(!already_applied(&SessionToken::new(), &[(3, 1).into()]));
+    }
+}
@@ -5,16 +5,21 @@
 
 
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
 use std::io::{self, Read, Write};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use packets::*;
 use packets::buffer2::Buffer;
+use packets::hello::{self, Credentials, Hello, Rejection};
 
-use hash::{HashMap, HashSet, UuidHashMap};
+use hash::{HashMap, HashSet, UuidHashMap, UuidHashSet};
 //use servers2::spsc;
 use fuzzy_log_util::socket_addr::Ipv4SocketAddr;
 
@@ -32,9 +37,54 @@ pub trait AsyncStoreClient {
 
     fn on_io_error(&mut self, err: io::Error, server: usize) -> Result<(), ()>;
 
+    /// A server refused a request because we may not use `color`.
+    fn on_permission_denied(&mut self, color: order, server: usize) -> Result<(), ()> {
+        let err = io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("permission denied on {:?}", color));
+        self.on_io_error(err, server)
+    }
+
+    /// A server refused an append because it would exceed the quota `color` is charged to.
+    fn on_quota_exceeded(&mut self, color: order, server: usize) -> Result<(), ()> {
+        let err = io::Error::new(io::ErrorKind::Other,
+            format!("quota exceeded on {:?}", color));
+        self.on_io_error(err, server)
+    }
+
+    /// The append `id` failed its checksum at a server, see `packets::checksum`.
+    fn on_corrupted(&mut self, id: Uuid, server: usize) -> Result<(), ()> {
+        let err = io::Error::new(io::ErrorKind::InvalidData,
+            format!("append {:?} corrupted in transit", id));
+        self.on_io_error(err, server)
+    }
+
+    /// The transaction `id` was aborted, `stale` is the first location
+    /// after one of its reads, see `EntryFlag::Transaction`.
+    /// A multiappend aborted while being recovered has a `stale` of `(0, 0)`.
+    fn on_aborted(&mut self, id: Uuid, stale: OrderIndex, server: usize) -> Result<(), ()> {
+        let err = io::Error::new(io::ErrorKind::Other,
+            format!("transaction {:?} aborted at {:?}", id, stale));
+        self.on_io_error(err, server)
+    }
+
     //TODO fn should_shutdown(&mut self) -> bool { false }
 }
 
+/// A no-op request for the store. Sent by its timer thread, see `RetryBackoff`,
+/// so the store resends appends and looks for stalled multiappends when they are due
+/// even when nothing else is happening.
+pub fn recovery_tick() -> Vec<u8> {
+    let mut tick = vec![];
+    EntryContents::CheckSkeens1 {
+        id: &Uuid::nil(),
+        flags: &EntryFlag::Nothing,
+        data_bytes: &0,
+        dependency_bytes: &0,
+        loc: &OrderIndex(0.into(), 0.into()),
+    }.fill_vec(&mut tick);
+    tick
+}
+
 pub type FromClient =  mio::channel::Receiver<Vec<u8>>;
 pub type ToSelf =  mio::channel::Sender<Vec<u8>>;
 fn channel() -> (ToSelf, FromClient) {
@@ -65,6 +115,17 @@ struct StoreInner<C: AsyncStoreClient> {
     receiver: Ipv4SocketAddr,
 
     pending_skeens2: VecDeque<SK2Send>,
+
+    recovery: Option<MultiRecovery>,
+
+    retries: RetryBackoff,
+
+    //transactions a server voted to abort, and where their stale read was overtaken
+    stale_reads: UuidHashMap<OrderIndex>,
+
+    //multiappends some servers refused as corrupt in round 1, and those servers,
+    //the others have queued them so they are aborted there in round 2
+    corrupt_writes: UuidHashMap<Vec<usize>>,
 }
 
 counters!{
@@ -124,6 +185,59 @@ struct SK2Send {
     is_snapshot: bool,
 }
 
+/*
+  Recovery for multiappends whose client died between skeens rounds:
+    when one of our writes has been outstanding for longer than the timeout
+    we ask the servers for its chains what is blocking them (CheckSkeens1 with a nil id),
+    a server with a multiappend stuck in phase 1 replies with that multiappend.
+    We then take the recovery lock for it (UpdateRecovery),
+    check that it's still in phase 1 (CheckSkeens1),
+    and if so resend its skeens1, which servers treat idempotently, and finish it.
+    If someone else holds the lock, or the write finished in the meantime, we give up.
+    A server which only stores a sentinel for the multiappend doesn't have its payload,
+    so we first fetch it from a server storing one of its data chains
+    (CheckSkeens1 with the write's id and no timestamp).
+    If none of them has it waiting anymore the multiappend cannot be finished,
+    so once we hold the lock we abort it at the server it's blocking instead:
+    that server finishes it without a slot, using its own timestamp as the max.
+*/
+struct MultiRecovery {
+    timeout: Duration,
+    last_check: Instant,
+    //when the timer will next wake us to check, while we have writes outstanding
+    wake_at: Option<Instant>,
+    started_writes: UuidHashMap<(Instant, Vec<order>)>,
+    recovering: UuidHashMap<RecoveringWrite>,
+    fetching: UuidHashMap<RecoveringWrite>,
+    restarted: UuidHashSet,
+    to_send: VecDeque<(usize, Vec<u8>)>,
+    to_restart: VecDeque<Vec<u8>>,
+}
+
+/*
+  Appends a server asked us to send again later, because it has too much outstanding work:
+    each time the same write is refused we wait twice as long before resending it,
+    up to a limit. The reactor only wakes up for IO, so a timer thread sends us
+    a recovery_tick when the next resend is due.
+    The same timer wakes us every recovery timeout while we have writes
+    outstanding, so MultiRecovery checks for stalled ones however busy we are.
+*/
+struct RetryBackoff {
+    initial: Duration,
+    max: Duration,
+    attempts: UuidHashMap<u32>,
+    waiting: Vec<(Instant, usize, Uuid)>,
+    to_self: ToSelf,
+    timer: Option<mpsc::Sender<Instant>>,
+}
+
+struct RecoveringWrite {
+    multi: Vec<u8>,
+    blocked_at: OrderIndex,
+    server: usize,
+    abort: bool,
+}
+
 //TODO rename to AsyncStore
 impl<C> AsyncTcpStore<C>
 where C: AsyncStoreClient {
@@ -144,12 +258,24 @@ where C: AsyncStoreClient {
         chain_servers: I,
         client: C,
     ) -> Result<(Self, ToSelf), io::Error>
+    where I: IntoIterator<Item=SocketAddr> {
+        Self::authenticated_new_tcp(id, None, chain_servers, client)
+    }
+
+    /// `new_tcp` which authenticates to the servers with `credentials`,
+    /// anonymously if there are none.
+    pub fn authenticated_new_tcp<I>(
+        id: Ipv4SocketAddr,
+        credentials: Option<Credentials>,
+        chain_servers: I,
+        client: C,
+    ) -> Result<(Self, ToSelf), io::Error>
     where I: IntoIterator<Item=SocketAddr> {
         let servers: Vec<_> = chain_servers.into_iter()
             .map(Self::connect)
             .collect::<Result<_, _>>()?;
         let num_chain_servers = servers.len();
-        Self::build(id, servers, num_chain_servers, client, true)
+        Self::build(id, credentials, servers, num_chain_servers, client, true)
     }
 
     pub fn replicated_new_tcp<I>(
@@ -157,6 +283,16 @@ where C: AsyncStoreClient {
         chain_servers: I,
         client: C,
     ) -> Result<(Self, ToSelf), io::Error>
+    where I: IntoIterator<Item=(SocketAddr, SocketAddr)> {
+        Self::authenticated_replicated_new_tcp(id, None, chain_servers, client)
+    }
+
+    pub fn authenticated_replicated_new_tcp<I>(
+        id: Ipv4SocketAddr,
+        credentials: Option<Credentials>,
+        chain_servers: I,
+        client: C,
+    ) -> Result<(Self, ToSelf), io::Error>
     where I: IntoIterator<Item=(SocketAddr, SocketAddr)> {
         let (write_servers, read_servers): (Vec<_>, Vec<_>) =
             chain_servers.into_iter().inspect(|addrs| trace!("{:?}", addrs)).unzip();
@@ -167,7 +303,7 @@ where C: AsyncStoreClient {
             .chain(read_servers.into_iter())
             .map(Self::connect)
             .collect::<Result<_, _>>()?;
-        Self::build(id, servers, num_chain_servers, client, false)
+        Self::build(id, credentials, servers, num_chain_servers, client, false)
     }
 
     pub fn replicated_tcp<I>(
@@ -183,6 +319,7 @@ where C: AsyncStoreClient {
 
     fn build(
         id: Ipv4SocketAddr,
+        credentials: Option<Credentials>,
         mut servers: Vec<TcpStream>,
         num_chain_servers: usize,
         client: C,
@@ -191,14 +328,31 @@ where C: AsyncStoreClient {
         assert!(num_chain_servers <= servers.len());
         trace!("Client {:?} servers", num_chain_servers);
         {
-            for stream in servers.iter_mut().rev() {
-                blocking_read(stream, &mut [0]).unwrap();
-                blocking_write(stream, &[2]).unwrap();
+            let required = if credentials.is_some() { hello::feature::AUTH } else { 0 };
+            let ours = Hello::current(required);
+            let mut versioned = vec![false; servers.len()];
+            for (stream, versioned) in servers.iter_mut().zip(versioned.iter_mut()).rev() {
+                let mut server_first = [0];
+                blocking_read(stream, &mut server_first).unwrap();
+                let (bytes, sends_reply) = hello::peer_hello_bytes(server_first[0], 2, &ours)
+                    .map_err(|rejection| handshake_error(stream, rejection))?;
+                *versioned = sends_reply;
+                blocking_write(stream, &bytes).unwrap();
+                if let Some(ref credentials) = credentials {
+                    blocking_write(stream, &credentials.to_bytes()).unwrap();
+                }
                 blocking_write(stream, id.bytes()).unwrap();
             }
 
             let mut ack = [0; 16];
-            for stream in servers.iter_mut().rev() {
+            let mut reply = [0; hello::HELLO_REPLY_SIZE];
+            for (stream, &versioned) in servers.iter_mut().zip(versioned.iter()).rev() {
+                if versioned {
+                    blocking_read(stream, &mut reply[..]).unwrap();
+                    let negotiated = hello::check_reply(&ours, &reply)
+                        .map_err(|rejection| handshake_error(stream, rejection))?;
+                    trace!("protocol v{} with {:?}", negotiated.version, stream.peer_addr());
+                }
                 blocking_read(stream, &mut ack[..]).unwrap();
                 assert_eq!(Ipv4SocketAddr::from_bytes(ack), id);
             }
@@ -221,6 +375,11 @@ where C: AsyncStoreClient {
             max_timestamp_seen: Default::default(),
             pending_skeens2: Default::default(),
             receiver: id,
+            recovery: None,
+
+            retries: RetryBackoff::new(to_store.clone()),
+            stale_reads: Default::default(),
+            corrupt_writes: Default::default(),
 
             print_data: Default::default(),
         })?;
@@ -252,6 +411,19 @@ where C: AsyncStoreClient {
         self.reactor.inner().reads_my_writes = reads_my_writes
     }
 
+    pub fn set_recovery_timeout(&mut self, timeout: Option<Duration>) {
+        self.reactor.inner().recovery = timeout.map(MultiRecovery::new)
+    }
+
+    /// How long to wait before resending an append a server asked us to retry later,
+    /// the wait doubles with each retry of the same append up to `max`.
+    /// By default 1ms, up to 1s.
+    pub fn set_retry_backoff(&mut self, initial: Duration, max: Duration) {
+        let retries = &mut self.reactor.inner().retries;
+        retries.initial = initial;
+        retries.max = max;
+    }
+
     pub fn run(mut self) -> ! {
         self.reactor.run().unwrap();
         panic!("should not be");
@@ -272,7 +444,18 @@ where C: AsyncStoreClient {
             (c.kind(), *c.flag())
         };
         trace!("CLIENT got a {:?} from {:?}", kind, token);
-        if flag.contains(EntryFlag::ReadSuccess) {
+        if kind == EntryKind::Rejected {
+            self.handle_rejected(token, &packet)
+        }
+        else if kind == EntryKind::UpdateRecovery || kind == EntryKind::CheckSkeens1 {
+            self.handle_recovery_reply(&packet)
+        }
+        else if flag.contains(EntryFlag::Corrupted)
+            && self.sent_writes.contains_key(packet.contents().id()) {
+            // a replica found our append corrupt, but stored it so the chain stays consistent
+            self.handle_corrupt_append(token, &packet)
+        }
+        else if flag.contains(EntryFlag::ReadSuccess) {
             if !flag.contains(EntryFlag::Unlock)
                 || flag.contains(EntryFlag::NewMultiPut) {
                 let num_chain_servers = self.num_chain_servers;
@@ -285,6 +468,12 @@ where C: AsyncStoreClient {
             //A read that found an usused entry still contains useful data
             self.handle_completed_read(token, &packet, false);
         }
+        else if flag.contains(EntryFlag::TakeLock)
+            && (kind.layout() == EntryLayout::Multiput
+                || kind.layout() == EntryLayout::Sentinel) {
+            //A server is telling us what's blocking one of its chains
+            self.handle_blocking_multi(token, &packet)
+        }
         //TODO use option instead
         else {
             unimplemented!()
@@ -327,6 +516,25 @@ where C: AsyncStoreClient {
                     assert!(kind.contains(EntryKind::Multiput));
                     assert!(self.new_multi);
                     trace!("CLIENT finished sk1 section");
+                    if flag.contains(EntryFlag::Aborted) && !self.stale_reads.contains_key(&id) {
+                        //round 2 carries the vote to the other servers
+                        let mut b = buf.borrow_mut();
+                        let mut e = bytes_as_entry_mut(&mut *b);
+                        e.flag_mut().insert(EntryFlag::Aborted);
+                        //the lock of the vote is the position of the stale read,
+                        //or of a write to a color another transaction locked, after the reads
+                        let conflict = packet.contents().lock_num() as usize;
+                        let num_reads = e.as_ref().dependencies().len();
+                        let stale = if conflict < num_reads {
+                            let OrderIndex(o, horizon) = e.as_ref().dependencies()[conflict];
+                            OrderIndex(o, horizon + 1)
+                        } else {
+                            OrderIndex(e.as_ref().locs()[conflict - num_reads].0, 0.into())
+                        };
+                        trace!("CLIENT {:?} voted to abort {:?}, conflict on {:?}",
+                            token, id, stale.0);
+                        self.stale_reads.insert(id, stale);
+                    }
                     let ready_for_skeens2 = skeens_finished(
                         token,
                         packet,
@@ -354,6 +562,33 @@ where C: AsyncStoreClient {
                 WriteState::Skeens2(buf, remaining_servers, max_ts) => {
                     assert!(self.new_multi);
                     trace!("CLIENT finished multi sk2 section");
+                    let all_replied = {
+                        let mut r = remaining_servers.borrow_mut();
+                        r.remove(&token.0);
+                        r.is_empty()
+                    };
+                    if flag.contains(EntryFlag::Aborted) {
+                        let mut b = buf.borrow_mut();
+                        bytes_as_entry_mut(&mut *b).flag_mut().insert(EntryFlag::Aborted);
+                    }
+                    if bytes_as_entry(&buf.borrow()).flag().contains(EntryFlag::Aborted) {
+                        //an aborted multi has no locations to fill,
+                        //it is done once every server has finished it
+                        if !all_replied {
+                            self.sent_writes.insert(id,
+                                WriteState::Skeens2(buf, remaining_servers, max_ts));
+                            return Err(())
+                        }
+                        if let Some(refused) = self.corrupt_writes.remove(&id) {
+                            self.finish_corrupted(Token(refused[0]), id);
+                            return Err(())
+                        }
+                        //a multi aborted by a recoverer, see MultiRecovery, has no stale read
+                        let stale = self.stale_reads.remove(&id)
+                            .unwrap_or(OrderIndex(0.into(), 0.into()));
+                        self.finish_aborted(token, id, stale);
+                        return Err(())
+                    }
                     let ready_to_unlock = {
                         let mut b = buf.borrow_mut();
                         let mut finished_writes = true;
@@ -421,6 +656,7 @@ where C: AsyncStoreClient {
                                     assert!(i != entry::from(0), "0 location in {:?}", locs)
                                 }
                             }
+                            self.untrack_write(&id);
                             //TODO
                             let e = self.client.on_finished_write(id, locs);
                             if e.is_err() {
@@ -533,6 +769,7 @@ where C: AsyncStoreClient {
                         }
                     }
                     let locs = packet.contents().locs().to_vec();
+                    self.untrack_write(&id);
                     let e = self.client.on_finished_write(id, locs);
                     if e.is_err() {
                         self.finished = true
@@ -630,6 +867,377 @@ where C: AsyncStoreClient {
 
     //////////
 
+    fn handle_blocking_multi(&mut self, token: Token, packet: &Buffer) {
+        let num_chain_servers = self.num_chain_servers;
+        let id = *packet.contents().id();
+        if self.sent_writes.contains_key(&id) {
+            return
+        }
+        let recovery = match self.recovery {
+            Some(ref mut recovery) => recovery,
+            None => return,
+        };
+        let contents = packet.contents();
+        if let Some(fetched) = recovery.fetching.remove(&id) {
+            //a data server sent us the payload of a blocking sentinel
+            let multi = packet.entry_slice().to_vec();
+            return recovery.lock_for_recovery(id, RecoveringWrite { multi, ..fetched }, num_chain_servers)
+        }
+        if recovery.recovering.contains_key(&id) || recovery.restarted.contains(&id) {
+            return
+        }
+        let blocked_at = contents.locs().iter().cloned().find(|&OrderIndex(o, i)|
+            o != order::from(0) && i != entry::from(0)
+            && write_server_for_chain(o, num_chain_servers) == token.0
+        );
+        let blocked_at = match blocked_at {
+            Some(blocked_at) => blocked_at,
+            None => return,
+        };
+        trace!("CLIENT {:?} blocked by {:?}, recovering", blocked_at, id);
+        let blocked = RecoveringWrite {
+            multi: packet.entry_slice().to_vec(),
+            blocked_at,
+            server: token.0,
+            abort: false,
+        };
+        if contents.layout() != EntryLayout::Sentinel {
+            return recovery.lock_for_recovery(id, blocked, num_chain_servers)
+        }
+        let data_chain = contents.locs().iter()
+            .take_while(|&&oi| oi != OrderIndex(0.into(), 0.into()))
+            .next();
+        match data_chain {
+            Some(&OrderIndex(chain, _)) => {
+                trace!("CLIENT fetching {:?} from the server for {:?}", id, chain);
+                let mut fetch = vec![];
+                EntryContents::CheckSkeens1 {
+                    id: &id,
+                    flags: &EntryFlag::Nothing,
+                    data_bytes: &0,
+                    dependency_bytes: &0,
+                    loc: &OrderIndex(chain, entry::from(0)),
+                }.fill_vec(&mut fetch);
+                let data_server = write_server_for_chain(chain, num_chain_servers);
+                recovery.to_send.push_back((data_server, fetch));
+                recovery.fetching.insert(id, blocked);
+            },
+            None => recovery.lock_for_recovery(
+                id, RecoveringWrite { abort: true, ..blocked }, num_chain_servers
+            ),
+        }
+    }
+
+    fn handle_rejected(&mut self, token: Token, packet: &Buffer) {
+        match packet.contents().rejection_status() {
+            Some(reject::CORRUPT) => self.handle_corrupt_append(token, packet),
+            Some(reject::PERMISSION_DENIED) => self.handle_denied(token, packet),
+            Some(reject::RETRY_LATER) => self.handle_retry_later(token, packet),
+            Some(reject::QUOTA_EXCEEDED) => self.handle_over_quota(token, packet),
+            Some(reject::ABORTED) => self.handle_aborted(token, packet),
+            status => error!("CLIENT unknown rejection {:?} from {:?}", status, token),
+        }
+    }
+
+    fn handle_corrupt_append(&mut self, token: Token, packet: &Buffer) {
+        let id = *packet.contents().id();
+        error!("CLIENT append {:?} was corrupted on the way to {:?}", id, token);
+        let refused = packet.contents().kind() == EntryKind::Rejected;
+        match self.sent_writes.remove(&id) {
+            // we already heard from another server
+            None => return,
+            Some(WriteState::Skeens1(buf, remaining_servers, timestamps, is_sentinel)) =>
+                if refused {
+                    return self.abort_corrupt_multi(
+                        token, buf, remaining_servers, timestamps, is_sentinel
+                    )
+                } else {
+                    self.sent_writes.insert(id,
+                        WriteState::Skeens1(buf, remaining_servers, timestamps, is_sentinel));
+                    return
+                },
+            Some(..) => {},
+        }
+        self.finish_corrupted(token, id)
+    }
+
+    // The other servers of a multiappend may already have queued it in round 1,
+    // so, like a transaction a server voted against, it is aborted at them in round 2.
+    fn abort_corrupt_multi(
+        &mut self,
+        token: Token,
+        buf: Rc<RefCell<Vec<u8>>>,
+        remaining_servers: Rc<RefCell<HashSet<usize>>>,
+        timestamps: Rc<RefCell<Box<[u64]>>>,
+        is_sentinel: bool,
+    ) {
+        let id = *bytes_as_entry(&buf.borrow()).id();
+        let (first_reply, all_replied) = {
+            let mut r = remaining_servers.borrow_mut();
+            let first_reply = r.remove(&self.read_server_for_write_server(token.0));
+            (first_reply, r.is_empty())
+        };
+        if first_reply {
+            bytes_as_entry_mut(&mut *buf.borrow_mut()).flag_mut().insert(EntryFlag::Aborted);
+            self.corrupt_writes.entry(id).or_insert_with(Vec::new).push(token.0);
+        }
+        if !first_reply || !all_replied {
+            self.sent_writes.insert(id,
+                WriteState::Skeens1(buf, remaining_servers, timestamps, is_sentinel));
+            return
+        }
+        let max_ts = timestamps.borrow().iter().cloned().max().unwrap_or(0);
+        self.add_skeens2(buf, max_ts)
+    }
+
+    fn finish_corrupted(&mut self, token: Token, id: Uuid) {
+        self.untrack_write(&id);
+        if self.client.on_corrupted(id, token.0).is_err() {
+            self.finished = true
+        }
+    }
+
+    fn handle_denied(&mut self, token: Token, packet: &Buffer) {
+        let contents = packet.contents();
+        let (request, color) = (contents.rejected_request(), contents.locs()[0].0);
+        error!("CLIENT {:?} refused {:?} on {:?}", token, request, color);
+        match request {
+            EntryKind::Read => {
+                self.sent_reads.remove(&contents.locs()[0]);
+            },
+            EntryKind::Data | EntryKind::Multiput | EntryKind::Sentinel => {
+                if self.sent_writes.remove(contents.id()).is_none() {
+                    // we already heard from another server
+                    return
+                }
+            },
+            // only snapshots of several chains are tracked
+            EntryKind::Snapshot => {
+                let tracked = self.sent_writes.remove(contents.id()).is_some();
+                if !tracked && contents.locs().len() > 1 {
+                    return
+                }
+            },
+            _ => {},
+        }
+        if self.client.on_permission_denied(color, token.0).is_err() {
+            self.finished = true
+        }
+    }
+
+    fn handle_over_quota(&mut self, token: Token, packet: &Buffer) {
+        let (id, color) = {
+            let contents = packet.contents();
+            (*contents.id(), contents.locs()[0].0)
+        };
+        error!("CLIENT {:?} refused {:?}, over the quota of {:?}", token, id, color);
+        if self.sent_writes.remove(&id).is_none() {
+            // we already heard from another server
+            return
+        }
+        self.untrack_write(&id);
+        if self.client.on_quota_exceeded(color, token.0).is_err() {
+            self.finished = true
+        }
+    }
+
+    fn handle_aborted(&mut self, token: Token, packet: &Buffer) {
+        let (id, stale) = {
+            let contents = packet.contents();
+            (*contents.id(), contents.locs()[0])
+        };
+        if self.sent_writes.remove(&id).is_none() {
+            return
+        }
+        self.finish_aborted(token, id, stale)
+    }
+
+    fn finish_aborted(&mut self, token: Token, id: Uuid, stale: OrderIndex) {
+        trace!("CLIENT transaction {:?} aborted, stale read before {:?}", id, stale);
+        self.untrack_write(&id);
+        if self.client.on_aborted(id, stale, token.0).is_err() {
+            self.finished = true
+        }
+    }
+
+    fn handle_retry_later(&mut self, token: Token, packet: &Buffer) {
+        let id = *packet.contents().id();
+        if !self.sent_writes.contains_key(&id) {
+            return
+        }
+        let wait = self.retries.schedule(token.0, id);
+        trace!("CLIENT {:?} is busy, resending {:?} in {:?}", token, id, wait);
+    }
+
+    fn resend_retries(&mut self, inner: &mut IoState<PerStream>) {
+        let due = match self.retries.take_due(Instant::now()) {
+            None => return,
+            Some(due) => due,
+        };
+        let receiver = self.receiver.bytes();
+        let num_servers = self.num_chain_servers;
+        for (server, id) in due {
+            let msg = match self.sent_writes.get(&id) {
+                Some(&WriteState::SingleServer(ref msg)) => msg.clone(),
+                Some(&WriteState::Skeens1(ref msg, ..)) => {
+                    // each server gets a multi or a sentinel, see add_skeens1
+                    let mut msg = msg.borrow().clone();
+                    let is_data = bytes_as_entry(&msg).locs().into_iter()
+                        .take_while(|&&oi| oi != OrderIndex(0.into(), 0.into()))
+                        .any(|oi| is_write_server_for(oi.0, server.into(), num_servers));
+                    if is_data {
+                        slice_to_multi(&mut msg[..]);
+                    } else {
+                        slice_to_sentinel(&mut msg[..]);
+                    }
+                    let len = bytes_as_entry(&msg).len();
+                    msg.truncate(len);
+                    msg
+                },
+                _ => {
+                    // the write finished or failed in the meantime
+                    self.retries.attempts.remove(&id);
+                    continue
+                },
+            };
+            trace!("CLIENT resending {:?} to {:?}", id, server);
+            inner.mutate(server.into(), |ps| ps.add_writes(&[&msg[..], receiver]));
+        }
+    }
+
+    fn handle_recovery_reply(&mut self, packet: &Buffer) {
+        let num_chain_servers = self.num_chain_servers;
+        let recovery = match self.recovery {
+            Some(ref mut recovery) => recovery,
+            None => return,
+        };
+        let contents = packet.contents();
+        let id = *contents.id();
+        let succeeded = contents.flag().contains(EntryFlag::ReadSuccess);
+        if id == Uuid::nil() {
+            //nothing is blocking the chain we asked about
+            return
+        }
+        if let Some(blocked) = recovery.fetching.remove(&id) {
+            //the data server no longer has the multi waiting,
+            //so its sentinels can only be aborted
+            trace!("CLIENT cannot fetch {:?}, aborting", id);
+            let blocked = RecoveringWrite { abort: true, ..blocked };
+            return recovery.lock_for_recovery(id, blocked, num_chain_servers)
+        }
+        if contents.kind() == EntryKind::UpdateRecovery {
+            if !succeeded {
+                trace!("CLIENT someone else is recovering {:?}", id);
+                recovery.recovering.remove(&id);
+                return
+            }
+            let (server, check) = match recovery.recovering.get(&id) {
+                None => return,
+                Some(recovering) => {
+                    let mut check = vec![];
+                    EntryContents::CheckSkeens1 {
+                        id: &id,
+                        flags: &EntryFlag::Nothing,
+                        data_bytes: &0,
+                        dependency_bytes: &0,
+                        loc: &recovering.blocked_at,
+                    }.fill_vec(&mut check);
+                    (recovering.server, check)
+                },
+            };
+            recovery.to_send.push_back((server, check));
+            return
+        }
+
+        let recovering = match recovery.recovering.remove(&id) {
+            None => return,
+            Some(recovering) => recovering,
+        };
+        if !succeeded {
+            trace!("CLIENT {:?} finished before recovery", id);
+            return
+        }
+        if recovering.abort {
+            trace!("CLIENT aborting {:?} at {:?}", id, recovering.blocked_at);
+            let mut abort = recovering.multi;
+            {
+                let mut e = bytes_as_entry_mut(&mut abort);
+                e.flag_mut().remove(EntryFlag::ReadSuccess);
+                e.flag_mut().insert(EntryFlag::TakeLock | EntryFlag::Unlock
+                    | EntryFlag::NewMultiPut | EntryFlag::Aborted);
+                *e.lock_mut() = u64::from(recovering.blocked_at.1);
+                e.locs_mut().into_iter()
+                    .fold((), |_, &mut OrderIndex(_,ref mut i)| *i = 0.into());
+            }
+            recovery.to_send.push_back((recovering.server, abort));
+            return
+        }
+        trace!("CLIENT restarting {:?}", id);
+        let mut multi = recovering.multi;
+        {
+            let mut e = bytes_as_entry_mut(&mut multi);
+            e.flag_mut().remove(EntryFlag::ReadSuccess);
+            e.flag_mut().insert(EntryFlag::TakeLock | EntryFlag::NewMultiPut);
+            *e.lock_mut() = 0;
+            e.locs_mut().into_iter()
+                .fold((), |_, &mut OrderIndex(_,ref mut i)| *i = 0.into());
+        }
+        recovery.restarted.insert(id);
+        recovery.to_restart.push_back(multi);
+    }
+
+    fn drive_recovery(&mut self, inner: &mut IoState<PerStream>) {
+        let num_chain_servers = self.num_chain_servers;
+        let (to_send, to_restart, wake_at) = match self.recovery {
+            None => return,
+            Some(ref mut recovery) => {
+                let now = Instant::now();
+                if now.duration_since(recovery.last_check) >= recovery.timeout {
+                    recovery.last_check = now;
+                    recovery.find_stalled_writes(now, num_chain_servers);
+                }
+                let wake_at = recovery.next_wake(now);
+                (mem::replace(&mut recovery.to_send, VecDeque::new()),
+                    mem::replace(&mut recovery.to_restart, VecDeque::new()),
+                    wake_at)
+            },
+        };
+        if let Some(at) = wake_at {
+            self.retries.wake_at(at)
+        }
+        {
+            let receiver = self.receiver.bytes();
+            for (server, packet) in to_send {
+                inner.mutate(server.into(), |ps| ps.add_writes(&[&packet[..], receiver]));
+            }
+        }
+        for multi in to_restart {
+            self.add_skeens1(inner, multi);
+        }
+    }
+
+    fn track_write(&mut self, msg: &[u8]) {
+        if let Some(ref mut recovery) = self.recovery {
+            let e = bytes_as_entry(msg);
+            let chains = e.locs().iter()
+                .filter(|oi| oi.0 != order::from(0))
+                .map(|oi| oi.0)
+                .collect();
+            recovery.started_writes.insert(*e.id(), (Instant::now(), chains));
+        }
+    }
+
+    fn untrack_write(&mut self, id: &Uuid) {
+        self.retries.attempts.remove(id);
+        self.stale_reads.remove(id);
+        self.corrupt_writes.remove(id);
+        if let Some(ref mut recovery) = self.recovery {
+            recovery.started_writes.remove(id);
+        }
+    }
+
+    //////////
+
     fn handle_completed_read(&mut self, _token: Token, packet: &Buffer, my_write: bool) -> bool {
         use std::collections::hash_map::Entry::Occupied;
 
@@ -761,6 +1369,10 @@ where C: AsyncStoreClient {
             self.finished = true;
             return false
         }
+        if bytes_as_entry(&msg).kind() == EntryKind::CheckSkeens1 {
+            //a recovery_tick, after_work will look for stalled writes
+            return true
+        }
         let new_msg_kind = bytes_as_entry(&msg).layout();
         match new_msg_kind {
             EntryLayout::Read => {
@@ -774,6 +1386,7 @@ where C: AsyncStoreClient {
                     panic!("cannot sent read to {}, {}", s, self.num_chain_servers))
             }
             EntryLayout::Data => {
+                self.track_write(&msg);
                 let loc;
                 {
 
@@ -797,6 +1410,7 @@ where C: AsyncStoreClient {
             }
             EntryLayout::Multiput => {
                 trace!("CLIENT will multi write");
+                self.track_write(&msg);
                 //FIXME set max_timestamp from local
                 //TODO
                 let use_fastpath = true;
@@ -922,7 +1536,8 @@ where C: AsyncStoreClient {
                                 debug_assert!(flag.contains(EntryFlag::TakeLock));
                                 flag.insert(EntryFlag::TakeLock);
                             }
-                            if !is_data {
+                            //a server which only stores reads of a transaction just votes on it
+                            if !is_data && !e.as_ref().flag().contains(EntryFlag::Transaction) {
                                 debug_assert!(e.as_ref().locs()
                                     .contains(&OrderIndex(0.into(), 0.into())));
                             }
@@ -996,13 +1611,17 @@ where C: AsyncStoreClient {
     fn add_sk2(
         &mut self, mut buf: Rc<RefCell<Vec<u8>>>, max_ts: u64, is_snapshot: bool,
     ) {
-        let servers = match Rc::get_mut(&mut buf) {
+        let mut servers = match Rc::get_mut(&mut buf) {
             None => unreachable!(),
             Some(mut buf) => {
                 let buf = buf.get_mut();
                 self.get_servers_for_multi(&buf)
             }
         };
+        //a server which refused round 1 has nothing to finish
+        if let Some(refused) = self.corrupt_writes.get(bytes_as_entry(&buf.borrow()).id()) {
+            servers.retain(|s| !refused.contains(s))
+        }
         self.pending_skeens2.push_back(SK2Send{
             buf: buf.clone(),
             max_ts,
@@ -1037,6 +1656,19 @@ where C: AsyncStoreClient {
                 unsafe { buf.set_len(size) };
             }
         };
+        //the servers which only store reads of a transaction keep them locked until round 2,
+        //they have nothing to finish so we don't wait for their reply
+        let mut read_only = self.read_only_servers(&buf.borrow(), &servers);
+        let id = *bytes_as_entry(&buf.borrow()).id();
+        if let Some(refused) = self.corrupt_writes.get(&id) {
+            read_only.retain(|s| !refused.contains(s))
+        }
+        for token in read_only {
+            let ts = buf.borrow();
+            let send_end = bytes_as_entry(&*ts).len();
+            let receiver = self.receiver.bytes();
+            inner.mutate(token.into(), |ps| ps.add_writes(&[&ts[..send_end], receiver]));
+        }
         let mut remaining_servers: HashSet<usize> = Default::default();
         remaining_servers.reserve(servers.len());
         for &writer in servers.iter() {
@@ -1071,10 +1703,13 @@ where C: AsyncStoreClient {
                 }
             });
         }
-        send.map(|sent| {
-            let id = sent.id();
-            self.sent_writes.insert(id, sent);
-        });
+        match send {
+            Some(sent) => { self.sent_writes.insert(id, sent); },
+            //every server storing one of its writes refused it
+            None => if let Some(refused) = self.corrupt_writes.remove(&id) {
+                self.finish_corrupted(Token(refused[0]), id)
+            },
+        }
     }
 
     ////////////////////
@@ -1085,7 +1720,13 @@ where C: AsyncStoreClient {
             (0..bytes_as_entry(&msg).locs().len())
                 .map(|_| 0u64).collect::<Vec<_>>().into_boxed_slice()
         ));
-        let servers = self.get_servers_for_multi(&msg);
+        let mut servers = self.get_servers_for_multi(&msg);
+        //the servers storing the reads of a transaction vote on it in round 1
+        for server in self.read_only_servers(&msg, &servers) {
+            if let Err(i) = servers.binary_search(&server) {
+                servers.insert(i, server)
+            }
+        }
         let mut remaining_servers: HashSet<usize> = Default::default();
         remaining_servers.reserve(servers.len());
         for &writer in servers.iter() {
@@ -1102,8 +1743,15 @@ where C: AsyncStoreClient {
     fn is_single_node_append(&self, msg: &[u8]) -> bool {
         let mut single = true;
         let mut server_token = None;
-        let locked_chains = bytes_as_entry(&msg).locs()
-            .iter().cloned().filter(|&oi| oi != OrderIndex(0.into(), 0.into()));
+        let entry = bytes_as_entry(&msg);
+        //a transaction must also reach the servers storing its reads
+        let reads = if entry.flag().contains(EntryFlag::Transaction) {
+            entry.dependencies()
+        } else {
+            &[]
+        };
+        let locked_chains = entry.locs().iter().chain(reads.iter())
+            .cloned().filter(|&oi| oi != OrderIndex(0.into(), 0.into()));
         for c in locked_chains {
             if let Some(server_token) = server_token {
                 single &= self.write_server_for_chain(c.0) == server_token
@@ -1115,6 +1763,22 @@ where C: AsyncStoreClient {
         single
     }
 
+    // The servers which store reads of a transaction, but none of its writes.
+    fn read_only_servers(&self, msg: &[u8], servers: &[usize]) -> Vec<usize> {
+        let entry = bytes_as_entry(msg);
+        if !entry.flag().contains(EntryFlag::Transaction) {
+            return vec![]
+        }
+        let mut read_only = vec![];
+        for &OrderIndex(o, _) in entry.dependencies() {
+            let server = self.write_server_for_chain(o);
+            if !servers.contains(&server) && !read_only.contains(&server) {
+                read_only.push(server)
+            }
+        }
+        read_only
+    }
+
     fn get_servers_for_multi(&self, msg: &[u8]) -> Vec<usize> {
         debug_assert!(
             bytes_as_entry(msg).layout() == EntryLayout::Multiput
@@ -1281,10 +1945,23 @@ where C: AsyncStoreClient {
         let mut pending_sk2 = mem::replace(&mut self.pending_skeens2, VecDeque::new());
 
         for SK2Send { servers, buf, max_ts, is_snapshot } in pending_sk2.drain(..) {
+            let restarted = match self.recovery {
+                Some(ref mut recovery) if !recovery.restarted.is_empty() => {
+                    let id = *bytes_as_entry(&buf.borrow()).id();
+                    if recovery.restarted.remove(&id) { Some(id) } else { None }
+                },
+                _ => None,
+            };
             self.send_skeens2(inner, buf, max_ts, servers, is_snapshot);
-
+            if let Some(id) = restarted {
+                //The acks for a recovered write may go to its original client,
+                //so we don't wait for them
+                self.sent_writes.remove(&id);
+            }
         }
         self.pending_skeens2 = pending_sk2;
+        self.drive_recovery(inner);
+        self.resend_retries(inner);
     }
 }
 
@@ -1292,6 +1969,82 @@ where C: AsyncStoreClient {
 /////////////////////////////////////////////////
 /////////////////////////////////////////////////
 
+impl MultiRecovery {
+    fn new(timeout: Duration) -> Self {
+        MultiRecovery {
+            timeout,
+            last_check: Instant::now(),
+            wake_at: None,
+            started_writes: Default::default(),
+            recovering: Default::default(),
+            fetching: Default::default(),
+            restarted: Default::default(),
+            to_send: Default::default(),
+            to_restart: Default::default(),
+        }
+    }
+
+    fn lock_for_recovery(&mut self, id: Uuid, write: RecoveringWrite, num_chain_servers: usize) {
+        let locs: Vec<_> = bytes_as_entry(&write.multi).locs().iter()
+            .map(|&OrderIndex(o, _)| OrderIndex(o, entry::from(0)))
+            .collect();
+        let mut update = vec![];
+        EntryContents::UpdateRecovery {
+            old_recoverer: &Uuid::nil(),
+            write_id: &id,
+            flags: &EntryFlag::Nothing,
+            lock: &0,
+            locs: &locs,
+        }.fill_vec(&mut update);
+        //The recovery lock is kept with the first chain of the multiappend
+        let lock_server = write_server_for_chain(locs[0].0, num_chain_servers);
+        self.to_send.push_back((lock_server, update));
+        self.recovering.insert(id, write);
+    }
+
+    /// When the timer should next wake us to check for stalled writes,
+    /// `None` if it already will, or there is nothing to check.
+    fn next_wake(&mut self, now: Instant) -> Option<Instant> {
+        match self.wake_at {
+            Some(at) if at > now => return None,
+            _ => self.wake_at = None,
+        }
+        if self.started_writes.is_empty() {
+            return None
+        }
+        self.wake_at = Some(self.last_check + self.timeout);
+        self.wake_at
+    }
+
+    fn find_stalled_writes(&mut self, now: Instant, num_chain_servers: usize) {
+        let timeout = self.timeout;
+        let mut probed: HashSet<order> = Default::default();
+        for &mut (ref mut started, ref chains) in self.started_writes.values_mut() {
+            if now.duration_since(*started) < timeout {
+                continue
+            }
+            *started = now;
+            for &chain in chains {
+                if !probed.insert(chain) {
+                    continue
+                }
+                trace!("CLIENT write to {:?} stalled", chain);
+                let mut probe = vec![];
+                EntryContents::CheckSkeens1 {
+                    id: &Uuid::nil(),
+                    flags: &EntryFlag::Nothing,
+                    data_bytes: &0,
+                    dependency_bytes: &0,
+                    loc: &OrderIndex(chain, entry::from(0)),
+                }.fill_vec(&mut probe);
+                self.to_send.push_back((write_server_for_chain(chain, num_chain_servers), probe));
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////
+
 impl WriteState {
     fn with_packet<F, R>(&self, f: F) -> R
     where F: for<'a> FnOnce(&'a [u8]) -> R {
@@ -1369,6 +2122,93 @@ impl WriteState {
 /////////////////////////////////////////////////
 /////////////////////////////////////////////////
 
+impl RetryBackoff {
+    fn new(to_self: ToSelf) -> Self {
+        RetryBackoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_secs(1),
+            attempts: Default::default(),
+            waiting: Vec::new(),
+            to_self,
+            timer: None,
+        }
+    }
+
+    /// Resend write `id` to `server` later, returns how long until then.
+    fn schedule(&mut self, server: usize, id: Uuid) -> Duration {
+        let attempts = self.attempts.entry(id).or_insert(0);
+        let wait = self.initial.checked_mul(1 << ::std::cmp::min(*attempts, 16))
+            .map_or(self.max, |wait| ::std::cmp::min(wait, self.max));
+        *attempts += 1;
+        let at = Instant::now() + wait;
+        self.waiting.push((at, server, id));
+        self.wake_at(at);
+        wait
+    }
+
+    /// Have the timer send us a recovery_tick at `at`.
+    fn wake_at(&mut self, at: Instant) {
+        if self.timer.is_none() {
+            let (timer, deadlines) = mpsc::channel();
+            let to_self = self.to_self.clone();
+            thread::spawn(move || wake_at_deadlines(deadlines, to_self));
+            self.timer = Some(timer);
+        }
+        let _ = self.timer.as_ref().unwrap().send(at);
+    }
+
+    fn take_due(&mut self, now: Instant) -> Option<Vec<(usize, Uuid)>> {
+        if self.waiting.iter().all(|&(at, ..)| at > now) {
+            return None
+        }
+        let (due, waiting): (Vec<_>, Vec<_>) = mem::replace(&mut self.waiting, Vec::new())
+            .into_iter()
+            .partition(|&(at, ..)| at <= now);
+        self.waiting = waiting;
+        Some(due.into_iter().map(|(_, server, id)| (server, id)).collect())
+    }
+}
+
+fn wake_at_deadlines(deadlines: mpsc::Receiver<Instant>, to_self: ToSelf) {
+    use std::sync::mpsc::RecvTimeoutError;
+    let mut waiting = BinaryHeap::new();
+    loop {
+        let next = waiting.peek().map(|&Reverse(at)| at);
+        let deadline = match next {
+            None => deadlines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(at) => {
+                let now = Instant::now();
+                if at <= now {
+                    waiting.pop();
+                    if to_self.send(recovery_tick()).is_err() {
+                        return
+                    }
+                    continue
+                }
+                deadlines.recv_timeout(at - now)
+            },
+        };
+        match deadline {
+            Ok(at) => waiting.push(Reverse(at)),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/////////////////////////////////////////////////
+
+fn handshake_error(stream: &TcpStream, rejection: Rejection) -> io::Error {
+    let kind = match rejection {
+        Rejection::BadCredentials => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(
+        kind,
+        format!("could not connect to {:?}: {}", stream.peer_addr(), rejection),
+    )
+}
+
 fn blocking_write<W: Write>(w: &mut W, mut buffer: &[u8]) -> io::Result<()> {
     use std::thread;
     //like Write::write_all but doesn't die on WouldBlock
@@ -0,0 +1,81 @@
+//! Counters which only grow, and counters which also shrink.
+
+use std::collections::HashMap;
+
+use ::{order, Crdt, OpMeta};
+
+/// A counter which only grows, kept as the sum each replica added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    // by the writer's color
+    counts: HashMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounterOp(u64);
+
+impl GCounter {
+    pub fn increment(&self, by: u64) -> GCounterOp {
+        GCounterOp(by)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// What the replica writing to `writer` added.
+    pub fn value_of(&self, writer: order) -> u64 {
+        self.counts.get(&u64::from(writer)).cloned().unwrap_or(0)
+    }
+
+    fn add(&mut self, writer: order, by: u64) {
+        *self.counts.entry(u64::from(writer)).or_insert(0) += by
+    }
+}
+
+impl Crdt for GCounter {
+    type Op = GCounterOp;
+
+    fn apply(&mut self, GCounterOp(by): GCounterOp, meta: &OpMeta) {
+        self.add(meta.writer, by)
+    }
+}
+
+/// A counter which grows and shrinks,
+/// kept as one `GCounter` of increments and another of decrements.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PNCounterOp {
+    Increment(u64),
+    Decrement(u64),
+}
+
+impl PNCounter {
+    pub fn increment(&self, by: u64) -> PNCounterOp {
+        PNCounterOp::Increment(by)
+    }
+
+    pub fn decrement(&self, by: u64) -> PNCounterOp {
+        PNCounterOp::Decrement(by)
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PNCounter {
+    type Op = PNCounterOp;
+
+    fn apply(&mut self, op: PNCounterOp, meta: &OpMeta) {
+        match op {
+            PNCounterOp::Increment(by) => self.increments.add(meta.writer, by),
+            PNCounterOp::Decrement(by) => self.decrements.add(meta.writer, by),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! CRDTs materialized from the log.
+//!
+//! Each replica of a CRDT appends its operations to a color no other replica
+//! writes to, and reads the colors of every replica. An append depends on the
+//! last entry the replica has read in each of the other colors, see
+//! `LogHandle::causal_color_append`, so every replica applies an operation
+//! after everything its writer had seen when it made it. Concurrent operations
+//! may be applied in different orders by different replicas; the CRDTs here
+//! are built so that they converge regardless.
+//!
+//! A CRDT implements `Crdt`: how an operation read from the log changes its
+//! state. Operations are made from the state of the replica making them, eg.
+//! `OrSet::remove` removes the adds the replica has seen, and take effect at
+//! every replica, including the one which made them, once it is `sync`ed.
+//!
+//! `Replica` replays a CRDT as a `state_machine::StateMachine`, so it can be
+//! checkpointed like any other, the state is saved with serde.
+
+extern crate bincode;
+extern crate fuzzy_log_client;
+
+pub extern crate serde;
+
+#[macro_use] extern crate serde_derive;
+
+use bincode::{deserialize, serialize_into, Infinite};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use fuzzy_log_client::state_machine;
+
+pub use fuzzy_log_client::state_machine::{Checkpoint, ReplicaError, StateMachine};
+pub use fuzzy_log_client::fuzzy_log::log_handle::{
+    entry,
+    order,
+    GetRes,
+    LogHandle,
+    OrderIndex,
+    SessionToken,
+    TryWaitRes,
+    Uuid,
+};
+
+pub use counter::{GCounter, GCounterOp, PNCounter, PNCounterOp};
+pub use map::{CrdtMap, MapOp};
+pub use or_set::{OrSet, OrSetOp};
+pub use register::{LwwOp, LwwRegister};
+pub use sequence::{Rga, RgaOp};
+
+pub mod counter;
+pub mod map;
+pub mod or_set;
+pub mod register;
+pub mod sequence;
+
+#[cfg(test)]
+mod tests;
+
+pub trait Crdt: Default + Serialize + DeserializeOwned {
+    type Op: Serialize + DeserializeOwned;
+
+    /// Apply an op read from the log.
+    /// Ops are applied after every op their writer had applied when making them.
+    fn apply(&mut self, op: Self::Op, meta: &OpMeta);
+}
+
+/// Where an op was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpMeta {
+    /// The id of the op's entry, which is unique.
+    pub id: Uuid,
+    /// The color of the replica which made the op.
+    pub writer: order,
+    pub loc: OrderIndex,
+}
+
+/// Identifies an op, eg. the add an `OrSet` remove cancels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Tag([u8; 16]);
+
+impl<'a> From<&'a Uuid> for Tag {
+    fn from(id: &'a Uuid) -> Self {
+        Tag(*id.as_bytes())
+    }
+}
+
+/// A Lamport timestamp, with the writer's color breaking ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub time: u64,
+    pub writer: u64,
+}
+
+impl Timestamp {
+    pub fn new(time: u64, writer: order) -> Self {
+        Timestamp { time, writer: writer.into() }
+    }
+}
+
+/// The `StateMachine` a `Replica` replays a CRDT with,
+/// ops and checkpoints are stored serialized with bincode.
+pub struct Materialized<C: Crdt>(C);
+
+impl<C: Crdt> StateMachine for Materialized<C> {
+    type Op = C::Op;
+
+    fn encode(op: &C::Op, buffer: &mut Vec<u8>) {
+        serialize_into(buffer, op, Infinite).expect("cannot serialize op")
+    }
+
+    fn decode(bytes: &[u8]) -> Option<C::Op> {
+        deserialize(bytes).ok()
+    }
+
+    fn apply(&mut self, op: C::Op, locs: &[OrderIndex], id: &Uuid) {
+        let meta = OpMeta { id: *id, writer: locs[0].0, loc: locs[0] };
+        self.0.apply(op, &meta)
+    }
+
+    fn snapshot(&self, buffer: &mut Vec<u8>) {
+        serialize_into(buffer, &self.0, Infinite).expect("cannot serialize state")
+    }
+
+    fn restore(&mut self, bytes: &[u8]) {
+        self.0 = deserialize(bytes).expect("invalid checkpoint")
+    }
+}
+
+/// Drives a CRDT: appends the ops made at this replica,
+/// and replays the ops of every replica into the state.
+pub struct Replica<C: Crdt> {
+    inner: state_machine::Replica<Materialized<C>>,
+}
+
+impl<C: Crdt> Replica<C> {
+    /// `colors` are the colors of every replica, including `my_color`,
+    /// which no other replica may append to.
+    pub fn new(handle: LogHandle<[u8]>, my_color: order, colors: Vec<order>) -> Self {
+        Self::start(handle, my_color, colors, None)
+    }
+
+    /// Start from a checkpoint another replica of the same colors took.
+    pub fn from_checkpoint(
+        handle: LogHandle<[u8]>,
+        my_color: order,
+        colors: Vec<order>,
+        checkpoint: &Checkpoint,
+    ) -> Self {
+        Self::start(handle, my_color, colors, Some(checkpoint))
+    }
+
+    fn start(
+        handle: LogHandle<[u8]>,
+        my_color: order,
+        mut colors: Vec<order>,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Self {
+        // the replica appends to the first of its colors
+        colors.retain(|&c| c != my_color);
+        colors.insert(0, my_color);
+        let state = Materialized(C::default());
+        let inner = match checkpoint {
+            None => state_machine::Replica::new(handle, state, colors),
+            Some(checkpoint) =>
+                state_machine::Replica::from_checkpoint(handle, state, colors, checkpoint),
+        };
+        // updates only wait when asked to
+        Replica { inner: inner.batch_appends(::std::usize::MAX) }
+    }
+
+    /// Append the op `make_op` makes from the current state.
+    pub fn async_update<F>(&mut self, make_op: F) -> Result<Uuid, TryWaitRes>
+    where F: FnOnce(&mut C) -> C::Op {
+        let op = make_op(&mut self.inner.state_mut().0);
+        self.inner.causal_append(&op)
+    }
+
+    pub fn update<F>(&mut self, make_op: F) -> Result<(), TryWaitRes>
+    where F: FnOnce(&mut C) -> C::Op {
+        let id = self.async_update(make_op)?;
+        self.inner.handle().wait_for_a_specific_append(id).map(|_| ())
+    }
+
+    pub fn wait_for_all_updates(&mut self) -> Result<(), TryWaitRes> {
+        self.inner.flush()
+    }
+
+    /// Apply every op appended to the replicas' colors before this call.
+    pub fn sync(&mut self) -> Result<(), ReplicaError> {
+        self.inner.barrier()
+    }
+
+    /// The state as of the last `sync`.
+    pub fn state(&self) -> &C {
+        &self.inner.state().0
+    }
+
+    pub fn my_color(&self) -> order {
+        self.inner.local_colors()[0]
+    }
+
+    /// The last entry applied in each color.
+    pub fn applied(&self) -> &SessionToken {
+        self.inner.applied()
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.inner.checkpoint()
+    }
+
+    pub fn handle(&mut self) -> &mut LogHandle<[u8]> {
+        self.inner.handle()
+    }
+
+    pub fn into_inner(self) -> (LogHandle<[u8]>, C) {
+        let (handle, Materialized(state)) = self.inner.into_inner();
+        (handle, state)
+    }
+}
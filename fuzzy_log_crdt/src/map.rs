@@ -0,0 +1,70 @@
+//! A map whose values are themselves CRDTs.
+
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::hash::Hash;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use ::{Crdt, OpMeta};
+
+/// A map from keys to CRDTs, each of which converges on its own.
+/// A key is in the map once an op on its value has been made or applied;
+/// keys are never removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrdtMap<K: Hash + Eq, C> {
+    entries: HashMap<K, C>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapOp<K, O> {
+    key: K,
+    op: O,
+}
+
+impl<K: Hash + Eq, C> Default for CrdtMap<K, C> {
+    fn default() -> Self {
+        CrdtMap { entries: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Crdt> CrdtMap<K, C> {
+    /// Apply the op `make_op` makes from the value at `key` to that value.
+    pub fn update<F>(&mut self, key: K, make_op: F) -> MapOp<K, C::Op>
+    where F: FnOnce(&mut C) -> C::Op {
+        let op = make_op(self.entries.entry(key.clone()).or_insert_with(C::default));
+        MapOp { key, op }
+    }
+}
+
+impl<K: Hash + Eq, C> CrdtMap<K, C> {
+    pub fn get(&self, key: &K) -> Option<&C> {
+        self.entries.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<K, C> {
+        self.entries.iter()
+    }
+}
+
+impl<K, C> Crdt for CrdtMap<K, C>
+where K: Hash + Eq + Serialize + DeserializeOwned, C: Crdt {
+    type Op = MapOp<K, C::Op>;
+
+    fn apply(&mut self, MapOp { key, op }: MapOp<K, C::Op>, meta: &OpMeta) {
+        self.entries.entry(key).or_insert_with(C::default).apply(op, meta)
+    }
+}
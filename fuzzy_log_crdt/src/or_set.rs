@@ -0,0 +1,79 @@
+//! An observed-remove set, generalizing `examples/or_set`.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use ::{Crdt, OpMeta, Tag};
+
+/// A set in which each add is tagged with the id of its entry, and a remove
+/// only cancels the adds its replica had seen, so an add concurrent with a
+/// remove of the same value wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet<T: Hash + Eq> {
+    elements: HashMap<T, HashSet<Tag>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrSetOp<T> {
+    Add(T),
+    Remove(T, Vec<Tag>),
+}
+
+impl<T: Hash + Eq> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet { elements: HashMap::new() }
+    }
+}
+
+impl<T: Hash + Eq> OrSet<T> {
+    pub fn add(&self, val: T) -> OrSetOp<T> {
+        OrSetOp::Add(val)
+    }
+
+    pub fn remove(&self, val: T) -> OrSetOp<T> {
+        let tags = self.elements.get(&val)
+            .map(|tags| tags.iter().cloned().collect())
+            .unwrap_or_else(Vec::new);
+        OrSetOp::Remove(val, tags)
+    }
+
+    pub fn contains(&self, val: &T) -> bool {
+        self.elements.contains_key(val)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        self.elements.keys()
+    }
+}
+
+impl<T> Crdt for OrSet<T>
+where T: Hash + Eq + ::serde::Serialize + ::serde::de::DeserializeOwned {
+    type Op = OrSetOp<T>;
+
+    fn apply(&mut self, op: OrSetOp<T>, meta: &OpMeta) {
+        use std::collections::hash_map::Entry;
+        match op {
+            OrSetOp::Add(val) => {
+                self.elements.entry(val).or_insert_with(HashSet::new).insert(Tag::from(&meta.id));
+            },
+            OrSetOp::Remove(val, tags) => {
+                if let Entry::Occupied(mut entry) = self.elements.entry(val) {
+                    for tag in &tags {
+                        entry.get_mut().remove(tag);
+                    }
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+            },
+        }
+    }
+}
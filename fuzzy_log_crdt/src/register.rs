@@ -0,0 +1,55 @@
+//! A register holding the value written last.
+
+use std::cmp::max;
+
+use ::{Crdt, OpMeta, Timestamp};
+
+/// A register whose value is the one with the greatest `Timestamp`.
+/// A write made after a replica applied another always wins over it;
+/// of concurrent writes the one with the greatest writer color wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<(Timestamp, T)>,
+    clock: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwOp<T> {
+    time: u64,
+    value: T,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister { value: None, clock: 0 }
+    }
+}
+
+impl<T> LwwRegister<T> {
+    pub fn set(&mut self, value: T) -> LwwOp<T> {
+        self.clock += 1;
+        LwwOp { time: self.clock, value }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref().map(|&(_, ref value)| value)
+    }
+
+    /// When the current value was written.
+    pub fn written_at(&self) -> Option<Timestamp> {
+        self.value.as_ref().map(|&(stamp, _)| stamp)
+    }
+}
+
+impl<T> Crdt for LwwRegister<T>
+where T: ::serde::Serialize + ::serde::de::DeserializeOwned {
+    type Op = LwwOp<T>;
+
+    fn apply(&mut self, LwwOp { time, value }: LwwOp<T>, meta: &OpMeta) {
+        self.clock = max(self.clock, time);
+        let stamp = Timestamp::new(time, meta.writer);
+        if self.written_at().map_or(true, |current| current < stamp) {
+            self.value = Some((stamp, value))
+        }
+    }
+}
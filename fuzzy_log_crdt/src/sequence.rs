@@ -0,0 +1,124 @@
+//! A replicated growable array, a sequence which may be edited anywhere.
+
+use std::cmp::max;
+
+use ::{Crdt, OpMeta, Timestamp};
+
+/// A sequence in which each element is named by the `Timestamp` of its insert,
+/// and inserted after the element which preceded it at its writer.
+/// Removed elements are kept as tombstones, so later inserts can still find them.
+///
+/// Of the elements inserted after the same one, the newest comes first.
+/// An insert's timestamp is greater than that of every element it was made
+/// after, so an insert skipping the elements newer than it skips their
+/// descendants as well, and every replica ends up with the same order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rga<T> {
+    elements: Vec<Element<T>>,
+    clock: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Element<T> {
+    id: Timestamp,
+    value: T,
+    removed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RgaOp<T> {
+    /// `time` and the writer's color name the new element.
+    Insert { after: Option<Timestamp>, time: u64, value: T },
+    Remove(Timestamp),
+}
+
+impl<T> Default for Rga<T> {
+    fn default() -> Self {
+        Rga { elements: vec![], clock: 0 }
+    }
+}
+
+impl<T> Rga<T> {
+    /// Insert `value` so that it is at `index` of this replica's sequence.
+    /// Panics if `index > len`.
+    pub fn insert(&mut self, index: usize, value: T) -> RgaOp<T> {
+        assert!(index <= self.len(), "insert at {} of a sequence of {}", index, self.len());
+        let after = match index {
+            0 => None,
+            i => Some(self.visible().nth(i - 1).unwrap().id),
+        };
+        self.clock += 1;
+        RgaOp::Insert { after, time: self.clock, value }
+    }
+
+    /// Panics if `index >= len`.
+    pub fn remove(&self, index: usize) -> RgaOp<T> {
+        let element = self.visible().nth(index)
+            .unwrap_or_else(|| panic!("remove at {} of a sequence of {}", index, self.len()));
+        RgaOp::Remove(element.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.visible().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.visible().nth(index).map(|e| &e.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        self.visible().map(|e| &e.value)
+    }
+
+    fn visible(&self) -> impl Iterator<Item=&Element<T>> {
+        self.elements.iter().filter(|e| !e.removed)
+    }
+
+    fn position(&self, id: Timestamp) -> Option<usize> {
+        self.elements.iter().position(|e| e.id == id)
+    }
+}
+
+impl<T: Clone> Rga<T> {
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T> Crdt for Rga<T>
+where T: ::serde::Serialize + ::serde::de::DeserializeOwned {
+    type Op = RgaOp<T>;
+
+    fn apply(&mut self, op: RgaOp<T>, meta: &OpMeta) {
+        match op {
+            RgaOp::Insert { after, time, value } => {
+                self.clock = max(self.clock, time);
+                let id = Timestamp::new(time, meta.writer);
+                if self.position(id).is_some() {
+                    return
+                }
+                let mut i = match after {
+                    None => 0,
+                    Some(after) => match self.position(after) {
+                        Some(i) => i + 1,
+                        // ops are applied after the ones their writer saw
+                        None => panic!("insert after unknown element {:?}", after),
+                    },
+                };
+                while i < self.elements.len() && self.elements[i].id > id {
+                    i += 1
+                }
+                self.elements.insert(i, Element { id, value, removed: false });
+            },
+            RgaOp::Remove(id) => {
+                if let Some(i) = self.position(id) {
+                    self.elements[i].removed = true
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Convergence tests for the CRDTs, against a server in this process.
+
+extern crate fuzzy_log_server;
+
+use std::{
+    net::SocketAddr,
+    sync::{Once, ONCE_INIT},
+    sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT},
+    thread,
+};
+
+use ::*;
+
+use tests::fuzzy_log_server::tcp::run_server;
+
+const ADDR: &'static str = "127.0.0.1:8233";
+
+fn start_server() -> SocketAddr {
+    static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+    static START: Once = ONCE_INIT;
+    START.call_once(|| {
+        thread::spawn(|| run_server(ADDR.parse().unwrap(), 0, 1, None, None, 1, &STARTED));
+    });
+    while STARTED.load(Ordering::Relaxed) == 0 {
+        thread::yield_now()
+    }
+    ADDR.parse().unwrap()
+}
+
+/// A replica for each of the `n` colors starting at `first_color`.
+fn replicas<C: Crdt>(first_color: u64, n: u64) -> Vec<Replica<C>> {
+    let addr = start_server();
+    let colors: Vec<order> = (first_color..first_color + n).map(order::from).collect();
+    colors.iter().map(|&color| {
+        let handle = LogHandle::unreplicated_with_servers(Some(addr))
+            .chains(colors.iter().cloned())
+            .build();
+        Replica::new(handle, color, colors.clone())
+    }).collect()
+}
+
+fn sync_all<C: Crdt>(replicas: &mut [Replica<C>]) {
+    for replica in replicas.iter_mut() {
+        replica.wait_for_all_updates().unwrap();
+    }
+    for replica in replicas.iter_mut() {
+        replica.sync().unwrap();
+    }
+}
+
+#[test]
+fn counters() {
+    let mut counters: Vec<Replica<PNCounter>> = replicas(10, 3);
+    for (i, counter) in counters.iter_mut().enumerate() {
+        for _ in 0..10 {
+            counter.async_update(|c| c.increment(i as u64 + 1)).unwrap();
+        }
+        counter.async_update(|c| c.decrement(5)).unwrap();
+    }
+    sync_all(&mut counters);
+    for counter in &counters {
+        assert_eq!(counter.state().value(), 10 + 20 + 30 - 15);
+    }
+
+    let mut counters: Vec<Replica<GCounter>> = replicas(20, 2);
+    counters[0].update(|c| c.increment(3)).unwrap();
+    counters[1].update(|c| c.increment(4)).unwrap();
+    sync_all(&mut counters);
+    for counter in &counters {
+        assert_eq!(counter.state().value(), 7);
+        assert_eq!(counter.state().value_of(order::from(21)), 4);
+    }
+}
+
+#[test]
+fn checkpoint() {
+    let mut sets: Vec<Replica<OrSet<u64>>> = replicas(70, 2);
+    sets[0].update(|s| s.add(1)).unwrap();
+    sets[1].update(|s| s.add(2)).unwrap();
+    sync_all(&mut sets);
+    let checkpoint = sets[0].checkpoint();
+
+    // a new replica can still remove what was added before the checkpoint
+    let colors: Vec<order> = (70..73).map(order::from).collect();
+    let handle = LogHandle::unreplicated_with_servers(Some(start_server()))
+        .chains(colors.iter().cloned())
+        .build();
+    let mut restored: Replica<OrSet<u64>> =
+        Replica::from_checkpoint(handle, order::from(72), colors, &checkpoint);
+    assert!(restored.state().contains(&1) && restored.state().contains(&2));
+    restored.update(|s| s.remove(1)).unwrap();
+    sets[1].update(|s| s.add(3)).unwrap();
+    restored.sync().unwrap();
+    assert!(!restored.state().contains(&1));
+    assert!(restored.state().contains(&2) && restored.state().contains(&3));
+}
+
+#[test]
+fn register() {
+    let mut registers: Vec<Replica<LwwRegister<String>>> = replicas(30, 3);
+    for (i, register) in registers.iter_mut().enumerate() {
+        register.update(|r| r.set(format!("concurrent {}", i))).unwrap();
+    }
+    sync_all(&mut registers);
+    let winner = registers[0].state().get().cloned();
+    assert!(winner.is_some());
+    for register in &registers {
+        assert_eq!(register.state().get(), winner.as_ref());
+    }
+
+    // a write made after seeing the others wins, whoever made it
+    registers[0].update(|r| r.set("later".to_string())).unwrap();
+    sync_all(&mut registers);
+    for register in &registers {
+        assert_eq!(register.state().get().map(|s| &**s), Some("later"));
+    }
+}
+
+#[test]
+fn or_set() {
+    let mut sets: Vec<Replica<OrSet<u64>>> = replicas(40, 3);
+    sets[0].update(|s| s.add(1)).unwrap();
+    sets[0].update(|s| s.add(2)).unwrap();
+    sync_all(&mut sets);
+
+    // an add concurrent with a remove wins
+    sets[1].update(|s| s.remove(1)).unwrap();
+    sets[2].update(|s| s.add(1)).unwrap();
+    sets[2].update(|s| s.remove(2)).unwrap();
+    sync_all(&mut sets);
+    for set in &sets {
+        assert!(set.state().contains(&1));
+        assert!(!set.state().contains(&2));
+        assert_eq!(set.state().len(), 1);
+    }
+
+    // a remove which saw every add removes the value
+    sets[1].update(|s| s.remove(1)).unwrap();
+    sync_all(&mut sets);
+    for set in &sets {
+        assert!(set.state().is_empty());
+    }
+}
+
+#[test]
+fn sequence() {
+    let mut sequences: Vec<Replica<Rga<char>>> = replicas(50, 3);
+    // an op is made from the state as of the last sync
+    for (i, c) in "ace".chars().enumerate() {
+        sequences[0].update(|s| s.insert(i, c)).unwrap();
+        sequences[0].sync().unwrap();
+    }
+    sync_all(&mut sequences);
+    assert_eq!(sequences[1].state().to_vec(), vec!['a', 'c', 'e']);
+
+    sequences[1].update(|s| s.insert(1, 'b')).unwrap();
+    sequences[1].update(|s| s.insert(3, 'f')).unwrap();
+    sequences[2].update(|s| s.insert(2, 'd')).unwrap();
+    sequences[2].update(|s| s.insert(1, 'x')).unwrap();
+    sequences[0].update(|s| s.remove(0)).unwrap();
+    sync_all(&mut sequences);
+    let merged = sequences[0].state().to_vec();
+    for sequence in &sequences {
+        assert_eq!(sequence.state().to_vec(), merged);
+    }
+    assert_eq!(merged.len(), 6);
+    assert!(!merged.contains(&'a'));
+    let pos = |c| merged.iter().position(|&m| m == c).unwrap();
+    // each replica's own inserts keep their places around the elements it saw
+    assert!(pos('b') < pos('c') && pos('c') < pos('d') && pos('d') < pos('e'));
+    assert!(pos('x') < pos('c') && pos('e') < pos('f'));
+}
+
+#[test]
+fn sequence_concurrent_inserts() {
+    fn meta(writer: u64) -> OpMeta {
+        OpMeta { id: Uuid::new_v4(), writer: writer.into(), loc: OrderIndex(writer.into(), 1.into()) }
+    }
+    let mut base = Rga::default();
+    let op = base.insert(0, 'a');
+    base.apply(op, &meta(1));
+    let (mut left, mut right) = (base.clone(), base.clone());
+    let l = left.insert(1, 'l');
+    let ll = {
+        let mut after_l = left.clone();
+        after_l.apply(l.clone(), &meta(2));
+        after_l.insert(2, 'm')
+    };
+    let r = right.insert(1, 'r');
+    // both replicas see the ops in different orders
+    left.apply(l.clone(), &meta(2));
+    left.apply(ll.clone(), &meta(2));
+    left.apply(r.clone(), &meta(3));
+    right.apply(r, &meta(3));
+    right.apply(l, &meta(2));
+    right.apply(ll, &meta(2));
+    assert_eq!(left.to_vec(), right.to_vec());
+    assert_eq!(left.to_vec(), vec!['a', 'r', 'l', 'm']);
+}
+
+#[test]
+fn map() {
+    let mut maps: Vec<Replica<CrdtMap<String, PNCounter>>> = replicas(60, 2);
+    maps[0].update(|m| m.update("apples".to_string(), |c| c.increment(3))).unwrap();
+    maps[1].update(|m| m.update("apples".to_string(), |c| c.decrement(1))).unwrap();
+    maps[1].update(|m| m.update("pears".to_string(), |c| c.increment(2))).unwrap();
+    sync_all(&mut maps);
+    for map in &maps {
+        let m = map.state();
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.get(&"apples".to_string()).map(|c| c.value()), Some(2));
+        assert_eq!(m.get(&"pears".to_string()).map(|c| c.value()), Some(2));
+    }
+}
@@ -0,0 +1,26 @@
+//! Print the layout of the FuzzyLog packets for use outside of rust.
+//!
+//!     cargo run --example packet_schema -- json > packets.json
+//!     cargo run --example packet_schema -- c > fuzzylog_packets.h
+//!     cargo run --example packet_schema -- python > fuzzylog_packets.py
+
+extern crate fuzzy_log_packets;
+
+use std::env;
+use std::process;
+
+use fuzzy_log_packets::Packet;
+
+fn main() {
+    let schema = Packet::schema();
+    let out = match env::args().nth(1).as_ref().map(|s| &**s) {
+        Some("json") => schema.to_json(),
+        Some("c") => schema.c_header(),
+        Some("python") => schema.python_parser(),
+        _ => {
+            eprintln!("usage: packet_schema <json|c|python>");
+            process::exit(1)
+        },
+    };
+    println!("{}", out);
+}
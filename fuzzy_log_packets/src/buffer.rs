@@ -1,5 +1,5 @@
 //use storeables::Storeable;
-use super::{Entry, MutEntry, EntryContents, EntryContentsMut};
+use super::{Entry, MutEntry, EntryContents, EntryContentsMut, OrderIndex, order};
 use super::Packet::WrapErr;
 //use util::hash::HashMap;
 
@@ -145,6 +145,18 @@ impl Buffer {
         unsafe { EntryContents::try_ref(&self.inner[..]).is_ok() }
     }
 
+    /// Replace a request with the reply refusing it, see `Packet::Ref::rejection`.
+    pub fn reject(&mut self, status: u8, first: Option<order>) {
+        let rejection = self.contents().rejection(status, first);
+        self.fill_from_entry_contents(super::bytes_as_entry(&rejection));
+    }
+
+    /// Replace a transaction with the reply aborting it, see `Packet::Ref::abortion`.
+    pub fn abort(&mut self, conflict: OrderIndex) {
+        let abortion = self.contents().abortion(conflict);
+        self.fill_from_entry_contents(super::bytes_as_entry(&abortion));
+    }
+
     pub fn to_sentinel(&mut self) -> bool {
         //FIXME
         //assert_eq!(EntryKind::from_bits(self.inner[0]), EntryKind::Multiput);
@@ -0,0 +1,172 @@
+//! CRC32C checksums for entries.
+//!
+//! An entry with `EntryFlag::Checksummed` set has the little-endian CRC32C of
+//! its id, dependencies, and payload as the last four bytes of its data.
+//! These are exactly the parts of an entry the servers never rewrite,
+//! so the checksum stays valid from the client's append, through replication
+//! and storage, to the reads which return it.
+//! The client may still add dependencies before sending an append,
+//! so appends are written `with_room_for_checksum` and sealed just before they are sent,
+//! see `Packet::Mut::seal_checksum`.
+
+use std::fmt;
+use std::sync::{Once, ONCE_INIT};
+
+use {OrderIndex, Uuid};
+
+pub const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumError {
+    /// The entry is too short to contain a checksum.
+    Truncated,
+    Mismatch { expected: u32, found: u32 },
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChecksumError::Truncated => write!(f, "entry too short for a checksum"),
+            ChecksumError::Mismatch { expected, found } =>
+                write!(f, "checksum mismatch, expected {:#010x} found {:#010x}", expected, found),
+        }
+    }
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    !update(!0, bytes)
+}
+
+/// The checksum of the parts of an entry the servers do not modify.
+pub fn entry_checksum(id: &Uuid, deps: &[OrderIndex], payload: &[u8]) -> u32 {
+    let mut crc = update(!0, id.as_bytes());
+    for &OrderIndex(chain, index) in deps {
+        crc = update(crc, &u64_bytes(chain.into()));
+        crc = update(crc, &u64_bytes(index.into()));
+    }
+    !update(crc, payload)
+}
+
+/// `data` followed by the checksum of the entry it will be part of,
+/// for use as the data of an entry with `EntryFlag::Checksummed` set.
+pub fn with_checksum(id: &Uuid, deps: &[OrderIndex], data: &[u8]) -> Vec<u8> {
+    let mut checked = Vec::with_capacity(data.len() + CHECKSUM_SIZE);
+    checked.extend_from_slice(data);
+    checked.extend_from_slice(&u32_bytes(entry_checksum(id, deps, data)));
+    checked
+}
+
+/// `data` followed by zeros where `seal` will write the checksum.
+pub fn with_room_for_checksum(data: &[u8]) -> Vec<u8> {
+    let mut checked = Vec::with_capacity(data.len() + CHECKSUM_SIZE);
+    checked.extend_from_slice(data);
+    checked.extend_from_slice(&[0; CHECKSUM_SIZE]);
+    checked
+}
+
+/// Overwrite the last four bytes of `data` with the checksum of the entry it is part of.
+pub fn seal(id: &Uuid, deps: &[OrderIndex], data: &mut [u8]) {
+    if data.len() < CHECKSUM_SIZE {
+        return
+    }
+    let split = data.len() - CHECKSUM_SIZE;
+    let checksum = entry_checksum(id, deps, &data[..split]);
+    data[split..].copy_from_slice(&u32_bytes(checksum));
+}
+
+/// Split checksummed data into its payload and the checksum it carries.
+pub fn split(data: &[u8]) -> Result<(&[u8], u32), ChecksumError> {
+    if data.len() < CHECKSUM_SIZE {
+        return Err(ChecksumError::Truncated)
+    }
+    let (payload, checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+    let checksum = checksum.iter().rev().fold(0, |crc, &b| (crc << 8) | b as u32);
+    Ok((payload, checksum))
+}
+
+pub fn verify(id: &Uuid, deps: &[OrderIndex], data: &[u8]) -> Result<(), ChecksumError> {
+    let (payload, expected) = split(data)?;
+    let found = entry_checksum(id, deps, payload);
+    if found != expected {
+        return Err(ChecksumError::Mismatch { expected, found })
+    }
+    Ok(())
+}
+
+fn update(mut crc: u32, bytes: &[u8]) -> u32 {
+    let table = table();
+    for &b in bytes {
+        crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn table() -> &'static [u32; 256] {
+    static INIT: Once = ONCE_INIT;
+    static mut TABLE: [u32; 256] = [0; 256];
+    unsafe {
+        INIT.call_once(|| {
+            for i in 0..256 {
+                let mut crc = i as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+                }
+                TABLE[i] = crc;
+            }
+        });
+        &TABLE
+    }
+}
+
+fn u64_bytes(val: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (val >> (8 * i)) as u8
+    }
+    bytes
+}
+
+fn u32_bytes(val: u32) -> [u8; 4] {
+    [val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn round_trip() {
+        let id = Uuid::new_v4();
+        let deps = [OrderIndex(3.into(), 7.into())];
+        let data = with_checksum(&id, &deps, b"hello");
+        assert_eq!(data.len(), 5 + CHECKSUM_SIZE);
+        assert_eq!(split(&data).unwrap().0, b"hello");
+        assert_eq!(verify(&id, &deps, &data), Ok(()));
+
+        let mut flipped = data.clone();
+        flipped[1] ^= 0x10;
+        assert!(match verify(&id, &deps, &flipped) {
+            Err(ChecksumError::Mismatch{..}) => true,
+            _ => false,
+        });
+        assert!(verify(&id, &[OrderIndex(3.into(), 8.into())], &data).is_err());
+        assert!(verify(&Uuid::new_v4(), &deps, &data).is_err());
+        assert_eq!(verify(&id, &deps, &[1, 2]), Err(ChecksumError::Truncated));
+    }
+
+    #[test]
+    fn seal() {
+        let id = Uuid::new_v4();
+        let deps = [OrderIndex(3.into(), 7.into()), OrderIndex(4.into(), 1.into())];
+        let mut data = with_room_for_checksum(b"hello");
+        assert!(verify(&id, &deps, &data).is_err());
+        super::seal(&id, &deps, &mut data);
+        assert_eq!(data, with_checksum(&id, &deps, b"hello"));
+    }
+}
@@ -0,0 +1,476 @@
+//! The versioned hello peers exchange when a connection is opened.
+//!
+//! connection
+//! 1. server writes `HANDSHAKE_VERSIONED` (servers without hellos write 0)
+//! 2. down sends `1 | VERSIONED`, client sends `2 | VERSIONED`
+//!    (if the server wrote 0, or the peer predates hellos, the bit is unset
+//!     and steps 3 and 5 are skipped)
+//! 3. down/client sends its `Hello`,
+//!    followed by its `Credentials` if the hello requires `feature::AUTH`
+//! 4. down/client sends its id
+//! 5. server sends a `HelloReply`, and closes the connection if it is not `OK`
+//! 6. server sends the id
+//!
+//! A peer which does not send a hello is treated as though it sent `Hello::legacy()`.
+//! Version 2 widened `EntryFlag` to two bytes, so since then such peers are refused.
+//! All integers are little-endian.
+
+use std::fmt;
+
+use EntryKind;
+
+/// The version of the protocol this build speaks.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest version of the protocol this build can still speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// The version of peers which predate the hello exchange.
+pub const LEGACY_VERSION: u16 = 0;
+
+/// The first byte a server which understands hellos writes.
+pub const HANDSHAKE_VERSIONED: u8 = 1;
+/// Set in the peer type byte when the peer will send a hello.
+pub const VERSIONED: u8 = 0x80;
+
+pub const HELLO_SIZE: usize = 2 + 2 + 4 + 4 + 32;
+pub const HELLO_REPLY_SIZE: usize = 1 + HELLO_SIZE;
+pub const CREDENTIALS_SIZE: usize = 2 * CREDENTIAL_FIELD_SIZE;
+/// The longest principal or secret `Credentials` can carry.
+pub const CREDENTIAL_FIELD_SIZE: usize = 32;
+
+pub mod feature {
+    pub const REPLICATION: u32 = 0x1;
+    pub const SNAPSHOTS: u32 = 0x2;
+    pub const GC: u32 = 0x4;
+    pub const COMPRESSION: u32 = 0x8;
+    /// The peer sends `Credentials` after its hello.
+    /// Peers with credentials require this, so servers which would ignore them refuse the peer.
+    pub const AUTH: u32 = 0x10;
+
+    /// The features this build implements.
+    pub const SUPPORTED: u32 = REPLICATION | SNAPSHOTS | GC | AUTH;
+}
+
+pub mod status {
+    pub const OK: u8 = 0;
+    pub const BAD_VERSION: u8 = 1;
+    pub const MISSING_FEATURES: u8 = 2;
+    pub const BAD_CREDENTIALS: u8 = 3;
+    pub const UNEXPECTED_PEER: u8 = 4;
+    pub const MALFORMED: u8 = 5;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    /// The features the sender implements.
+    pub features: u32,
+    /// The features the sender will not run without.
+    pub required_features: u32,
+    /// A bitmap of the `EntryKind`s the sender understands.
+    pub kinds: [u8; 32],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u16,
+    /// The features both sides implement.
+    pub features: u32,
+    /// The `EntryKind`s both sides understand.
+    pub kinds: [u8; 32],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// There is no version both sides speak.
+    Version { ours: (u16, u16), theirs: (u16, u16) },
+    /// Features one side requires which the other does not implement.
+    MissingFeatures { ours: u32, theirs: u32 },
+    /// The server does not know the principal, the secret is wrong,
+    /// or the server only accepts authenticated peers and none were sent.
+    BadCredentials,
+    /// The peer's type byte is unknown, or the server does not take
+    /// peers of that type at its position in the chain.
+    UnexpectedPeer,
+    /// A hello, reply, or credentials shorter than its fixed size.
+    Malformed,
+    /// The server refused the connection for a reason we could not reproduce.
+    Refused { status: u8 },
+}
+
+impl Hello {
+    /// The hello of this build.
+    pub fn current(required_features: u32) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: feature::SUPPORTED,
+            required_features,
+            kinds: known_kinds(),
+        }
+    }
+
+    /// The hello we assume a peer which does not send one would have sent.
+    pub fn legacy() -> Self {
+        Hello {
+            version: LEGACY_VERSION,
+            min_version: LEGACY_VERSION,
+            features: feature::REPLICATION | feature::SNAPSHOTS | feature::GC,
+            required_features: 0,
+            kinds: known_kinds(),
+        }
+    }
+
+    pub fn supports_kind(&self, kind: EntryKind::Kind) -> bool {
+        has_kind(&self.kinds, kind)
+    }
+
+    /// Decide how to talk to a peer which sent `theirs`,
+    /// gives the same result on both sides of the connection.
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Negotiated, Rejection> {
+        let version = ::std::cmp::min(self.version, theirs.version);
+        if version < self.min_version || version < theirs.min_version {
+            return Err(Rejection::Version {
+                ours: (self.min_version, self.version),
+                theirs: (theirs.min_version, theirs.version),
+            })
+        }
+        let ours_missing = self.required_features & !theirs.features;
+        let theirs_missing = theirs.required_features & !self.features;
+        if ours_missing != 0 || theirs_missing != 0 {
+            return Err(Rejection::MissingFeatures { ours: ours_missing, theirs: theirs_missing })
+        }
+        let mut kinds = [0; 32];
+        for (k, (a, b)) in kinds.iter_mut().zip(self.kinds.iter().zip(theirs.kinds.iter())) {
+            *k = a & b
+        }
+        Ok(Negotiated { version, features: self.features & theirs.features, kinds })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HELLO_SIZE] {
+        let mut bytes = [0; HELLO_SIZE];
+        write_le(&mut bytes[0..2], self.version as u64);
+        write_le(&mut bytes[2..4], self.min_version as u64);
+        write_le(&mut bytes[4..8], self.features as u64);
+        write_le(&mut bytes[8..12], self.required_features as u64);
+        bytes[12..].copy_from_slice(&self.kinds);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Rejection> {
+        if bytes.len() < HELLO_SIZE {
+            return Err(Rejection::Malformed)
+        }
+        let mut kinds = [0; 32];
+        kinds.copy_from_slice(&bytes[12..HELLO_SIZE]);
+        Ok(Hello {
+            version: read_le(&bytes[0..2]) as u16,
+            min_version: read_le(&bytes[2..4]) as u16,
+            features: read_le(&bytes[4..8]) as u32,
+            required_features: read_le(&bytes[8..12]) as u32,
+            kinds,
+        })
+    }
+}
+
+impl Negotiated {
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn supports_kind(&self, kind: EntryKind::Kind) -> bool {
+        has_kind(&self.kinds, kind)
+    }
+}
+
+impl Rejection {
+    pub fn status(&self) -> u8 {
+        match *self {
+            Rejection::Version{..} => status::BAD_VERSION,
+            Rejection::MissingFeatures{..} => status::MISSING_FEATURES,
+            Rejection::BadCredentials => status::BAD_CREDENTIALS,
+            Rejection::UnexpectedPeer => status::UNEXPECTED_PEER,
+            Rejection::Malformed => status::MALFORMED,
+            Rejection::Refused { status } => status,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::Version { ours, theirs } => write!(f,
+                "no common protocol version, we speak {}..={} they speak {}..={}",
+                ours.0, ours.1, theirs.0, theirs.1,
+            ),
+            Rejection::MissingFeatures { ours, theirs } => write!(f,
+                "missing features, they lack {:#x} which we require, we lack {:#x} which they require",
+                ours, theirs,
+            ),
+            Rejection::BadCredentials =>
+                write!(f, "bad or missing credentials"),
+            Rejection::UnexpectedPeer =>
+                write!(f, "the server does not take this type of peer"),
+            Rejection::Malformed =>
+                write!(f, "the handshake was cut short"),
+            Rejection::Refused { status } =>
+                write!(f, "the server refused the connection with status {}", status),
+        }
+    }
+}
+
+/// The server's response to a hello, sent back to the peer before its id.
+pub fn reply_bytes(ours: &Hello, result: &Result<Negotiated, Rejection>)
+-> [u8; HELLO_REPLY_SIZE] {
+    let mut bytes = [0; HELLO_REPLY_SIZE];
+    bytes[0] = match *result {
+        Ok(..) => status::OK,
+        Err(ref rejection) => rejection.status(),
+    };
+    bytes[1..].copy_from_slice(&ours.to_bytes());
+    bytes
+}
+
+/// What a peer sends after reading the server's first byte, up to its id.
+/// `Err` if the server is too old to talk to.
+pub fn peer_hello_bytes(server_first: u8, peer_type: u8, ours: &Hello)
+-> Result<(Vec<u8>, bool), Rejection> {
+    if server_first != HANDSHAKE_VERSIONED {
+        ours.negotiate(&Hello::legacy())?;
+        return Ok((vec![peer_type], false))
+    }
+    let mut bytes = Vec::with_capacity(1 + HELLO_SIZE);
+    bytes.push(peer_type | VERSIONED);
+    bytes.extend_from_slice(&ours.to_bytes());
+    Ok((bytes, true))
+}
+
+/// Check the server's `HelloReply`.
+pub fn check_reply(ours: &Hello, reply: &[u8]) -> Result<Negotiated, Rejection> {
+    if reply.len() < HELLO_REPLY_SIZE {
+        return Err(Rejection::Malformed)
+    }
+    let theirs = Hello::from_bytes(&reply[1..])?;
+    let negotiated = ours.negotiate(&theirs)?;
+    // both sides run the same negotiation, so they should agree
+    match reply[0] {
+        status::OK => Ok(negotiated),
+        status::BAD_CREDENTIALS => Err(Rejection::BadCredentials),
+        status::UNEXPECTED_PEER => Err(Rejection::UnexpectedPeer),
+        status::MALFORMED => Err(Rejection::Malformed),
+        status => Err(Rejection::Refused { status }),
+    }
+}
+
+/// Who a peer claims to be, sent in the clear after its hello,
+/// so it should only be used on networks where eavesdropping is not a concern.
+/// Both fields are zero-padded to `CREDENTIAL_FIELD_SIZE` bytes.
+/// There is deliberately no `PartialEq`, secrets are compared with `secret_matches`.
+#[derive(Copy, Clone)]
+pub struct Credentials {
+    principal: [u8; CREDENTIAL_FIELD_SIZE],
+    secret: [u8; CREDENTIAL_FIELD_SIZE],
+}
+
+impl Credentials {
+    pub fn new(principal: &str, secret: &[u8]) -> Self {
+        assert!(!principal.is_empty(), "empty principal");
+        assert!(principal.len() <= CREDENTIAL_FIELD_SIZE && !principal.contains('\0'),
+            "principal must be at most {} bytes without NULs", CREDENTIAL_FIELD_SIZE);
+        assert!(secret.len() <= CREDENTIAL_FIELD_SIZE,
+            "secret must be at most {} bytes", CREDENTIAL_FIELD_SIZE);
+        let mut credentials = Credentials {
+            principal: [0; CREDENTIAL_FIELD_SIZE],
+            secret: [0; CREDENTIAL_FIELD_SIZE],
+        };
+        credentials.principal[..principal.len()].copy_from_slice(principal.as_bytes());
+        credentials.secret[..secret.len()].copy_from_slice(secret);
+        credentials
+    }
+
+    pub fn principal(&self) -> &str {
+        let len = self.principal.iter().position(|&b| b == 0).unwrap_or(CREDENTIAL_FIELD_SIZE);
+        ::std::str::from_utf8(&self.principal[..len]).unwrap_or("")
+    }
+
+    /// Compares the secrets in time independent of where they differ.
+    pub fn secret_matches(&self, other: &Credentials) -> bool {
+        self.secret.iter().zip(other.secret.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; CREDENTIALS_SIZE] {
+        let mut bytes = [0; CREDENTIALS_SIZE];
+        bytes[..CREDENTIAL_FIELD_SIZE].copy_from_slice(&self.principal);
+        bytes[CREDENTIAL_FIELD_SIZE..].copy_from_slice(&self.secret);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Rejection> {
+        if bytes.len() < CREDENTIALS_SIZE {
+            return Err(Rejection::Malformed)
+        }
+        let mut credentials = Credentials {
+            principal: [0; CREDENTIAL_FIELD_SIZE],
+            secret: [0; CREDENTIAL_FIELD_SIZE],
+        };
+        credentials.principal.copy_from_slice(&bytes[..CREDENTIAL_FIELD_SIZE]);
+        credentials.secret.copy_from_slice(&bytes[CREDENTIAL_FIELD_SIZE..CREDENTIALS_SIZE]);
+        Ok(credentials)
+    }
+}
+
+// never print the secret
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials").field("principal", &self.principal()).finish()
+    }
+}
+
+fn known_kinds() -> [u8; 32] {
+    use EntryKind::*;
+    let mut kinds = [0; 32];
+    let known = [
+        Data, Multiput, Read, Lock, Sentinel, Skeens2ToReplica,
+        SingleToReplica, MultiputToReplica, SentinelToReplica,
+        FenceClient, UpdateRecovery, CheckSkeens1, GC,
+        Snapshot, SnapshotToReplica, CitedBy, Rejected,
+    ];
+    for kind in known.iter() {
+        let bits = kind.bits() as usize;
+        kinds[bits / 8] |= 1 << (bits % 8);
+    }
+    kinds
+}
+
+fn has_kind(kinds: &[u8; 32], kind: EntryKind::Kind) -> bool {
+    let bits = kind.bits() as usize;
+    kinds[bits / 8] & (1 << (bits % 8)) != 0
+}
+
+fn write_le(bytes: &mut [u8], val: u64) {
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (val >> (8 * i)) as u8
+    }
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |val, (i, &b)| val | (b as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello { required_features: feature::GC, ..Hello::current(0) };
+        assert_eq!(Hello::from_bytes(&hello.to_bytes()), Ok(hello));
+        assert_eq!(Hello::from_bytes(&hello.to_bytes()[1..]), Err(Rejection::Malformed));
+        assert!(hello.supports_kind(EntryKind::Multiput));
+        assert!(hello.supports_kind(EntryKind::CitedBy));
+        assert!(hello.supports_kind(EntryKind::Rejected));
+        assert!(!hello.supports_kind(EntryKind::Invalid));
+    }
+
+    #[test]
+    fn negotiate_versions() {
+        let current = Hello::current(0);
+        let legacy = Hello::legacy();
+        // legacy peers use one byte flags
+        assert_eq!(current.negotiate(&legacy), Err(Rejection::Version {
+            ours: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            theirs: (LEGACY_VERSION, LEGACY_VERSION),
+        }));
+        assert!(legacy.negotiate(&current).is_err());
+        assert_eq!(current.negotiate(&current).map(|n| n.version), Ok(PROTOCOL_VERSION));
+
+        let previous = Hello { version: 1, min_version: 0, ..current };
+        assert!(current.negotiate(&previous).is_err());
+
+        let future = Hello { version: PROTOCOL_VERSION + 2, min_version: PROTOCOL_VERSION + 1, ..current };
+        assert_eq!(current.negotiate(&future), Err(Rejection::Version {
+            ours: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            theirs: (PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+        }));
+        assert!(future.negotiate(&current).is_err());
+    }
+
+    #[test]
+    fn negotiate_features() {
+        let current = Hello::current(0);
+        let compressed = Hello::current(feature::COMPRESSION);
+        assert_eq!(compressed.negotiate(&current), Err(Rejection::MissingFeatures {
+            ours: feature::COMPRESSION, theirs: 0,
+        }));
+        assert_eq!(current.negotiate(&compressed), Err(Rejection::MissingFeatures {
+            ours: 0, theirs: feature::COMPRESSION,
+        }));
+        let snapshots = Hello::current(feature::SNAPSHOTS).negotiate(&current).unwrap();
+        assert!(snapshots.has_feature(feature::SNAPSHOTS));
+        assert!(!snapshots.has_feature(feature::COMPRESSION));
+    }
+
+    #[test]
+    fn peer_and_reply() {
+        let current = Hello::current(0);
+        assert!(peer_hello_bytes(0, 2, &current).is_err());
+        let (bytes, versioned) = peer_hello_bytes(HANDSHAKE_VERSIONED, 2, &current).unwrap();
+        assert!(versioned);
+        assert_eq!(bytes[0], 2 | VERSIONED);
+        assert_eq!(Hello::from_bytes(&bytes[1..]), Ok(current));
+
+        let server = Hello::current(0);
+        let reply = reply_bytes(&server, &server.negotiate(&current));
+        assert_eq!(reply[0], status::OK);
+        assert!(check_reply(&current, &reply).is_ok());
+
+        let picky = Hello::current(feature::COMPRESSION);
+        let reply = reply_bytes(&picky, &picky.negotiate(&current));
+        assert_eq!(reply[0], status::MISSING_FEATURES);
+        assert_eq!(check_reply(&current, &reply), Err(Rejection::MissingFeatures {
+            ours: 0, theirs: feature::COMPRESSION,
+        }));
+
+        let reply = reply_bytes(&server, &Err(Rejection::BadCredentials));
+        assert_eq!(check_reply(&current, &reply), Err(Rejection::BadCredentials));
+
+        // a short reply is an error, not a panic
+        assert_eq!(check_reply(&current, &reply[..HELLO_SIZE]), Err(Rejection::Malformed));
+        assert_eq!(check_reply(&current, &[]), Err(Rejection::Malformed));
+    }
+
+    #[test]
+    fn rejection_status() {
+        let rejections = [
+            (Rejection::Version { ours: (0, 1), theirs: (2, 3) }, status::BAD_VERSION),
+            (Rejection::MissingFeatures { ours: feature::GC, theirs: 0 }, status::MISSING_FEATURES),
+            (Rejection::BadCredentials, status::BAD_CREDENTIALS),
+            (Rejection::UnexpectedPeer, status::UNEXPECTED_PEER),
+            (Rejection::Malformed, status::MALFORMED),
+            (Rejection::Refused { status: 200 }, 200),
+        ];
+        for &(rejection, status) in rejections.iter() {
+            assert_eq!(rejection.status(), status, "{:?}", rejection);
+            assert_ne!(rejection.status(), status::OK);
+            let reply = reply_bytes(&Hello::current(0), &Err(rejection));
+            assert_eq!(reply[0], status);
+        }
+    }
+
+    #[test]
+    fn credentials() {
+        let alice = Credentials::new("alice", b"hunter2");
+        let decoded = Credentials::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(decoded.principal(), "alice");
+        assert!(decoded.secret_matches(&alice));
+        assert!(!decoded.secret_matches(&Credentials::new("alice", b"hunter3")));
+        assert!(!format!("{:?}", alice).contains("hunter2"));
+        assert!(Credentials::from_bytes(&alice.to_bytes()[..CREDENTIALS_SIZE - 1]).is_err());
+
+        // a peer with credentials cannot talk to a server which would ignore them
+        let authenticated = Hello::current(feature::AUTH);
+        assert!(peer_hello_bytes(0, 2, &authenticated).is_err());
+        let old_server = Hello { features: feature::SUPPORTED & !feature::AUTH, ..Hello::current(0) };
+        assert!(old_server.negotiate(&authenticated).is_err());
+    }
+}
@@ -18,6 +18,7 @@ use std::slice;
 pub use uuid::Uuid;
 
 pub use storeables::{Storeable, UnStoreable};
+pub use packet_macro2::schema;
 
 pub use self::Packet::Ref as EntryContents;
 pub use self::Packet::Mut as EntryContentsMut;
@@ -29,7 +30,9 @@ use self::EntryFlag::Flag;
 pub mod buffer;
 pub mod buffer2;
 pub mod storeables;
+pub mod checksum;
 pub mod double_buffer;
+pub mod hello;
 
 custom_derive! {
     #[derive(Debug, Hash, PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Default, RustcDecodable, RustcEncodable, NewtypeFrom, NewtypeBitAnd(u64), NewtypeAdd(u64), NewtypeSub(u64), NewtypeMul(u64), NewtypeRem(u64))]
@@ -105,6 +108,10 @@ pub mod EntryKind {
 
             const Snapshot = 0x60,
             const SnapshotToReplica = 0x70,
+
+            const CitedBy = 0x80,
+
+            const Rejected = 0x90,
         }
     }
 
@@ -161,7 +168,7 @@ pub mod EntryKind {
 pub mod EntryFlag {
     #![allow(non_upper_case_globals)]
     bitflags! {
-        flags Flag: u8 {
+        flags Flag: u16 {
             const Nothing = 0x0,
             const ReadSuccess = 0x1,
             const NewMultiPut = 0x2, //TODO this flag should always be on, remove?
@@ -171,6 +178,17 @@ pub mod EntryFlag {
             const NoRemote = 0x20,
             const DirectWrite = 0x40,
             const SnapshotAndFetch = 0x80,
+            // the last four bytes of the data are a checksum, see `checksum`
+            const Checksummed = 0x100,
+            // a replica found the entry's checksum did not match when it was replicated,
+            // the entry is stored anyway so the replicas agree on the chain
+            const Corrupted = 0x200,
+            // the entry's dependencies are the read set of a transaction,
+            // the servers storing those colors check it is still current, see `reject::ABORTED`
+            const Transaction = 0x400,
+            // a server found a transaction's read set stale during the skeens round,
+            // the transaction is not given a location in any of its colors
+            const Aborted = 0x800,
         }
     }
 
@@ -262,6 +280,7 @@ define_packet!{
 
         Skeens2ToReplica: EntryKind::Skeens2ToReplica => {
             id: Uuid,
+            flags: EntryFlag::Flag,
             lock: u64,
             loc: OrderIndex,
         },
@@ -324,9 +343,43 @@ define_packet!{
             locs: [OrderIndex | cols],
             queue_nums: [u64 | cols],
         },
+
+        CitedBy: EntryKind::CitedBy => {
+            id: Uuid,
+            flags: EntryFlag::Flag,
+            num_citations: u32,
+            loc: OrderIndex,
+            citations: [OrderIndex | num_citations],
+        },
+
+        // a server's refusal of a request, see `reject`
+        Rejected: EntryKind::Rejected => {
+            id: Uuid,
+            flags: EntryFlag::Flag,
+            status: u8,
+            request: EntryKind::Kind,
+            cols: u16,
+            locs: [OrderIndex | cols],
+        },
     }
 }
 
+/// Why a server refused a request, sent as the `status` of a `Packet::Rejected`.
+pub mod reject {
+    /// An append's checksum did not match, see `checksum`.
+    pub const CORRUPT: u8 = 1;
+    /// The client may not use the color at `locs()[0]`, see `hello::Credentials`.
+    pub const PERMISSION_DENIED: u8 = 2;
+    /// The server has too much outstanding work to accept an append now,
+    /// the client should send it again after a backoff.
+    pub const RETRY_LATER: u8 = 3;
+    /// An append would exceed the quota the color at `locs()[0]` is charged to.
+    pub const QUOTA_EXCEEDED: u8 = 4;
+    /// A transaction read a color which has since been appended to,
+    /// `locs()[0]` is the first location after the read.
+    pub const ABORTED: u8 = 5;
+}
+
 impl<'a> Packet::Ref<'a> {
     pub fn read(loc: &'a OrderIndex) -> Self {
         static NO_FLAG: &'static EntryFlag::Flag = &EntryFlag::Nothing;
@@ -353,15 +406,15 @@ impl<'a> Packet::Ref<'a> {
             | Senti{flags, ..} | SentiToReplica{flags, ..}
             | GC{flags, ..}
             | UpdateRecovery{flags, ..} | CheckSkeens1{flags, ..}
-            | Snapshot{flags, ..} | SnapshotToReplica{flags, ..} =>
+            | Snapshot{flags, ..} | SnapshotToReplica{flags, ..}
+            | CitedBy{flags, ..} | Rejected{flags, ..}
+            | Skeens2ToReplica{flags, ..} =>
                 flags,
 
             FenceClient{..} => {
                 static NO_FLAG: EntryFlag::Flag = EntryFlag::Nothing;
                 &NO_FLAG
             },
-
-            Skeens2ToReplica{..} => unreachable!(),
         }
     }
 
@@ -382,6 +435,8 @@ impl<'a> Packet::Ref<'a> {
             CheckSkeens1{..} => EntryKind::CheckSkeens1,
             Snapshot{..} => EntryKind::Snapshot,
             SnapshotToReplica{..} => EntryKind::SnapshotToReplica,
+            CitedBy{..} => EntryKind::CitedBy,
+            Rejected{..} => EntryKind::Rejected,
         }
     }
 
@@ -402,7 +457,11 @@ impl<'a> Packet::Ref<'a> {
             UpdateRecovery{write_id, ..} => write_id,
             FenceClient{fencing_write, ..} => fencing_write,
 
-            Snapshot{id, ..} | SnapshotToReplica{id, ..} => id
+            Snapshot{id, ..} | SnapshotToReplica{id, ..} => id,
+
+            CitedBy{id, ..} => id,
+
+            Rejected{id, ..} => id,
         }
     }
 
@@ -410,7 +469,8 @@ impl<'a> Packet::Ref<'a> {
         use self::Packet::Ref::*;
         match self {
             Read{loc, ..} | Single{loc, ..} | SingleToReplica{loc, ..}
-            | Skeens2ToReplica{loc, ..} | CheckSkeens1{loc, ..} => unsafe {
+            | Skeens2ToReplica{loc, ..} | CheckSkeens1{loc, ..}
+            | CitedBy{loc, ..} => unsafe {
                 slice::from_raw_parts(loc, 1)
             },
 
@@ -419,7 +479,8 @@ impl<'a> Packet::Ref<'a> {
             | GC{locs, ..}
             | UpdateRecovery{locs, ..}
             | Snapshot{locs, ..}
-            | SnapshotToReplica{locs, ..} => locs,
+            | SnapshotToReplica{locs, ..}
+            | Rejected{locs, ..} => locs,
 
             FenceClient{..} => unreachable!(),
         }
@@ -443,7 +504,7 @@ impl<'a> Packet::Ref<'a> {
             Read{..} | Single{..} | Multi{..} | Senti{..} | Skeens2ToReplica{..}
             | GC{..}
             | UpdateRecovery{..} | FenceClient{..} | CheckSkeens1{..}
-            | Snapshot{..}  => unreachable!(),
+            | Snapshot{..} | CitedBy{..} | Rejected{..} => unreachable!(),
         }
     }
 
@@ -457,7 +518,7 @@ impl<'a> Packet::Ref<'a> {
             | GC{..}
             | UpdateRecovery{..} | FenceClient{..}
             | CheckSkeens1{..}
-            | Snapshot{..} | SnapshotToReplica{..} => unreachable!(),
+            | Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} => unreachable!(),
         }
     }
 
@@ -484,7 +545,7 @@ impl<'a> Packet::Ref<'a> {
             Read{..} | Single{..} | SingleToReplica{..} | Skeens2ToReplica{..}
             | GC{..}
             | UpdateRecovery{..} | FenceClient{..} | CheckSkeens1{..}
-            |Snapshot{..} | SnapshotToReplica{..} => unreachable!(),
+            |Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} => unreachable!(),
         }
     }
 
@@ -502,7 +563,7 @@ impl<'a> Packet::Ref<'a> {
             Read{..} => 0,
 
             GC{..}
-            | FenceClient{..} | CheckSkeens1{..}  => unreachable!(),
+            | FenceClient{..} | CheckSkeens1{..} | CitedBy{..} | Rejected{..} => unreachable!(),
         }
     }
 
@@ -513,7 +574,7 @@ impl<'a> Packet::Ref<'a> {
             Read{..} | Senti{..} | SentiToReplica{..} | Skeens2ToReplica{..}
             | GC{..}
             | UpdateRecovery{..} | FenceClient{..} | CheckSkeens1{..}
-            | Snapshot{..} | SnapshotToReplica{..} => unreachable!(),
+            | Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} => unreachable!(),
 
             SingleToReplica{deps, data, ..}
             | MultiToReplica{deps, data, ..}
@@ -534,11 +595,95 @@ impl<'a> Packet::Ref<'a> {
 
             Read{..} | Skeens2ToReplica{..}| GC{..} | UpdateRecovery{..} | FenceClient{..}
             |CheckSkeens1{..}
-            | Snapshot{..} | SnapshotToReplica{..} =>
+            | Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} =>
                 unreachable!(),
         }
     }
 
+    /// The data the client appended, without the checksum if there is one.
+    /// `Err` if the entry is checksummed but too short to hold the checksum.
+    pub fn payload(self) -> Result<&'a [u8], checksum::ChecksumError> {
+        let data = self.data();
+        if self.is_checksummed() {
+            checksum::split(data).map(|(payload, _)| payload)
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn is_checksummed(self) -> bool {
+        use self::Packet::Ref::*;
+        match self {
+            Single{flags, ..} | Multi{flags, ..}
+            | SingleToReplica{flags, ..} | MultiToReplica{flags, ..} =>
+                flags.contains(EntryFlag::Checksummed),
+            _ => false,
+        }
+    }
+
+    /// Check the entry's checksum, entries without one always pass.
+    pub fn verify_checksum(self) -> Result<(), checksum::ChecksumError> {
+        if !self.is_checksummed() {
+            return Ok(())
+        }
+        checksum::verify(self.id(), self.dependencies(), self.data())
+    }
+
+    /// The reply which tells the sender a server refused this request,
+    /// `status` is one of `reject`.
+    /// If `first` is one of the request's colors it is moved to the front of the
+    /// reply's locations, so the sender can report it.
+    pub fn rejection(self, status: u8, first: Option<order>) -> Vec<u8> {
+        let mut locs = self.locs().to_vec();
+        if let Some(first) = first {
+            if let Some(i) = locs.iter().position(|&OrderIndex(o, _)| o == first) {
+                locs.swap(0, i)
+            }
+        }
+        let mut reply = vec![];
+        Packet::Ref::Rejected {
+            id: self.id(),
+            flags: &EntryFlag::Nothing,
+            status: &status,
+            request: &self.kind(),
+            locs: &locs,
+        }.fill_vec(&mut reply);
+        reply
+    }
+
+    /// The reply aborting a transaction whose read of `conflict.0` is stale,
+    /// `conflict` is the first location after the read, see `reject::ABORTED`.
+    pub fn abortion(self, conflict: OrderIndex) -> Vec<u8> {
+        let locs: Vec<_> = ::std::iter::once(conflict)
+            .chain(self.locs().iter().cloned())
+            .collect();
+        let mut reply = vec![];
+        Packet::Ref::Rejected {
+            id: self.id(),
+            flags: &EntryFlag::Nothing,
+            status: &reject::ABORTED,
+            request: &self.kind(),
+            locs: &locs,
+        }.fill_vec(&mut reply);
+        reply
+    }
+
+    /// If this is a server's refusal of a request, why, see `reject`.
+    pub fn rejection_status(self) -> Option<u8> {
+        match self {
+            Packet::Ref::Rejected{status, ..} => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The kind of request a server refused.
+    pub fn rejected_request(self) -> EntryKind::Kind {
+        match self {
+            Packet::Ref::Rejected{request, ..} => *request,
+            o => panic!("tried to get the rejected request of {:?}.", o),
+        }
+    }
+
     pub fn horizon(self) -> OrderIndex {
         use self::Packet::Ref::*;
         match self {
@@ -547,18 +692,35 @@ impl<'a> Packet::Ref<'a> {
             | Skeens2ToReplica{..} | GC{..}
             | UpdateRecovery{..} | FenceClient{..}
             | CheckSkeens1{..}
-            | Snapshot{..} | SnapshotToReplica{..} =>
+            | Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} =>
                 unreachable!(),
             Read{horizon, ..} => *horizon,
         }
     }
 
+    /// The first entry the server still stores on the chain read,
+    /// those before it were trimmed.
+    pub fn min(self) -> OrderIndex {
+        use self::Packet::Ref::*;
+        match self {
+            Single{..} | Multi{..} | Senti{..}
+            | SingleToReplica{..} | MultiToReplica{..} | SentiToReplica{..}
+            | Skeens2ToReplica{..} | GC{..}
+            | UpdateRecovery{..} | FenceClient{..}
+            | CheckSkeens1{..}
+            | Snapshot{..} | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} =>
+                unreachable!(),
+            Read{min, ..} => *min,
+        }
+    }
+
     pub fn non_replicated_len(self) -> usize {
         use self::Packet::Ref::*;
         match self {
             c @ Read {..} | c @ Single {..} | c @ Multi{..} | c @Senti{..} | c @ GC{..}
             | c @ UpdateRecovery{..} | c @ CheckSkeens1{..}
-            | c @ Snapshot{..} | c @ SnapshotToReplica{..} => c.len(),
+            | c @ Snapshot{..} | c @ SnapshotToReplica{..}
+            | c @ CitedBy{..} | c @ Rejected{..} => c.len(),
 
             SingleToReplica{ id, flags, loc, deps, data, timestamp, ..} =>
                 Single{id: id, flags: flags, loc: loc, deps: deps, data: data, timestamp}.len(),
@@ -595,7 +757,8 @@ impl<'a> Packet::Ref<'a> {
             SentiToReplica{id, flags, data_bytes, lock, locs, deps: _, queue_nums, } =>
                 SentiToReplica{id, flags, data_bytes, lock, locs, deps: new, queue_nums, },
 
-            p @ Read{..} | p @ Skeens2ToReplica{..} | p @ GC{..} | p @ FenceClient{..} | p @ UpdateRecovery{..} | p @ CheckSkeens1{..} | p @ Snapshot{..} | p @ SnapshotToReplica{..} =>
+            p @ Read{..} | p @ Skeens2ToReplica{..} | p @ GC{..} | p @ FenceClient{..} | p @ UpdateRecovery{..} | p @ CheckSkeens1{..} | p @ Snapshot{..} | p @ SnapshotToReplica{..}
+            | p @ CitedBy{..} | p @ Rejected{..} =>
                     unreachable!("{:?}", p),
         }
     }
@@ -703,6 +866,15 @@ impl<'a> Packet::Ref<'a> {
         v
     }
 
+    pub fn citations(self) -> &'a [OrderIndex] {
+        use self::Packet::Ref::*;
+        match self {
+            CitedBy{citations, ..} => citations,
+
+            o => panic!("tried to get citations from {:?}.", o),
+        }
+    }
+
     pub fn write_id_and_old_recoverer(self) -> (&'a Uuid, &'a Uuid) {
         use self::Packet::Ref::*;
         match self {
@@ -729,10 +901,28 @@ impl<'a> Packet::Mut<'a> {
             | &mut UpdateRecovery{ref mut flags, ..}
             | &mut CheckSkeens1{ref mut flags, ..}
             | &mut Snapshot{ref mut flags, ..}
-            | &mut SnapshotToReplica{ref mut flags, ..} =>
+            | &mut SnapshotToReplica{ref mut flags, ..}
+            | &mut CitedBy{ref mut flags, ..}
+            | &mut Rejected{ref mut flags, ..}
+            | &mut Skeens2ToReplica{ref mut flags, ..} =>
                 &mut **flags,
 
-            &mut Skeens2ToReplica{..} | &mut FenceClient{..} => unreachable!(),
+            &mut FenceClient{..} => unreachable!(),
+        }
+    }
+
+    /// Write the checksum of a `Checksummed` entry into the space left for it
+    /// at the end of its data, see `checksum::with_room_for_checksum`.
+    /// The checksum covers the dependencies, so this must be done once they are final.
+    pub fn seal_checksum(&mut self) {
+        use self::Packet::Mut::*;
+        match self {
+            &mut Single{ref flags, ref id, ref deps, ref mut data, ..}
+            | &mut Multi{ref flags, ref id, ref deps, ref mut data, ..} =>
+                if flags.contains(EntryFlag::Checksummed) {
+                    checksum::seal(id, deps, data)
+                },
+            _ => {},
         }
     }
 
@@ -750,10 +940,13 @@ impl<'a> Packet::Mut<'a> {
             | &mut UpdateRecovery{ref mut flags, ..}
             | &mut CheckSkeens1{ref mut flags, ..}
             | &mut Snapshot{ref mut flags, ..}
-            | &mut SnapshotToReplica{ref mut flags, ..} =>
+            | &mut SnapshotToReplica{ref mut flags, ..}
+            | &mut CitedBy{ref mut flags, ..}
+            | &mut Rejected{ref mut flags, ..}
+            | &mut Skeens2ToReplica{ref mut flags, ..} =>
                 &mut **flags,
 
-            &mut Skeens2ToReplica{..} | &mut FenceClient{..} => unreachable!(),
+            &mut FenceClient{..} => unreachable!(),
         }
     }
 
@@ -764,7 +957,8 @@ impl<'a> Packet::Mut<'a> {
             | &mut Single{ref mut loc, ..}
             | &mut SingleToReplica{ref mut loc, ..}
             | &mut Skeens2ToReplica{ref mut loc, ..}
-            | &mut CheckSkeens1{ref mut loc, ..} => unsafe {
+            | &mut CheckSkeens1{ref mut loc, ..}
+            | &mut CitedBy{ref mut loc, ..} => unsafe {
                 slice::from_raw_parts_mut(&mut **loc, 1)
             },
 
@@ -775,7 +969,8 @@ impl<'a> Packet::Mut<'a> {
             | &mut GC{ref mut locs, ..}
             | &mut UpdateRecovery{ref mut locs, ..}
             | &mut Snapshot{ref mut locs, ..}
-            | &mut SnapshotToReplica{ref mut locs, ..} => &mut *locs,
+            | &mut SnapshotToReplica{ref mut locs, ..}
+            | &mut Rejected{ref mut locs, ..} => &mut *locs,
 
             &mut FenceClient{..} => unreachable!(),
         }
@@ -798,7 +993,9 @@ impl<'a> Packet::Mut<'a> {
             &mut Read{..}
             | &mut GC{..}
             | &mut FenceClient{..}
-            | &mut CheckSkeens1{..} => unreachable!(),
+            | &mut CheckSkeens1{..}
+            | &mut CitedBy{..}
+            | &mut Rejected{..} => unreachable!(),
         }
     }
 
@@ -1030,7 +1227,7 @@ pub unsafe fn data_bytes(bytes: &[u8]) -> &[u8] {
         Read{..} | Senti{..} | SentiToReplica{..} | Skeens2ToReplica{..}
         | GC{..}
         | FenceClient{..} | UpdateRecovery{..} | CheckSkeens1{..}
-        | Snapshot{..}  | SnapshotToReplica{..} => unreachable!(),
+        | Snapshot{..}  | SnapshotToReplica{..} | CitedBy{..} | Rejected{..} => unreachable!(),
 
         Single{data, ..} | Multi{data, ..}
         | SingleToReplica{data, ..} | MultiToReplica{data, ..} => data,
@@ -1125,6 +1322,119 @@ mod test {
         assert_eq!(bytes_as_entry_mut(&mut bytes).as_ref(), contents);
     }
 
+    #[test]
+    fn seal_checksum_after_new_deps() {
+        let id = Uuid::new_v4();
+        let data = checksum::with_room_for_checksum(&[1, 2, 3]);
+        let mut bytes = vec![];
+        EntryContents::Single {
+            id: &id,
+            flags: &EntryFlag::Checksummed,
+            loc: &(3u64, 0).into(),
+            deps: &[],
+            data: &data,
+            timestamp: &0,
+        }.fill_vec(&mut bytes);
+        // the client's log thread may add dependencies before sending an append
+        let deps = [(1u64, 4).into()];
+        let mut bytes = bytes_as_entry(&bytes).with_deps(&deps).to_vec();
+        assert!(bytes_as_entry(&bytes).verify_checksum().is_err());
+        bytes_as_entry_mut(&mut bytes).seal_checksum();
+        let sealed = bytes_as_entry(&bytes);
+        assert_eq!(sealed.verify_checksum(), Ok(()));
+        assert_eq!(sealed.payload(), Ok(&[1, 2, 3][..]));
+    }
+
+    #[test]
+    fn truncated_checksum() {
+        let id = Uuid::new_v4();
+        let mut bytes = vec![];
+        EntryContents::Single {
+            id: &id,
+            flags: &EntryFlag::Checksummed,
+            loc: &(3u64, 0).into(),
+            deps: &[],
+            data: &[1, 2],
+            timestamp: &0,
+        }.fill_vec(&mut bytes);
+        let truncated = bytes_as_entry(&bytes);
+        assert_eq!(truncated.verify_checksum(), Err(checksum::ChecksumError::Truncated));
+        assert_eq!(truncated.payload(), Err(checksum::ChecksumError::Truncated));
+    }
+
+    #[test]
+    fn rejected() {
+        let id = Uuid::new_v4();
+        let mut bytes = vec![];
+        EntryContents::Multi {
+            id: &id,
+            flags: &(EntryFlag::NewMultiPut | EntryFlag::TakeLock),
+            lock: &0,
+            locs: &[(1u64, 0).into(), (5u64, 0).into(), (7u64, 0).into()],
+            deps: &[],
+            data: &[1, 2, 3],
+        }.fill_vec(&mut bytes);
+        assert_eq!(bytes_as_entry(&bytes).rejection_status(), None);
+        let reply = bytes_as_entry(&bytes).rejection(reject::CORRUPT, Some(5u64.into()));
+        let reply = bytes_as_entry(&reply);
+        assert_eq!(reply.kind(), EntryKind::Rejected);
+        assert_eq!(reply.rejection_status(), Some(reject::CORRUPT));
+        assert_eq!(reply.rejected_request(), EntryKind::Multiput);
+        assert_eq!(reply.id(), &id);
+        assert_eq!(reply.locs(), &[(5u64, 0).into(), (1u64, 0).into(), (7u64, 0).into()]);
+    }
+
+    #[test]
+    fn denied() {
+        let loc = (4u64, 10).into();
+        let mut bytes = vec![];
+        EntryContents::read(&loc).fill_vec(&mut bytes);
+        let reply = bytes_as_entry(&bytes).rejection(reject::PERMISSION_DENIED, Some(4u64.into()));
+        let reply = bytes_as_entry(&reply);
+        assert_eq!(reply.rejection_status(), Some(reject::PERMISSION_DENIED));
+        assert_eq!(reply.rejected_request(), EntryKind::Read);
+        assert_eq!(reply.locs(), &[loc]);
+    }
+
+    #[test]
+    fn retry_later() {
+        let id = Uuid::new_v4();
+        let mut bytes = vec![];
+        EntryContents::Single {
+            id: &id,
+            flags: &EntryFlag::Nothing,
+            loc: &(3u64, 0).into(),
+            deps: &[],
+            data: &[1, 2, 3],
+            timestamp: &0,
+        }.fill_vec(&mut bytes);
+        let reply = bytes_as_entry(&bytes).rejection(reject::RETRY_LATER, None);
+        let reply = bytes_as_entry(&reply);
+        assert_eq!(reply.rejection_status(), Some(reject::RETRY_LATER));
+        assert_eq!(reply.rejected_request(), EntryKind::Data);
+        assert_eq!(reply.id(), &id);
+        assert_eq!(reply.locs(), &[(3u64, 0).into()]);
+    }
+
+    #[test]
+    fn over_quota() {
+        let id = Uuid::new_v4();
+        let mut bytes = vec![];
+        EntryContents::Multi {
+            id: &id,
+            flags: &EntryFlag::NewMultiPut,
+            lock: &0,
+            locs: &[(2u64, 0).into(), (9u64, 0).into()],
+            deps: &[],
+            data: &[4, 5],
+        }.fill_vec(&mut bytes);
+        let reply = bytes_as_entry(&bytes).rejection(reject::QUOTA_EXCEEDED, Some(9u64.into()));
+        let reply = bytes_as_entry(&reply);
+        assert_eq!(reply.rejection_status(), Some(reject::QUOTA_EXCEEDED));
+        assert_eq!(reply.id(), &id);
+        assert_eq!(reply.locs(), &[(9u64, 0).into(), (2u64, 0).into()]);
+    }
+
     #[test]
     fn packet_sanity_check() {
         let id = Uuid::new_v4();
@@ -1162,7 +1472,9 @@ mod test {
 
         let mut cannonical = Vec::new();
         cannonical.extend_from_slice(id.as_bytes());
-        unsafe { cannonical.push(mem::transmute(flag)) };
+        let mut flag_bytes = [0u8; 2];
+        LittleEndian::write_u16(&mut flag_bytes, flag.bits());
+        cannonical.extend_from_slice(&flag_bytes);
         cannonical.extend_from_slice(&data_bytes);
         cannonical.extend_from_slice(&num_deps);
         cannonical.extend_from_slice(&cols);
@@ -0,0 +1,190 @@
+//! Per-color access control.
+//!
+//! Clients authenticate during the handshake by sending `hello::Credentials`,
+//! which bind their connection to a principal; peers which send none act as
+//! `ANONYMOUS`. Before a request reaches an ordering thread, or a read is
+//! served, the worker which received it checks that the principal has the
+//! needed permission on every color the request touches. Requests which are
+//! not allowed are bounced back as `EntryKind::Rejected` with `reject::PERMISSION_DENIED`.
+//!
+//! A multiappend is checked against all of its colors, not only those stored
+//! locally, so as long as every server is given the same `AccessControl` they
+//! all come to the same decision, and none is left waiting on a skeens round
+//! another one refused.
+//!
+//! A chain's servers forward a client's credentials up the chain when they
+//! connect for it, so replication peers are authenticated as the client they
+//! speak for. Credentials are sent in the clear, so a chain's servers should be
+//! on a network eavesdroppers cannot reach.
+
+use hash::HashMap;
+use packets::{order, EntryKind, OrderIndex};
+use packets::hello::Credentials;
+
+pub mod permission {
+    pub const READ: u8 = 0x1;
+    pub const APPEND: u8 = 0x2;
+    /// Garbage collect the color.
+    pub const TRIM: u8 = 0x4;
+    /// Implies every other permission on the color.
+    pub const ADMIN: u8 = 0x8;
+
+    pub const NONE: u8 = 0;
+    pub const ALL: u8 = READ | APPEND | TRIM | ADMIN;
+}
+
+/// The principal of peers which did not authenticate.
+pub const ANONYMOUS: &'static str = "anonymous";
+
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    users: HashMap<String, Credentials>,
+    require_authentication: bool,
+    acls: HashMap<order, HashMap<String, u8>>,
+    default_permissions: u8,
+}
+
+impl AccessControl {
+    /// Anyone may do anything to any color,
+    /// which is how a server without access control behaves.
+    pub fn open() -> Self {
+        AccessControl {
+            users: Default::default(),
+            require_authentication: false,
+            acls: Default::default(),
+            default_permissions: permission::ALL,
+        }
+    }
+
+    /// Nobody may do anything until they are granted permissions.
+    pub fn closed() -> Self {
+        AccessControl { default_permissions: permission::NONE, ..Self::open() }
+    }
+
+    pub fn user(mut self, principal: &str, secret: &[u8]) -> Self {
+        assert!(principal != ANONYMOUS, "{:?} is reserved", ANONYMOUS);
+        self.users.insert(principal.to_owned(), Credentials::new(principal, secret));
+        self
+    }
+
+    /// Refuse connections from clients which do not send credentials.
+    pub fn require_authentication(self) -> Self {
+        AccessControl { require_authentication: true, ..self }
+    }
+
+    /// Give `principal` `permissions` on `color`.
+    /// Once a color has any grant, principals without one have no permissions on it.
+    pub fn grant(mut self, color: order, principal: &str, permissions: u8) -> Self {
+        *self.acls.entry(color).or_insert_with(Default::default)
+            .entry(principal.to_owned()).or_insert(0) |= permissions;
+        self
+    }
+
+    /// What everyone may do to colors which have no grants.
+    pub fn default_permissions(self, permissions: u8) -> Self {
+        AccessControl { default_permissions: permissions, ..self }
+    }
+
+    /// The principal of a peer which sent `credentials`,
+    /// `None` if its connection should be refused.
+    pub fn authenticate(&self, credentials: Option<&Credentials>) -> Option<String> {
+        match credentials {
+            None if self.require_authentication => None,
+            None => Some(ANONYMOUS.to_owned()),
+            Some(credentials) => {
+                // the secret is compared in constant time, even for unknown principals,
+                // so how long a refusal takes tells a peer nothing
+                let unknown = Credentials::new(ANONYMOUS, &[]);
+                let known = self.users.get(credentials.principal());
+                let matches = known.unwrap_or(&unknown).secret_matches(credentials);
+                match known {
+                    Some(..) if matches => Some(credentials.principal().to_owned()),
+                    _ => None,
+                }
+            },
+        }
+    }
+
+    pub fn permissions(&self, principal: &str, color: order) -> u8 {
+        match self.acls.get(&color) {
+            None => self.default_permissions,
+            Some(acl) => acl.get(principal).cloned().unwrap_or(permission::NONE),
+        }
+    }
+
+    pub fn allows(&self, principal: &str, color: order, needed: u8) -> bool {
+        let permissions = self.permissions(principal, color);
+        permissions & permission::ADMIN != 0 || permissions & needed == needed
+    }
+
+    /// The first color in `locs` on which `principal` lacks `needed`.
+    pub fn check(&self, principal: &str, locs: &[OrderIndex], needed: u8) -> Result<(), order> {
+        if self.acls.is_empty() && self.default_permissions == permission::ALL {
+            return Ok(())
+        }
+        for &OrderIndex(color, _) in locs {
+            // the placeholder location of a multiappend
+            if color == order::from(0) {
+                continue
+            }
+            if !self.allows(principal, color, needed) {
+                return Err(color)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The permission a client needs to send a request of `kind`,
+/// `None` for those which are not checked.
+pub fn needed_for(kind: EntryKind::Kind) -> Option<u8> {
+    use packets::EntryKind::*;
+    match kind {
+        Read | Snapshot | CitedBy => Some(permission::READ),
+        // recovery finishes another client's multiappend
+        Data | Multiput | Sentinel | UpdateRecovery | CheckSkeens1 => Some(permission::APPEND),
+        GC => Some(permission::TRIM),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::permission::*;
+
+    fn locs(colors: &[u64]) -> Vec<OrderIndex> {
+        colors.iter().map(|&c| (c, 0).into()).collect()
+    }
+
+    #[test]
+    fn open_allows_everything() {
+        let open = AccessControl::open();
+        assert_eq!(open.authenticate(None), Some(ANONYMOUS.to_owned()));
+        assert_eq!(open.check(ANONYMOUS, &locs(&[1, 2]), ALL), Ok(()));
+    }
+
+    #[test]
+    fn authentication() {
+        let access = AccessControl::closed().user("alice", b"hunter2").require_authentication();
+        assert_eq!(access.authenticate(None), None);
+        assert_eq!(access.authenticate(Some(&Credentials::new("alice", b"hunter2"))),
+            Some("alice".to_owned()));
+        assert_eq!(access.authenticate(Some(&Credentials::new("alice", b"hunter3"))), None);
+        assert_eq!(access.authenticate(Some(&Credentials::new("bob", b"hunter2"))), None);
+    }
+
+    #[test]
+    fn grants() {
+        let access = AccessControl::open()
+            .grant(3.into(), "alice", READ | APPEND)
+            .grant(3.into(), ANONYMOUS, READ)
+            .grant(5.into(), "bob", ADMIN);
+        assert_eq!(access.check("alice", &locs(&[1, 3]), APPEND), Ok(()));
+        assert_eq!(access.check(ANONYMOUS, &locs(&[3]), READ), Ok(()));
+        assert_eq!(access.check(ANONYMOUS, &locs(&[1, 3]), APPEND), Err(3.into()));
+        assert_eq!(access.check("alice", &locs(&[0, 3, 5]), READ), Err(5.into()));
+        assert_eq!(access.check("bob", &locs(&[5]), TRIM), Ok(()));
+        assert_eq!(access.check("bob", &locs(&[5, 3]), READ), Err(3.into()));
+    }
+}
@@ -0,0 +1,242 @@
+//! Limiting the work clients can queue up at a server.
+//!
+//! Before an append is handed to an ordering thread, the worker which received
+//! it checks that neither its client nor the server as a whole would have more
+//! outstanding appends, or bytes of them, than the `AdmissionControl` allows.
+//! An append is outstanding from then until its ordering thread starts on it.
+//! Appends which are not admitted are bounced back as `EntryKind::Rejected`
+//! with `reject::RETRY_LATER`, and the client sends them again after a backoff.
+//!
+//! An append is always admitted if its client, or the server, has nothing
+//! outstanding, so one bigger than a byte limit is not refused forever.
+//! Only new appends are limited; the second round of a multiappend, reads,
+//! and the traffic between replicas always get through, since refusing them
+//! would only hold up work which was already admitted.
+//!
+//! Ordering threads take the work queued for them a client at a time, see
+//! `FairQueue`, so a client with many outstanding appends does not delay the
+//! others by more than one append each.
+
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use hash::HashMap;
+use packets::{EntryFlag, EntryLayout, Packet};
+use socket_addr::Ipv4SocketAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AdmissionControl {
+    max_appends_per_client: Option<u64>,
+    max_bytes_per_client: Option<u64>,
+    max_appends: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl AdmissionControl {
+    /// Admit everything,
+    /// which is how a server without admission control behaves.
+    pub fn unlimited() -> Self {
+        Default::default()
+    }
+
+    pub fn max_appends_per_client(self, max: u64) -> Self {
+        AdmissionControl { max_appends_per_client: Some(max), ..self }
+    }
+
+    pub fn max_bytes_per_client(self, max: u64) -> Self {
+        AdmissionControl { max_bytes_per_client: Some(max), ..self }
+    }
+
+    /// The most appends outstanding at the server, from all clients together.
+    pub fn max_appends(self, max: u64) -> Self {
+        AdmissionControl { max_appends: Some(max), ..self }
+    }
+
+    pub fn max_bytes(self, max: u64) -> Self {
+        AdmissionControl { max_bytes: Some(max), ..self }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::unlimited()
+    }
+}
+
+/// The appends outstanding at a server, shared by its workers and ordering threads.
+pub struct Admission {
+    control: AdmissionControl,
+    outstanding: Mutex<Outstanding>,
+}
+
+#[derive(Default)]
+struct Outstanding {
+    total: Usage,
+    per_client: HashMap<Ipv4SocketAddr, Usage>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    appends: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    fn admits(&self, bytes: u64, max_appends: Option<u64>, max_bytes: Option<u64>) -> bool {
+        if self.appends == 0 {
+            return true
+        }
+        max_appends.map_or(true, |max| self.appends + 1 <= max)
+            && max_bytes.map_or(true, |max| self.bytes + bytes <= max)
+    }
+}
+
+impl Admission {
+    pub fn new(control: AdmissionControl) -> Self {
+        Admission { control: control, outstanding: Default::default() }
+    }
+
+    /// Whether `packet`, a request from a client, is limited at all.
+    pub fn counts(&self, packet: Packet::Ref) -> bool {
+        if self.control.is_unlimited() {
+            return false
+        }
+        let flag = *packet.flag();
+        match packet.layout() {
+            EntryLayout::Data | EntryLayout::Multiput | EntryLayout::Sentinel =>
+                !flag.contains(EntryFlag::Unlock) && !flag.contains(EntryFlag::DirectWrite),
+            _ => false,
+        }
+    }
+
+    /// Try to start an append of `bytes` from `client`,
+    /// which must be `finished` if it is admitted.
+    pub fn try_admit(&self, client: Ipv4SocketAddr, bytes: u64) -> bool {
+        let c = &self.control;
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let outstanding = &mut *outstanding;
+        let client_usage = outstanding.per_client.entry(client).or_insert_with(Usage::default);
+        let admitted = client_usage.admits(bytes, c.max_appends_per_client, c.max_bytes_per_client)
+            && outstanding.total.admits(bytes, c.max_appends, c.max_bytes);
+        if admitted {
+            client_usage.appends += 1;
+            client_usage.bytes += bytes;
+            outstanding.total.appends += 1;
+            outstanding.total.bytes += bytes;
+        } else if client_usage.appends == 0 {
+            outstanding.per_client.remove(&client);
+        }
+        admitted
+    }
+
+    pub fn finished(&self, client: Ipv4SocketAddr, bytes: u64) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let done = match outstanding.per_client.get_mut(&client) {
+            None => return,
+            Some(usage) => {
+                usage.appends = usage.appends.saturating_sub(1);
+                usage.bytes = usage.bytes.saturating_sub(bytes);
+                usage.appends == 0
+            },
+        };
+        if done {
+            outstanding.per_client.remove(&client);
+        }
+        outstanding.total.appends = outstanding.total.appends.saturating_sub(1);
+        outstanding.total.bytes = outstanding.total.bytes.saturating_sub(bytes);
+    }
+}
+
+/// A queue which takes turns between the keys its values were pushed with,
+/// and is first in first out for each key.
+pub struct FairQueue<K: Hash + Eq + Clone, T> {
+    queues: HashMap<K, VecDeque<T>>,
+    turns: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, T> FairQueue<K, T> {
+    pub fn new() -> Self {
+        FairQueue { queues: Default::default(), turns: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, key: K, val: T) {
+        let turns = &mut self.turns;
+        self.queues.entry(key.clone()).or_insert_with(|| {
+            turns.push_back(key);
+            VecDeque::new()
+        }).push_back(val)
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let key = match self.turns.pop_front() {
+            None => return None,
+            Some(key) => key,
+        };
+        let (val, is_empty) = {
+            let queue = self.queues.get_mut(&key).expect("turn without a queue");
+            (queue.pop_front(), queue.is_empty())
+        };
+        if is_empty {
+            self.queues.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        val
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(port: u64) -> Ipv4SocketAddr {
+        Ipv4SocketAddr::from_u64(port)
+    }
+
+    #[test]
+    fn per_client() {
+        let admission = Admission::new(
+            AdmissionControl::unlimited().max_appends_per_client(2).max_bytes_per_client(100));
+        assert!(admission.try_admit(client(1), 10));
+        assert!(admission.try_admit(client(1), 10));
+        assert!(!admission.try_admit(client(1), 10));
+        assert!(admission.try_admit(client(2), 10));
+        admission.finished(client(1), 10);
+        assert!(!admission.try_admit(client(1), 91));
+        assert!(admission.try_admit(client(1), 90));
+        admission.finished(client(1), 10);
+        admission.finished(client(1), 90);
+        // a client with nothing outstanding is always admitted
+        assert!(admission.try_admit(client(1), 1000));
+    }
+
+    #[test]
+    fn global() {
+        let admission = Admission::new(AdmissionControl::unlimited().max_appends(2));
+        assert!(admission.try_admit(client(1), 10));
+        assert!(admission.try_admit(client(2), 10));
+        assert!(!admission.try_admit(client(3), 10));
+        admission.finished(client(1), 10);
+        assert!(admission.try_admit(client(3), 10));
+    }
+
+    #[test]
+    fn fair_queue() {
+        let mut queue = FairQueue::new();
+        for i in 0..4 {
+            queue.push('a', i);
+        }
+        queue.push('b', 10);
+        queue.push('c', 20);
+        queue.push('b', 11);
+        let mut order = vec![];
+        while let Some(i) = queue.pop() {
+            order.push(i)
+        }
+        assert_eq!(order, vec![0, 10, 20, 1, 11, 2, 3]);
+        assert!(queue.is_empty());
+    }
+}
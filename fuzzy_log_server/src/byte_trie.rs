@@ -194,14 +194,53 @@ impl Trie  {
 
         let mut next = 0;
         let root = &mut self.root.storage_root;
+        let free_front_blocks = &mut self.root.free_front_blocks;
         walk!(1 slotv in root {
             if next >= num_blocks {
                 return
             }
             let slotv: &mut ValEdge = slotv;
-            *slotv = None;
-            next += 1
+            // blocks may already have been freed by free_blocks_before
+            if slotv.is_some() {
+                *slotv = None;
+                *free_front_blocks += 1;
+                next += 1
+            }
+        });
+    }
+
+    /// Free every block before the one holding `ptr`,
+    /// returns how many were freed, `None` if no block holds `ptr`.
+    pub fn free_blocks_before(&mut self, ptr: *const u8) -> Option<u64> {
+        let holds = |val: &ValEdge| val.as_ref().map_or(false, |val| {
+            let start = val.as_ptr() as usize;
+            start <= ptr as usize && (ptr as usize) < start + LEVEL_BYTES
         });
+        if !self.vals_mut().any(|val| holds(&*val)) {
+            return None
+        }
+        let mut freed = 0;
+        for val in self.vals_mut().take_while(|val| !holds(&**val)) {
+            if val.is_some() {
+                *val = None;
+                freed += 1;
+            }
+        }
+        self.root.free_front_blocks += freed;
+        Some(freed)
+    }
+
+    /// The bytes of the blocks which have not been freed.
+    pub fn resident_bytes(&self) -> u64 {
+        self.root.stored_bytes.saturating_sub(self.root.free_front_blocks * LEVEL_BYTES as u64)
+    }
+
+    fn vals_mut<'s>(&'s mut self) -> Box<Iterator<Item=&'s mut ValEdge> + 's> {
+        Box::new(self.root.storage_root.iter_mut().flat_map(|l0| l0.iter_mut())
+            .flat_map(|l1| l1.iter_mut().flat_map(|l1| l1.iter_mut()))
+            .flat_map(|l2| l2.iter_mut().flat_map(|l2| l2.iter_mut()))
+            .flat_map(|l3| l3.iter_mut().flat_map(|l3| l3.iter_mut()))
+            .flat_map(|l4| l4.iter_mut().flat_map(|l4| l4.iter_mut())))
     }
 }
 
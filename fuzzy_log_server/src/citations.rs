@@ -0,0 +1,123 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hash::{HashMap, UuidHashMap};
+use packets::{entry, order, OrderIndex, Uuid};
+
+/// A reverse index of the `deps` of the entries stored at this server;
+/// for each entry that has been cited, the locations of the entries citing it.
+///
+/// The index is shared by all of a server's ordering threads,
+/// appends without deps never touch the lock.
+///
+/// It holds one location for each dep of each entry stored here, so it is bounded
+/// only by the log itself: it shrinks when GC or retention trims a chain,
+/// and a server which does neither keeps every citation in memory.
+/// Replicas build the same index from the entries they store,
+/// so it is already in place when one of them takes over as the head.
+#[derive(Default)]
+pub struct CitationIndex {
+    inner: Mutex<Inner>,
+    num_pending: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Inner {
+    cited_by: HashMap<OrderIndex, Vec<OrderIndex>>,
+    // the deps of appends which are still waiting for skeens to give them a location,
+    // along with the number of chains at this server they are waiting in
+    pending: UuidHashMap<(Box<[OrderIndex]>, usize)>,
+}
+
+impl CitationIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_citations(&self, citing: OrderIndex, deps: &[OrderIndex]) {
+        if deps.is_empty() {
+            return
+        }
+        cite(&mut self.inner.lock().unwrap().cited_by, citing, deps)
+    }
+
+    pub fn add_pending(&self, id: Uuid, deps: &[OrderIndex], num_chains: usize) {
+        if deps.is_empty() || num_chains == 0 {
+            return
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let num_pending = &self.num_pending;
+        //a repeated skeens-1 must not reset the count
+        inner.pending.entry(id).or_insert_with(|| {
+            num_pending.fetch_add(1, Ordering::Release);
+            (deps.to_vec().into_boxed_slice(), num_chains)
+        });
+    }
+
+    /// A pending append has been given its location in one of its chains,
+    /// `None` if it is only a sentinel in that chain.
+    pub fn finished_at(&self, id: &Uuid, citing: Option<OrderIndex>) {
+        if self.num_pending.load(Ordering::Acquire) == 0 {
+            return
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let done = match inner.pending.get_mut(id) {
+            None => return,
+            Some(&mut (ref deps, ref mut remaining)) => {
+                if let Some(citing) = citing {
+                    cite(&mut inner.cited_by, citing, deps)
+                }
+                *remaining -= 1;
+                *remaining == 0
+            },
+        };
+        if done {
+            inner.pending.remove(id);
+            self.num_pending.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    pub fn cited_by(&self, loc: OrderIndex) -> Vec<OrderIndex> {
+        self.inner.lock().unwrap().cited_by.get(&loc).cloned().unwrap_or_else(Vec::new)
+    }
+
+    /// The first entry of `chain` in `from..to` which is cited by an append still waiting
+    /// for its location, or by an entry for which `is_live` is true, `to` if there is none.
+    pub fn first_needed<F>(&self, chain: order, from: u64, to: u64, mut is_live: F) -> u64
+    where F: FnMut(OrderIndex) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let in_range = |&OrderIndex(o, i): &OrderIndex|
+            o == chain && u64::from(i) >= from && u64::from(i) < to;
+        let pending = inner.pending.values()
+            .flat_map(|&(ref deps, _)| deps.iter())
+            .filter(|l| in_range(*l))
+            .map(|l| u64::from(l.1));
+        let cited = inner.cited_by.iter()
+            .filter(|&(l, _)| in_range(l))
+            .filter(|&(_, citing)| citing.iter().any(|&c| is_live(c)))
+            .map(|(l, _)| u64::from(l.1));
+        pending.chain(cited).min().unwrap_or(to)
+    }
+
+    /// Drop the citations of the entries of `chain` before `min`.
+    pub fn forget_before(&self, chain: order, min: u64) {
+        self.inner.lock().unwrap().cited_by.retain(|&OrderIndex(o, i), _|
+            o != chain || u64::from(i) >= min)
+    }
+}
+
+fn cite(
+    cited_by: &mut HashMap<OrderIndex, Vec<OrderIndex>>,
+    citing: OrderIndex,
+    deps: &[OrderIndex],
+) {
+    for &dep in deps {
+        //an empty read of a color, eg. in a transaction's read set, cites nothing
+        if dep.1 == entry::from(0) {
+            continue
+        }
+        trace!("SERVER {:?} cites {:?}", citing, dep);
+        cited_by.entry(dep).or_insert_with(Vec::new).push(citing)
+    }
+}
@@ -6,6 +6,7 @@ extern crate byteorder;
 extern crate deque;
 extern crate evmap;
 extern crate lazycell;
+extern crate libc;
 extern crate mio;
 extern crate uuid;
 extern crate reactor;
@@ -51,13 +52,24 @@ use self::trie::ValEdge;
 
 use self::shared_slice::RcSlice;
 
+use self::citations::CitationIndex;
+use self::shards::{Shard, ShardMap, Shards};
+
 pub mod tcp;
 // pub mod udp;
 
+pub mod access;
+pub mod admission;
+pub mod quota;
+pub mod retention;
+pub mod tiered;
+
 pub mod spmc;
 pub mod spsc;
 
 mod skeens;
+mod citations;
+pub mod shards;
 //TODO remove `pub`, it only exists for testing purposes
 pub mod trie;
 pub mod byte_trie;
@@ -76,13 +88,22 @@ pub type ChainReader<T> = ReadHandle<order, TrivialEqArc<Chain<T>>>;
 
 pub struct ServerLog<T: Send + Sync + Copy, ToWorkers>
 where ToWorkers: DistributeToWorkers<T> {
-    log: ChainStore<T>,
-    //TODO per chain locks...
+    log: Shard<T>,
     total_servers: u32,
     this_server_num: u32,
     // seen_ids: hash::UuidHashSet,
     pub to_workers: ToWorkers, //spmc::Sender<ToWorker<T>>,
     _pd: PhantomData<T>,
+    citations: Arc<CitationIndex>,
+    quotas: Option<Arc<quota::QuotaEnforcer>>,
+    // trims made by retention which the replicas have not been sent yet
+    unreplicated_trims: Vec<OrderIndex>,
+    // the colors read by transactions between their skeens rounds, see vote_on_transaction
+    read_locks: hash::UuidHashMap<Vec<order>>,
+    // appends to colors read by such transactions, waiting for the transactions' round 2
+    waiting_for_reads: VecDeque<(
+        BufferSlice, Troption<SkeensMultiStorage, Box<(RcSlice, RcSlice)>>, T
+    )>,
 
     print_data: LogData,
 }
@@ -102,6 +123,8 @@ counters! {
 pub struct Chain<T: Copy> {
     trie: Trie,
     skeens: SkeensState<T>,
+    // the transactions between their skeens rounds which read this chain
+    readers: u32,
 }
 
 unsafe impl<T: Copy> Sync for Chain<T> {}
@@ -1,6 +1,9 @@
 use super::*;
 use super::shared_slice::RcSlice;
 
+use std::cmp::max;
+use std::time::Instant;
+
 use skeens::{
     SkeensState,
     SkeensAppendRes,
@@ -11,6 +14,9 @@ use skeens::{
     QueueIndex,
 };
 use trie::{ByteLoc, Trie, ValEdge};
+use quota::QuotaEnforcer;
+use retention::Retainer;
+use tiered::Spiller;
 
 
 impl<T: Copy> Chain<T> {
@@ -83,38 +89,53 @@ impl<T: Copy> Chain<T> {
         }
     }
 
+    // Returns false if the multi already got its max timestamp,
+    // eg. because both its client and a recoverer sent skeens-2,
+    // in which case the sender of this skeens-2 is not answered by the flush.
     fn finish_multi<F>(
-        &mut self, id: Uuid, max_timestamp: u64, chain: order, mut on_finish: F)
-    where F: FnMut(FinishSkeens<T>) { //Ret val?
+        &mut self, id: Uuid, max_timestamp: u64, chain: order, mut on_finish: F) -> bool
+    where F: FnMut(FinishSkeens<T>) {
         use self::FinishSkeens::*;
         let r = self.skeens.set_max_timestamp(id, max_timestamp);
         match r {
             SkeensSetMaxRes::Ok => trace!("multi with ts {:?} must wait", max_timestamp),
-            SkeensSetMaxRes::Duplicate(_ts) => unimplemented!(),
-            SkeensSetMaxRes::NotWaiting => unimplemented!(),
+            SkeensSetMaxRes::Duplicate(_ts) => {
+                trace!("duplicate skeens-2 for {:?} @ {:?}", id, max_timestamp);
+                return false
+            },
+            //the multi was already flushed
+            SkeensSetMaxRes::NotWaiting => {
+                trace!("late skeens-2 for {:?} @ {:?}", id, max_timestamp);
+                return false
+            },
             SkeensSetMaxRes::NeedsFlush => {
                 trace!("multi flush due to {:?}", max_timestamp);
                 let trie = &mut self.trie;
                 self.skeens.flush_got_max_timestamp(|g| {
                     match g {
-                        GotMax::SimpleSingle{storage, t, timestamp, ..}
-                        | GotMax::Single{storage, t, timestamp, ..} => unsafe {
+                        GotMax::SimpleSingle{storage, t, id, timestamp, ..}
+                        | GotMax::Single{storage, t, id, timestamp, ..} => unsafe {
                             trace!("flush single {:?}", timestamp);
                             let (loc, ptr) = trie.prep_append(ValEdge::null());
                             //println!("s id {:?} ts {:?}", id, timestamp);
-                            on_finish(Single(loc, ptr, storage, timestamp, t));
+                            on_finish(Single(id, loc, ptr, storage, timestamp, t));
                         },
                         GotMax::Multi{storage, t, id, timestamp, ..} => unsafe {
                             trace!("flush multi {:?}: {:?}", id, timestamp);
                             //println!("m id {:?} ts {:?}", id, timestamp);
-                            let (loc, ptr) = trie.prep_append(ValEdge::null());
-                            on_finish(Multi(loc, ptr, storage, timestamp, t));
+                            //an aborted transaction finishes skeens without a slot
+                            let (loc, ptr) = if is_aborted(&storage) {
+                                (trie.horizon(), ptr::null_mut())
+                            } else {
+                                trie.prep_append(ValEdge::null())
+                            };
+                            on_finish(Multi(id, loc, ptr, storage, timestamp, t));
                         },
                         GotMax::Senti{storage, t, id, timestamp, ..} => {
                             trace!("flush senti {:?}: {:?}", id, timestamp);
                             let loc = horizon_or_add_blank(trie, chain);
                             on_finish(Multi(
-                                loc, ptr::null_mut(), storage, timestamp, t)
+                                id, loc, ptr::null_mut(), storage, timestamp, t)
                             );
                         },
                         GotMax::Snap{storage, t, id, timestamp, ..} => {
@@ -128,6 +149,7 @@ impl<T: Copy> Chain<T> {
                 })
             }
         }
+        true
     }
 }
 
@@ -156,35 +178,40 @@ fn horizon_or_add_blank(trie: &mut Trie, chain: order) -> u64 {
     trie.horizon()
 }
 
-//SAFETY: log is single writer and Chain refs never escape this thread
-fn ensure_chain<T: Copy>(log: &mut ChainStore<T>, chain: order) -> &mut Chain<T> {
-    let c = log.get_and(&chain, |chains| unsafe { &mut *UnsafeCell::get(&chains[0]) });
-    if let Some(chain) = c {
-        return chain
-    }
-
-    let mut t = Trie::new();
-    //FIXME remove for GC
-    unsafe {
-        t.partial_append(1).write_byte(mem::transmute(EntryKind::Read));
-    };
-    let contents = TrivialEqArc::new(Chain{ trie: t, skeens: SkeensState::new()});
-    log.insert(chain, contents);
-    log.refresh();
-    get_chain_mut(log, chain).unwrap()
+fn is_aborted(storage: &SkeensMultiStorage) -> bool {
+    let (_, _, st0, _) = unsafe { storage.get() };
+    bytes_as_entry(st0).flag().contains(EntryFlag::Aborted)
 }
 
-fn get_chain_mut<T: Copy>(log: &mut ChainStore<T>, chain: order) -> Option<&mut Chain<T>> {
-    log.get_and(&chain, |chains| unsafe { &mut *UnsafeCell::get(&chains[0]) })
+fn mark_aborted(storage: &SkeensMultiStorage) {
+    let (_, _, st0, _) = unsafe { storage.get_mut() };
+    bytes_as_entry_mut(st0).flag_mut().insert(EntryFlag::Aborted)
 }
 
-fn get_chain<T: Copy>(log: &ChainStore<T>, chain: order) -> Option<&Chain<T>> {
-    log.get_and(&chain, |chains| unsafe { &*UnsafeCell::get(&chains[0]) })
+fn recovery_entry(storage: &SkeensMultiStorage) -> Option<Buffer> {
+    unsafe {
+        let (timestamps, _, st0, _) = storage.get();
+        //FIXME the worker fills in the storage after we assign timestamps,
+        //      so a very new multi may not be there yet
+        if EntryContents::try_ref(&st0[..]).is_err() {
+            return None
+        }
+        let mut recovered = Buffer::wrap_vec(st0.to_vec());
+        {
+            let mut e = recovered.contents_mut();
+            e.flag_mut().remove(EntryFlag::ReadSuccess);
+            let locs = e.locs_mut();
+            for (loc, &ts) in locs.iter_mut().zip(timestamps.iter()) {
+                loc.1 = entry::from(ts)
+            }
+        }
+        Some(recovered)
+    }
 }
 
 enum FinishSkeens<T> {
-    Single(u64, *mut ValEdge, ValEdge, u64, T),
-    Multi(u64, *mut ValEdge, SkeensMultiStorage, u64, T),
+    Single(Uuid, u64, *mut ValEdge, ValEdge, u64, T),
+    Multi(Uuid, u64, *mut ValEdge, SkeensMultiStorage, u64, T),
     Snap(u64, SkeensMultiStorage, u64, T),
 }
 
@@ -202,17 +229,33 @@ where ToWorkers: DistributeToWorkers<T> {
         assert!(this_server_num <= total_servers,
             "this_server_num <= total_servers, {:?} <= {:?}",
             this_server_num, total_servers);
+        let map = ShardMap::new(this_server_num, total_servers, 1);
+        Self::new_shard(to_workers, Arc::new(Shards::new(map, chains)), 0)
+    }
+
+    /// The ordering thread of `shard`, one of several sharing a server's chains.
+    pub fn new_shard(to_workers: ToWorkers, shards: Arc<Shards<T>>, shard: usize) -> Self {
         ServerLog {
-            log: chains,
             // seen_ids: Default::default(),
-            this_server_num: this_server_num,
-            total_servers: total_servers,
+            this_server_num: shards.map().this_server_num(),
+            total_servers: shards.map().total_servers(),
             to_workers: to_workers,
             _pd: PhantomData,
+            citations: shards.citations().clone(),
+            quotas: None,
+            unreplicated_trims: Vec::new(),
+            read_locks: Default::default(),
+            waiting_for_reads: VecDeque::new(),
+            log: Shard::new(shards, shard),
             print_data: Default::default(),
         }
     }
 
+    /// Tell `quotas` what is trimmed from the chains, so stored bytes are freed.
+    pub fn set_quotas(&mut self, quotas: Arc<QuotaEnforcer>) {
+        self.quotas = Some(quotas)
+    }
+
     #[cfg(feature = "print_stats")]
     pub fn print_stats(&self) {
         println!("{:?}, {:?}", self.print_data, self.this_server_num);
@@ -223,11 +266,27 @@ where ToWorkers: DistributeToWorkers<T> {
     //TODO replace T with U and T: ReturnAs<U> so we don't have to send as much data
     pub fn handle_op(
         &mut self,
-        mut buffer: BufferSlice,
+        buffer: BufferSlice,
         storage: Troption<SkeensMultiStorage, Box<(RcSlice, RcSlice)>>,
         t: T
     ) {
         self.print_data.msgs_recvd(1);
+        self.replicate_trims(t);
+        self.order_op(buffer, storage, t)
+    }
+
+    fn order_op(
+        &mut self,
+        mut buffer: BufferSlice,
+        storage: Troption<SkeensMultiStorage, Box<(RcSlice, RcSlice)>>,
+        t: T
+    ) {
+        if self.waits_for_reads(&buffer) {
+            trace!("SERVER {:?} append waits for transactions reading {:?}",
+                self.this_server_num, buffer.contents().locs());
+            self.waiting_for_reads.push_back((buffer, storage, t));
+            return
+        }
         let (kind, flag) = {
             let c = buffer.contents();
             (c.kind(), *c.flag())
@@ -250,7 +309,7 @@ where ToWorkers: DistributeToWorkers<T> {
                 let OrderIndex(chain, index) = buffer.contents().locs()[0];
                 //TODO validate lock
                 //     this will come after per-chain locks
-                match get_chain(&self.log, chain) {
+                match self.log.get(chain) {
                     None => {
                         trace!("SERVER {:?} Read Vacant chain {:?}",
                             self.this_server_num, chain);
@@ -321,10 +380,20 @@ where ToWorkers: DistributeToWorkers<T> {
                 };
                 match send {
                     SingleAppendKind::Regular(slot) => {
+                        {
+                            let contents = buffer.contents();
+                            self.citations.add_citations(
+                                contents.locs()[0], contents.dependencies());
+                        }
                         self.print_data.msgs_sent(1);
                         self.to_workers.send_to_worker(Write(buffer, slot, t))
                     }
                     SingleAppendKind::Skeens((slot, storage_loc, time, queue_num)) => {
+                        {
+                            let contents = buffer.contents();
+                            self.citations.add_pending(
+                                *contents.id(), contents.dependencies(), 1);
+                        }
                         self.print_data.msgs_sent(1);
                         let msg = SingleSkeens {
                             buffer: buffer,
@@ -391,7 +460,7 @@ where ToWorkers: DistributeToWorkers<T> {
                     continue
                 }
 
-                let horizon = get_chain(&self.log, o).map(|c| c.trie.horizon()).unwrap_or(0);
+                let horizon = self.log.get(o).map(|c| c.trie.horizon()).unwrap_or(0);
                 *i = entry::from(horizon as u64);
             }
         }
@@ -412,9 +481,7 @@ where ToWorkers: DistributeToWorkers<T> {
         assert!(kind.contains(EntryFlag::TakeLock));
         trace!("SERVER {:?} new-style multisnap {:?}", self.this_server_num, kind);
         if kind.contains(EntryFlag::Unlock) {
-            self.new_multiappend_round2(kind, &mut buffer);
-            self.print_data.msgs_sent(1);
-            self.to_workers.send_to_worker(ReturnBuffer(buffer, t))
+            self.finish_round2(kind, buffer, t)
         } else {
             let storage = storage.unwrap_left();
             self.skeens_snapshot_round1(kind, &mut buffer, &storage, t);
@@ -481,10 +548,27 @@ where ToWorkers: DistributeToWorkers<T> {
     fn handle_single_server_append(
         &mut self,
         kind: EntryFlag::Flag,
-        buffer: BufferSlice,
+        mut buffer: BufferSlice,
         storage: Troption<SkeensMultiStorage, Box<(RcSlice, RcSlice)>>,
         t: T
     ) {
+        if kind.contains(EntryFlag::Transaction) {
+            //every read of the transaction is stored here,
+            //so it can be refused before it is given any location
+            let stale = {
+                let deps = buffer.contents().dependencies();
+                self.stale_read(deps).map(|i| deps[i])
+            };
+            if let Some(OrderIndex(o, horizon)) = stale {
+                trace!("SERVER {:?} abort transaction, stale read of {:?} @ {:?}",
+                    self.this_server_num, o, horizon);
+                buffer.abort(OrderIndex(o, horizon + 1));
+                self.print_data.msgs_sent(1);
+                self.to_workers.send_to_worker(Reply(buffer, t));
+                return
+            }
+        }
+
         let (mut needs_lock, mut needs_skeens) = (false, false);
         {
             for &OrderIndex(c, _) in buffer.contents().locs() {
@@ -549,6 +633,15 @@ where ToWorkers: DistributeToWorkers<T> {
                 }
             }
         }
+        {
+            let contents = buffer.contents();
+            let deps = contents.dependencies();
+            for &loc in contents.locs() {
+                if loc.0 == order::from(0) { break }
+                if loc.1 == entry::from(0) { continue }
+                self.citations.add_citations(loc, deps);
+            }
+        }
 
         self.print_data.msgs_sent(1);
         self.to_workers.send_to_worker(MultiFastPath(buffer, storage, t))
@@ -619,11 +712,21 @@ where ToWorkers: DistributeToWorkers<T> {
         trace!("SERVER {:?} new-style multiput {:?}", self.this_server_num, kind);
         assert!(kind.contains(EntryFlag::TakeLock));
         if kind.contains(EntryFlag::Unlock) {
-            self.new_multiappend_round2(kind, &mut buffer);
-            self.print_data.msgs_sent(1);
-            self.to_workers.send_to_worker(ReturnBuffer(buffer, t))
+            if kind.contains(EntryFlag::Transaction) {
+                let (id, max_timestamp) = {
+                    let contents = buffer.contents();
+                    (*contents.id(), contents.lock_num())
+                };
+                self.finish_round2(kind, buffer, t);
+                self.release_reads(&id, max_timestamp)
+            } else {
+                self.finish_round2(kind, buffer, t)
+            }
         } else {
             let storage = storage.unwrap_left();
+            if kind.contains(EntryFlag::Transaction) {
+                self.vote_on_transaction(&mut buffer)
+            }
             self.new_multiappend_round1(kind, &mut buffer, &storage, false, t);
             self.print_data.msgs_sent(1);
             self.to_workers.send_to_worker(
@@ -632,6 +735,142 @@ where ToWorkers: DistributeToWorkers<T> {
         }
     }
 
+    // The other servers may already have queued a multi-server transaction,
+    // so instead of refusing it this server votes to abort,
+    // and the client carries the vote to every server in round 2.
+    // A server which only stores reads of the transaction votes without queuing it.
+    // If it votes to commit, its reads stay locked until round 2, see waits_for_reads.
+    //FIXME if the client dies between the rounds the reads stay locked until
+    //      someone blocked on one of the transaction's writes recovers it
+    // A transaction which writes a color another one has locked votes to abort,
+    // waiting could deadlock, as it may hold locks of its own at other servers.
+    fn vote_on_transaction(&mut self, buffer: &mut BufferSlice) {
+        let id = *buffer.contents().id();
+        //a repeated skeens-1 keeps the locks it already has
+        if self.read_locks.contains_key(&id) {
+            return
+        }
+        let conflict = {
+            let contents = buffer.contents();
+            let deps = contents.dependencies();
+            self.stale_read(deps)
+                .or_else(|| self.locked_write(contents.locs()).map(|i| deps.len() + i))
+        };
+        if let Some(i) = conflict {
+            trace!("SERVER {:?} vote to abort transaction {:?}",
+                self.this_server_num, id);
+            let mut contents = buffer.contents_mut();
+            contents.flag_mut().insert(EntryFlag::Aborted);
+            //the lock is unused in round 1, it tells the client what conflicted,
+            //the position of the stale read, or of the locked write after the reads
+            *contents.lock_mut() = i as u64;
+            return
+        }
+        let reads: Vec<_> = buffer.contents().dependencies().iter()
+            .map(|&OrderIndex(o, _)| o)
+            .filter(|&o| o != order::from(0) && self.stores_chain(o))
+            .collect();
+        if reads.is_empty() {
+            return
+        }
+        for &o in &reads {
+            self.ensure_chain(o).readers += 1;
+        }
+        self.read_locks.insert(id, reads);
+    }
+
+    // An append to a color read by a transaction which is between its skeens rounds
+    // could be ordered before the transaction, which would commit on a stale read,
+    // so it waits for the transaction's round 2.
+    // Round 2 of other multiappends must not wait, they may be what the transaction is
+    // waiting on, nor must multi-server transactions, see vote_on_transaction.
+    fn waits_for_reads(&mut self, buffer: &BufferSlice) -> bool {
+        let contents = buffer.contents();
+        let flag = *contents.flag();
+        let appends = match contents.kind().layout() {
+            EntryLayout::Data => true,
+            EntryLayout::Multiput | EntryLayout::Sentinel =>
+                !flag.contains(EntryFlag::Unlock)
+                && !flag.contains(EntryFlag::TakeLock | EntryFlag::Transaction),
+            _ => false,
+        };
+        appends && contents.locs().iter().any(|&OrderIndex(o, _)|
+            o != order::from(0) && self.stores_chain(o)
+            && self.log.get(o).map_or(false, |chain| chain.readers > 0)
+        )
+    }
+
+    // Round 2 of a transaction unlocks its reads.
+    // Everything that waited on them is ordered after the transaction,
+    // so the colors' next timestamps must be later than its max timestamp.
+    // The appends which waited here are retried if this thread holds their locks,
+    // the rest, and those waiting on other ordering threads,
+    // by retry_waiting_for_reads.
+    fn release_reads(&mut self, id: &Uuid, max_timestamp: u64) {
+        let reads = match self.read_locks.remove(id) {
+            None => return,
+            Some(reads) => reads,
+        };
+        trace!("SERVER {:?} transaction {:?} unlocked {:?}",
+            self.this_server_num, id, reads);
+        for o in reads {
+            let chain = self.ensure_chain(o);
+            chain.readers -= 1;
+            chain.skeens.order_after(max_timestamp);
+        }
+        let waiting = mem::replace(&mut self.waiting_for_reads, VecDeque::new());
+        for (buffer, storage, t) in waiting {
+            if self.log.holds(buffer.contents()) {
+                self.order_op(buffer, storage, t)
+            } else {
+                self.waiting_for_reads.push_back((buffer, storage, t))
+            }
+        }
+    }
+
+    /// Whether some appends are waiting for transactions to unlock the colors they read.
+    pub fn has_waiting_for_reads(&self) -> bool {
+        !self.waiting_for_reads.is_empty()
+    }
+
+    /// Retry the appends waiting for transactions' reads,
+    /// `lock` takes the shard locks each one needs.
+    pub fn retry_waiting_for_reads<L, G>(&mut self, mut lock: L)
+    where L: FnMut(Packet::Ref) -> G {
+        let waiting = mem::replace(&mut self.waiting_for_reads, VecDeque::new());
+        for (buffer, storage, t) in waiting {
+            let _locked = lock(buffer.contents());
+            self.order_op(buffer, storage, t)
+        }
+    }
+
+    // The position of the first write of a transaction to a color
+    // which another transaction has read and locked.
+    fn locked_write(&mut self, locs: &[OrderIndex]) -> Option<usize> {
+        for (i, &OrderIndex(o, _)) in locs.iter().enumerate() {
+            if o == order::from(0) || !self.stores_chain(o) { continue }
+            if self.log.get(o).map_or(false, |chain| chain.readers > 0) {
+                return Some(i)
+            }
+        }
+        None
+    }
+
+    // The position of the first read of a transaction which is no longer current,
+    // either its color has been appended to since, or an append to it is being ordered.
+    fn stale_read(&mut self, deps: &[OrderIndex]) -> Option<usize> {
+        for (i, &OrderIndex(o, horizon)) in deps.iter().enumerate() {
+            if o == order::from(0) || !self.stores_chain(o) { continue }
+            let stale = match self.log.get(o) {
+                None => false,
+                Some(chain) =>
+                    chain.trie.horizon() > u64::from(horizon) || !chain.skeens.is_empty(),
+            };
+            if stale { return Some(i) }
+        }
+        None
+    }
+
     fn new_multiappend_round1(
         &mut self,
         kind: EntryFlag::Flag,
@@ -647,6 +886,13 @@ where ToWorkers: DistributeToWorkers<T> {
         let timestamps = &mut unsafe { storage.get_mut().0 }[..locs.len()];
         let queue_indicies = &mut unsafe { storage.get_mut().1 }[..locs.len()];
         let id = val.id().clone();
+        //every local chain, sentinel or not, will finish skeens exactly once
+        let local_chains = locs.iter()
+            .filter(|&&OrderIndex(o, _)| o != order::from(0) && self.stores_chain(o))
+            .count();
+        if val.kind().layout() == EntryLayout::Multiput {
+            self.citations.add_pending(id, val.dependencies(), local_chains);
+        }
         let mut is_sentinel = false;
         for i in 0..locs.len() {
             let chain = locs[i].0;
@@ -658,14 +904,18 @@ where ToWorkers: DistributeToWorkers<T> {
             }
 
             let chain = self.ensure_chain(chain);
-            //FIXME handle repeat skeens-1
+            //FIXME repeats of finished multis are treated as new appends
+            // a repeat skeens-1 (eg. from a recovering client) gets its old timestamp
             let (local_timestamp, num) =
-                chain.timestamp_for_multi(
+                match chain.timestamp_for_multi(
                     id,
                     storage.clone(),
                     distinguish_sentinels && is_sentinel,
                     t
-                ).unwrap();
+                ) {
+                    Ok(ts_and_num) => ts_and_num,
+                    Err(ts) => (ts, 0),
+                };
             timestamps[i] = local_timestamp;
             queue_indicies[i] = num;
         }
@@ -673,11 +923,24 @@ where ToWorkers: DistributeToWorkers<T> {
             self.this_server_num, timestamps);
     }
 
+    // The flush answers whoever sent skeens-1,
+    // a duplicate skeens-2 gets its packet back so its sender isn't left waiting.
+    fn finish_round2(&mut self, kind: EntryFlag::Flag, mut buffer: BufferSlice, t: T) {
+        let first_skeens2 = self.new_multiappend_round2(kind, &mut buffer);
+        self.print_data.msgs_sent(1);
+        if first_skeens2 {
+            self.to_workers.send_to_worker(ReturnBuffer(buffer, t))
+        } else {
+            self.to_workers.send_to_worker(Reply(buffer, t))
+        }
+    }
+
+    // Returns false if this skeens-2 is a duplicate, see finish_multi.
     fn new_multiappend_round2(
         &mut self,
         kind: EntryFlag::Flag,
         buffer: &mut BufferSlice,
-    ) {
+    ) -> bool {
         assert!(kind.contains(EntryFlag::Unlock), "Bad skeens 2 {:?}", buffer.contents());
         // In round two we flush some the queues... an possibly a partial entry...
         let mut val = buffer.contents_mut();
@@ -687,6 +950,7 @@ where ToWorkers: DistributeToWorkers<T> {
         trace!("SERVER {:?} new-style multiput Round 2 {:?} mts {:?}",
             self.this_server_num, kind, max_timestamp
         );
+        let mut first_skeens2 = true;
         for i in 0..locs.len() {
             let chain_num = locs[i].0;
             if chain_num == order::from(0) || !self.stores_chain(chain_num) {
@@ -695,8 +959,11 @@ where ToWorkers: DistributeToWorkers<T> {
             }
 
             //let chain = self.ensure_trie(chain);
-            let chain = get_chain_mut(&mut self.log, chain_num)
+            let chain = self.log.get(chain_num)
                 .expect("cannot have skeens-2 as the first op on a chain");
+            if kind.contains(EntryFlag::Aborted) {
+                chain.skeens.waiting_multi(&id).map(|storage| mark_aborted(&storage));
+            }
                 /*.or_insert_with(|| {
                 let mut t = Trie::new();
                 t.append(&EntryContents::Data(&(), &[]).clone_entry());
@@ -704,14 +971,18 @@ where ToWorkers: DistributeToWorkers<T> {
             });*/
             let to_workers = &mut self.to_workers;
             let print_data = &mut self.print_data;
-            chain.finish_multi(id, max_timestamp, chain_num,
+            let citations = &self.citations;
+            first_skeens2 &= chain.finish_multi(id, max_timestamp, chain_num,
                 |finished| match finished {
-                    FinishSkeens::Multi(index, trie_slot, storage, timestamp, t) => {
+                    FinishSkeens::Multi(id, index, trie_slot, storage, timestamp, t) => {
                         trace!("server finish sk multi");
+                        let loc = OrderIndex(chain_num, (index as u64).into());
+                        //sentinels don't get their own entry
+                        citations.finished_at(&id, if trie_slot.is_null() { None } else { Some(loc) });
                         print_data.msgs_sent(1);
                         to_workers.send_to_worker(
                             SkeensFinished {
-                                loc: OrderIndex(chain_num, (index as u64).into()),
+                                loc: loc,
                                 trie_slot: trie_slot,
                                 storage: storage,
                                 timestamp: timestamp,
@@ -733,8 +1004,9 @@ where ToWorkers: DistributeToWorkers<T> {
                         )
                     },
 
-                    FinishSkeens::Single(index, trie_slot, storage, timestamp, t) => {
+                    FinishSkeens::Single(id, index, trie_slot, storage, timestamp, t) => {
                         trace!("server finish sk single");
+                        citations.finished_at(&id, Some(OrderIndex(chain_num, (index as u64).into())));
                         print_data.msgs_sent(1);
                         to_workers.send_to_worker(
                             DelayedSingle {
@@ -749,6 +1021,7 @@ where ToWorkers: DistributeToWorkers<T> {
                 }
             );
         }
+        first_skeens2
     }
 
     //////////////////////
@@ -979,11 +1252,10 @@ where ToWorkers: DistributeToWorkers<T> {
         chain % u64::from(self.total_servers) == u64::from(self.this_server_num).into()
     }
 
-    //Safety: since this thread is the only one that mutates the map,
-    //        as long as this thread holds a mutable refrence it is effectively immutable
-    //FIXME: uniqueness is a lie
+    //Safety: the shard of every chain an op touches is locked while the op is handled,
+    //        and Shard checks that before handing out the chain, see shards
     fn ensure_chain(&mut self, chain: order) -> &mut Chain<T> {
-        ensure_chain(&mut self.log, chain)
+        self.log.ensure(chain)
     }
 
     fn ensure_trie(&mut self, chain: order) -> &mut Trie {
@@ -1021,6 +1293,7 @@ where ToWorkers: DistributeToWorkers<T> {
                             storage_loc, size).extend_lifetime()
                     }
                 };
+                self.citations.add_citations(loc, buffer.contents().dependencies());
 
                 self.print_data.msgs_sent(1);
                 self.to_workers.send_to_worker(Write(buffer, slot, t))
@@ -1035,6 +1308,7 @@ where ToWorkers: DistributeToWorkers<T> {
                         e.lock_num())
                 };
                 trace!("SERVER {:?} replicate skeens single {:?} {}", self.this_server_num, c, ts);
+                self.citations.add_pending(id, buffer.contents().dependencies(), 1);
                 let storage;
                 {
                     let c = self.ensure_chain(c);
@@ -1062,6 +1336,15 @@ where ToWorkers: DistributeToWorkers<T> {
                     (*c.id(), *c.flag())
                 };
                 let is_single_server = !flag.contains(EntryFlag::TakeLock);
+                {
+                    let val = buffer.contents();
+                    if val.kind().layout() == EntryLayout::Multiput {
+                        let local_chains = val.locs().iter()
+                            .filter(|&&OrderIndex(o, _)| o != order::from(0) && self.stores_chain(o))
+                            .count();
+                        self.citations.add_pending(id, val.dependencies(), local_chains);
+                    }
+                }
                 let mut is_sentinel = false;
                 'sk_rep: for (&OrderIndex(o, i), &node_num) in buffer.contents().locs_and_node_nums() {
                     if o == order::from(0) || !self.stores_chain(o) {
@@ -1123,18 +1406,29 @@ where ToWorkers: DistributeToWorkers<T> {
                 trace!("SERVER {:?} replicate skeens2 max {:?}, {:?}",
                     self.this_server_num, max_timestamp, id);
                 assert!(max_timestamp > 0, "SERVER {}: replicate 0 max ts {:#?}", self.this_server_num, buffer.contents());
+                let aborted = buffer.contents().flag().contains(EntryFlag::Aborted);
                 'sk2_rep: for &OrderIndex(o, i) in buffer.contents().locs() {
                     if o == order::from(0) || !self.stores_chain(o) { continue 'sk2_rep }
                     //let c = self.ensure_chain(chain);
-                    let c = ensure_chain(&mut self.log, o);
+                    let c = self.log.ensure(o);
+                    if aborted {
+                        c.skeens.waiting_multi(&id).map(|storage| mark_aborted(&storage));
+                    }
                     let to_workers = &mut self.to_workers;
                     let print_data = &mut self.print_data;
+                    let citations = &self.citations;
                     let index = u64::from(i);
                     let trie = &mut c.trie;
                     c.skeens.replicate_round2(&id, max_timestamp, index, |rep| match rep {
                         Multi{index, storage, max_timestamp, t} => {
                             trace!("SERVER finish sk multi rep ({:?}, {:?}, {})", o, index, max_timestamp);
-                            let slot = unsafe { trie.prep_append_at(index) };
+                            let slot = if is_aborted(&storage) {
+                                citations.finished_at(&id, None);
+                                ptr::null_mut()
+                            } else {
+                                citations.finished_at(&id, Some(OrderIndex(o, (index as u64).into())));
+                                unsafe { trie.prep_append_at(index) }
+                            };
                             print_data.msgs_sent(1);
                             to_workers.send_to_worker(
                                 Skeens2MultiReplica {
@@ -1148,6 +1442,7 @@ where ToWorkers: DistributeToWorkers<T> {
                         },
                         Senti{index, storage, max_timestamp, t} => {
                             trace!("SERVER finish sk multi rep ({:?}, {:?}, {})", o, index, max_timestamp);
+                            citations.finished_at(&id, None);
                             print_data.msgs_sent(1);
                             to_workers.send_to_worker(
                                 Skeens2MultiReplica {
@@ -1175,6 +1470,7 @@ where ToWorkers: DistributeToWorkers<T> {
                             //trace!("SERVER finish sk single rep");
                             //let size = buffer.entry_size();
                             trace!("SERVER replicating single sk2 ({:?}, {:?}, {})", o, index, max_timestamp);
+                            citations.finished_at(&id, Some(OrderIndex(o, (index as u64).into())));
                             let slot = trie.prep_append_at(index);
                             print_data.msgs_sent(1);
                             to_workers.send_to_worker(
@@ -1272,6 +1568,15 @@ where ToWorkers: DistributeToWorkers<T> {
                         }
                     }
                 }
+                {
+                    let contents = buffer.contents();
+                    let deps = contents.dependencies();
+                    for &loc in contents.locs() {
+                        if loc.0 == order::from(0) { break }
+                        if !self.stores_chain(loc.0) { continue }
+                        self.citations.add_citations(loc, deps);
+                    }
+                }
 
                 self.print_data.msgs_sent(1);
                 self.to_workers.send_to_worker(
@@ -1323,12 +1628,13 @@ where ToWorkers: DistributeToWorkers<T> {
             let locs = buffer.contents().locs();
             for &OrderIndex(o, i) in locs {
                 let i = u64::from(i);
-                get_chain_mut(&mut self.log, o).map(|c| c.trie.set_min(i));
+                self.credit_trim(o, i);
+                self.log.get(o).map(|c| c.trie.set_min(i));
             }
-            self.log.set_meta(());
             self.log.refresh();
-            for &OrderIndex(o, _) in locs {
-                get_chain_mut(&mut self.log, o).map(|c| c.trie.delete_free());
+            for &OrderIndex(o, i) in locs {
+                self.log.get(o).map(|c| c.trie.delete_free());
+                self.citations.forget_before(o, u64::from(i));
             }
         }
         //TODO send down before sending to ordering thread...
@@ -1336,6 +1642,176 @@ where ToWorkers: DistributeToWorkers<T> {
         self.to_workers.send_to_worker(Reply(buffer, t));
     }
 
+    /// Credit the quota of `chain` with the bytes trimming it to `min` frees.
+    fn credit_trim(&mut self, chain: order, min: u64) {
+        let quotas = match self.quotas {
+            Some(ref quotas) if quotas.counts_stored(chain) => quotas,
+            _ => return,
+        };
+        if let Some(c) = self.log.get(chain) {
+            let trie = &c.trie;
+            let bytes = (trie.bounds().start..min)
+                .filter_map(|i| trie.atomic_get(i).map(|p| p.contents().len() as u64))
+                .sum();
+            quotas.trimmed(chain, bytes)
+        }
+    }
+
+    /// Trim the chains of `shard` which exceed their retention policy,
+    /// see `retention` for what is kept regardless.
+    /// Entries in any shard may cite the trimmed ones, so every shard must be locked.
+    /// Only the head of the replication chain should call this,
+    /// the trims reach the replicas with the next message this shard handles.
+    pub fn apply_retention(&mut self, retainer: &mut Retainer, shard: usize) {
+        let now = Instant::now();
+        let (this_server_num, total_servers) = (self.this_server_num, self.total_servers);
+        let mut trims = Vec::new();
+        for chain in self.log.colors_in(shard) {
+            let (min, written) = match self.log.get(chain) {
+                None => continue,
+                // pending appends hold storage in the middle of the chain
+                Some(ref c) if !c.skeens.is_empty() => continue,
+                Some(c) => {
+                    let trie = &c.trie;
+                    // entry 0 is a placeholder
+                    let min = max(trie.bounds().start, 1);
+                    let wanted = retainer.trim_point(chain, trie.len(), now,
+                        |i| trie.atomic_get(i).map(|p| p.contents().len()));
+                    let written = (min..wanted).find(|&i| trie.atomic_get(i).is_none())
+                        .unwrap_or(wanted);
+                    (min, written)
+                },
+            };
+            if written <= min {
+                continue
+            }
+            let log = &mut self.log;
+            let trim_to = self.citations.first_needed(chain, min, written, |OrderIndex(o, i)| {
+                if o == chain {
+                    return false
+                }
+                // we cannot know when another server trims its chains
+                if o % u64::from(total_servers) != u64::from(this_server_num).into() {
+                    return true
+                }
+                log.get(o).map_or(false, |c| u64::from(i) >= c.trie.bounds().start)
+            });
+            if trim_to > min {
+                trims.push((chain, trim_to))
+            }
+        }
+        if !trims.is_empty() {
+            for &(chain, trim_to) in &trims {
+                trace!("SERVER {:?} retention trims {:?} to {:?}",
+                    self.this_server_num, chain, trim_to);
+                self.credit_trim(chain, trim_to);
+                self.log.get(chain).map(|c| c.trie.set_min(trim_to));
+            }
+            self.log.refresh();
+            for &(chain, trim_to) in &trims {
+                self.log.get(chain).map(|c| c.trie.delete_free());
+                self.citations.forget_before(chain, trim_to);
+                self.unreplicated_trims.push(OrderIndex(chain, entry::from(trim_to)));
+            }
+        }
+        retainer.checked(now);
+    }
+
+    /// Send the trims retention made since the last message downstream,
+    /// along the replication stream of the client which sent it,
+    /// the replicas apply them as they would a client's GC.
+    fn replicate_trims(&mut self, t: T) {
+        if self.unreplicated_trims.is_empty() {
+            return
+        }
+        let mut gc = Buffer::empty();
+        gc.fill_from_entry_contents(EntryContents::GC {
+            // a nil id tells the tail there is no client waiting for the reply
+            id: &Uuid::nil(),
+            flags: &EntryFlag::Nothing,
+            locs: &self.unreplicated_trims,
+        });
+        self.unreplicated_trims.clear();
+        self.print_data.msgs_sent(1);
+        self.to_workers.send_to_worker(Reply(gc, t));
+    }
+
+    /// If the chains of `shard` hold more than their share of the memory budget,
+    /// move the oldest entries of the largest ones to segment files,
+    /// see `tiered` for what always stays in memory.
+    pub fn spill_cold_entries(&mut self, spiller: &mut Spiller, shard: usize) {
+        let now = Instant::now();
+        let mut sizes: Vec<_> = self.log.colors_in(shard).into_iter().filter_map(|chain|
+            self.log.get(chain).and_then(|c| {
+                // pending appends hold storage in the middle of the chain
+                if !c.skeens.is_empty() { return None }
+                Some((c.trie.resident_bytes(), chain))
+            })
+        ).collect();
+        let mut resident: u64 = sizes.iter().map(|&(bytes, _)| bytes).sum();
+        if resident <= spiller.budget() {
+            spiller.checked(now);
+            return
+        }
+        sizes.sort_by(|a, b| b.cmp(a));
+        let mut spills = Vec::new();
+        for (_, chain) in sizes {
+            if resident <= spiller.budget() {
+                break
+            }
+            let c = match self.log.get(chain) {
+                None => continue,
+                Some(c) => c,
+            };
+            let upto = c.trie.spill_point(spiller.hot_entries());
+            match unsafe { c.trie.spill(upto, spiller.segment_path(chain)) } {
+                Ok(None) => {},
+                Ok(Some(spilled)) => {
+                    trace!("SERVER {:?} spilled {:?} up to {:?}",
+                        self.this_server_num, chain, upto);
+                    resident = resident.saturating_sub(spilled.bytes());
+                    spills.push((chain, spilled))
+                },
+                Err(e) => error!("SERVER {:?} could not spill {:?}: {}",
+                    self.this_server_num, chain, e),
+            }
+        }
+        if !spills.is_empty() {
+            // readers may still be using the old storage
+            self.log.refresh();
+            for (chain, spilled) in spills {
+                self.log.get(chain).map(|c| unsafe { c.trie.free_spilled(spilled) });
+            }
+        }
+        spiller.checked(now);
+    }
+
+    pub fn handle_cited_by(&mut self, buffer: BufferSlice, t: T) {
+        let answered = buffer.contents().flag().contains(EntryFlag::ReadSuccess);
+        let reply = if answered {
+            // the head of the replication chain already answered,
+            // replicas have no index of their own and only pass it along
+            buffer
+        } else {
+            let (id, loc) = {
+                let c = buffer.contents();
+                (*c.id(), c.locs()[0])
+            };
+            let citations = self.citations.cited_by(loc);
+            trace!("SERVER {:?} {:?} cited by {:?}", self.this_server_num, loc, citations);
+            let mut reply = Buffer::empty();
+            reply.fill_from_entry_contents(EntryContents::CitedBy {
+                id: &id,
+                flags: &EntryFlag::ReadSuccess,
+                loc: &loc,
+                citations: citations,
+            });
+            reply
+        };
+        self.print_data.msgs_sent(1);
+        self.to_workers.send_to_worker(Reply(reply, t));
+    }
+
     pub fn handle_recovery(&mut self, recovery: Recovery, t: T) {
         match recovery {
             Recovery::TasRecoverer(mut buffer, recoverer) => {
@@ -1363,8 +1839,14 @@ where ToWorkers: DistributeToWorkers<T> {
                     let c = buffer.contents();
                     (*c.id(), c.locs()[0])
                 };
+                if id == Uuid::nil() {
+                    return self.find_blocking_multi(buffer, chain, t)
+                }
+                if time == entry::from(0) {
+                    return self.fetch_waiting_multi(buffer, chain, id, t)
+                }
                 let time = u64::from(time);
-                let still_there = get_chain(&self.log, chain)
+                let still_there = self.log.get(chain)
                     .map(|c| c.skeens.check_skeens1(id, time))
                     .unwrap_or(false);
                 self.to_workers.send_to_worker(if still_there {
@@ -1375,4 +1857,26 @@ where ToWorkers: DistributeToWorkers<T> {
             },
         }
     }
+
+    // A CheckSkeens1 with a nil id asks what is blocking a chain.
+    // If a multiappend is stuck in phase 1 we reply with its entry,
+    // with the locs filled in with our timestamps, so the asker can recover it,
+    // otherwise the request is sent back unchanged.
+    fn find_blocking_multi(&mut self, buffer: BufferSlice, chain: order, t: T) {
+        let blocker = self.log.get(chain)
+            .and_then(|c| c.skeens.blocking_multi())
+            .and_then(|storage| recovery_entry(&storage));
+        self.print_data.msgs_sent(1);
+        self.to_workers.send_to_worker(ToWorker::EndRecovery(blocker.unwrap_or(buffer), t));
+    }
+
+    // A CheckSkeens1 with an id but no timestamp asks for a multiappend
+    // whose sentinel is blocking another server, see find_blocking_multi.
+    fn fetch_waiting_multi(&mut self, buffer: BufferSlice, chain: order, id: Uuid, t: T) {
+        let multi = self.log.get(chain)
+            .and_then(|c| c.skeens.waiting_multi(&id))
+            .and_then(|storage| recovery_entry(&storage));
+        self.print_data.msgs_sent(1);
+        self.to_workers.send_to_worker(ToWorker::EndRecovery(multi.unwrap_or(buffer), t));
+    }
 }
@@ -0,0 +1,332 @@
+//! Per-tenant quotas on append rates and stored bytes.
+//!
+//! A `Quota` limits the appends per second, the bytes appended per second,
+//! and the bytes stored of either a principal, see `access`, or a range of
+//! colors, eg. those handed out to one team. The rates are token buckets which
+//! hold up to one second's worth of appends or bytes. Before an append is handed
+//! to an ordering thread, the worker which received it charges it against the
+//! quota of its client's principal, and the quota of every range one of its
+//! colors is in. An append which would exceed one of them is bounced back as
+//! `EntryKind::Rejected` with `reject::QUOTA_EXCEEDED`, with the offending color
+//! first in its locations, which the client reports as an error.
+//!
+//! Stored bytes are only limited for ranges of colors: a server knows what each
+//! of its colors holds, counting from when it started, and what is trimmed from
+//! them, but not who wrote each entry. A principal's `stored_bytes` is ignored.
+//!
+//! Multiappends which need a skeens round across servers are never refused,
+//! since the other servers would be left waiting on their second round, but
+//! they are charged, so the appends after them are refused instead.
+//!
+//! Each server enforces quotas on its own, so a tenant whose appends are spread
+//! across `n` servers may append up to `n` times its rates.
+//! `QuotaEnforcer::usage` reports what each tenant used so far.
+
+use std::cmp::min;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use packets::{order, EntryFlag, EntryLayout, OrderIndex, Packet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    appends_per_sec: Option<u64>,
+    bytes_per_sec: Option<u64>,
+    stored_bytes: Option<u64>,
+}
+
+impl Quota {
+    pub fn unlimited() -> Self {
+        Default::default()
+    }
+
+    pub fn appends_per_sec(self, max: u64) -> Self {
+        Quota { appends_per_sec: Some(max), ..self }
+    }
+
+    pub fn bytes_per_sec(self, max: u64) -> Self {
+        Quota { bytes_per_sec: Some(max), ..self }
+    }
+
+    /// Only limits ranges of colors.
+    pub fn stored_bytes(self, max: u64) -> Self {
+        Quota { stored_bytes: Some(max), ..self }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Tenant {
+    Principal(String),
+    Colors(Range<u64>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    tenants: Vec<(Tenant, Quota)>,
+}
+
+impl Quotas {
+    /// No quotas, which is how a server without quotas behaves.
+    pub fn none() -> Self {
+        Default::default()
+    }
+
+    pub fn principal(mut self, principal: &str, quota: Quota) -> Self {
+        self.tenants.push((Tenant::Principal(principal.to_string()), quota));
+        self
+    }
+
+    /// The colors in `colors` together get `quota`.
+    pub fn colors(mut self, colors: Range<u64>, quota: Quota) -> Self {
+        self.tenants.push((Tenant::Colors(colors), quota));
+        self
+    }
+}
+
+/// What a tenant used at this server, since it started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub tenant: Tenant,
+    pub quota: Quota,
+    pub appends: u64,
+    pub bytes: u64,
+    /// Only counted for ranges of colors.
+    pub stored_bytes: u64,
+    /// Appends which were refused for exceeding the quota.
+    pub refused: u64,
+}
+
+/// A server's quotas, shared by its workers and ordering threads.
+pub struct QuotaEnforcer {
+    tenants: Mutex<Vec<TenantState>>,
+}
+
+struct TenantState {
+    usage: QuotaUsage,
+    appends: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl QuotaEnforcer {
+    pub fn new(quotas: Quotas) -> Self {
+        let now = Instant::now();
+        let tenants = quotas.tenants.into_iter().map(|(tenant, quota)| TenantState {
+            appends: quota.appends_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            bytes: quota.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            usage: QuotaUsage {
+                tenant: tenant,
+                quota: quota,
+                appends: 0,
+                bytes: 0,
+                stored_bytes: 0,
+                refused: 0,
+            },
+        }).collect();
+        QuotaEnforcer { tenants: Mutex::new(tenants) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.lock().unwrap().is_empty()
+    }
+
+    /// Whether `packet`, a request from a client, is charged at all,
+    /// and if so whether it may be refused.
+    pub fn charges(packet: Packet::Ref) -> Option<bool> {
+        let flag = *packet.flag();
+        if flag.contains(EntryFlag::Unlock) || flag.contains(EntryFlag::DirectWrite) {
+            return None
+        }
+        match packet.layout() {
+            EntryLayout::Data => Some(true),
+            // the ones which take locks go through a skeens round across servers
+            EntryLayout::Multiput | EntryLayout::Sentinel =>
+                Some(!flag.contains(EntryFlag::TakeLock)),
+            _ => None,
+        }
+    }
+
+    /// Charge an append of `bytes` by `principal` to `locs`, of which `stored` are stored here.
+    /// If the append is `refusable`, and would exceed a quota,
+    /// nothing is charged and the color whose quota it would exceed is returned.
+    pub fn charge<F>(
+        &self,
+        principal: Option<&str>,
+        locs: &[OrderIndex],
+        bytes: u64,
+        refusable: bool,
+        mut stored: F,
+    ) -> Result<(), order>
+    where F: FnMut(order) -> bool {
+        let now = Instant::now();
+        let mut tenants = self.tenants.lock().unwrap();
+        let charged: Vec<_> = tenants.iter().enumerate().filter_map(|(i, t)| {
+            match t.usage.tenant {
+                Tenant::Principal(ref p) if Some(&**p) == principal =>
+                    Some((i, locs[0].0, 0)),
+                Tenant::Principal(..) => None,
+                Tenant::Colors(ref range) => {
+                    let colors: Vec<_> = locs.iter()
+                        .map(|&OrderIndex(o, _)| o)
+                        .filter(|&o| o != order::from(0) && in_range(range, o))
+                        .collect();
+                    let stored_here = colors.iter().filter(|&&o| stored(o)).count() as u64;
+                    colors.first().map(|&o| (i, o, stored_here * bytes))
+                },
+            }
+        }).collect();
+        if charged.is_empty() {
+            return Ok(())
+        }
+        if refusable {
+            for &(i, color, stored_bytes) in &charged {
+                let t = &mut tenants[i];
+                let has_room = t.appends.as_mut().map_or(true, |b| b.has(1, now))
+                    && t.bytes.as_mut().map_or(true, |b| b.has(bytes, now))
+                    && t.usage.quota.stored_bytes.map_or(true, |max|
+                        !is_colors(&t.usage.tenant)
+                        || stored_bytes == 0
+                        || t.usage.stored_bytes + stored_bytes <= max);
+                if !has_room {
+                    t.usage.refused += 1;
+                    return Err(color)
+                }
+            }
+        }
+        for (i, _, stored_bytes) in charged {
+            let t = &mut tenants[i];
+            t.appends.as_mut().map(|b| b.take(1, now));
+            t.bytes.as_mut().map(|b| b.take(bytes, now));
+            t.usage.appends += 1;
+            t.usage.bytes += bytes;
+            t.usage.stored_bytes += stored_bytes;
+        }
+        Ok(())
+    }
+
+    /// `bytes` of `color` were trimmed.
+    pub fn trimmed(&self, color: order, bytes: u64) {
+        let mut tenants = self.tenants.lock().unwrap();
+        for t in tenants.iter_mut() {
+            if let Tenant::Colors(ref range) = t.usage.tenant {
+                if in_range(range, color) {
+                    t.usage.stored_bytes = t.usage.stored_bytes.saturating_sub(bytes)
+                }
+            }
+        }
+    }
+
+    /// Whether the stored bytes of `color` are counted.
+    pub fn counts_stored(&self, color: order) -> bool {
+        self.tenants.lock().unwrap().iter().any(|t| match t.usage.tenant {
+            Tenant::Colors(ref range) => in_range(range, color),
+            _ => false,
+        })
+    }
+
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        self.tenants.lock().unwrap().iter().map(|t| t.usage.clone()).collect()
+    }
+}
+
+fn in_range(range: &Range<u64>, color: order) -> bool {
+    let color = u64::from(color);
+    range.start <= color && color < range.end
+}
+
+fn is_colors(tenant: &Tenant) -> bool {
+    match *tenant {
+        Tenant::Colors(..) => true,
+        Tenant::Principal(..) => false,
+    }
+}
+
+/// Holds up to one second's worth of `rate`.
+struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last_fill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket { rate: rate, tokens: rate, last_fill: now }
+    }
+
+    fn fill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_fill);
+        let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        let new = (self.rate as u128 * nanos as u128 / 1_000_000_000) as u64;
+        if new > 0 {
+            self.tokens = min(self.tokens.saturating_add(new), self.rate);
+            self.last_fill = now;
+        }
+    }
+
+    /// A full bucket always has room, so nothing bigger than it is refused forever.
+    fn has(&mut self, n: u64, now: Instant) -> bool {
+        self.fill(now);
+        self.tokens >= n || self.tokens == self.rate
+    }
+
+    fn take(&mut self, n: u64, now: Instant) {
+        self.fill(now);
+        self.tokens = self.tokens.saturating_sub(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn locs(colors: &[u64]) -> Vec<OrderIndex> {
+        colors.iter().map(|&c| OrderIndex(c.into(), 0.into())).collect()
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        assert!(bucket.has(10, start));
+        bucket.take(8, start);
+        assert!(!bucket.has(3, start));
+        assert!(bucket.has(3, start + Duration::from_millis(100)));
+        assert!(bucket.has(10, start + Duration::from_secs(5)));
+        bucket.take(10, start + Duration::from_secs(5));
+        // a full bucket lets anything through
+        assert!(bucket.has(100, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn principal_rate() {
+        let quotas = QuotaEnforcer::new(
+            Quotas::none().principal("alice", Quota::unlimited().appends_per_sec(2)));
+        let all = |_| true;
+        assert_eq!(quotas.charge(Some("alice"), &locs(&[1]), 10, true, all), Ok(()));
+        assert_eq!(quotas.charge(Some("alice"), &locs(&[1]), 10, true, all), Ok(()));
+        assert_eq!(quotas.charge(Some("alice"), &locs(&[3]), 10, true, all), Err(3.into()));
+        assert_eq!(quotas.charge(Some("bob"), &locs(&[1]), 10, true, all), Ok(()));
+        assert_eq!(quotas.charge(None, &locs(&[1]), 10, true, all), Ok(()));
+        // appends which cannot be refused are still charged
+        assert_eq!(quotas.charge(Some("alice"), &locs(&[1, 2]), 10, false, all), Ok(()));
+        let usage = quotas.usage();
+        assert_eq!((usage[0].appends, usage[0].bytes, usage[0].refused), (3, 30, 1));
+    }
+
+    #[test]
+    fn stored_bytes() {
+        let quotas = QuotaEnforcer::new(
+            Quotas::none().colors(10..20, Quota::unlimited().stored_bytes(100)));
+        let even = |o: order| u64::from(o) % 2 == 0;
+        assert!(quotas.counts_stored(10.into()) && !quotas.counts_stored(20.into()));
+        assert_eq!(quotas.charge(None, &locs(&[10]), 60, true, even), Ok(()));
+        // only the colors stored here count
+        assert_eq!(quotas.charge(None, &locs(&[11, 21]), 60, true, even), Ok(()));
+        assert_eq!(quotas.charge(None, &locs(&[12]), 60, true, even), Err(12.into()));
+        quotas.trimmed(10.into(), 30);
+        assert_eq!(quotas.charge(None, &locs(&[12]), 60, true, even), Ok(()));
+        assert_eq!(quotas.usage()[0].stored_bytes, 90);
+        assert_eq!(quotas.charge(None, &locs(&[21]), 60, true, even), Ok(()));
+    }
+}
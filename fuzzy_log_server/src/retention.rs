@@ -0,0 +1,237 @@
+//! Automatically trimming colors which would otherwise grow forever.
+//!
+//! Every `Retention::check_every` each ordering thread compares the colors of
+//! its shard against their `RetentionPolicy`, and trims those which exceed it
+//! the same way a client's GC does. A color is never trimmed past
+//!
+//!  * an entry which has not been written yet, and colors with appends still
+//!    waiting in skeens are skipped entirely, as those hold storage in the
+//!    middle of the color,
+//!  * an entry which is in the `deps` of an entry of another color which has
+//!    not been trimmed, or of an append still waiting for its location.
+//!    Entries of colors stored at other servers are always assumed to be live,
+//!    as this server cannot know when they are trimmed,
+//!
+//! so a color may keep more than its policy asks for.
+//!
+//! The `timestamp` of a `Single` is its skeens time, a logical clock, so ages
+//! are measured from the first check which saw an entry instead; entries may be
+//! kept up to one `check_every` longer than their `max_age`.
+//!
+//! Only the head of a replication chain checks its colors, so that every
+//! replica trims the same entries. Its trims are sent down the chain as a GC
+//! along with the next message its ordering thread handles, so replicas may
+//! keep entries the head has already trimmed while the shard is idle.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hash::HashMap;
+use packets::order;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetentionPolicy {
+    max_entries: Option<u64>,
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Never trim anything.
+    pub fn forever() -> Self {
+        Default::default()
+    }
+
+    /// Keep at most the latest `max` entries.
+    pub fn max_entries(self, max: u64) -> Self {
+        RetentionPolicy { max_entries: Some(max), ..self }
+    }
+
+    pub fn max_age(self, max: Duration) -> Self {
+        RetentionPolicy { max_age: Some(max), ..self }
+    }
+
+    /// Keep at most the latest `max` bytes of entries.
+    pub fn max_bytes(self, max: u64) -> Self {
+        RetentionPolicy { max_bytes: Some(max), ..self }
+    }
+
+    pub fn is_forever(&self) -> bool {
+        *self == Self::forever()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Retention {
+    policies: HashMap<order, RetentionPolicy>,
+    default_policy: RetentionPolicy,
+    interval: Duration,
+}
+
+impl Retention {
+    /// Keep every color forever,
+    /// which is how a server without retention behaves.
+    pub fn none() -> Self {
+        Retention {
+            policies: Default::default(),
+            default_policy: RetentionPolicy::forever(),
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn color(mut self, color: order, policy: RetentionPolicy) -> Self {
+        self.policies.insert(color, policy);
+        self
+    }
+
+    /// The policy of colors which were not given one.
+    pub fn default_policy(self, policy: RetentionPolicy) -> Self {
+        Retention { default_policy: policy, ..self }
+    }
+
+    /// How often colors are checked, once a second by default.
+    pub fn check_every(self, interval: Duration) -> Self {
+        Retention { interval: interval, ..self }
+    }
+
+    pub fn policy(&self, color: order) -> RetentionPolicy {
+        self.policies.get(&color).cloned().unwrap_or(self.default_policy)
+    }
+
+    pub fn keeps_everything(&self) -> bool {
+        self.default_policy.is_forever() && self.policies.values().all(|p| p.is_forever())
+    }
+}
+
+/// An ordering thread's progress in applying a `Retention`.
+pub struct Retainer {
+    retention: Arc<Retention>,
+    next_check: Option<Instant>,
+    // for each color, its length at each of the checks which may still matter
+    lengths: HashMap<order, VecDeque<(Instant, u64)>>,
+}
+
+impl Retainer {
+    pub fn new(retention: Arc<Retention>) -> Self {
+        let next_check = if retention.keeps_everything() {
+            None
+        } else {
+            Some(Instant::now() + retention.interval)
+        };
+        Retainer { retention: retention, next_check: next_check, lengths: Default::default() }
+    }
+
+    /// How long until the next check, `None` if there will never be one.
+    pub fn until_check(&self) -> Option<Duration> {
+        self.next_check.map(|next| {
+            let now = Instant::now();
+            if next > now { next - now } else { Duration::from_secs(0) }
+        })
+    }
+
+    pub fn check_due(&self) -> bool {
+        self.next_check.map_or(false, |next| Instant::now() >= next)
+    }
+
+    pub fn checked(&mut self, now: Instant) {
+        self.next_check = self.next_check.map(|_| now + self.retention.interval);
+    }
+
+    /// The index below which the policy of `color` would trim it,
+    /// given that it is `len` entries long and that the entry at `i`
+    /// is `size_of(i)` bytes, or `None` if the entry is not written yet.
+    /// 0 if nothing should be trimmed.
+    ///
+    /// Entry 0 of every color is a placeholder.
+    pub fn trim_point<F>(&mut self, color: order, len: u64, now: Instant, mut size_of: F) -> u64
+    where F: FnMut(u64) -> Option<usize> {
+        let policy = self.retention.policy(color);
+        if policy.is_forever() {
+            return 0
+        }
+        let mut trim_to = 0;
+        if let Some(max) = policy.max_entries {
+            trim_to = len.saturating_sub(max)
+        }
+        if let Some(max_age) = policy.max_age {
+            let lengths = self.lengths.entry(color).or_insert_with(VecDeque::new);
+            // every entry before the newest length at least max_age old is that old
+            while lengths.len() > 1 && now - lengths[1].0 >= max_age {
+                lengths.pop_front();
+            }
+            if let Some(&(seen, old_len)) = lengths.front() {
+                if now - seen >= max_age {
+                    trim_to = ::std::cmp::max(trim_to, old_len)
+                }
+            }
+            if lengths.back().map_or(true, |&(_, l)| l < len) {
+                lengths.push_back((now, len))
+            }
+        }
+        if let Some(max) = policy.max_bytes {
+            let mut kept = 0;
+            for i in (trim_to..len).rev() {
+                kept += size_of(i).unwrap_or(0) as u64;
+                if kept > max {
+                    trim_to = i + 1;
+                    break
+                }
+            }
+        }
+        trim_to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retainer(policy: RetentionPolicy) -> Retainer {
+        Retainer::new(Arc::new(Retention::none().color(7.into(), policy)))
+    }
+
+    #[test]
+    fn forever() {
+        let mut r = Retainer::new(Arc::new(Retention::none()));
+        assert_eq!(r.until_check(), None);
+        assert!(!r.check_due());
+        assert_eq!(r.trim_point(7.into(), 100, Instant::now(), |_| Some(1)), 0);
+
+        let mut r = retainer(RetentionPolicy::forever().max_entries(1));
+        assert!(r.until_check().is_some());
+        assert_eq!(r.trim_point(8.into(), 100, Instant::now(), |_| Some(1)), 0);
+    }
+
+    #[test]
+    fn max_entries() {
+        let mut r = retainer(RetentionPolicy::forever().max_entries(10));
+        let now = Instant::now();
+        assert_eq!(r.trim_point(7.into(), 5, now, |_| Some(1)), 0);
+        assert_eq!(r.trim_point(7.into(), 25, now, |_| Some(1)), 15);
+    }
+
+    #[test]
+    fn max_age() {
+        let mut r = retainer(RetentionPolicy::forever().max_age(Duration::from_secs(10)));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(r.trim_point(7.into(), 5, at(0), |_| Some(1)), 0);
+        assert_eq!(r.trim_point(7.into(), 8, at(5), |_| Some(1)), 0);
+        assert_eq!(r.trim_point(7.into(), 9, at(10), |_| Some(1)), 5);
+        assert_eq!(r.trim_point(7.into(), 9, at(14), |_| Some(1)), 5);
+        assert_eq!(r.trim_point(7.into(), 12, at(16), |_| Some(1)), 8);
+        assert_eq!(r.trim_point(7.into(), 12, at(30), |_| Some(1)), 12);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut r = retainer(RetentionPolicy::forever().max_bytes(100).max_entries(8));
+        let now = Instant::now();
+        assert_eq!(r.trim_point(7.into(), 5, now, |_| Some(10)), 0);
+        assert_eq!(r.trim_point(7.into(), 20, now, |_| Some(10)), 12);
+        assert_eq!(r.trim_point(7.into(), 20, now, |_| Some(30)), 17);
+        // entries which are not written yet take no space
+        assert_eq!(r.trim_point(7.into(), 20, now, |i| if i > 17 { None } else { Some(30) }), 15);
+    }
+}
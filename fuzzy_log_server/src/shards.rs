@@ -0,0 +1,380 @@
+//! Splitting the chains of a server between several ordering threads.
+//!
+//! Each chain belongs to a single shard, picked by its color, and each shard
+//! gets its own ordering thread. A chain is only ever mutated with its shard's
+//! lock held. Ops which only touch the chains of one shard are handled by that
+//! shard's thread with only that lock held; ops which touch the chains of
+//! several shards, such as multiappends, are sent to the lowest of those shards,
+//! whose thread also takes the locks of the rest before handling the op.
+//! A transaction also touches the chains it read, which it keeps locked between
+//! its skeens rounds, see `ShardMap::op_shards`.
+//! Locks are always taken in ascending order, so this cannot deadlock, and the
+//! per-chain skeens state already keeps multiappends ordered consistently
+//! across chains, so nothing else needs to change when a multi spans shards.
+//! Retention reads the chains citing the ones it trims, which may be in any
+//! shard, so it takes the locks of every shard.
+//!
+//! Each ordering thread hands out `&mut Chain`s from its own `Shard` without
+//! locking the store, which is only sound while it holds the lock of the
+//! chain's shard; `Shards` records which thread holds each lock, and `Shard`
+//! checks it before handing out a chain.
+//!
+//! The chains themselves are kept in a single store, so workers need only the
+//! one reader regardless of the number of shards.
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hash::HashMap;
+use packets::{order, EntryFlag, EntryKind, OrderIndex, Packet};
+
+use citations::CitationIndex;
+use skeens::SkeensState;
+use trie::Trie;
+use trivial_eq_arc::TrivialEqArc;
+use {Chain, ChainStore};
+#[cfg(test)]
+use ChainReader;
+
+/// Which shard of which server stores a given chain.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShardMap {
+    this_server_num: u32,
+    total_servers: u32,
+    num_shards: usize,
+}
+
+impl ShardMap {
+    pub fn new(this_server_num: u32, total_servers: u32, num_shards: usize) -> Self {
+        assert!(num_shards > 0, "a server needs at least one ordering thread");
+        //shard sets are stored in a u64
+        assert!(num_shards <= 64, "at most 64 ordering threads are supported");
+        ShardMap { this_server_num, total_servers, num_shards }
+    }
+
+    pub fn this_server_num(&self) -> u32 {
+        self.this_server_num
+    }
+
+    pub fn total_servers(&self) -> u32 {
+        self.total_servers
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.num_shards
+    }
+
+    pub fn stores_chain(&self, chain: order) -> bool {
+        chain != order::from(0)
+            && chain % u64::from(self.total_servers) == u64::from(self.this_server_num).into()
+    }
+
+    pub fn shard_for_chain(&self, chain: order) -> usize {
+        let chain: u64 = chain.into();
+        ((chain / u64::from(self.total_servers)) % self.num_shards as u64) as usize
+    }
+
+    /// The set of shards whose chains are in `locs`, one bit per shard.
+    pub fn shards_touched(&self, locs: &[OrderIndex]) -> u64 {
+        if self.num_shards == 1 {
+            return 1
+        }
+        locs.iter()
+            .filter(|&&OrderIndex(o, _)| self.stores_chain(o))
+            .fold(0, |shards, &OrderIndex(o, _)| shards | 1 << self.shard_for_chain(o))
+    }
+
+    /// The shard whose thread handles an op on `locs`.
+    pub fn shard_for(&self, locs: &[OrderIndex]) -> usize {
+        lowest(self.shards_touched(locs))
+    }
+
+    /// The set of shards an op touches, its locs and, for a transaction, its reads.
+    pub fn op_shards(&self, op: Packet::Ref) -> u64 {
+        let reads = match op.kind() {
+            EntryKind::Multiput | EntryKind::Sentinel
+            | EntryKind::MultiputToReplica | EntryKind::SentinelToReplica
+                if op.flag().contains(EntryFlag::Transaction) => op.dependencies(),
+            _ => &[],
+        };
+        self.shards_touched(op.locs()) | self.shards_touched(reads)
+    }
+
+    /// The shard whose thread handles `op`.
+    pub fn shard_for_op(&self, op: Packet::Ref) -> usize {
+        lowest(self.op_shards(op))
+    }
+}
+
+fn lowest(shards: u64) -> usize {
+    match shards {
+        0 => 0,
+        shards => shards.trailing_zeros() as usize,
+    }
+}
+
+/// The state the ordering threads of a server share.
+pub struct Shards<T: Copy> {
+    map: ShardMap,
+    store: Mutex<ChainStore<T>>,
+    locks: Box<[Mutex<()>]>,
+    // for each lock, the shard whose thread holds it, plus one, 0 if it's free
+    held_by: Box<[AtomicUsize]>,
+    citations: Arc<CitationIndex>,
+    // every chain in the store, in the order they were created
+    colors: Mutex<Vec<order>>,
+}
+
+impl<T: Copy> Shards<T> {
+    pub fn new(map: ShardMap, store: ChainStore<T>) -> Self {
+        Shards {
+            map: map,
+            store: Mutex::new(store),
+            locks: (0..map.num_shards()).map(|_| Mutex::new(())).collect::<Vec<_>>()
+                .into_boxed_slice(),
+            held_by: (0..map.num_shards()).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>()
+                .into_boxed_slice(),
+            citations: Arc::new(CitationIndex::new()),
+            colors: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    pub fn citations(&self) -> &Arc<CitationIndex> {
+        &self.citations
+    }
+
+    /// Lock every shard an op on `locs`, handled by `shard`, needs.
+    pub fn lock(&self, shard: usize, locs: &[OrderIndex]) -> ShardGuards {
+        self.lock_touched(shard, self.map.shards_touched(locs))
+    }
+
+    /// Lock every shard `op`, handled by `shard`, needs.
+    pub fn lock_op(&self, shard: usize, op: Packet::Ref) -> ShardGuards {
+        self.lock_touched(shard, self.map.op_shards(op))
+    }
+
+    fn lock_touched(&self, shard: usize, touched: u64) -> ShardGuards {
+        let others = touched & !(1 << shard);
+        debug_assert!(others.trailing_zeros() as usize > shard,
+            "op sent to shard {} instead of {}", shard, others.trailing_zeros());
+        self.lock_set(shard, others | 1 << shard)
+    }
+
+    /// Lock every shard, for ops which may read any chain.
+    pub fn lock_all(&self, shard: usize) -> ShardGuards {
+        let all = if self.map.num_shards() == 64 { !0 } else { (1 << self.map.num_shards()) - 1 };
+        self.lock_set(shard, all)
+    }
+
+    fn lock_set(&self, shard: usize, set: u64) -> ShardGuards {
+        if self.map.num_shards() == 1 {
+            return ShardGuards { held_by: &[], guards: Vec::new() }
+        }
+        let guards = (0..self.locks.len())
+            .filter(|&s| set & (1 << s) != 0)
+            .map(|s| {
+                let guard = self.locks[s].lock().unwrap();
+                self.held_by[s].store(shard + 1, Ordering::Relaxed);
+                (s, guard)
+            })
+            .collect();
+        ShardGuards { held_by: &self.held_by, guards: guards }
+    }
+
+    // chains of other servers are never in the store, so they need no lock
+    fn is_held_by(&self, chain: order, shard: usize) -> bool {
+        self.map.num_shards() == 1 || !self.map.stores_chain(chain)
+            || self.held_by[self.map.shard_for_chain(chain)].load(Ordering::Relaxed) == shard + 1
+    }
+}
+
+/// The shard locks held while handling an op.
+pub struct ShardGuards<'s> {
+    held_by: &'s [AtomicUsize],
+    guards: Vec<(usize, MutexGuard<'s, ()>)>,
+}
+
+impl<'s> Drop for ShardGuards<'s> {
+    fn drop(&mut self) {
+        // the locks are released after this, when the guards are dropped
+        for &(s, _) in &self.guards {
+            self.held_by[s].store(0, Ordering::Relaxed)
+        }
+    }
+}
+
+/// An ordering thread's view of the shared chains.
+///
+/// Chains are never removed from the store, so once looked up a chain is cached
+/// and further accesses need not lock the store.
+pub struct Shard<T: Copy> {
+    shards: Arc<Shards<T>>,
+    shard: usize,
+    chains: HashMap<order, *mut Chain<T>>,
+}
+
+impl<T: Copy> Shard<T> {
+    pub fn new(shards: Arc<Shards<T>>, shard: usize) -> Self {
+        Shard { shards: shards, shard: shard, chains: Default::default() }
+    }
+
+    //SAFETY: the chain's shard is locked by this thread, so no other thread
+    //        can get the chain until the op that got it is finished,
+    //        and Chain refs never escape that op
+    pub fn get(&mut self, chain: order) -> Option<&mut Chain<T>> {
+        assert!(self.shards.is_held_by(chain, self.shard),
+            "shard {} used {:?} without holding its lock", self.shard, chain);
+        if let Some(&c) = self.chains.get(&chain) {
+            return Some(unsafe { &mut *c })
+        }
+        let c = {
+            let store = self.shards.store.lock().unwrap();
+            store.get_and(&chain, |chains| UnsafeCell::get(&chains[0]))
+        };
+        c.map(|c| {
+            self.chains.insert(chain, c);
+            unsafe { &mut *c }
+        })
+    }
+
+    /// Whether this thread holds the locks of every shard `op` touches.
+    pub fn holds(&self, op: Packet::Ref) -> bool {
+        let map = &self.shards.map;
+        if map.num_shards() == 1 {
+            return true
+        }
+        let touched = map.op_shards(op);
+        (0..map.num_shards())
+            .filter(|&s| touched & (1 << s) != 0)
+            .all(|s| self.shards.held_by[s].load(Ordering::Relaxed) == self.shard + 1)
+    }
+
+    pub fn ensure(&mut self, chain: order) -> &mut Chain<T> {
+        assert!(self.shards.is_held_by(chain, self.shard),
+            "shard {} used {:?} without holding its lock", self.shard, chain);
+        if let Some(&c) = self.chains.get(&chain) {
+            return unsafe { &mut *c }
+        }
+        let c = {
+            let mut store = self.shards.store.lock().unwrap();
+            let c = store.get_and(&chain, |chains| UnsafeCell::get(&chains[0]));
+            match c {
+                Some(c) => c,
+                None => {
+                    let mut t = Trie::new();
+                    //FIXME remove for GC
+                    unsafe {
+                        t.partial_append(1).write_byte(mem::transmute(EntryKind::Read));
+                    };
+                    let contents = TrivialEqArc::new(
+                        Chain{ trie: t, skeens: SkeensState::new(), readers: 0 });
+                    store.insert(chain, contents);
+                    store.refresh();
+                    self.shards.colors.lock().unwrap().push(chain);
+                    store.get_and(&chain, |chains| UnsafeCell::get(&chains[0])).unwrap()
+                },
+            }
+        };
+        self.chains.insert(chain, c);
+        unsafe { &mut *c }
+    }
+
+    /// The chains stored in `shard`.
+    pub fn colors_in(&self, shard: usize) -> Vec<order> {
+        let map = &self.shards.map;
+        self.shards.colors.lock().unwrap().iter()
+            .cloned()
+            .filter(|&c| map.stores_chain(c) && map.shard_for_chain(c) == shard)
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub fn reader(&self) -> ChainReader<T> {
+        (*self.shards.store.lock().unwrap()).clone()
+    }
+
+    /// Make all changes to the store visible to readers.
+    pub fn refresh(&mut self) {
+        let mut store = self.shards.store.lock().unwrap();
+        store.set_meta(());
+        store.refresh();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_shards() -> (Arc<Shards<()>>, order, order) {
+        let map = ShardMap::new(0, 1, 2);
+        let (store, _) = ::new_chain_store_and_reader();
+        let (c0, c1) = (order::from(2), order::from(3));
+        assert_eq!((map.shard_for_chain(c0), map.shard_for_chain(c1)), (0, 1));
+        (Arc::new(Shards::new(map, store)), c0, c1)
+    }
+
+    #[test]
+    fn chains_of_locked_shards() {
+        let (shards, c0, c1) = two_shards();
+        let mut shard = Shard::new(shards.clone(), 0);
+        {
+            let _locked = shards.lock(0, &[OrderIndex(c0, 0.into()), OrderIndex(c1, 0.into())]);
+            shard.ensure(c0);
+            shard.ensure(c1);
+        }
+        {
+            let _locked = shards.lock(0, &[OrderIndex(c0, 0.into())]);
+            assert!(shard.get(c0).is_some());
+        }
+        let _locked = shards.lock_all(0);
+        assert!(shard.get(c1).is_some());
+    }
+
+    #[test]
+    fn transaction_touches_its_reads() {
+        use buffer::Buffer;
+        use packets::{EntryContents, Uuid};
+
+        let (shards, c0, c1) = two_shards();
+        let transaction = |flags: EntryFlag::Flag| {
+            let mut buffer = Buffer::empty();
+            buffer.fill_from_entry_contents(EntryContents::Multi {
+                id: &Uuid::new_v4(),
+                flags: &flags,
+                locs: &[OrderIndex(c1, 0.into())],
+                lock: &0,
+                deps: &[OrderIndex(c0, 1.into())],
+                data: &[],
+            });
+            buffer
+        };
+        let map = shards.map();
+        let multi = transaction(EntryFlag::TakeLock | EntryFlag::NewMultiPut);
+        assert_eq!(map.op_shards(multi.contents()), 0b10);
+        assert_eq!(map.shard_for_op(multi.contents()), 1);
+
+        let txn = transaction(
+            EntryFlag::TakeLock | EntryFlag::NewMultiPut | EntryFlag::Transaction);
+        assert_eq!(map.op_shards(txn.contents()), 0b11);
+        assert_eq!(map.shard_for_op(txn.contents()), 0);
+        let mut shard = Shard::new(shards.clone(), 0);
+        let _locked = shards.lock_op(0, txn.contents());
+        assert!(shard.holds(txn.contents()));
+        shard.ensure(c1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn chain_of_unlocked_shard() {
+        let (shards, c0, c1) = two_shards();
+        let mut shard = Shard::new(shards.clone(), 0);
+        let _locked = shards.lock(0, &[OrderIndex(c0, 0.into())]);
+        shard.ensure(c1);
+    }
+}
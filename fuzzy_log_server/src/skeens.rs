@@ -613,6 +613,13 @@ impl<T: Copy> SkeensState<T> {
         && self.got_max_timestamp.is_empty()
     }
 
+    // Everything added from now on gets a timestamp later than `timestamp`.
+    pub fn order_after(&mut self, timestamp: Time) {
+        if self.next_timestamp <= timestamp {
+            self.next_timestamp = timestamp + 1;
+        }
+    }
+
     pub fn tas_recoverer(
         &mut self,
         write_id: Uuid,
@@ -663,6 +670,28 @@ impl<T: Copy> SkeensState<T> {
         }
     }
 
+    /// The storage of the multiappend at the front of the phase 1 queue, if any.
+    /// Anything still in phase 1 there is preventing the chain from making progress.
+    pub fn blocking_multi(&self) -> Option<SkeensMultiStorage> {
+        match self.phase1_queue.front() {
+            Some(&WaitingForMax::Multi{ref storage, ..})
+            | Some(&WaitingForMax::Senti{ref storage, ..}) => Some(storage.clone()),
+            _ => None,
+        }
+    }
+
+    /// The storage of a multiappend which is still waiting for its max timestamp.
+    pub fn waiting_multi(&self, id: &Uuid) -> Option<SkeensMultiStorage> {
+        match self.append_status.get(id) {
+            Some(&AppendStatus::Phase1(i)) => match self.phase1_queue.get(i) {
+                Some(&WaitingForMax::Multi{ref storage, ..})
+                | Some(&WaitingForMax::Senti{ref storage, ..}) => Some(storage.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn check_skeens1(&self, write_id: Uuid, timestamp: Time) -> bool {
         let status = self.append_status.get(&write_id);
         if let Some(status) = status {
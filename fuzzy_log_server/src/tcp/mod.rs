@@ -2,12 +2,19 @@ use std::collections::hash_map::Entry as HashEntry;
 use std::io::{self, Read, Write};
 use std::thread;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::sync::atomic::{AtomicUsize, Ordering};
 // use std::time::Duration;
 
 // use prelude::*;
-use ::{spsc, ServerLog};
+use ::{spsc, DistributeToWorkers, Recovery, ServerLog, ToReplicate};
+use buffer::Buffer;
+use access::AccessControl;
+use admission::{Admission, AdmissionControl, FairQueue};
+use quota::{QuotaEnforcer, Quotas};
+use retention::{Retainer, Retention};
+use tiered::{Spiller, TieredStorage};
+use shards::{ShardMap, Shards};
 use hash::HashMap;
 use socket_addr::Ipv4SocketAddr;
 
@@ -146,6 +153,8 @@ const NUMBER_READ_BUFFERS: usize = 15;
 
 type WorkerNum = usize;
 
+type LogTag = (WorkerNum, mio::Token, Ipv4SocketAddr);
+
 pub fn run_server(
     addr: SocketAddr,
     server_num: u32,
@@ -181,12 +190,134 @@ pub fn run_with_replication(
     num_workers: usize,
     ready: &AtomicUsize,
 ) -> ! {
-    use std::cmp::max;
+    run_with_config(
+        acceptor,
+        this_server_num,
+        total_chain_servers,
+        prev_server,
+        next_server,
+        num_workers,
+        ServerConfig::new(),
+        ready,
+    )
+}
+
+/// Run a server whose chains are split between `num_ordering_threads`
+/// ordering threads, see `shards` for details.
+pub fn run_sharded(
+    acceptor: TcpListener,
+    this_server_num: u32,
+    total_chain_servers: u32,
+    prev_server: Option<SocketAddr>,
+    next_server: Option<IpAddr>,
+    num_workers: usize,
+    num_ordering_threads: usize,
+    ready: &AtomicUsize,
+) -> ! {
+    run_with_config(
+        acceptor,
+        this_server_num,
+        total_chain_servers,
+        prev_server,
+        next_server,
+        num_workers,
+        ServerConfig::new().ordering_threads(num_ordering_threads),
+        ready,
+    )
+}
+
+/// The optional parts of a server, by default
+/// one ordering thread, open to everyone, keeping everything in memory forever,
+/// without limits on what clients append.
+pub struct ServerConfig {
+    num_ordering_threads: usize,
+    access: AccessControl,
+    retention: Retention,
+    storage: TieredStorage,
+    admission: AdmissionControl,
+    quotas: Arc<QuotaEnforcer>,
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        ServerConfig {
+            num_ordering_threads: 1,
+            access: AccessControl::open(),
+            retention: Retention::none(),
+            storage: TieredStorage::in_memory(),
+            admission: AdmissionControl::unlimited(),
+            quotas: Arc::new(QuotaEnforcer::new(Quotas::none())),
+        }
+    }
+
+    /// Split the chains between `num` ordering threads, see `shards`.
+    pub fn ordering_threads(self, num: usize) -> Self {
+        ServerConfig { num_ordering_threads: num, ..self }
+    }
+
+    /// Only let clients use the colors `access` allows, see `access`.
+    pub fn access(self, access: AccessControl) -> Self {
+        ServerConfig { access: access, ..self }
+    }
+
+    /// Trim colors according to `retention`, see `retention`.
+    pub fn retention(self, retention: Retention) -> Self {
+        ServerConfig { retention: retention, ..self }
+    }
+
+    /// Keep at most about `storage`s memory budget of entries in memory,
+    /// moving older ones to disk, see `tiered`.
+    pub fn tiered_storage(self, storage: TieredStorage) -> Self {
+        ServerConfig { storage: storage, ..self }
+    }
+
+    /// Ask clients to retry appends later once they, or the server,
+    /// have more outstanding than `admission` allows, see `admission`.
+    pub fn admission(self, admission: AdmissionControl) -> Self {
+        ServerConfig { admission: admission, ..self }
+    }
+
+    /// Refuse appends exceeding the quotas of `quotas`, see `quota`.
+    /// The caller can keep a clone of `quotas` to read what each tenant used.
+    pub fn quotas(self, quotas: Arc<QuotaEnforcer>) -> Self {
+        ServerConfig { quotas: quotas, ..self }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig::new()
+    }
+}
+
+pub fn run_with_config(
+    acceptor: TcpListener,
+    this_server_num: u32,
+    total_chain_servers: u32,
+    prev_server: Option<SocketAddr>,
+    next_server: Option<IpAddr>,
+    num_workers: usize,
+    config: ServerConfig,
+    ready: &AtomicUsize,
+) -> ! {
+    use std::cmp::{max, min};
+
+    let ServerConfig {
+        num_ordering_threads, access, retention, storage, admission, quotas,
+    } = config;
+    let access = Arc::new(access);
+    // the head decides what is trimmed, the replicas follow it, see `retention`
+    let retention = Arc::new(if prev_server.is_none() { retention } else { Retention::none() });
+    let storage = Arc::new(storage);
+    let admission = Arc::new(Admission::new(admission));
 
     //let (dist_to_workers, recv_from_dist) = spmc::channel();
     //let (log_to_workers, recv_from_log) = spmc::channel();
+    let num_shards = max(num_ordering_threads, 1);
+    let shard_map = ShardMap::new(this_server_num, total_chain_servers, num_shards);
     //TODO or sync channel?
-    let (workers_to_log, recv_from_workers) = mpsc::channel();
+    let (workers_to_log, recv_from_workers): (Vec<_>, Vec<_>) =
+        (0..num_shards).map(|_| mpsc::channel()).unzip();
     let (workers_to_dist, dist_from_workers) = mio::channel::channel();
     if num_workers == 0 {
         warn!("SERVER {} started with 0 workers.", this_server_num);
@@ -274,7 +405,9 @@ pub fn run_with_replication(
     // drop(downstream_admin_socket);
     // drop(upstream_admin_socket);
 
-    let mut log_to_workers: Vec<_> = Vec::with_capacity(num_workers);
+    //for each ordering thread, a channel to each worker
+    let mut log_to_workers: Vec<Vec<_>> =
+        (0..num_shards).map(|_| Vec::with_capacity(num_workers)).collect();
     let mut dist_to_workers: Vec<_> = Vec::with_capacity(num_workers);
     let (log_writer, log_reader) = ::new_chain_store_and_reader();
     for n in 0..num_workers {
@@ -282,16 +415,24 @@ pub fn run_with_replication(
         let to_dist   = workers_to_dist.clone();
         //let from_log  = recv_from_log.clone();
         let to_log = workers_to_log.clone();
-        let (to_worker, from_log) = spsc::channel();
+        let (to_worker, from_log): (Vec<_>, Vec<_>) =
+            (0..num_shards).map(|_| spsc::channel()).unzip();
         let (dist_to_worker, from_dist) = spsc::channel();
         let log_reader = log_reader.clone();
+        let access = access.clone();
+        let admission = admission.clone();
+        let quotas = quotas.clone();
         thread::spawn(move ||
             Worker::new(
                 from_dist,
                 to_dist,
                 from_log,
                 to_log,
+                shard_map,
                 log_reader,
+                access,
+                admission,
+                quotas,
                 num_workers,
                 is_unreplicated,
                 prev_server.is_some(),
@@ -299,7 +440,9 @@ pub fn run_with_replication(
                 n,
             ).run()
         );
-        log_to_workers.push(to_worker);
+        for (shard, to_worker) in to_worker.into_iter().enumerate() {
+            log_to_workers[shard].push(to_worker);
+        }
         dist_to_workers.push(dist_to_worker);
     }
     assert_eq!(dist_to_workers.len(), num_workers);
@@ -312,43 +455,81 @@ pub fn run_with_replication(
         // mio::Ready::readable(),
         // mio::PollOpt::level()
     // ).expect("cannot pol from log on dist");
-    thread::spawn(move || {
-        let mut log = ServerLog::new(
-            this_server_num, total_chain_servers, log_to_workers, log_writer
-        );
-        #[cfg(not(feature = "print_stats"))]
-        for to_log in recv_from_workers.iter() {
-            match to_log {
-                ToLog::New(buffer, storage, st) => {
-                    // assert!(!is_replica);
-                    log.handle_op(buffer, storage, st)
-                },
-                ToLog::Replication(tr, st) => {
-                    // assert!(is_replica);
-                    log.handle_replication(tr, st)
-                },
-                ToLog::Recovery(r, st) => log.handle_recovery(r, st),
-            }
-        }
-        #[cfg(feature = "print_stats")]
-        loop {
+    let shards = Arc::new(Shards::new(shard_map, log_writer));
+    let shard_channels = recv_from_workers.into_iter().zip(log_to_workers.into_iter());
+    for (shard, (recv_from_workers, log_to_workers)) in shard_channels.enumerate() {
+        let shards = shards.clone();
+        let mut retainer = Retainer::new(retention.clone());
+        let mut spiller = Spiller::new(storage.clone(), num_shards);
+        let admission = admission.clone();
+        let quotas = quotas.clone();
+        thread::spawn(move || {
             use std::sync::mpsc::RecvTimeoutError;
-            let msg = recv_from_workers.recv_timeout(Duration::from_secs(10));
-            match msg {
-                Ok(ToLog::New(buffer, storage, st)) => {
-                    assert!(!is_replica);
-                    log.handle_op(buffer, storage, st)
-                },
-                Ok(ToLog::Replication(tr, st)) => {
-                    assert!(is_replica);
-                    log.handle_replication(tr, st)
-                },
-                Ok(ToLog::Recovery(r, st)) => log.handle_recovery(r, st),
-                Err(RecvTimeoutError::Timeout) => log.print_stats(),
-                Err(RecvTimeoutError::Disconnected) => panic!("log disconnected"),
+            let mut log = ServerLog::new_shard(log_to_workers, shards.clone(), shard);
+            if !quotas.is_empty() {
+                log.set_quotas(quotas)
             }
-        }
-    });
+            #[cfg(not(feature = "print_stats"))]
+            let mut queued = FairQueue::new();
+            #[cfg(not(feature = "print_stats"))]
+            loop {
+                let until_check = match (retainer.until_check(), spiller.until_check()) {
+                    (Some(r), Some(s)) => Some(min(r, s)),
+                    (r, s) => r.or(s),
+                };
+                // appends may wait on a transaction another ordering thread finishes
+                let until_check = if log.has_waiting_for_reads() {
+                    let retry = Duration::from_millis(1);
+                    Some(until_check.map_or(retry, |check| min(check, retry)))
+                } else {
+                    until_check
+                };
+                let msg = match (queued.pop(), until_check) {
+                    (Some(to_log), _) => Ok(to_log),
+                    (None, None) =>
+                        recv_from_workers.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    (None, Some(timeout)) => recv_from_workers.recv_timeout(timeout),
+                };
+                match msg {
+                    Ok(to_log) => handle_to_log(&mut log, &shards, shard, &admission, to_log),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                // take turns between the clients with work waiting,
+                // instead of doing it in the order it arrived
+                for to_log in recv_from_workers.try_iter() {
+                    queued.push(client_of(&to_log), to_log)
+                }
+                if retainer.check_due() {
+                    apply_retention(&mut log, &shards, shard, &mut retainer)
+                }
+                if spiller.check_due() {
+                    spill_cold_entries(&mut log, &shards, shard, &mut spiller)
+                }
+                if log.has_waiting_for_reads() {
+                    retry_waiting_for_reads(&mut log, &shards, shard)
+                }
+            }
+            #[cfg(feature = "print_stats")]
+            loop {
+                let msg = recv_from_workers.recv_timeout(Duration::from_secs(10));
+                match msg {
+                    Ok(to_log) => handle_to_log(&mut log, &shards, shard, &admission, to_log),
+                    Err(RecvTimeoutError::Timeout) => log.print_stats(),
+                    Err(RecvTimeoutError::Disconnected) => panic!("log disconnected"),
+                }
+                if retainer.check_due() {
+                    apply_retention(&mut log, &shards, shard, &mut retainer)
+                }
+                if spiller.check_due() {
+                    spill_cold_entries(&mut log, &shards, shard, &mut spiller)
+                }
+                if log.has_waiting_for_reads() {
+                    retry_waiting_for_reads(&mut log, &shards, shard)
+                }
+            }
+        });
+    }
 
     poll.register(&dist_from_workers,
         FROM_WORKERS,
@@ -369,7 +550,7 @@ pub fn run_with_replication(
         (None,           Some(..)) => socket_negotiate::Position::Head,
         (Some(upstream), Some(..)) => socket_negotiate::Position::Mid(upstream),
     };
-    let mut negotiator = socket_negotiate::Negotiator::new(position);
+    let mut negotiator = socket_negotiate::Negotiator::new(position, access);
 
     // for (mut socket, addr) in other_sockets {
     //     let up_tok = get_next_token(&mut next_token);
@@ -398,13 +579,14 @@ pub fn run_with_replication(
                             let _ = socket.set_nodelay(true);
                             //TODO oveflow
                             let client = negotiator.got_connection(socket, &mut poll, || get_next_token(&mut next_token));
-                            if let Ok((id, up_tok, upstream, down)) = client {
+                            if let Ok((id, up_tok, upstream, down, principal)) = client {
                                 let worker = worker_for_ip(id, num_workers as u64);
                                 let old = worker_for_client.insert(id, (worker, up_tok));
                                 assert!(old.is_none(), "Duplicate id {:?}", id);
                                 // println!("SERVER accepting connection @ {:?}, {:?}", (_addr, id), (worker, up_tok));
-                                dist_to_workers[worker]
-                                    .send(DistToWorker::NewClient(up_tok, upstream, down, id));
+                                dist_to_workers[worker].send(
+                                    DistToWorker::NewClient(up_tok, upstream, down, id, principal)
+                                );
                                 // accepted += 1;
                                 // println!("accepted {:?}", accepted);
                             }
@@ -417,7 +599,7 @@ pub fn run_with_replication(
 
                 recv_tok => {
                     let client = negotiator.handle_event(recv_tok, &mut poll);
-                    if let Ok((id, up_tok, upstream, down)) = client {
+                    if let Ok((id, up_tok, upstream, down, principal)) = client {
                         let worker = worker_for_ip(id, num_workers as u64);
                         let old = worker_for_client.insert(id, (worker, up_tok));
                         assert!(old.is_none(), "Duplicate id {:?}", id);
@@ -427,7 +609,7 @@ pub fn run_with_replication(
                         //     down.as_ref().map(|&(_, ref d)| d.peer_addr()),
                         //     id, (worker, up_tok));
                         dist_to_workers[worker]
-                            .send(DistToWorker::NewClient(up_tok, upstream, down, id));
+                            .send(DistToWorker::NewClient(up_tok, upstream, down, id, principal));
                         // accepted += 1;
                         // println!("accepted {:?}", accepted);
                     }
@@ -1397,6 +1579,94 @@ mod tests {
     }
 }
 
+fn handle_to_log<W>(
+    log: &mut ServerLog<LogTag, W>,
+    shards: &Shards<LogTag>,
+    shard: usize,
+    admission: &Admission,
+    to_log: ToLog<LogTag>,
+)
+where W: DistributeToWorkers<LogTag> {
+    let _locked = match op_to_lock(&to_log) {
+        Some(buffer) => shards.lock_op(shard, buffer.contents()),
+        None => shards.lock(shard, &[]),
+    };
+    match to_log {
+        ToLog::New(buffer, storage, st) => {
+            if admission.counts(buffer.contents()) {
+                admission.finished(st.2, buffer.contents().len() as u64)
+            }
+            log.handle_op(buffer, storage, st)
+        },
+        ToLog::Replication(tr, st) => log.handle_replication(tr, st),
+        ToLog::Recovery(r, st) => log.handle_recovery(r, st),
+        ToLog::CitedBy(buffer, st) => log.handle_cited_by(buffer, st),
+    }
+}
+
+fn apply_retention<W>(
+    log: &mut ServerLog<LogTag, W>,
+    shards: &Shards<LogTag>,
+    shard: usize,
+    retainer: &mut Retainer,
+)
+where W: DistributeToWorkers<LogTag> {
+    // what is cited from other shards decides what can be trimmed
+    let _locked = shards.lock_all(shard);
+    log.apply_retention(retainer, shard)
+}
+
+fn retry_waiting_for_reads<W>(
+    log: &mut ServerLog<LogTag, W>,
+    shards: &Shards<LogTag>,
+    shard: usize,
+)
+where W: DistributeToWorkers<LogTag> {
+    log.retry_waiting_for_reads(|op| shards.lock_op(shard, op))
+}
+
+fn spill_cold_entries<W>(
+    log: &mut ServerLog<LogTag, W>,
+    shards: &Shards<LogTag>,
+    shard: usize,
+    spiller: &mut Spiller,
+)
+where W: DistributeToWorkers<LogTag> {
+    let _locked = shards.lock(shard, &[]);
+    log.spill_cold_entries(spiller, shard)
+}
+
+fn client_of(to_log: &ToLog<LogTag>) -> Ipv4SocketAddr {
+    match *to_log {
+        ToLog::New(_, _, (_, _, client))
+        | ToLog::Replication(_, (_, _, client))
+        | ToLog::Recovery(_, (_, _, client))
+        | ToLog::CitedBy(_, (_, _, client)) => client,
+    }
+}
+
+fn op_to_lock<T>(to_log: &ToLog<T>) -> Option<&Buffer> {
+    let buffer = match *to_log {
+        ToLog::New(ref buffer, ..) => buffer,
+        ToLog::Replication(ref tr, _) => match *tr {
+            ToReplicate::Data(ref buffer, _)
+            | ToReplicate::Multi(ref buffer, _)
+            | ToReplicate::Skeens1(ref buffer, _)
+            | ToReplicate::SingleSkeens1(ref buffer, _)
+            | ToReplicate::SnapshotSkeens1(ref buffer, _)
+            | ToReplicate::Skeens2(ref buffer)
+            | ToReplicate::GC(ref buffer)
+            | ToReplicate::TasRecoverer(ref buffer, _) => buffer,
+            ToReplicate::UnLock(..) => return None,
+        },
+        ToLog::Recovery(Recovery::TasRecoverer(ref buffer, _), _)
+        | ToLog::Recovery(Recovery::CheckSkeens1(ref buffer), _) => buffer,
+        // the citation index does its own locking
+        ToLog::CitedBy(..) => return None,
+    };
+    Some(buffer)
+}
+
 fn get_next_token(token: &mut mio::Token) -> mio::Token {
     *token = mio::Token(token.0.checked_add(1).unwrap());
     *token
@@ -4,12 +4,17 @@ use std::io::{self, Read};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use mio;
 use mio::tcp::*;
 
 use socket_addr::Ipv4SocketAddr as ClientId;
 
+use packets::hello::{self, Credentials, Hello, Rejection};
+
+use access::AccessControl;
+
 use hash::{HashMap, IdHashMap};
 
 use self::Position::*;
@@ -25,11 +30,18 @@ use self::Position::*;
 //         /
 // got down - I'm mid -> send up -> WaitForUpAck - send down -> Done
 
-// connection
-// 1. server writes 0
-// 2. down sends 1, client sends 2
-// 3. down/client sends id
-// 4. server sends id
+// connection, see packets::hello
+// 1. server writes HANDSHAKE_VERSIONED
+// 2. down sends 1, client sends 2, | VERSIONED if they send a hello
+// 3. down/client sends hello if versioned, then credentials if the hello requires AUTH
+// 4. down/client sends id
+// 5. server sends hello reply if versioned
+// 6. server sends id
+//
+// down peers are authenticated like clients, a server forwards the credentials
+// of the client (or down peer) it connects up for, so every server in a chain
+// binds the connection to the same principal. Only servers with a down peer,
+// Mid and Head, take down peers, and only Head takes both kinds.
 
 #[derive(Debug)]
 pub struct NegotiateState {
@@ -38,6 +50,10 @@ pub struct NegotiateState {
     server_ready: Reader<[u8; 1]>,
     id: IdRead,
     down_type: DownRead,
+    hello: HelloRead,
+    credentials: CredentialsRead,
+    // set once the peer is authenticated
+    principal: Option<String>,
     up: Option<UpState>
 }
 
@@ -47,6 +63,9 @@ struct UpState {
     server_ready: Reader<[u8; 1]>,
     id2: IdRead,
     token: mio::Token,
+    sent_id: bool,
+    // the upstream server's hello reply, if it sends one
+    reply: Option<Reader<Vec<u8>>>,
 }
 
 impl NegotiateState {
@@ -58,9 +77,11 @@ impl NegotiateState {
                 FromRawFd::from_raw_fd(up.upstream.into_raw_fd())
             });
             let downstream = FromRawFd::from_raw_fd(self.downstream.into_raw_fd());
+            let principal = self.principal.expect("unauthenticated peer");
             match up {
-                None => (self.id.unwrap(), token, downstream, None),
-                Some(upstream) => (self.id.unwrap(), token, upstream, Some((token, downstream))),
+                None => (self.id.unwrap(), token, downstream, None, principal),
+                Some(upstream) =>
+                    (self.id.unwrap(), token, upstream, Some((token, downstream)), principal),
             }
         }
     }
@@ -110,10 +131,13 @@ enum DownRead {
 }
 
 impl DownRead {
-    fn try_read_from<R: Read>(&mut self, read: R) -> Result<Option<ClientType>, io::Error> {
-        let kind = match self {
-            &mut DownRead::Client => return Ok(Some(ClientType::Client)),
-            &mut DownRead::Server => return Ok(Some(ClientType::Server)),
+    /// On the read which completes the type, also returns whether the peer
+    /// will send a hello.
+    fn try_read_from<R: Read>(&mut self, read: R)
+    -> Result<Option<(ClientType, Option<bool>)>, io::Error> {
+        let (kind, versioned) = match self {
+            &mut DownRead::Client => return Ok(Some((ClientType::Client, None))),
+            &mut DownRead::Server => return Ok(Some((ClientType::Server, None))),
             &mut DownRead::Pending(ref mut reader) => {
                 let kind = reader.try_read_from(read)?;
                 match kind {
@@ -126,7 +150,7 @@ impl DownRead {
             ClientType::Client => DownRead::Client,
             ClientType::Server => DownRead::Server,
         };
-        Ok(Some(kind))
+        Ok(Some((kind, Some(versioned))))
     }
 }
 
@@ -136,15 +160,100 @@ impl Default for DownRead {
     }
 }
 
+#[derive(Debug)]
+enum HelloRead {
+    // waiting to find out if the peer sends one
+    Unknown,
+    Legacy,
+    Pending(Reader<Vec<u8>>),
+    Done(Hello),
+}
+
+impl HelloRead {
+    fn pending() -> Self {
+        HelloRead::Pending(Reader { buffer: vec![0; hello::HELLO_SIZE], read: 0 })
+    }
+
+    /// Returns the peer's hello, and whether it actually sent one.
+    fn try_read_from<R: Read>(&mut self, read: R) -> Result<Option<(Hello, bool)>, io::Error> {
+        let hello = match self {
+            &mut HelloRead::Unknown => return Ok(None),
+            &mut HelloRead::Legacy => return Ok(Some((Hello::legacy(), false))),
+            &mut HelloRead::Done(hello) => return Ok(Some((hello, true))),
+            &mut HelloRead::Pending(ref mut reader) => {
+                if !reader.try_fill_from(read)? {
+                    return Ok(None)
+                }
+                Hello::from_bytes(&reader.buffer).map_err(malformed)?
+            },
+        };
+        *self = HelloRead::Done(hello);
+        Ok(Some((hello, true)))
+    }
+}
+
+fn malformed(rejection: Rejection) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, rejection.to_string())
+}
+
+#[derive(Debug)]
+enum CredentialsRead {
+    // waiting for the peer's hello
+    Unknown,
+    Pending(Reader<Vec<u8>>),
+    Done(Option<Credentials>),
+}
+
+impl CredentialsRead {
+    /// Returns the peer's credentials, if its hello said it would send them.
+    fn try_read_from<R: Read>(&mut self, peer_hello: &Hello, read: R)
+    -> Result<Option<Option<Credentials>>, io::Error> {
+        if let CredentialsRead::Unknown = *self {
+            *self = if peer_hello.required_features & hello::feature::AUTH != 0 {
+                CredentialsRead::Pending(Reader {
+                    buffer: vec![0; hello::CREDENTIALS_SIZE], read: 0,
+                })
+            } else {
+                CredentialsRead::Done(None)
+            }
+        }
+        let credentials = match self {
+            &mut CredentialsRead::Unknown => unreachable!(),
+            &mut CredentialsRead::Done(credentials) => return Ok(Some(credentials)),
+            &mut CredentialsRead::Pending(ref mut reader) => {
+                if !reader.try_fill_from(read)? {
+                    return Ok(None)
+                }
+                Credentials::from_bytes(&reader.buffer).map_err(malformed)?
+            },
+        };
+        *self = CredentialsRead::Done(Some(credentials));
+        Ok(Some(Some(credentials)))
+    }
+
+    /// The credentials the peer sent, once they have been read.
+    fn sent(&self) -> Option<Credentials> {
+        match *self {
+            CredentialsRead::Done(credentials) => credentials,
+            _ => None,
+        }
+    }
+}
+
 type R<T> = Rc<RefCell<T>>;
 
-pub type NewClient = (ClientId, mio::Token, TcpStream, Option<(mio::Token, TcpStream)>);
+/// The last field is the client's principal, see `access`.
+pub type NewClient = (
+    ClientId, mio::Token, TcpStream, Option<(mio::Token, TcpStream)>, String
+);
 
 #[derive(Debug)]
 pub struct Negotiator {
     for_id: IdHashMap<ClientId, R<NegotiateState>>,
     for_token: HashMap<mio::Token, R<NegotiateState>>,
     position: Position,
+    hello: Hello,
+    access: Arc<AccessControl>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -155,6 +264,17 @@ pub enum Position {
 #[derive(Debug, Copy, Clone)]
 pub struct NegotiateNotDone;
 
+impl Position {
+    fn takes(&self, kind: ClientType) -> bool {
+        match (*self, kind) {
+            (Head, _) => true,
+            (Mid(..), ClientType::Server) => true,
+            (Solo, ClientType::Client) | (Tail(..), ClientType::Client) => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<io::Error> for NegotiateNotDone {
     fn from(_: io::Error) -> Self {
         NegotiateNotDone
@@ -168,11 +288,13 @@ impl From<()> for NegotiateNotDone {
 }
 
 impl Negotiator {
-    pub fn new(position: Position) -> Self {
+    pub fn new(position: Position, access: Arc<AccessControl>) -> Self {
         Self {
             for_id: Default::default(),
             for_token: Default::default(),
             position,
+            hello: Hello::current(0),
+            access,
         }
     }
 
@@ -180,7 +302,7 @@ impl Negotiator {
         &mut self, mut socket: TcpStream, poll: &mut mio::Poll, mut get_next_token: NextToken
     ) -> Result<NewClient, NegotiateNotDone>
     where NextToken: FnMut() -> mio::Token {
-        super::blocking_write(&mut socket, &[0]).unwrap();
+        super::blocking_write(&mut socket, &[hello::HANDSHAKE_VERSIONED]).unwrap();
         let token = get_next_token();
         poll.register(&socket, token, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
         self.for_token.insert(token, Rc::new(NegotiateState {
@@ -188,12 +310,35 @@ impl Negotiator {
             token,
             server_ready: Reader{ buffer: [0; 1], read: 1},
             down_type: Default::default(),
+            hello: HelloRead::Unknown,
+            credentials: CredentialsRead::Unknown,
+            principal: None,
             id: Default::default(),
             up: None,
         }.into()));
         self.handle_event(token, poll)
     }
 
+    /// Peers whose handshake cannot be parsed are dropped without a reply,
+    /// since we cannot tell what they expect to read.
+    fn drop_if_invalid<T>(
+        &mut self,
+        read: Result<Option<T>, io::Error>,
+        token: mio::Token,
+        socket: &TcpStream,
+        poll: &mut mio::Poll,
+    ) -> Result<T, NegotiateNotDone> {
+        match read {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                error!("rejecting {:?}: {}", token, e);
+                drop(self.for_token.remove(&token));
+                let _ = poll.deregister(socket);
+                Err(NegotiateNotDone)
+            },
+            read => Ok(read?.ok_or(())?),
+        }
+    }
+
     pub fn handle_event(
         &mut self, token: mio::Token, poll: &mut mio::Poll,
     ) -> Result<NewClient, NegotiateNotDone> {
@@ -206,8 +351,51 @@ impl Negotiator {
             let negotiation = &mut *negotiation;
             let ready = negotiation.server_ready.try_fill_from(&mut negotiation.downstream)?;
             if !ready { return Err(())? }
-            kind = negotiation.down_type.try_read_from(&mut negotiation.downstream)?.ok_or(())?;
-            negotiation.id.try_read_from(&mut negotiation.downstream)?.ok_or(())?
+            let down_type = negotiation.down_type.try_read_from(&mut negotiation.downstream);
+            let (down_type, versioned) =
+                self.drop_if_invalid(down_type, token, &negotiation.downstream, poll)?;
+            kind = down_type;
+            match versioned {
+                Some(true) => negotiation.hello = HelloRead::pending(),
+                Some(false) => negotiation.hello = HelloRead::Legacy,
+                None => {},
+            }
+            let peer_hello = negotiation.hello.try_read_from(&mut negotiation.downstream);
+            let (peer_hello, sent_hello) =
+                self.drop_if_invalid(peer_hello, token, &negotiation.downstream, poll)?;
+            let credentials = negotiation.credentials
+                .try_read_from(&peer_hello, &mut negotiation.downstream);
+            let credentials =
+                self.drop_if_invalid(credentials, token, &negotiation.downstream, poll)?;
+            let (id, first) = negotiation.id.try_read_from(&mut negotiation.downstream)?
+                .ok_or(())?;
+            if first {
+                let mut negotiated = self.hello.negotiate(&peer_hello);
+                if negotiated.is_ok() && !self.position.takes(kind) {
+                    negotiated = Err(Rejection::UnexpectedPeer)
+                }
+                if negotiated.is_ok() {
+                    match self.access.authenticate(credentials.as_ref()) {
+                        Some(principal) => {
+                            trace!("{:?} is {:?}", id, principal);
+                            negotiation.principal = Some(principal)
+                        },
+                        None => negotiated = Err(Rejection::BadCredentials),
+                    }
+                }
+                // peers without hellos get no reply
+                if sent_hello {
+                    let reply = hello::reply_bytes(&self.hello, &negotiated);
+                    super::blocking_write(&mut negotiation.downstream, &reply).unwrap();
+                }
+                if let Err(rejection) = negotiated {
+                    error!("rejecting {:?}: {}", id, rejection);
+                    drop(self.for_token.remove(&token));
+                    let _ = poll.deregister(&negotiation.downstream);
+                    return Err(NegotiateNotDone)
+                }
+            }
+            (id, first)
         };
         {
             let other = self.for_id.entry(id);
@@ -223,7 +411,15 @@ impl Negotiator {
                 Entry::Occupied(o) => {
                     let other_ref = o.into_mut();
                     if first && !Rc::ptr_eq(&negotiation_ref, other_ref) {
-                        assert_eq!(self.position, Head);
+                        let same_kind =
+                            other_ref.borrow().down_type == negotiation_ref.borrow().down_type;
+                        // only at the head do a client and a down peer share an id
+                        if self.position != Head || same_kind {
+                            error!("rejecting {:?}: the id is in use", id);
+                            drop(self.for_token.remove(&token));
+                            let _ = poll.deregister(&negotiation_ref.borrow().downstream);
+                            return Err(NegotiateNotDone)
+                        }
                         // in some cases we must remove one that got here earlier and make it upstream
                         // @Head: server is downstream, client is upstream
                         match kind {
@@ -274,11 +470,14 @@ impl Negotiator {
                             debug_assert_eq!(from.down_type, DownRead::Client);
                             debug_assert_eq!(to.down_type, DownRead::Server);
                             debug_assert!(to.up.is_none());
+                            to.principal = from.principal;
                             to.up = Some(UpState {
                                 upstream: from.downstream,
                                 server_ready: from.server_ready,
                                 id2: from.id,
                                 token: from.token,
+                                sent_id: true,
+                                reply: None,
                             });
                         }
                     }
@@ -294,11 +493,7 @@ impl Negotiator {
                     super::blocking_write(&mut negotiation.downstream, id.bytes()).unwrap();
                 },
                 &Tail(upstream_addr) | &Mid(upstream_addr) => {
-                    match &self.position {
-                        &Tail(..) => assert_eq!(kind, ClientType::Client),
-                        &Mid(..) => assert_eq!(kind, ClientType::Server),
-                        _ => unreachable!()
-                    }
+                    debug_assert!(self.position.takes(kind));
                     if let None = negotiation.up {
                         let mut upstream = TcpStream::connect(&upstream_addr).unwrap();
                         let _ = upstream.set_keepalive_ms(Some(1000));
@@ -310,21 +505,51 @@ impl Negotiator {
                             server_ready: Default::default(),
                             id2: Default::default(),
                             token: token,
+                            sent_id: false,
+                            reply: None,
                         });
                     }
                     let up = negotiation.up.as_mut().unwrap();
                     if !up.server_ready.try_fill_from(&up.upstream)? {
                         return Err(())?
                     }
-                    super::blocking_write(&mut up.upstream, &mut [1]).unwrap();
-                    super::blocking_write(&mut up.upstream, id.bytes()).unwrap();
+                    if !up.sent_id {
+                        // the upstream server authenticates our peer with its own credentials
+                        let credentials = negotiation.credentials.sent();
+                        let ours = match credentials {
+                            Some(..) => {
+                                let required = self.hello.required_features | hello::feature::AUTH;
+                                Hello { required_features: required, ..self.hello }
+                            },
+                            None => self.hello,
+                        };
+                        let (bytes, versioned) =
+                            hello::peer_hello_bytes(up.server_ready.buffer[0], 1, &ours)
+                            .unwrap_or_else(|rejection| panic!(
+                                "cannot replicate from {}: {}", upstream_addr, rejection
+                            ));
+                        super::blocking_write(&mut up.upstream, &bytes).unwrap();
+                        if let Some(credentials) = credentials {
+                            super::blocking_write(&mut up.upstream, &credentials.to_bytes()).unwrap();
+                        }
+                        super::blocking_write(&mut up.upstream, id.bytes()).unwrap();
+                        if versioned {
+                            up.reply = Some(Reader {
+                                buffer: vec![0; hello::HELLO_REPLY_SIZE], read: 0,
+                            });
+                        }
+                        up.sent_id = true;
+                    }
+                    if let Some(ref mut reply) = up.reply {
+                        if !reply.try_fill_from(&up.upstream)? {
+                            return Err(())?
+                        }
+                        if let Err(rejection) = hello::check_reply(&self.hello, &reply.buffer) {
+                            panic!("{} refused replication: {}", upstream_addr, rejection)
+                        }
+                    }
                     let (id2, _) = up.id2.try_read_from(&up.upstream)?.ok_or(())?;
                     assert_eq!(id2, id);
-                    match &self.position {
-                        &Tail(..) => assert_eq!(kind, ClientType::Client),
-                        &Mid(..) => assert_eq!(kind, ClientType::Server),
-                        _ => unreachable!()
-                    }
                     super::blocking_write(&mut negotiation.downstream, id.bytes()).unwrap();
                 },
                 &Head => {
@@ -387,15 +612,20 @@ enum ClientType {
 }
 
 impl ClientTypeReader {
-    fn try_read_from<R: Read>(&mut self, read: R) -> Result<Option<ClientType>, io::Error> {
+    /// Returns the type, and whether the peer will send a hello.
+    fn try_read_from<R: Read>(&mut self, read: R)
+    -> Result<Option<(ClientType, bool)>, io::Error> {
         let done = self.buffer.try_fill_from(read)?;
         if !done {
             return Ok(None)
         }
-        match self.buffer.buffer[0] {
-            1 => Ok(Some(ClientType::Server)),
-            2 => Ok(Some(ClientType::Client)),
-            other => unreachable!("{:?}", other),
+        let versioned = self.buffer.buffer[0] & hello::VERSIONED != 0;
+        match self.buffer.buffer[0] & !hello::VERSIONED {
+            1 => Ok(Some((ClientType::Server, versioned))),
+            2 => Ok(Some((ClientType::Client, versioned))),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unknown peer type {}", other)
+            )),
         }
     }
 }
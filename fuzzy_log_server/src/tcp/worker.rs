@@ -1,18 +1,22 @@
 #![allow(non_snake_case)]
 
 use std::collections::VecDeque;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
 use ::{
     spsc, worker_thread, ToReplicate, ToWorker,
     DistributeToWorkers, Troption, Recovery, SkeensMultiStorage,
     ToSend, ChainReader,
 };
+use access::{self, AccessControl};
+use admission::Admission;
+use quota::QuotaEnforcer;
+use shards::ShardMap;
 use shared_slice::RcSlice;
 use hash::HashMap;
 use socket_addr::Ipv4SocketAddr;
 
-use packets::{EntryLayout, OrderIndex, EntryFlag};
+use packets::{EntryLayout, EntryKind, OrderIndex, EntryFlag, reject};
 
 use mio;
 use mio::tcp::*;
@@ -37,7 +41,9 @@ pub enum WorkerToDist {
 
 #[allow(dead_code)]
 pub enum DistToWorker {
-    NewClient(mio::Token, TcpStream, Option<(mio::Token, TcpStream)>, Ipv4SocketAddr),
+    NewClient(
+        mio::Token, TcpStream, Option<(mio::Token, TcpStream)>, Ipv4SocketAddr, String
+    ),
     FenceOff(mio::Token, Buffer),
     FinishedFence(mio::Token, Buffer),
 }
@@ -46,9 +52,8 @@ pub enum ToLog<T> {
     //TODO test different layouts.
     New(Buffer, Troption<SkeensMultiStorage, Box<(RcSlice, RcSlice)>>, T),
     Replication(ToReplicate, T),
-
-    #[allow(dead_code)]
     Recovery(Recovery, T),
+    CitedBy(Buffer, T),
 }
 
 pub struct Worker {
@@ -59,10 +64,17 @@ pub struct Worker {
 pub struct WorkerInner {
     from_dist: spsc::Receiver<DistToWorker>,
     to_dist: mio::channel::Sender<WorkerToDist>,
-    from_log: spsc::Receiver<ToWorker<(WorkerNum, mio::Token, Ipv4SocketAddr)>>,
-    to_log: mpsc::Sender<ToLog<(WorkerNum, mio::Token, Ipv4SocketAddr)>>,
+    //one per ordering thread
+    from_log: Vec<spsc::Receiver<ToWorker<(WorkerNum, mio::Token, Ipv4SocketAddr)>>>,
+    to_log: Vec<mpsc::Sender<ToLog<(WorkerNum, mio::Token, Ipv4SocketAddr)>>>,
+    shards: ShardMap,
     log_reader: ChainReader<(WorkerNum, mio::Token, Ipv4SocketAddr)>,
     downstream_for_addr: HashMap<Ipv4SocketAddr, mio::Token>,
+    access: Arc<AccessControl>,
+    admission: Arc<Admission>,
+    quotas: Arc<QuotaEnforcer>,
+    // every negotiated peer has one, those which do not are refused
+    principal_for_addr: HashMap<Ipv4SocketAddr, String>,
     worker_num: WorkerNum,
     num_workers: WorkerNum,
     poll: mio::Poll,
@@ -118,12 +130,14 @@ impl Wakeable for WorkerInner {
             mio::PollOpt::level() //TODO or edge?
         ).expect("cannot pol from dist on worker");
 
-        poll.register(
-            &self.from_log,
-            FROM_LOG,
-            mio::Ready::readable(),
-            mio::PollOpt::level() //TODO or edge?
-        ).expect("cannot pol from log on worker");
+        for from_log in &self.from_log {
+            poll.register(
+                from_log,
+                FROM_LOG,
+                mio::Ready::readable(),
+                mio::PollOpt::level() //TODO or edge?
+            ).expect("cannot pol from log on worker");
+        }
     }
 
     fn needs_to_mark_as_staying_awake(&mut self, _: mio::Token) -> bool { false }
@@ -165,23 +179,34 @@ impl Worker {
     pub fn new(
         from_dist: spsc::Receiver<DistToWorker>,
         to_dist: mio::channel::Sender<WorkerToDist>,
-        from_log: spsc::Receiver<ToWorker<(WorkerNum, mio::Token, Ipv4SocketAddr)>>,
-        to_log: mpsc::Sender<ToLog<(WorkerNum, mio::Token, Ipv4SocketAddr)>>,
+        from_log: Vec<spsc::Receiver<ToWorker<(WorkerNum, mio::Token, Ipv4SocketAddr)>>>,
+        to_log: Vec<mpsc::Sender<ToLog<(WorkerNum, mio::Token, Ipv4SocketAddr)>>>,
+        shards: ShardMap,
         log_reader: ChainReader<(WorkerNum, mio::Token, Ipv4SocketAddr)>,
+        access: Arc<AccessControl>,
+        admission: Arc<Admission>,
+        quotas: Arc<QuotaEnforcer>,
         num_workers: usize,
         is_unreplicated: bool,
         has_upstream: bool,
         has_downstream: bool,
         worker_num: WorkerNum,
     ) -> Self {
+        assert_eq!(from_log.len(), shards.num_shards());
+        assert_eq!(to_log.len(), shards.num_shards());
         let poll = mio::Poll::new().unwrap();
         let inner = WorkerInner {
             from_dist,
             to_dist,
             from_log,
             to_log,
+            shards,
             log_reader,
             downstream_for_addr: HashMap::default(),
+            access,
+            admission,
+            quotas,
+            principal_for_addr: HashMap::default(),
             worker_num,
             num_workers,
             poll,
@@ -209,7 +234,13 @@ impl Worker {
 impl WorkerInner {
 
     fn handle_from_log(&mut self, streams: &mut IoState<PerStream>) {
-        while let Some(log_work) = self.from_log.try_recv() {
+        for shard in 0..self.from_log.len() {
+            self.handle_from_shard(shard, streams)
+        }
+    }
+
+    fn handle_from_shard(&mut self, shard: usize, streams: &mut IoState<PerStream>) {
+        while let Some(log_work) = self.from_log[shard].try_recv() {
             self.print_data.from_log(1);
             let (_wk, recv_token, src_addr) = log_work.get_associated_data();
             debug_assert_eq!(_wk, self.worker_num);
@@ -352,7 +383,7 @@ impl WorkerInner {
             match self.from_dist.try_recv() {
                 None => return,
 
-                Some(DistToWorker::NewClient(tok, upstream, downstream, client_addr)) => {
+                Some(DistToWorker::NewClient(tok, upstream, downstream, client_addr, principal)) => {
                     debug_assert!(tok.0 >= FIRST_CLIENT_TOKEN.0);
                     self.print_data.from_dist_N(1);
                     let upstream_token = next_token(&mut self.next_token).into();
@@ -381,6 +412,7 @@ impl WorkerInner {
                         self.worker_num, (tok, client_addr));
                     let downstream_token = downstream_token.unwrap_or(upstream_token);
                     self.downstream_for_addr.insert(client_addr, downstream_token);
+                    self.principal_for_addr.insert(client_addr, principal);
                 },
 
                 Some(DistToWorker::FenceOff(_token, buffer)) => {
@@ -430,6 +462,93 @@ impl WorkerInner {
             let c = buffer.contents();
             (c.kind().clone(), c.flag().clone())
         };
+        if let Some(needed) = access::needed_for(k) {
+            // a peer without a principal never authenticated, so it may do nothing
+            let allowed = match self.principal_for_addr.get(&src_addr) {
+                Some(principal) => self.access.check(principal, buffer.contents().locs(), needed)
+                    .map_err(|color| (principal.clone(), Some(color))),
+                None => Err((String::from("unauthenticated"),
+                    buffer.contents().locs().first().map(|l| l.0))),
+            };
+            if let Err((principal, color)) = allowed {
+                warn!("WORKER {} refusing {:?} from {:?} ({}) on {:?}",
+                    worker_num, k, src_addr, principal, color);
+                buffer.reject(reject::PERMISSION_DENIED, color);
+                socket_state.add_bytes_to_write(&[buffer.entry_slice()]);
+                return
+            }
+        }
+        let shard = match k {
+            EntryKind::FenceClient => 0,
+            _ => self.shards.shard_for_op(buffer.contents()),
+        };
+        // recovery packets have no layout, so they need to be routed before we look for one
+        match k {
+            EntryKind::UpdateRecovery => {
+                let recoverer = {
+                    let locs = buffer.contents().locs().to_vec().into_boxed_slice();
+                    Box::new((src_addr.to_uuid(), locs))
+                };
+                let t = (worker_num, token, src_addr);
+                let to_send = ToLog::Recovery(Recovery::TasRecoverer(buffer, recoverer), t);
+                self.print_data.to_log(1);
+                return self.to_log[shard].send(to_send).expect("log gone")
+            },
+            EntryKind::CheckSkeens1 => {
+                let t = (worker_num, token, src_addr);
+                let to_send = ToLog::Recovery(Recovery::CheckSkeens1(buffer), t);
+                self.print_data.to_log(1);
+                return self.to_log[shard].send(to_send).expect("log gone")
+            },
+            EntryKind::CitedBy => {
+                let t = (worker_num, token, src_addr);
+                self.print_data.to_log(1);
+                return self.to_log[shard].send(ToLog::CitedBy(buffer, t)).expect("log gone")
+            },
+            _ => (),
+        }
+        // an append which was corrupted on the way here is bounced back to
+        // the client, which can retry it, rather than stored
+        if let Err(e) = buffer.contents().verify_checksum() {
+            error!("WORKER {} rejecting append from {:?}: {}", worker_num, src_addr, e);
+            buffer.reject(reject::CORRUPT, None);
+            socket_state.add_bytes_to_write(&[buffer.entry_slice()]);
+            return
+        }
+        let bytes = buffer.contents().len() as u64;
+        let admitted = self.admission.counts(buffer.contents());
+        if admitted {
+            if !self.admission.try_admit(src_addr, bytes) {
+                trace!("WORKER {} asking {:?} to retry {:?} later", worker_num, src_addr, k);
+                buffer.reject(reject::RETRY_LATER, None);
+                socket_state.add_bytes_to_write(&[buffer.entry_slice()]);
+                return
+            }
+        }
+        // charged after admission, so an append which is retried later is only charged once
+        let charges = if self.quotas.is_empty() {
+            None
+        } else {
+            QuotaEnforcer::charges(buffer.contents())
+        };
+        if let Some(refusable) = charges {
+            let charged = {
+                let shards = &self.shards;
+                let principal = self.principal_for_addr.get(&src_addr).map(|p| &**p);
+                self.quotas.charge(principal, buffer.contents().locs(), bytes, refusable,
+                    |o| shards.stores_chain(o))
+            };
+            if let Err(color) = charged {
+                warn!("WORKER {} refusing {:?} from {:?} over the quota of {:?}",
+                    worker_num, k, src_addr, color);
+                if admitted {
+                    self.admission.finished(src_addr, bytes);
+                }
+                buffer.reject(reject::QUOTA_EXCEEDED, Some(color));
+                socket_state.add_bytes_to_write(&[buffer.entry_slice()]);
+                return
+            }
+        }
         let kind = k.layout();
         let storage = match kind {
             EntryLayout::Read => {
@@ -472,7 +591,7 @@ impl WorkerInner {
                     let to_send = ToLog::Replication(ToReplicate::Multi(buffer, storage), t);
                     self.print_data.to_log(1);
                     //self.waiting_for_log += 1;
-                    return self.to_log.send(to_send).expect("log gone")
+                    return self.to_log[shard].send(to_send).expect("log gone")
                 } else if f.contains(EntryFlag::NewMultiPut) || !f.contains(EntryFlag::TakeLock) {
                     let senti_size = if has_senti { Some(senti_size) } else { None };
                     let mut storage = SkeensMultiStorage::new(num_locs, size, senti_size);
@@ -514,7 +633,7 @@ impl WorkerInner {
                 let to_send = ToLog::Replication(tr, t);
                 self.print_data.to_log(1);
                 //self.waiting_for_log += 1;
-                return self.to_log.send(to_send).expect("log gone")
+                return self.to_log[shard].send(to_send).expect("log gone")
             }
             _ => Troption::None,
         };
@@ -522,13 +641,13 @@ impl WorkerInner {
         self.print_data.to_log(1);
         //self.waiting_for_log += 1;
         let to_send = ToLog::New(buffer, storage, (worker_num, token, src_addr));
-        self.to_log.send(to_send).expect("log gone")
+        self.to_log[shard].send(to_send).expect("log gone")
     }
 
     fn send_replication_to_log(
         &mut self,
         token: mio::Token,
-        buffer: Buffer,
+        mut buffer: Buffer,
         storage_addr: u64,
         src_addr: Ipv4SocketAddr,
     ) {
@@ -536,7 +655,22 @@ impl WorkerInner {
         let worker_num = self.worker_num;
         trace!("WORKER {} send replica to log", self.worker_num);
         let kind = buffer.contents().kind();
+        let shard = self.shards.shard_for_op(buffer.contents());
+        // the head already stored this entry, so we must as well,
+        // but it is flagged so the tail's ack tells the client the append failed
+        if let Err(e) = buffer.contents().verify_checksum() {
+            error!("WORKER {} replicating corrupt entry {:?}: {}",
+                worker_num, buffer.contents().locs(), e);
+            buffer.contents_mut().flag_mut().insert(EntryFlag::Corrupted);
+        }
         let to_send = match kind {
+            EntryKind::CitedBy => {
+                //already answered by the head of the chain, pass it along
+                trace!("WORKER {} replicate cited by", self.worker_num);
+                self.print_data.to_log(1);
+                let to_send = ToLog::CitedBy(buffer, (worker_num, token, src_addr));
+                return self.to_log[shard].send(to_send).expect("log gone 2")
+            },
             EntryKind::Data => {
                 trace!("WORKER {} replicate Data", self.worker_num);
                 ToReplicate::Data(buffer, storage_addr)
@@ -618,7 +752,7 @@ impl WorkerInner {
         self.print_data.to_log(1);
         //self.waiting_for_log += 1;
         let to_send = ToLog::Replication(to_send, (worker_num, token, src_addr));
-        self.to_log.send(to_send).expect("log gone 2");
+        self.to_log[shard].send(to_send).expect("log gone 2");
     }
 
     pub fn end_backpressure(&mut self, token: mio::Token) {
@@ -17,7 +17,7 @@ fn read_from_log(
     let mut buffer = Buffer::empty();
     buffer.fill_from_entry_contents(EntryContents::read(&loc));
 
-    worker_thread::handle_read(&*server.log, &buffer, 0, f)
+    worker_thread::handle_read(&server.log.reader(), &buffer, 0, f)
 }
 
 fn multi_append_buffer(id: &Uuid, locs: &[OrderIndex], multi_server: bool) -> Buffer {
@@ -194,6 +194,89 @@ fn single_append2() {
     });
 }
 
+#[test]
+fn retention() {
+    use retention::{Retainer, Retention, RetentionPolicy};
+
+    let _ = env_logger::init();
+    let mut server = new_log();
+    for _ in 0..4 {
+        handle_op(&mut server, singe_append_buffer(&Uuid::new_v4(), 2.into()), Troption::None);
+    }
+    let mut buffer = Buffer::empty();
+    buffer.fill_from_entry_contents(EntryContents::Single {
+        id: &Uuid::new_v4(),
+        flags: &EntryFlag::Nothing,
+        loc: &OrderIndex(4.into(), 0.into()),
+        deps: &[OrderIndex(2.into(), 2.into())],
+        data: &[],
+        timestamp: &1,
+    });
+    handle_op(&mut server, buffer, Troption::None);
+
+    let retention = Retention::none()
+        .color(2.into(), RetentionPolicy::forever().max_entries(1))
+        .color(4.into(), RetentionPolicy::forever().max_entries(0));
+    let mut retainer = Retainer::new(Arc::new(retention));
+    let check_min = |server: &ServerLog<_, _>, loc: OrderIndex, expected: u64| {
+        read_from_log(server, loc, &mut |res| match res {
+            Err(EntryContents::Read{ min, .. }) =>
+                assert_eq!(min, &OrderIndex(loc.0, expected.into())),
+            Ok(..) if expected <= u64::from(loc.1) => {},
+            r => panic!("bad return {:#?}", r.map(|b| b.len())),
+        })
+    };
+
+    // (2, 2) is still cited by (4, 1)
+    server.apply_retention(&mut retainer, 0);
+    check_min(&server, OrderIndex(2.into(), 1.into()), 2);
+    check_min(&server, OrderIndex(2.into(), 2.into()), 2);
+    check_min(&server, OrderIndex(4.into(), 1.into()), 2);
+
+    server.apply_retention(&mut retainer, 0);
+    check_min(&server, OrderIndex(2.into(), 3.into()), 4);
+    check_min(&server, OrderIndex(2.into(), 4.into()), 4);
+}
+
+#[test]
+fn retention_replicates_trims() {
+    use retention::{Retainer, Retention, RetentionPolicy};
+
+    let _ = env_logger::init();
+    let mut head = new_log();
+    let mut replica = new_log();
+    for _ in 0..4 {
+        let id = Uuid::new_v4();
+        handle_op(&mut head, singe_append_buffer(&id, 2.into()), Troption::None);
+        handle_op(&mut replica, singe_append_buffer(&id, 2.into()), Troption::None);
+    }
+    let retention = Retention::none()
+        .color(2.into(), RetentionPolicy::forever().max_entries(1));
+    let mut retainer = Retainer::new(Arc::new(retention));
+    head.apply_retention(&mut retainer, 0);
+    assert!(head.to_workers.is_empty());
+
+    // the trims go down the chain ahead of the next message
+    head.handle_op(singe_append_buffer(&Uuid::new_v4(), 6.into()), Troption::None, ());
+    let gc = match head.to_workers.pop_back() {
+        Some(Reply(buffer, ())) => buffer,
+        _ => panic!("no trims replicated"),
+    };
+    assert_eq!(gc.contents().kind(), EntryKind::GC);
+    assert_eq!(gc.contents().locs(), &[OrderIndex(2.into(), 4.into())]);
+    head.to_workers.clear();
+    // the tail has no client to answer
+    let (_, sent) = handle_to_worker2(Reply(gc.clone(), ()), 0, false, |to_send, _, _|
+        match to_send { ToSend::Nothing => false, _ => true });
+    assert!(!sent);
+
+    replica.handle_replication(ToReplicate::GC(gc), ());
+    read_from_log(&replica, OrderIndex(2.into(), 3.into()), &mut |res| match res {
+        Err(EntryContents::Read{ min, .. }) => assert_eq!(min, &OrderIndex(2.into(), 4.into())),
+        r => panic!("bad return {:#?}", r.map(|b| b.len())),
+    });
+}
+
 #[test]
 fn snapshot() {
     let _ = env_logger::init();
@@ -600,3 +683,184 @@ fn single_after_partial_skeens() {
         }
     });
 }
+
+fn transaction_buffer(
+    id: &Uuid, locs: &[OrderIndex], reads: &[OrderIndex], lock: u64, round2: bool
+) -> Buffer {
+    let mut flags = EntryFlag::TakeLock | EntryFlag::NewMultiPut | EntryFlag::Transaction;
+    if round2 {
+        flags.insert(EntryFlag::Unlock)
+    }
+    let mut buffer = Buffer::empty();
+    buffer.fill_from_entry_contents(EntryContents::Multi {
+        id: id,
+        flags: &flags,
+        locs: locs,
+        lock: &lock,
+        deps: reads,
+        data: &[],
+    });
+    buffer
+}
+
+#[test]
+fn append_to_read_between_rounds() {
+    let _ = env_logger::init();
+    let mut server = new_log();
+    //the transaction writes 3, stored at the other server, having read 2 while it was empty
+    let tid = Uuid::new_v4();
+    let locs = &[OrderIndex(3.into(), 0.into())];
+    let reads = &[OrderIndex(2.into(), 0.into())];
+    let buffer = transaction_buffer(&tid, locs, reads, 1, false);
+    let storage = make_storage(&buffer);
+    let vote = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(!vote.contents().flag().contains(EntryFlag::Aborted));
+
+    //an append to 2 between the rounds waits for the transaction
+    let sid = Uuid::new_v4();
+    let waiting = handle_op(&mut server, singe_append_buffer(&sid, 2.into()), Troption::None);
+    assert!(waiting.is_none());
+    read_from_log(&server, OrderIndex(2.into(), 1.into()), &mut |res| {
+        match res {
+            Ok(bytes) => panic!("read unordered append {:#?}",
+                unsafe { EntryContents::try_ref(bytes) }),
+            Err(EntryContents::Read{ horizon, .. }) =>
+                assert_eq!(horizon, &OrderIndex(2.into(), 0.into())),
+            Err(e) => panic!("bad return {:#?}", e),
+        }
+    });
+
+    let buffer = transaction_buffer(&tid, locs, reads, 5, true);
+    handle_op(&mut server, buffer, Troption::None).unwrap();
+
+    read_from_log(&server, OrderIndex(2.into(), 1.into()), &mut |res| {
+        match res {
+            Err(e) => panic!("bad return {:#?}", e),
+            Ok(bytes) => unsafe {
+                let (e, _) = EntryContents::try_ref(bytes).unwrap();
+                match e {
+                    EntryContents::Single{ id, loc, .. } => {
+                        assert_eq!(id, &sid);
+                        assert_eq!(loc, &OrderIndex(2.into(), 1.into()));
+                    }
+                    e => panic!("wrong read {:#?}", e)
+                }
+            },
+        }
+    });
+
+    //a multiappend to 2 is ordered after the transaction on every server
+    let mid = Uuid::new_v4();
+    let multi_locs = &[OrderIndex(2.into(), 0.into()), OrderIndex(3.into(), 0.into())];
+    let buffer = multi_append_buffer(&mid, multi_locs, true);
+    let storage = make_storage(&buffer);
+    let timestamps = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(u64::from(timestamps.contents().locs()[0].1) > 5);
+
+    //the read is stale now
+    let buffer = transaction_buffer(&Uuid::new_v4(), locs, reads, 1, false);
+    let storage = make_storage(&buffer);
+    let vote = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(vote.contents().flag().contains(EntryFlag::Aborted));
+}
+
+#[test]
+fn transaction_writing_locked_read() {
+    let _ = env_logger::init();
+    let mut server = new_log();
+    //the first transaction reads 2 and writes 3, stored at the other server
+    let tid = Uuid::new_v4();
+    let locs = &[OrderIndex(3.into(), 0.into())];
+    let reads = &[OrderIndex(2.into(), 0.into())];
+    let buffer = transaction_buffer(&tid, locs, reads, 1, false);
+    let storage = make_storage(&buffer);
+    let vote = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(!vote.contents().flag().contains(EntryFlag::Aborted));
+
+    //the second one reads 5, at the other server, and writes 2,
+    //waiting for the first could deadlock, so it votes to abort
+    let locs2 = &[OrderIndex(2.into(), 0.into())];
+    let reads2 = &[OrderIndex(5.into(), 0.into())];
+    let buffer = transaction_buffer(&Uuid::new_v4(), locs2, reads2, 1, false);
+    let storage = make_storage(&buffer);
+    let vote = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(vote.contents().flag().contains(EntryFlag::Aborted));
+    //the conflict is the first write, after the reads
+    assert_eq!(vote.contents().lock_num(), 1);
+
+    //a repeated skeens-1 of the first does not conflict with its own locks
+    let buffer = transaction_buffer(&tid, locs, reads, 1, false);
+    let storage = make_storage(&buffer);
+    let vote = handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+    assert!(!vote.contents().flag().contains(EntryFlag::Aborted));
+}
+
+#[test]
+fn aborted_multi_frees_its_chains() {
+    let _ = env_logger::init();
+    let mut server = new_log();
+    //a multiappend to 2, and 5 at the other server, which refused it as corrupt
+    let id = Uuid::new_v4();
+    let locs = &[OrderIndex(2.into(), 0.into()), OrderIndex(5.into(), 0.into())];
+    let buffer = multi_append_buffer(&id, locs, true);
+    let storage = make_storage(&buffer);
+    handle_op(&mut server, buffer, Troption::Left(storage)).unwrap();
+
+    //an append to 2 is ordered after it
+    let sid = Uuid::new_v4();
+    handle_op(&mut server, singe_append_buffer(&sid, 2.into()), Troption::None);
+
+    //so the client aborts it in round 2
+    let mut buffer = skeens2_buffer(&id, locs, 1);
+    buffer.contents_mut().flag_mut().insert(EntryFlag::Aborted);
+    handle_op(&mut server, buffer, Troption::None);
+
+    read_from_log(&server, OrderIndex(2.into(), 1.into()), &mut |res| {
+        match res {
+            Err(e) => panic!("bad return {:#?}", e),
+            Ok(bytes) => unsafe {
+                let (e, _) = EntryContents::try_ref(bytes).unwrap();
+                match e {
+                    EntryContents::Single{ id, loc, .. } => {
+                        assert_eq!(id, &sid);
+                        assert_eq!(loc, &OrderIndex(2.into(), 1.into()));
+                    }
+                    e => panic!("wrong read {:#?}", e)
+                }
+            },
+        }
+    });
+}
+
+#[test]
+fn corrupt_entry_read_flagged() {
+    let _ = env_logger::init();
+    let mut server = new_log();
+    //a replica stores a corrupt entry the head already stored
+    let id = Uuid::new_v4();
+    let mut data = checksum::with_checksum(&id, &[], &[1, 2, 3]);
+    data[0] ^= 0xff;
+    let mut buffer = Buffer::empty();
+    buffer.fill_from_entry_contents(EntryContents::Single {
+        id: &id,
+        flags: &EntryFlag::Checksummed,
+        loc: &OrderIndex(2.into(), 0.into()),
+        deps: &[],
+        data: &data,
+        timestamp: &1,
+    });
+    handle_op(&mut server, buffer, Troption::None).unwrap();
+
+    //so reads tell every client, not only those which check checksums
+    read_from_log(&server, OrderIndex(2.into(), 1.into()), &mut |res| {
+        match res {
+            Err(e) => panic!("bad return {:#?}", e),
+            Ok(bytes) => {
+                let e = bytes_as_entry(bytes);
+                assert_eq!(e.id(), &id);
+                assert!(e.flag().contains(EntryFlag::Corrupted));
+                assert!(e.flag().contains(EntryFlag::ReadSuccess));
+            },
+        }
+    });
+}
@@ -0,0 +1,245 @@
+//! Bounding the memory a server uses by moving cold entries to disk.
+//!
+//! Every `TieredStorage::check_every`, each ordering thread adds up the bytes
+//! the chains of its shard hold in memory. If they exceed the shard's share of
+//! the `memory_budget`, it copies the oldest entries of its largest chains into
+//! segment files, which it memory-maps, and points the chains' indices into the
+//! mappings, so `worker_thread::handle_read` serves those entries from there
+//! unchanged. Once no reader can still be using the old storage, the same
+//! barrier a GC uses, it is freed.
+//!
+//! Entries are moved a whole block of storage at a time; the newest
+//! `hot_entries` of each chain, and the block currently being appended to,
+//! always stay in memory. Chains with appends still waiting in skeens are
+//! skipped, as those hold storage in the middle of the chain.
+//!
+//! Segments are scratch space, not a durable copy of the log: a segment is
+//! deleted once every entry in it is trimmed, and is never read back after a
+//! server restarts.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{process, ptr};
+
+use libc;
+
+use packets::order;
+
+#[derive(Debug, Clone)]
+pub struct TieredStorage {
+    dir: Option<PathBuf>,
+    memory_budget: u64,
+    hot_entries: u64,
+    interval: Duration,
+}
+
+impl TieredStorage {
+    /// Keep every entry in memory,
+    /// which is how a server without tiered storage behaves.
+    pub fn in_memory() -> Self {
+        TieredStorage {
+            dir: None,
+            memory_budget: ::std::u64::MAX,
+            hot_entries: 1024,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Move entries to segment files in `dir` once the server holds more than
+    /// `memory_budget` bytes of entries in memory.
+    pub fn spill_to<P: Into<PathBuf>>(dir: P, memory_budget: u64) -> Self {
+        TieredStorage { dir: Some(dir.into()), memory_budget: memory_budget, ..Self::in_memory() }
+    }
+
+    /// How many of the newest entries of each chain are never moved, 1024 by default.
+    pub fn hot_entries(self, hot_entries: u64) -> Self {
+        TieredStorage { hot_entries: ::std::cmp::max(hot_entries, 1), ..self }
+    }
+
+    /// How often memory use is checked, once a second by default.
+    pub fn check_every(self, interval: Duration) -> Self {
+        TieredStorage { interval: interval, ..self }
+    }
+}
+
+/// An ordering thread's progress in applying a `TieredStorage`.
+pub struct Spiller {
+    storage: Arc<TieredStorage>,
+    budget: u64,
+    next_check: Option<Instant>,
+    segments_created: u64,
+}
+
+impl Spiller {
+    pub fn new(storage: Arc<TieredStorage>, num_shards: usize) -> Self {
+        let budget = storage.memory_budget / ::std::cmp::max(num_shards, 1) as u64;
+        let next_check = storage.dir.as_ref().map(|_| Instant::now() + storage.interval);
+        Spiller { storage: storage, budget: budget, next_check: next_check, segments_created: 0 }
+    }
+
+    /// The bytes the chains of this shard may hold in memory.
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub fn hot_entries(&self) -> u64 {
+        self.storage.hot_entries
+    }
+
+    /// How long until the next check, `None` if there will never be one.
+    pub fn until_check(&self) -> Option<Duration> {
+        self.next_check.map(|next| {
+            let now = Instant::now();
+            if next > now { next - now } else { Duration::from_secs(0) }
+        })
+    }
+
+    pub fn check_due(&self) -> bool {
+        self.next_check.map_or(false, |next| Instant::now() >= next)
+    }
+
+    pub fn checked(&mut self, now: Instant) {
+        self.next_check = self.next_check.map(|_| now + self.storage.interval);
+    }
+
+    /// Where to put the next segment of `chain`.
+    pub fn segment_path(&mut self, chain: order) -> PathBuf {
+        let dir = self.storage.dir.as_ref().expect("spilling without a directory");
+        self.segments_created += 1;
+        dir.join(format!("fuzzy_log-{}-{}-{}.seg",
+            process::id(), u64::from(chain), self.segments_created))
+    }
+}
+
+/// A file of entries, mapped into memory.
+pub struct Segment {
+    path: PathBuf,
+    map: *mut u8,
+    len: usize,
+    // the indices of the entries in the segment
+    first: u64,
+    end: u64,
+}
+
+unsafe impl Send for Segment {}
+
+const ALIGNMENT: usize = 8;
+
+impl Segment {
+    /// Write `entries`, which are the entries at `first..`, to a new file at `path` and map it.
+    /// Returns the segment along with the offset of each entry in it.
+    pub fn create(path: PathBuf, first: u64, entries: &[&[u8]]) -> io::Result<(Self, Vec<usize>)> {
+        assert!(!entries.is_empty());
+        let written = write_entries(&path, entries);
+        let (file, offsets, len) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                return Err(e)
+            },
+        };
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if map == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            let _ = fs::remove_file(&path);
+            return Err(e)
+        }
+        let segment = Segment {
+            path: path,
+            map: map as *mut u8,
+            len: len,
+            first: first,
+            end: first + entries.len() as u64,
+        };
+        Ok((segment, offsets))
+    }
+
+    /// The entry at `offset`, which is always `ALIGNMENT` aligned.
+    pub fn at(&self, offset: usize) -> *const u8 {
+        assert!(offset < self.len);
+        unsafe { self.map.offset(offset as isize) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The indices of the entries in the segment.
+    pub fn entries(&self) -> ::std::ops::Range<u64> {
+        self.first..self.end
+    }
+}
+
+fn write_entries(path: &PathBuf, entries: &[&[u8]]) -> io::Result<(fs::File, Vec<usize>, usize)> {
+    const PADDING: [u8; ALIGNMENT] = [0; ALIGNMENT];
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut len = 0;
+    {
+        let mut writer = BufWriter::new(&file);
+        for entry in entries {
+            offsets.push(len);
+            let padding = (ALIGNMENT - entry.len() % ALIGNMENT) % ALIGNMENT;
+            writer.write_all(entry)?;
+            writer.write_all(&PADDING[..padding])?;
+            len += entry.len() + padding;
+        }
+        writer.flush()?;
+    }
+    Ok((file, offsets, len))
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove segment {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, slice};
+
+    #[test]
+    fn segment() {
+        let path = env::temp_dir()
+            .join(format!("fuzzy_log-{}-segment-test.seg", process::id()));
+        let entries: &[&[u8]] = &[&[1, 2, 3], &[4; 8], &[5; 9]];
+        let (segment, offsets) = Segment::create(path.clone(), 10, entries).unwrap();
+        assert_eq!(offsets, vec![0, 8, 16]);
+        assert_eq!(segment.len(), 32);
+        assert_eq!(segment.entries(), 10..13);
+        for (&entry, offset) in entries.iter().zip(offsets) {
+            let stored = unsafe { slice::from_raw_parts(segment.at(offset), entry.len()) };
+            assert_eq!(stored, entry);
+        }
+        assert!(path.exists());
+        drop(segment);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn spiller() {
+        let mut memory = Spiller::new(Arc::new(TieredStorage::in_memory()), 2);
+        assert_eq!(memory.until_check(), None);
+        assert!(!memory.check_due());
+        memory.checked(Instant::now());
+        assert_eq!(memory.until_check(), None);
+
+        let storage = TieredStorage::spill_to("/tmp", 1000).hot_entries(0);
+        let mut spiller = Spiller::new(Arc::new(storage), 2);
+        assert_eq!(spiller.budget(), 500);
+        assert_eq!(spiller.hot_entries(), 1);
+        assert!(spiller.until_check().is_some());
+        assert!(spiller.segment_path(7.into()) != spiller.segment_path(7.into()));
+    }
+}
@@ -6,10 +6,14 @@ use std::{mem, ptr, slice};
 //FIXME
 use packets::MutEntry as MutPacket;
 use packets::Entry as Packet;
-use packets::{EntryVar, EntryContents};
+use packets::{EntryContents, EntryKind, EntryVar};
 
 use byte_trie::{self, Trie as Alloc};
 use shared_slice::RcSlice;
+use tiered::Segment;
+
+use std::io;
+use std::path::PathBuf;
 
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
@@ -19,6 +23,25 @@ use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 pub struct Trie {
     //TODO should this be boxed?
     root: RootEdge,
+    // entries which have been moved out of memory, see `tiered`
+    segments: Vec<Segment>,
+    spilled_to: u64,
+}
+
+/// The storage entries used before they were spilled,
+/// which must not be freed while readers may still be using it.
+#[must_use]
+pub struct Spilled {
+    old: Vec<ValEdge>,
+    first_kept: ValEdge,
+    bytes: u64,
+}
+
+impl Spilled {
+    /// The bytes written to the segment.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
 }
 
 type RootEdge = Box<RootTable>;
@@ -414,7 +437,7 @@ impl Trie
     pub fn new() -> Self {
         unsafe {
             //FIXME gratuitously unsafe
-            let mut t = Trie { root: Box::new(mem::zeroed()) };
+            let mut t = Trie { root: Box::new(mem::zeroed()), segments: Vec::new(), spilled_to: 0 };
             ::std::ptr::write(&mut t.root.alloc, AllocPtr::new());
             //t.next_entry = 1;
             t
@@ -581,7 +604,17 @@ impl Trie
         (self.root.last_lock, self.root.last_unlock)
     }
 
-    #[allow(dead_code)]
+    // the array holding the entry at k
+    fn get_val_array_at(&mut self, k: TrieIndex) -> Option<&mut L6Edge> {
+        let root_index = ((k >> ROOT_SHIFT) & MASK) as usize;
+        let l1 = &mut self.root.array[root_index];
+        let l2 = index_mut!(l1, k, 1);
+        let l3 = index_mut!(l2, k, 2);
+        let l4 = index_mut!(l3, k, 3);
+        let l5 = index_mut!(l4, k, 4);
+        Some(index_mut!(l5, k, 5))
+    }
+
     #[inline]
     fn get_entry_at(&mut self, k: TrieIndex) -> Option<&mut ValEdge> {
         let root_index = ((k >> ROOT_SHIFT) & MASK) as usize;
@@ -658,57 +691,112 @@ impl Trie
     }
 
     pub fn delete_free(&mut self) {
-        let mut next = self.root.first_entry;
-        let max = self.root.min_entry;
-        //((key >> (ROOT_SHIFT - (SHIFT_LEN * depth))) & MASK) as usize;
-        macro_rules! iter {
-            ($slot:ident) => ($slot.as_mut().into_iter().flat_map(|v| v.iter_mut()))
-        }
-
-        macro_rules! walk {
-            (1 $new:ident in $old:ident $body:tt) => {
-                walk!(0 $new, $old, $body; 0)
-            };
-            (0 $new:ident, $old:ident, $body:tt; {{{{{0}}}}}) => {
-                for $new in iter!($old) $body;
+        let first = self.root.first_entry;
+        let min = self.root.min_entry;
+        // spilled entries point into their segments, their old storage is already freed
+        let first_in_memory = ::std::cmp::max(first, self.spilled_to);
+        // a block of storage is only free once the next one starts at or before min
+        let mut num_removed = 0;
+        for i in first..min {
+            let e = match self.get_entry_at(i) {
+                None => continue,
+                Some(slot) => mem::replace(slot, ValEdge::null()),
             };
-            (0 $new:ident, $old:ident, $body:tt; $depth:tt) => {
-                for slot in iter!($old) {
-                    walk!(0 $new, slot, $body; {$depth});
-                    *slot = None
+            if e.is_end() {
+                unsafe {
+                    if e.is_multi() {
+                        e.free_rc()
+                    } else if e.is_big() {
+                        e.free_box()
+                    } else if i > first_in_memory {
+                        num_removed += 1
+                    }
                 }
-            };
+            }
+        }
+        if min > first_in_memory && self.get_entry_at(min).map_or(false, |e| e.is_block_start()) {
+            num_removed += 1
         }
 
-        let mut num_removed = 0;
-        'remove: while next < max {
-            for slot0 in self.root.array.iter_mut() {
-                walk!(1 slot6 in slot0 {
-                    if next >= max {
-                        break 'remove
-                    }
-                    if slot6.is_end() {
-                        unsafe {
-                            if slot6.is_multi() {
-                                slot6.free_rc()
-                            } else if slot6.is_big() {
-                                slot6.free_box()
-                            } else {
-                                num_removed += 1
-                            }
-                        }
-                    }
-                    *slot6 = ValEdge::null();
-                    next += 1;
-                });
-                *slot0 = None
-            }
+        // the index arrays which only held removed entries
+        let mut array_start = first & !MASK;
+        while array_start + ARRAY_SIZE as u64 <= min
+            && array_start + (ARRAY_SIZE as u64) < self.root.next_entry {
+            self.get_val_array_at(array_start).map(|a| *a = None);
+            array_start += ARRAY_SIZE as u64;
         }
 
         self.root.first_entry = self.root.min_entry;
+        self.segments.retain(|s| s.entries().end > min);
 
         self.root.alloc.free_first(num_removed)
     }
+
+    /// The bytes of entries stored in memory, roughly;
+    /// multiappends and entries too big for a block are not counted.
+    pub fn resident_bytes(&self) -> u64 {
+        self.root.alloc.alloc.resident_bytes()
+    }
+
+    /// The first entry which should stay in memory to keep the newest `hot_entries`,
+    /// entries before it are in earlier blocks of storage, and have all been written.
+    pub fn spill_point(&mut self, hot_entries: u64) -> u64 {
+        let start = ::std::cmp::max(self.spilled_to, self.root.first_entry);
+        let end = self.len().saturating_sub(::std::cmp::max(hot_entries, 1));
+        let mut point = (start..end)
+            .find(|&i| self.get_entry_at(i).map_or(true, |e| e.is_null()))
+            .unwrap_or(end);
+        while point > start {
+            match self.get_entry_at(point) {
+                Some(&mut e) if e.is_block_start() => break,
+                _ => point -= 1,
+            }
+        }
+        point
+    }
+
+    /// Copy the entries in memory before `upto` into a new segment at `path`, and point
+    /// the index into it. `upto` must be a `spill_point`.
+    ///
+    /// The old storage must be freed with `free_spilled`, once no reader can still be using it.
+    pub unsafe fn spill(&mut self, upto: u64, path: PathBuf) -> io::Result<Option<Spilled>> {
+        let from = ::std::cmp::max(self.spilled_to, self.root.first_entry);
+        if upto <= from {
+            return Ok(None)
+        }
+        let first_kept = *self.get_entry_at(upto).unwrap();
+        let old: Vec<ValEdge> = (from..upto).map(|i| *self.get_entry_at(i).unwrap()).collect();
+        let entries: Vec<&[u8]> = old.iter().zip(from..).map(|(e, i)| {
+            // a chain's entry 0 is a placeholder, see Shard::ensure
+            let is_placeholder = i == 0 && *e.ptr() == EntryKind::Read.bits();
+            let len = if is_placeholder { 1 } else { e.as_packet().bytes().len() };
+            slice::from_raw_parts(e.ptr() as *const u8, len)
+        }).collect();
+        let (segment, offsets) = Segment::create(path, from, &entries)?;
+        for (i, offset) in (from..upto).zip(offsets) {
+            let slot: *mut ValEdge = self.get_entry_at(i).unwrap();
+            ValEdge::atomic_store(slot, ValEdge::mid_from_ptr(segment.at(offset)), Ordering::Release);
+        }
+        let bytes = segment.len() as u64;
+        self.segments.push(segment);
+        self.spilled_to = upto;
+        Ok(Some(Spilled { old: old, first_kept: first_kept, bytes: bytes }))
+    }
+
+    pub unsafe fn free_spilled(&mut self, spilled: Spilled) {
+        let Spilled { old, first_kept, .. } = spilled;
+        for e in old {
+            if e.is_end() {
+                if e.is_multi() {
+                    e.free_rc()
+                } else if e.is_big() {
+                    e.free_box()
+                }
+            }
+        }
+        let freed = self.root.alloc.alloc.free_blocks_before(first_kept.ptr());
+        debug_assert!(freed.is_some());
+    }
 }
 
 impl Trie {
@@ -1137,6 +1225,15 @@ impl ValEdge {
         self.0 as usize & 1 != 0
     }
 
+    fn is_null(self) -> bool {
+        self.ptr().is_null()
+    }
+
+    // the first entry stored in a block of the byte trie
+    fn is_block_start(self) -> bool {
+        self.is_end() && !self.is_multi() && !self.is_big()
+    }
+
     fn is_multi(self) -> bool {
         unsafe {
             self.ptr().as_ref().map(|p| {
@@ -1408,6 +1505,45 @@ pub mod test {
         }
     }
 
+    #[test]
+    pub fn spill() {
+        use std::env;
+        use std::process;
+
+        let mut p = Data(&[0u8; 100][..], &[OrderIndex(5.into(), 6.into())]).clone_entry();
+        let mut m = Trie::new();
+        let data = |i: u64| [i as u8; 100];
+        for i in 0..1000u64 {
+            Data(&data(i)[..], &[OrderIndex(5.into(), i.into())]).fill_entry(&mut p);
+            assert_eq!(m.append(p.entry()), i);
+        }
+        let check = |m: &Trie| for j in 0..1000u64 {
+            let r = m.atomic_get(j);
+            assert_eq!(r.map(|e| e.contents().into_singleton_builder()),
+                Some(Data(&data(j)[..], &[OrderIndex(5.into(), j.into())])));
+        };
+        let resident = m.resident_bytes();
+
+        let upto = m.spill_point(100);
+        assert!(upto > 0 && upto <= 900, "{}", upto);
+        assert!(m.get_entry_at(upto).unwrap().is_block_start());
+        let path = env::temp_dir().join(format!("fuzzy_log-{}-spill-test.seg", process::id()));
+        let spilled = unsafe { m.spill(upto, path.clone()) }.unwrap().unwrap();
+        assert!(spilled.bytes() >= upto * 100);
+        check(&m);
+        unsafe { m.free_spilled(spilled) };
+        check(&m);
+        assert!(m.resident_bytes() < resident);
+        assert_eq!(m.spill_point(100), upto);
+
+        m.set_min(upto + 1);
+        m.delete_free();
+        assert!(!path.exists());
+        for j in upto + 1..1000 {
+            assert!(m.atomic_get(j).is_some());
+        }
+    }
+
     #[cfg(TODO)]
     pub mod from_hash_map {
         use super::super::*;
@@ -27,6 +27,15 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
 
         Reply(buffer, t) => {
             trace!("WORKER {} finish reply", worker_num);
+            let from_retention = !continue_replication && {
+                let c = buffer.contents();
+                c.kind() == EntryKind::GC && *c.id() == Uuid::nil()
+            };
+            if from_retention {
+                // no client is waiting on the trims retention replicated
+                let u = send(ToSend::Nothing, false, t);
+                return (Some(buffer), u)
+            }
             let u = send(ToSend::Slice(buffer.entry_slice()), false, t);
             (Some(buffer), u)
             //ServerResponse::Echo(buffer, t)
@@ -151,6 +160,7 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
                 let e = storage.as_packet().contents();
                 send(ToSend::Contents(EntryContents::Skeens2ToReplica{
                     id: e.id(),
+                    flags: &EntryFlag::Nothing,
                     lock: &timestamp,
                     loc: &OrderIndex(color, entry::from(index as u64)),
                 }), false, t)
@@ -338,7 +348,7 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
             }
 
             let chain = loc.0;
-            let id;
+            let (id, aborted);
             {
                 let (_ts, _indicies, st0, st1) = storage.get_mut();
                 let is_sentinel = {
@@ -346,6 +356,7 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
                     // assert!(timestamp >= 1);
                     *st0.lock_mut() = timestamp;
                     id = *st0.as_ref().id();
+                    aborted = *st0.as_ref().flag() & EntryFlag::Aborted;
                     let st0_l = st0.locs_mut();
                     let i = st0_l.iter().position(|oi| oi.0 == chain).expect("no val");
                     //FIXME atomic?
@@ -363,7 +374,8 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
                         false
                     }
                 };
-                {
+                // an aborted transaction was not given a slot
+                if !trie_slot.is_null() {
                     let to_store = if is_sentinel {
                         let ptr = st1.clone().expect("no sentinel storage");
                         ValEdge::end_from_ptr(ptr.into_ptr())
@@ -378,6 +390,7 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
                 trace!("WORKER {} continue skeens2 replication @ {:?}", worker_num, loc);
                 send(ToSend::Contents(EntryContents::Skeens2ToReplica{
                     id: &id,
+                    flags: &aborted,
                     lock: &timestamp,
                     loc: &loc,
                 }), false, t)
@@ -501,6 +514,7 @@ where SendFn: for<'a> FnMut(ToSend<'a>, bool, T) -> U {
                     worker_num, timestamp, loc);
                 send(ToSend::Contents(EntryContents::Skeens2ToReplica{
                     id: &id,
+                    flags: &EntryFlag::Nothing,
                     lock: &timestamp,
                     loc: &loc,
                 }), false, t)
@@ -629,6 +643,7 @@ where SendFn: for<'a> FnOnce(ToSend<'a>, bool, T) -> U {
         trace!("WORKER continue fast skeens2 replication @ {:?}", loc);
         send(ToSend::Contents(EntryContents::Skeens2ToReplica{
             id: &id,
+            flags: &EntryFlag::Nothing,
             lock: &timestamp,
             loc: &loc,
         }), false, t)
@@ -653,10 +668,19 @@ where SendFn: for<'a> FnMut(Result<&'a [u8], EntryContents<'a>>) -> U {
     debug_assert!(index > entry::from(0)); //TODO return error on index < GC
     let res = chains.get_and(&chain, |logs| {
         let log = unsafe {&*UnsafeCell::get(&logs[0])};
+        // entries which were spilled are read from their segment's mapping, see tiered
         match log.trie.atomic_get(u64::from(index)) {
             Some(packet) => {
                 trace!("WORKER {:?} read occupied entry {:?} {:?}",
                     worker_num, (chain, index), packet.contents().id());
+                // flagged so clients which do not check checksums still see the corruption
+                if let Err(e) = packet.contents().verify_checksum() {
+                    error!("WORKER {:?} stored entry {:?} is corrupt: {}",
+                        worker_num, (chain, index), e);
+                    let mut flagged = packet.bytes().to_vec();
+                    bytes_as_entry_mut(&mut flagged).flag_mut().insert(EntryFlag::Corrupted);
+                    return Ok(send(Ok(&flagged)))
+                }
                 Ok(send(Ok(packet.bytes())))
             }
 
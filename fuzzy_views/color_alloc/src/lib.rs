@@ -1,3 +1,6 @@
+//! An allocator of numbered colors keyed by name, for new code the client's
+//! built-in `fuzzy_log_client::catalog` also stores per-color metadata.
+
 extern crate bincode;
 extern crate fuzzy_log_util;
 extern crate fuzzy_log_client;
@@ -0,0 +1,103 @@
+//! A Bloom filter, for asking whether an item might have been inserted.
+
+use std::f64::consts::LN_2;
+
+use ::{hash64, MergeErr, Sketch};
+
+/// Answers whether an item was inserted with no false negatives,
+/// and false positives at a rate chosen when it is made.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// A filter sized so that after `expected_items` inserts
+    /// it has a `false_positive_rate` of at most about the one given.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0,
+            "false positive rate {} is not a probability", false_positive_rate);
+        let n = expected_items.max(1) as f64;
+        let num_bits = (-n * false_positive_rate.ln() / (LN_2 * LN_2)).ceil();
+        let num_hashes = (num_bits / n * LN_2).round().max(1.0);
+        Self::with_params(num_bits as u64, num_hashes as u32)
+    }
+
+    pub fn with_params(num_bits: u64, num_hashes: u32) -> Self {
+        assert!(num_bits > 0 && num_hashes > 0);
+        let words = (num_bits + 63) / 64;
+        BloomFilter { bits: vec![0; words as usize], num_bits, num_hashes }
+    }
+
+    /// `false` if `item` was never inserted, `true` if it probably was.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_indices(item).all(|i| self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    fn bit_indices(&self, item: &[u8]) -> impl Iterator<Item=u64> {
+        // Kirsch and Mitzenmacher's double hashing
+        let (h1, h2) = (hash64(item, 0), hash64(item, 1) | 1);
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+impl Sketch for BloomFilter {
+    const IDEMPOTENT: bool = true;
+
+    fn insert(&mut self, item: &[u8]) {
+        for i in self.bit_indices(item) {
+            self.bits[(i / 64) as usize] |= 1 << (i % 64)
+        }
+    }
+
+    fn merge(&mut self, other: &Self) -> Result<(), MergeErr> {
+        if self.num_bits != other.num_bits || self.num_hashes != other.num_hashes {
+            return Err(MergeErr::Mismatched)
+        }
+        for (word, other) in self.bits.iter_mut().zip(&other.bits) {
+            *word |= *other
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000u32 {
+            filter.insert(i.to_string().as_bytes())
+        }
+        for i in 0..1000u32 {
+            assert!(filter.contains(i.to_string().as_bytes()))
+        }
+        let false_positives = (1000..11_000u32)
+            .filter(|i| filter.contains(i.to_string().as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn merge() {
+        let (mut a, mut b) = (BloomFilter::new(10, 0.01), BloomFilter::new(10, 0.01));
+        a.insert(b"a");
+        b.insert(b"b");
+        a.merge(&b).unwrap();
+        assert!(a.contains(b"a") && a.contains(b"b"));
+        assert_eq!(a.merge(&BloomFilter::with_params(8, 1)), Err(MergeErr::Mismatched));
+    }
+}
@@ -0,0 +1,113 @@
+//! A Count-Min sketch, for estimating how often each item was inserted.
+
+use std::f64::consts::E;
+
+use ::{hash64, MergeErr, Sketch};
+
+/// Estimates how many times an item was inserted, never underestimating.
+/// Each of the `depth` rows counts every item in one of `width` cells,
+/// and an item's estimate is the smallest of its cells.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountMin {
+    width: u64,
+    depth: u32,
+    counts: Vec<u64>,
+}
+
+impl CountMin {
+    pub fn new(width: u64, depth: u32) -> Self {
+        assert!(width > 0 && depth > 0);
+        CountMin { width, depth, counts: vec![0; (width * depth as u64) as usize] }
+    }
+
+    /// A sketch whose estimates are within `epsilon` times the total count
+    /// of the true count with probability `1 - delta`.
+    pub fn with_error(epsilon: f64, delta: f64) -> Self {
+        assert!(epsilon > 0.0 && delta > 0.0 && delta < 1.0);
+        let width = (E / epsilon).ceil() as u64;
+        let depth = (1.0 / delta).ln().ceil().max(1.0) as u32;
+        Self::new(width, depth)
+    }
+
+    /// Count `count` occurrences of `item`.
+    pub fn add(&mut self, item: &[u8], count: u64) {
+        for cell in self.cells(item) {
+            self.counts[cell] += count
+        }
+    }
+
+    /// The estimated number of times `item` was inserted.
+    pub fn estimate(&self, item: &[u8]) -> u64 {
+        self.cells(item).map(|cell| self.counts[cell]).min().unwrap_or(0)
+    }
+
+    pub fn width(&self) -> u64 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    fn cells(&self, item: &[u8]) -> impl Iterator<Item=usize> {
+        let width = self.width;
+        let item = item.to_vec();
+        (0..self.depth as u64).map(move |row| (row * width + hash64(&item, row) % width) as usize)
+    }
+}
+
+impl Sketch for CountMin {
+    const IDEMPOTENT: bool = false;
+
+    fn insert(&mut self, item: &[u8]) {
+        self.add(item, 1)
+    }
+
+    fn remove(&mut self, item: &[u8]) {
+        for cell in self.cells(item) {
+            self.counts[cell] = self.counts[cell].saturating_sub(1)
+        }
+    }
+
+    fn merge(&mut self, other: &Self) -> Result<(), MergeErr> {
+        if self.width != other.width || self.depth != other.depth {
+            return Err(MergeErr::Mismatched)
+        }
+        for (count, &other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let mut sketch = CountMin::with_error(0.001, 0.01);
+        for i in 0..100u32 {
+            sketch.add(i.to_string().as_bytes(), i as u64);
+        }
+        sketch.insert(b"7");
+        for i in 0..100u32 {
+            let expected = i as u64 + if i == 7 { 1 } else { 0 };
+            let estimate = sketch.estimate(i.to_string().as_bytes());
+            assert!(estimate >= expected && estimate <= expected + 5, "{}: {}", i, estimate);
+        }
+        sketch.remove(b"7");
+        assert_eq!(sketch.estimate(b"7"), 7);
+        assert_eq!(sketch.estimate(b"absent"), 0);
+    }
+
+    #[test]
+    fn merge() {
+        let (mut a, mut b) = (CountMin::new(64, 4), CountMin::new(64, 4));
+        a.add(b"x", 2);
+        b.add(b"x", 3);
+        a.merge(&b).unwrap();
+        assert_eq!(a.estimate(b"x"), 5);
+        assert_eq!(a.merge(&CountMin::new(32, 4)), Err(MergeErr::Mismatched));
+    }
+}
@@ -0,0 +1,106 @@
+//! HyperLogLog, for estimating how many distinct items were inserted.
+
+use ::{hash64, MergeErr, Sketch};
+
+/// Estimates the number of distinct items inserted in `2^precision` bytes,
+/// with a standard error of about `1.04 / sqrt(2^precision)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// `precision` must be between 4 and 16.
+    pub fn new(precision: u8) -> Self {
+        assert!(precision >= 4 && precision <= 16, "precision {} is not in 4..=16", precision);
+        HyperLogLog { precision, registers: vec![0; 1 << precision] }
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// The estimated number of distinct items inserted.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros != 0 {
+            // linear counting is more accurate for small sets
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+impl Sketch for HyperLogLog {
+    const IDEMPOTENT: bool = true;
+
+    fn insert(&mut self, item: &[u8]) {
+        let hash = hash64(item, 0);
+        let index = (hash >> (64 - self.precision)) as usize;
+        // the rank of the first set bit in the remaining 64 - precision bits
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if self.registers[index] < rank {
+            self.registers[index] = rank
+        }
+    }
+
+    fn merge(&mut self, other: &Self) -> Result<(), MergeErr> {
+        if self.precision != other.precision {
+            return Err(MergeErr::Mismatched)
+        }
+        for (register, &other) in self.registers.iter_mut().zip(&other.registers) {
+            if *register < other {
+                *register = other
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let mut hll = HyperLogLog::new(12);
+        for _ in 0..3 {
+            for i in 0..10_000u32 {
+                hll.insert(i.to_string().as_bytes())
+            }
+        }
+        let estimate = hll.estimate();
+        assert!(estimate > 9_500.0 && estimate < 10_500.0, "estimated {}", estimate);
+
+        let mut small = HyperLogLog::new(12);
+        for i in 0..10u32 {
+            small.insert(i.to_string().as_bytes())
+        }
+        assert_eq!(small.estimate().round(), 10.0);
+    }
+
+    #[test]
+    fn merge() {
+        let (mut a, mut b) = (HyperLogLog::new(10), HyperLogLog::new(10));
+        for i in 0..1000u32 {
+            a.insert(i.to_string().as_bytes());
+            b.insert((i + 500).to_string().as_bytes());
+        }
+        a.merge(&b).unwrap();
+        let estimate = a.estimate();
+        assert!(estimate > 1_350.0 && estimate < 1_650.0, "estimated {}", estimate);
+        assert_eq!(a.merge(&HyperLogLog::new(11)), Err(MergeErr::Mismatched));
+    }
+}
@@ -0,0 +1,343 @@
+//! Probabilistic summaries of colors, kept up to date from the log.
+//!
+//! A `SketchView` reads one or more colors and inserts the items of each entry,
+//! by default its whole payload, into a `Sketch`: a `BloomFilter` for
+//! membership, a `HyperLogLog` for the number of distinct items, or a
+//! `CountMin` for how often each item occurs. Each `update` applies the
+//! entries appended since the last one.
+//!
+//! A view can be saved as a `SketchCheckpoint` and resumed from it later,
+//! skipping the entries the checkpoint already includes. The checkpoint's
+//! horizon is the last entry applied in each of the view's colors, rather
+//! than a position in a total order, since the log has none.
+//!
+//! Checkpoints of views over different colors can be merged into one for all
+//! of their colors. An entry appended to colors of both views was applied by
+//! each view which had read up to it. Bloom filters and HyperLogLogs are not
+//! changed by inserting an item twice, but a Count-Min sketch is, so views
+//! whose sketch is not `IDEMPOTENT` remember the entries they applied which
+//! also inhabit colors outside the view, and a merge takes out the second copy
+//! of those both views applied. Only the latest `max_crossings` of those are
+//! remembered; a view which forgot some cannot be merged with a view over the
+//! colors they crossed into.
+
+extern crate bincode;
+extern crate fuzzy_log_client;
+
+pub extern crate serde;
+
+#[macro_use] extern crate serde_derive;
+
+use std::collections::VecDeque;
+
+use bincode::{deserialize, serialize, Infinite};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub use fuzzy_log_client::fuzzy_log::log_handle::{
+    entry,
+    order,
+    Event,
+    GetRes,
+    OrderIndex,
+    ReadHandle,
+};
+
+pub use bloom::BloomFilter;
+pub use count_min::CountMin;
+pub use hyperloglog::HyperLogLog;
+
+pub mod bloom;
+pub mod count_min;
+pub mod hyperloglog;
+
+/// How many entries which cross into other colors a view remembers by default.
+pub const DEFAULT_MAX_CROSSINGS: usize = 1 << 16;
+
+#[cfg(test)]
+mod tests;
+
+pub trait Sketch: Clone + Serialize + DeserializeOwned {
+    /// Whether inserting an item a second time leaves the sketch unchanged.
+    const IDEMPOTENT: bool;
+
+    fn insert(&mut self, item: &[u8]);
+
+    /// Undo an `insert` of `item`.
+    /// Only called on sketches which are not `IDEMPOTENT`.
+    fn remove(&mut self, _item: &[u8]) {
+        unreachable!("only sketches which are not idempotent need remove")
+    }
+
+    /// Add everything inserted into `other`.
+    /// Fails if the sketches were not made with the same parameters.
+    fn merge(&mut self, other: &Self) -> Result<(), MergeErr>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeErr {
+    /// The sketches' sizes or number of hashes differ.
+    Mismatched,
+    /// Both views read a color, so the entries in it would be counted twice.
+    OverlappingColors(order),
+    /// A view forgot some of its entries which cross into a color the other
+    /// view read, so they might be counted twice.
+    ForgotCrossings(order),
+}
+
+/// A sketch, and which entries have been inserted into it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SketchCheckpoint<S> {
+    sketch: S,
+    colors: Vec<u64>,
+    /// The last entry applied in each color.
+    horizon: Vec<(u64, u64)>,
+    /// The entries applied which also inhabit other colors, oldest first,
+    /// only kept for sketches which are not `IDEMPOTENT`.
+    crossing: VecDeque<Crossing>,
+    max_crossings: usize,
+    /// The other colors of the crossing entries which were forgotten.
+    forgotten: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Crossing {
+    id: [u8; 16],
+    colors: Vec<u64>,
+    items: Vec<Vec<u8>>,
+}
+
+impl<S: Sketch> SketchCheckpoint<S> {
+    fn new(sketch: S, colors: &[order]) -> Self {
+        let mut colors: Vec<u64> = colors.iter().map(|&c| c.into()).collect();
+        colors.sort();
+        colors.dedup();
+        SketchCheckpoint {
+            sketch,
+            colors,
+            horizon: vec![],
+            crossing: VecDeque::new(),
+            max_crossings: DEFAULT_MAX_CROSSINGS,
+            forgotten: vec![],
+        }
+    }
+
+    pub fn sketch(&self) -> &S {
+        &self.sketch
+    }
+
+    pub fn colors(&self) -> Vec<order> {
+        self.colors.iter().map(|&c| c.into()).collect()
+    }
+
+    /// The last entry applied in `color`.
+    pub fn horizon_for(&self, color: order) -> Option<entry> {
+        let color: u64 = color.into();
+        self.horizon.iter().find(|&&(c, _)| c == color).map(|&(_, i)| i.into())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self, Infinite).expect("cannot serialize checkpoint")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        deserialize(bytes)
+    }
+
+    /// Merge in the checkpoint of a view over other colors,
+    /// after which this includes every entry either included.
+    /// Fails without changing anything if the sketches differ, or if they
+    /// are not `IDEMPOTENT` and an entry might be counted twice.
+    pub fn merge(&mut self, other: &Self) -> Result<(), MergeErr> {
+        if !S::IDEMPOTENT {
+            if let Some(&c) = self.colors.iter().find(|c| other.colors.contains(c)) {
+                return Err(MergeErr::OverlappingColors(c.into()))
+            }
+            let forgot = self.forgotten.iter().find(|c| other.colors.contains(c))
+                .or_else(|| other.forgotten.iter().find(|c| self.colors.contains(c)));
+            if let Some(&c) = forgot {
+                return Err(MergeErr::ForgotCrossings(c.into()))
+            }
+        }
+        self.sketch.merge(&other.sketch)?;
+        for crossing in &other.crossing {
+            if self.crossing.iter().any(|c| c.id == crossing.id) {
+                // applied by both views
+                for item in &crossing.items {
+                    self.sketch.remove(item)
+                }
+            } else {
+                self.crossing.push_back(crossing.clone())
+            }
+        }
+        for &(color, index) in &other.horizon {
+            self.observe(color, index)
+        }
+        self.colors.extend_from_slice(&other.colors);
+        self.colors.sort();
+        self.colors.dedup();
+        self.forgotten.extend_from_slice(&other.forgotten);
+        self.forgotten.sort();
+        self.forgotten.dedup();
+        let colors = &self.colors;
+        self.crossing.retain(|c| c.colors.iter().any(|color| colors.binary_search(color).is_err()));
+        self.forget_old_crossings();
+        Ok(())
+    }
+
+    fn forget_old_crossings(&mut self) {
+        while self.crossing.len() > self.max_crossings {
+            let forgotten = self.crossing.pop_front().expect("crossing over the limit");
+            for color in forgotten.colors {
+                if !self.is_mine(color.into()) && !self.forgotten.contains(&color) {
+                    self.forgotten.push(color)
+                }
+            }
+        }
+        self.forgotten.sort();
+    }
+
+    fn is_mine(&self, color: order) -> bool {
+        self.colors.binary_search(&color.into()).is_ok()
+    }
+
+    fn observe(&mut self, color: u64, index: u64) {
+        match self.horizon.iter_mut().find(|&&mut (c, _)| c == color) {
+            Some(&mut (_, ref mut i)) => if *i < index { *i = index },
+            None => self.horizon.push((color, index)),
+        }
+    }
+
+    /// Whether the entry at `locs` was already applied.
+    /// Since a view reads its colors in full, an entry was applied iff one of
+    /// its locations in those colors is at or before the horizon.
+    fn includes(&self, locs: &[OrderIndex]) -> bool {
+        locs.iter().any(|&OrderIndex(o, i)| {
+            o != order::from(0) && self.is_mine(o)
+                && self.horizon_for(o).map_or(false, |h| i <= h)
+        })
+    }
+
+    fn apply(&mut self, event: &Event<[u8]>, items: &mut Vec<Vec<u8>>) {
+        if self.includes(event.inhabits) {
+            return
+        }
+        for item in items.iter() {
+            self.sketch.insert(item)
+        }
+        let mut crosses = false;
+        for &OrderIndex(o, i) in event.inhabits {
+            if o == order::from(0) {
+                continue
+            }
+            if self.is_mine(o) {
+                self.observe(o.into(), i.into())
+            } else {
+                crosses = true
+            }
+        }
+        if !S::IDEMPOTENT && crosses {
+            let mut colors: Vec<u64> = event.inhabits.iter()
+                .map(|&OrderIndex(o, _)| u64::from(o))
+                .filter(|&o| o != 0)
+                .collect();
+            colors.sort();
+            self.crossing.push_back(Crossing {
+                id: *event.id.as_bytes(),
+                colors,
+                items: items.clone(),
+            });
+            self.forget_old_crossings()
+        }
+    }
+}
+
+/// Keeps a sketch of the entries in some colors.
+pub struct SketchView<S: Sketch> {
+    handle: ReadHandle<[u8]>,
+    state: SketchCheckpoint<S>,
+    extract: Box<FnMut(&[u8], &mut Vec<Vec<u8>>)>,
+    items: Vec<Vec<u8>>,
+}
+
+impl<S: Sketch> SketchView<S> {
+    /// `handle` must be interested in `colors`.
+    pub fn new(handle: ReadHandle<[u8]>, colors: &[order], sketch: S) -> Self {
+        assert!(!colors.is_empty(), "a sketch view needs a color to read");
+        Self::from_checkpoint(handle, SketchCheckpoint::new(sketch, colors))
+    }
+
+    /// Resume from a checkpoint; the entries it includes are read but not applied.
+    pub fn from_checkpoint(handle: ReadHandle<[u8]>, checkpoint: SketchCheckpoint<S>) -> Self {
+        SketchView {
+            handle,
+            state: checkpoint,
+            extract: Box::new(|data, items| items.push(data.to_vec())),
+            items: vec![],
+        }
+    }
+
+    /// Insert the items `extract` finds in each entry's payload,
+    /// instead of the whole payload.
+    pub fn items<F>(mut self, extract: F) -> Self
+    where F: FnMut(&[u8], &mut Vec<Vec<u8>>) + 'static {
+        self.extract = Box::new(extract);
+        self
+    }
+
+    /// Remember at most `max` of the entries applied which also inhabit
+    /// colors outside the view, `DEFAULT_MAX_CROSSINGS` by default.
+    /// Only matters for sketches which are not `IDEMPOTENT`, see `SketchCheckpoint::merge`.
+    pub fn max_crossings(mut self, max: usize) -> Self {
+        self.state.max_crossings = max;
+        self.state.forget_old_crossings();
+        self
+    }
+
+    /// Apply every entry appended to the view's colors before this call.
+    pub fn update(&mut self) -> Result<(), GetRes> {
+        let colors = self.state.colors();
+        self.handle.snapshot_colors(&colors);
+        loop {
+            match self.handle.get_next_event() {
+                Ok(event) => {
+                    self.items.clear();
+                    (self.extract)(event.data, &mut self.items);
+                    self.state.apply(&event, &mut self.items)
+                },
+                Err(GetRes::Done) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn sketch(&self) -> &S {
+        &self.state.sketch
+    }
+
+    pub fn checkpoint(&self) -> SketchCheckpoint<S> {
+        self.state.clone()
+    }
+
+    pub fn handle(&mut self) -> &mut ReadHandle<[u8]> {
+        &mut self.handle
+    }
+
+    pub fn into_inner(self) -> (ReadHandle<[u8]>, SketchCheckpoint<S>) {
+        (self.handle, self.state)
+    }
+}
+
+/// A 64 bit hash of `item` which is the same in every process and release,
+/// so sketches can be saved and merged. FNV-1a, finished with splitmix64's mixer.
+pub fn hash64(item: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    for &byte in item {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,180 @@
+// Sketch views against a server in this process.
+
+extern crate fuzzy_log_server;
+
+use std::{
+    net::SocketAddr,
+    sync::{Once, ONCE_INIT},
+    sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT},
+    thread,
+};
+
+use ::*;
+
+use fuzzy_log_client::fuzzy_log::log_handle::LogHandle;
+
+use tests::fuzzy_log_server::tcp::run_server;
+
+const ADDR: &'static str = "127.0.0.1:8234";
+
+fn start_server() -> SocketAddr {
+    static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+    static START: Once = ONCE_INIT;
+    START.call_once(|| {
+        thread::spawn(|| run_server(ADDR.parse().unwrap(), 0, 1, None, None, 1, &STARTED));
+    });
+    while STARTED.load(Ordering::Relaxed) == 0 {
+        thread::yield_now()
+    }
+    ADDR.parse().unwrap()
+}
+
+fn colors(colors: &[u64]) -> Vec<order> {
+    colors.iter().cloned().map(order::from).collect()
+}
+
+fn writer(chains: &[u64]) -> LogHandle<[u8]> {
+    LogHandle::unreplicated_with_servers(Some(start_server()))
+        .chains(colors(chains))
+        .build()
+}
+
+fn reader(chains: &[u64]) -> ReadHandle<[u8]> {
+    writer(chains).split().0
+}
+
+fn view<S: Sketch>(chains: &[u64], sketch: S) -> SketchView<S> {
+    SketchView::new(reader(chains), &colors(chains), sketch)
+}
+
+#[test]
+fn resume_from_checkpoint() {
+    let mut log = writer(&[10, 11]);
+    log.append(10.into(), b"a", &[]);
+    log.append(10.into(), b"b", &[]);
+    log.append(11.into(), b"a", &[]);
+    log.multiappend(&colors(&[10, 11]), b"a", &[]);
+
+    let mut counts = view(&[10, 11], CountMin::new(256, 4));
+    counts.update().unwrap();
+    assert_eq!(counts.sketch().estimate(b"a"), 3);
+    assert_eq!(counts.sketch().estimate(b"b"), 1);
+    let saved = counts.checkpoint().to_bytes();
+    drop(counts);
+
+    log.append(11.into(), b"a", &[]);
+    let checkpoint = SketchCheckpoint::from_bytes(&saved).unwrap();
+    assert!(checkpoint.horizon_for(10.into()).is_some());
+    let mut counts: SketchView<CountMin> = SketchView::from_checkpoint(reader(&[10, 11]), checkpoint);
+    counts.update().unwrap();
+    assert_eq!(counts.sketch().estimate(b"a"), 4);
+    assert_eq!(counts.sketch().estimate(b"b"), 1);
+}
+
+#[test]
+fn extracted_items() {
+    let mut log = writer(&[20]);
+    log.append(20.into(), b"red green", &[]);
+    log.append(20.into(), b"blue green", &[]);
+    log.append(20.into(), b"red", &[]);
+
+    let split_words = |data: &[u8], items: &mut Vec<Vec<u8>>| {
+        items.extend(data.split(|&b| b == b' ').map(|word| word.to_vec()))
+    };
+    let mut members = view(&[20], BloomFilter::new(100, 0.01)).items(split_words);
+    let mut distinct = view(&[20], HyperLogLog::new(8)).items(split_words);
+    members.update().unwrap();
+    distinct.update().unwrap();
+    for word in &[&b"red"[..], b"green", b"blue"] {
+        assert!(members.sketch().contains(word));
+    }
+    assert!(!members.sketch().contains(b"red green"));
+    assert_eq!(distinct.sketch().estimate().round(), 3.0);
+
+    log.append(20.into(), b"yellow", &[]);
+    members.update().unwrap();
+    distinct.update().unwrap();
+    assert!(members.sketch().contains(b"yellow"));
+    assert_eq!(distinct.sketch().estimate().round(), 4.0);
+}
+
+#[test]
+fn merge_counts_shared_entries_once() {
+    let mut log = writer(&[30, 31]);
+    log.append(30.into(), b"x", &[]);
+    log.append(31.into(), b"x", &[]);
+    log.multiappend(&colors(&[30, 31]), b"x", &[]);
+    log.multiappend(&colors(&[30, 31]), b"y", &[]);
+
+    let (mut left, mut right) = (view(&[30], CountMin::new(256, 4)), view(&[31], CountMin::new(256, 4)));
+    left.update().unwrap();
+    right.update().unwrap();
+    assert_eq!(left.sketch().estimate(b"x"), 2);
+    assert_eq!(right.sketch().estimate(b"x"), 2);
+
+    let mut merged = left.checkpoint();
+    merged.merge(&right.checkpoint()).unwrap();
+    assert_eq!(merged.colors(), colors(&[30, 31]));
+    assert_eq!(merged.sketch().estimate(b"x"), 3);
+    assert_eq!(merged.sketch().estimate(b"y"), 1);
+
+    // the merged view carries on from both horizons
+    log.append(31.into(), b"y", &[]);
+    let mut both = SketchView::from_checkpoint(reader(&[30, 31]), merged);
+    both.update().unwrap();
+    assert_eq!(both.sketch().estimate(b"x"), 3);
+    assert_eq!(both.sketch().estimate(b"y"), 2);
+
+    // counts in a color both views read cannot be told apart
+    let mut other = right.checkpoint();
+    assert_eq!(other.merge(&both.checkpoint()), Err(MergeErr::OverlappingColors(31.into())));
+}
+
+#[test]
+fn merge_idempotent_overlapping() {
+    let mut log = writer(&[40, 41]);
+    log.append(40.into(), b"p", &[]);
+    log.multiappend(&colors(&[40, 41]), b"q", &[]);
+    log.append(41.into(), b"r", &[]);
+
+    let mut left = view(&[40, 41], HyperLogLog::new(8));
+    let mut right = view(&[41], HyperLogLog::new(8));
+    left.update().unwrap();
+    right.update().unwrap();
+    let mut merged = left.checkpoint();
+    merged.merge(&right.checkpoint()).unwrap();
+    assert_eq!(merged.sketch().estimate().round(), 3.0);
+    assert_eq!(merged.sketch(), left.sketch());
+
+    let mut bad = right.checkpoint();
+    let other = SketchView::new(reader(&[41]), &colors(&[41]), HyperLogLog::new(9)).checkpoint();
+    assert_eq!(bad.merge(&other), Err(MergeErr::Mismatched));
+}
+
+#[test]
+fn forgotten_crossings() {
+    let mut log = writer(&[50, 51, 52]);
+    for _ in 0..3 {
+        log.multiappend(&colors(&[50, 51]), b"z", &[]);
+    }
+    log.multiappend(&colors(&[50, 52]), b"z", &[]);
+
+    let mut left = view(&[50], CountMin::new(256, 4)).max_crossings(1);
+    let mut right = view(&[51], CountMin::new(256, 4));
+    let mut other = view(&[52], CountMin::new(256, 4));
+    left.update().unwrap();
+    right.update().unwrap();
+    other.update().unwrap();
+    assert_eq!(left.sketch().estimate(b"z"), 4);
+
+    // left only remembers the entry it shares with 52
+    let mut merged = left.checkpoint();
+    assert_eq!(merged.merge(&right.checkpoint()), Err(MergeErr::ForgotCrossings(51.into())));
+    assert_eq!(merged, left.checkpoint());
+    let mut merged = right.checkpoint();
+    assert_eq!(merged.merge(&left.checkpoint()), Err(MergeErr::ForgotCrossings(51.into())));
+
+    let mut merged = left.checkpoint();
+    merged.merge(&other.checkpoint()).unwrap();
+    assert_eq!(merged.sketch().estimate(b"z"), 4);
+}
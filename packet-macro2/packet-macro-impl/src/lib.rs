@@ -61,6 +61,8 @@ fn impl_ref(body: PacketBody) -> quote::Tokens {
             let try_mut = struct_try_mut(&fields);
             let as_ref = struct_mut_as_ref(&fields);
 
+            let schema = struct_schema(&fields);
+
             let size_vars = size_vars(&fields);
             let (field, typ): (Vec<_>, Vec<_>) = fields.iter()
                 .filter_map(|f| match size_vars.contains(&f.ident) {
@@ -102,6 +104,8 @@ fn impl_ref(body: PacketBody) -> quote::Tokens {
                         _ => 0,
                     }
                 }
+
+                #schema
             }
         },
         PacketBody::Enum(KindFlag(tag_ident, tag_ty), variants) => {
@@ -143,6 +147,8 @@ fn impl_ref(body: PacketBody) -> quote::Tokens {
             let try_var = enum_try_var(&tag_ident, &tag_ty, &variants);
 
             let min_len = enum_minsize(&tag_ty, &variants);
+
+            let schema = enum_schema(&tag_ident, &tag_ty, &variants);
             quote!{
                 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
                 pub enum Ref<'a> {
@@ -192,6 +198,8 @@ fn impl_ref(body: PacketBody) -> quote::Tokens {
                 }
 
                 #min_len
+
+                #schema
             }
         },
     }
@@ -735,6 +743,49 @@ fn minsize(fields: &[Field]) -> quote::Tokens {
     }
 }
 
+fn struct_schema(fields: &[Field]) -> quote::Tokens {
+    let fields = schema_fields(fields);
+    quote!{
+        fn schema_body() -> __packet_schema::Body {
+            __packet_schema::Body::Struct(#fields)
+        }
+    }
+}
+
+fn enum_schema(tag_ident: &Ident, tag_ty: &Ty, variants: &[Variant]) -> quote::Tokens {
+    let tag_name = tag_ident.as_ref();
+    let tag_schema = tag_ty.schema_type();
+    let variants = variants.iter().map(|v| {
+        let &Variant{ident: ref variant, flag: ref tag_val, ref body} = v;
+        let name = variant.as_ref();
+        let fields = schema_fields(body);
+        quote!{
+            __packet_schema::Variant {
+                name: #name,
+                tag: __packet_schema::tag_value::<#tag_ty>(&#tag_val),
+                fields: #fields,
+            }
+        }
+    });
+    quote!{
+        fn schema_body() -> __packet_schema::Body {
+            __packet_schema::Body::Enum {
+                tag: __packet_schema::Field { name: #tag_name, ty: #tag_schema },
+                variants: vec![#(#variants),*],
+            }
+        }
+    }
+}
+
+fn schema_fields(fields: &[Field]) -> quote::Tokens {
+    let fields = fields.iter().map(|f| {
+        let name = f.ident.as_ref();
+        let ty = f.ty.schema_type();
+        quote!( __packet_schema::Field { name: #name, ty: #ty } )
+    });
+    quote!( vec![#(#fields),*] )
+}
+
 fn size_vars(fields: &[Field]) -> HashSet<&Ident> {
     fields.iter().filter_map(|f| f.ty.size_var()).collect()
 }
@@ -801,6 +852,34 @@ impl Ty {
         }
     }
 
+    fn schema_type(&self) -> quote::Tokens {
+        use Ty::*;
+        match self {
+            ty @ &Path(..) | ty @ &Tuple(..) => {
+                let name: String = ty.ref_type().to_string().split_whitespace().collect();
+                quote! {
+                    __packet_schema::Type::Fixed {
+                        name: #name,
+                        size: ::std::mem::size_of::<#ty>(),
+                    }
+                }
+            },
+            &Array(ref ty, ref len) => {
+                let elem = ty.schema_type();
+                quote! {
+                    __packet_schema::Type::Array { elem: Box::new(#elem), len: (#len) as usize }
+                }
+            },
+            &VarArray(ref ty, ref count) => {
+                let elem = ty.schema_type();
+                let count = count.as_ref();
+                quote! {
+                    __packet_schema::Type::VarArray { elem: Box::new(#elem), count: #count }
+                }
+            },
+        }
+    }
+
     fn size_var(&self) -> Option<&Ident> {
         use Ty::*;
         match self {
@@ -3,6 +3,8 @@
 #[allow(unused_imports)]
 #[macro_use] extern crate packet_macro_impl;
 
+pub mod schema;
+
 #[macro_export]
 macro_rules! define_packet {
     (struct $name:ident $t:tt) => {
@@ -13,12 +15,19 @@ macro_rules! define_packet {
             #[allow(unused_imports)]
             use super::*;
 
+            use $crate::schema as __packet_schema;
+
             #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
             pub enum WrapErr {
                 BadTag,
                 NotEnoughBytes(usize),
             }
 
+            /// The layout of this packet, see `packet_macro2::schema`.
+            pub fn schema() -> __packet_schema::Packet {
+                __packet_schema::Packet { name: stringify!($name), body: schema_body() }
+            }
+
             #[derive(packet_macro_impl)]
             #[allow(unused)]
             enum ProceduralMasqueradeDummyType {
@@ -34,12 +43,19 @@ macro_rules! define_packet {
             #[allow(unused_imports)]
             use super::*;
 
+            use $crate::schema as __packet_schema;
+
             #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
             pub enum WrapErr {
                 BadTag,
                 NotEnoughBytes(usize),
             }
 
+            /// The layout of this packet, see `packet_macro2::schema`.
+            pub fn schema() -> __packet_schema::Packet {
+                __packet_schema::Packet { name: stringify!($name), body: schema_body() }
+            }
+
             #[derive(packet_macro_impl)]
             #[allow(unused)]
             enum ProceduralMasqueradeDummyType {
@@ -264,4 +280,56 @@ mod tests {
     fn print_enum() {
         TestEnum::print_string()
     }*/
+
+    #[test]
+    fn struct_schema() {
+        use schema::*;
+        assert_eq!(TestVarArray::schema(), Packet {
+            name: "TestVarArray",
+            body: Body::Struct(vec![
+                Field { name: "size", ty: Type::Fixed { name: "u16", size: 2 } },
+                Field { name: "bytes", ty: Type::VarArray {
+                    elem: Box::new(Type::Fixed { name: "u8", size: 1 }),
+                    count: "size",
+                } },
+            ]),
+        });
+        let array = TestArray::schema();
+        match array.body {
+            Body::Struct(ref fields) => assert_eq!(fields[1].ty.size(), Some(8)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn enum_schema() {
+        use schema::*;
+        let schema = TestEmptyVariant::schema();
+        let variants = match schema.body {
+            Body::Enum { ref tag, ref variants } => {
+                assert_eq!(tag, &Field { name: "tag", ty: Type::Fixed { name: "u8", size: 1 } });
+                variants
+            },
+            _ => unreachable!(),
+        };
+        let names: Vec<_> = variants.iter().map(|v| (v.name, v.tag, v.fields.len())).collect();
+        assert_eq!(names, vec![("Variant1", 1, 2), ("Variant2", 2, 1), ("Variant3", 3, 0)]);
+
+        let json = schema.to_json();
+        assert!(json.starts_with("{\"name\":\"TestEmptyVariant\","));
+        assert!(json.contains("{\"name\":\"Variant2\",\"tag\":2,\"fields\":\
+            [{\"name\":\"flags\",\"type\":{\"kind\":\"fixed\",\"name\":\"u32\",\"size\":4}}]}"));
+
+        let header = schema.c_header();
+        assert!(header.contains("#define TESTEMPTYVARIANT_TAG_VARIANT2 0x2"));
+        assert!(header.contains("struct __attribute__((packed)) testemptyvariant_variant1 {\n\
+            \x20   testemptyvariant_tag_t tag;\n\
+            \x20   uint16_t size;\n\
+            \x20   // followed by\n\
+            \x20   //   bytes: size elements of 1 bytes\n\
+            };"));
+
+        let python = schema.python_parser();
+        assert!(python.contains("    0x3: (\"Variant3\", []),"));
+    }
 }
@@ -0,0 +1,345 @@
+//! Machine-readable descriptions of the packets generated by `define_packet!`.
+//!
+//! Every packet module gets a `schema()` function which returns the layout of
+//! the packet as described in its definition, with the sizes of the field
+//! types filled in by the compiler. From that we can generate JSON for other
+//! tools, C headers, and Python parsers which agree with the Rust code.
+//!
+//! Fields are laid out in definition order without padding,
+//! each in the byte order of the machine which wrote it.
+
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub name: &'static str,
+    pub body: Body,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Body {
+    Struct(Vec<Field>),
+    Enum { tag: Field, variants: Vec<Variant> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    pub name: &'static str,
+    pub tag: u64,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: Type,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    /// A fixed size value, `name` is its Rust type.
+    Fixed { name: &'static str, size: usize },
+    Array { elem: Box<Type>, len: usize },
+    /// An array whose length is the value of the field `count`.
+    VarArray { elem: Box<Type>, count: &'static str },
+}
+
+/// The value of an enum tag, as it would be read from the wire.
+#[doc(hidden)]
+pub fn tag_value<T: Copy>(tag: &T) -> u64 {
+    let size = ::std::mem::size_of::<T>();
+    assert!(size <= 8, "packet tags must fit in a u64");
+    let bytes = unsafe { ::std::slice::from_raw_parts(tag as *const T as *const u8, size) };
+    read_uint(bytes)
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    if cfg!(target_endian = "little") {
+        bytes.iter().rev().fold(0, |val, &b| (val << 8) | b as u64)
+    } else {
+        bytes.iter().fold(0, |val, &b| (val << 8) | b as u64)
+    }
+}
+
+pub fn byte_order() -> &'static str {
+    if cfg!(target_endian = "little") { "little" } else { "big" }
+}
+
+impl Type {
+    /// The size of a value of this type, `None` if it depends on another field.
+    pub fn size(&self) -> Option<usize> {
+        match *self {
+            Type::Fixed { size, .. } => Some(size),
+            Type::Array { ref elem, len } => elem.size().map(|s| s * len),
+            Type::VarArray { .. } => None,
+        }
+    }
+
+    /// The C type of an integer with this type, if it is one.
+    fn c_int(&self) -> Option<&'static str> {
+        match *self {
+            Type::Fixed { name, .. } => match name {
+                "u8" => Some("uint8_t"),
+                "u16" => Some("uint16_t"),
+                "u32" => Some("uint32_t"),
+                "u64" => Some("uint64_t"),
+                "i8" => Some("int8_t"),
+                "i16" => Some("int16_t"),
+                "i32" => Some("int32_t"),
+                "i64" => Some("int64_t"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match *self {
+            Type::Fixed { name, size } => {
+                let _ = write!(out, "{{\"kind\":\"fixed\",\"name\":{},\"size\":{}}}", json_str(name), size);
+            },
+            Type::Array { ref elem, len } => {
+                out.push_str("{\"kind\":\"array\",\"elem\":");
+                elem.write_json(out);
+                let _ = write!(out, ",\"len\":{}}}", len);
+            },
+            Type::VarArray { ref elem, count } => {
+                out.push_str("{\"kind\":\"var_array\",\"elem\":");
+                elem.write_json(out);
+                let _ = write!(out, ",\"count\":{}}}", json_str(count));
+            },
+        }
+    }
+
+    fn write_python(&self, out: &mut String) {
+        match *self {
+            Type::Fixed { name, size } =>
+                { let _ = write!(out, "('fixed', {:?}, {})", name, size); },
+            Type::Array { ref elem, len } => {
+                out.push_str("('array', ");
+                elem.write_python(out);
+                let _ = write!(out, ", {})", len);
+            },
+            Type::VarArray { ref elem, count } => {
+                out.push_str("('var_array', ");
+                elem.write_python(out);
+                let _ = write!(out, ", {:?})", count);
+            },
+        }
+    }
+}
+
+impl Packet {
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let _ = write!(out, "{{\"name\":{},\"byte_order\":\"{}\",", json_str(self.name), byte_order());
+        match self.body {
+            Body::Struct(ref fields) => {
+                out.push_str("\"fields\":");
+                write_json_fields(fields, &mut out);
+            },
+            Body::Enum { ref tag, ref variants } => {
+                out.push_str("\"tag\":");
+                write_json_fields(&[tag.clone()], &mut out);
+                out.push_str(",\"variants\":[");
+                for (i, v) in variants.iter().enumerate() {
+                    if i > 0 { out.push(',') }
+                    let _ = write!(out, "{{\"name\":{},\"tag\":{},\"fields\":", json_str(v.name), v.tag);
+                    write_json_fields(&v.fields, &mut out);
+                    out.push('}');
+                }
+                out.push(']');
+            },
+        }
+        out.push('}');
+        out
+    }
+
+    /// A C header with a packed struct for each variant.
+    /// Since C has no variable length members, each struct ends at the first
+    /// variable length field; the remaining fields are listed after it.
+    pub fn c_header(&self) -> String {
+        let prefix = self.name.to_lowercase();
+        let mut out = String::new();
+        let _ = writeln!(out, "// Generated from the definition of `{}`, do not edit.", self.name);
+        let _ = writeln!(out, "// Fields are {}-endian.\n", byte_order());
+        let _ = writeln!(out, "#include <stdint.h>\n");
+        match self.body {
+            Body::Struct(ref fields) => write_c_struct(&prefix, None, fields, &mut out),
+            Body::Enum { ref tag, ref variants } => {
+                // tags are often newtypes or bitflags, so we go by size
+                let tag_ty = match tag.ty.size() {
+                    Some(1) => "uint8_t",
+                    Some(2) => "uint16_t",
+                    Some(4) => "uint32_t",
+                    Some(8) => "uint64_t",
+                    size => panic!("packet tag of unsupported size {:?}", size),
+                };
+                let tag_typedef = format!("{}_{}_t", prefix, tag.name);
+                let _ = writeln!(out, "typedef {} {};\n", tag_ty, tag_typedef);
+                for v in variants {
+                    let _ = writeln!(out, "#define {}_{}_{} {:#x}",
+                        prefix.to_uppercase(), tag.name.to_uppercase(), v.name.to_uppercase(), v.tag);
+                }
+                out.push('\n');
+                for v in variants {
+                    let name = format!("{}_{}", prefix, v.name.to_lowercase());
+                    let tag = (&*tag_typedef, tag.name);
+                    write_c_struct(&name, Some(tag), &v.fields, &mut out);
+                }
+            },
+        }
+        out
+    }
+
+    /// A python module with a `parse(buf)` function which returns the name of
+    /// the variant, a `dict` of its fields and the unparsed remainder of `buf`.
+    pub fn python_parser(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# Generated from the definition of `{}`, do not edit.\n", self.name);
+        out.push_str("import struct\n\n");
+        let _ = writeln!(out, "BYTE_ORDER = {:?}\n", if byte_order() == "little" { "<" } else { ">" });
+        match self.body {
+            Body::Struct(ref fields) => {
+                out.push_str("TAG = None\n\nLAYOUTS = {\n    None: (");
+                let _ = write!(out, "{:?}, ", self.name);
+                write_python_fields(fields, &mut out);
+                out.push_str("),\n}\n");
+            },
+            Body::Enum { ref tag, ref variants } => {
+                out.push_str("TAG = ");
+                write_python_fields(&[tag.clone()], &mut out);
+                out.push_str("[0]\n\nLAYOUTS = {\n");
+                for v in variants {
+                    let _ = write!(out, "    {:#x}: ({:?}, ", v.tag, v.name);
+                    write_python_fields(&v.fields, &mut out);
+                    out.push_str("),\n");
+                }
+                out.push_str("}\n");
+            },
+        }
+        out.push_str(PYTHON_PARSER);
+        out
+    }
+}
+
+const PYTHON_PARSER: &'static str = r#"
+_INTS = {'u8': 'B', 'u16': 'H', 'u32': 'I', 'u64': 'Q',
+         'i8': 'b', 'i16': 'h', 'i32': 'i', 'i64': 'q'}
+
+# tags are often newtypes or bitflags, so we go by size
+_TAGS = {1: 'B', 2: 'H', 4: 'I', 8: 'Q'}
+
+
+class NotEnoughBytes(Exception):
+    pass
+
+
+def _read(ty, buf, offset, fields):
+    kind = ty[0]
+    if kind == 'fixed':
+        _, name, size = ty
+        if len(buf) < offset + size:
+            raise NotEnoughBytes(offset + size)
+        raw = bytes(buf[offset:offset + size])
+        if name in _INTS:
+            return struct.unpack(BYTE_ORDER + _INTS[name], raw)[0], offset + size
+        return raw, offset + size
+    if kind == 'array':
+        count = ty[2]
+    else:
+        count = fields[ty[2]]
+    values = []
+    for _ in range(count):
+        value, offset = _read(ty[1], buf, offset, fields)
+        values.append(value)
+    return values, offset
+
+
+def parse(buf):
+    offset = 0
+    tag = None
+    if TAG is not None:
+        offset = TAG[1][2]
+        if len(buf) < offset:
+            raise NotEnoughBytes(offset)
+        tag = struct.unpack(BYTE_ORDER + _TAGS[offset], bytes(buf[:offset]))[0]
+    if tag not in LAYOUTS:
+        raise ValueError('bad tag %r' % (tag,))
+    name, layout = LAYOUTS[tag]
+    fields = {}
+    for field, ty in layout:
+        fields[field], offset = _read(ty, buf, offset, fields)
+    return name, fields, buf[offset:]
+"#;
+
+fn write_json_fields(fields: &[Field], out: &mut String) {
+    out.push('[');
+    for (i, f) in fields.iter().enumerate() {
+        if i > 0 { out.push(',') }
+        let _ = write!(out, "{{\"name\":{},\"type\":", json_str(f.name));
+        f.ty.write_json(out);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+fn write_python_fields(fields: &[Field], out: &mut String) {
+    out.push('[');
+    for (i, f) in fields.iter().enumerate() {
+        if i > 0 { out.push_str(", ") }
+        let _ = write!(out, "({:?}, ", f.name);
+        f.ty.write_python(out);
+        out.push(')');
+    }
+    out.push(']');
+}
+
+fn write_c_struct(name: &str, tag: Option<(&str, &str)>, fields: &[Field], out: &mut String) {
+    let _ = writeln!(out, "struct __attribute__((packed)) {} {{", name);
+    if let Some((tag_ty, tag)) = tag {
+        let _ = writeln!(out, "    {} {};", tag_ty, tag);
+    }
+    let mut fields = fields.iter();
+    for f in &mut fields {
+        match (&f.ty, f.ty.c_int()) {
+            (_, Some(c_ty)) => { let _ = writeln!(out, "    {} {};", c_ty, f.name); },
+            (&Type::VarArray { ref elem, count }, _) => {
+                let _ = writeln!(out, "    // followed by");
+                let _ = writeln!(out, "    //   {}: {} elements of {} bytes",
+                    f.name, count, elem.size().unwrap_or(0));
+                break
+            },
+            (ty, _) => {
+                let _ = writeln!(out, "    uint8_t {}[{}];", f.name, ty.size().unwrap_or(0));
+            },
+        }
+    }
+    for f in fields {
+        match f.ty {
+            Type::VarArray { ref elem, count } => {
+                let _ = writeln!(out, "    //   {}: {} elements of {} bytes",
+                    f.name, count, elem.size().unwrap_or(0));
+            },
+            ref ty => {
+                let _ = writeln!(out, "    //   {}: {} bytes", f.name, ty.size().unwrap_or(0));
+            },
+        }
+    }
+    let _ = writeln!(out, "}};\n");
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
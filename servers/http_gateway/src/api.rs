@@ -0,0 +1,154 @@
+//! The JSON bodies the gateway accepts and returns, see `Readme.md`.
+//!
+//! Locations are `[chain, index]` pairs. Entry data is sent as a UTF-8 string
+//! in `data`, or as base64 in `data_base64` if it is arbitrary bytes.
+
+use base64;
+
+use fuzzy_log_client::packets::{order, entry, OrderIndex};
+
+pub type Loc = (u64, u64);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NewSession {
+    pub chains: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionCreated {
+    pub session: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Append {
+    pub chain: u64,
+    #[serde(default)]
+    pub deps: Vec<Loc>,
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub data_base64: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Multiappend {
+    pub chains: Vec<u64>,
+    #[serde(default)]
+    pub deps: Vec<Loc>,
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub data_base64: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub chains: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Appended {
+    pub locs: Vec<Loc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    pub locs: Vec<Loc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_base64: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entries {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Error {
+    pub error: String,
+}
+
+impl Entry {
+    pub fn new(data: &[u8], locs: &[OrderIndex]) -> Self {
+        let (data, data_base64) = match ::std::str::from_utf8(data) {
+            Ok(s) => (Some(s.to_owned()), None),
+            Err(..) => (None, Some(base64::encode(data))),
+        };
+        Entry { locs: from_locs(locs), data, data_base64 }
+    }
+}
+
+/// The bytes to append, exactly one of `data` and `data_base64` must be present.
+pub fn payload(data: &Option<String>, data_base64: &Option<String>) -> Result<Vec<u8>, String> {
+    match (data, data_base64) {
+        (&Some(ref data), &None) => Ok(data.clone().into_bytes()),
+        (&None, &Some(ref encoded)) =>
+            base64::decode(encoded).map_err(|e| format!("bad data_base64: {}", e)),
+        (&None, &None) => Err("missing data".to_owned()),
+        (&Some(..), &Some(..)) => Err("only one of data and data_base64 may be set".to_owned()),
+    }
+}
+
+pub fn to_chains(chains: &[u64]) -> Vec<order> {
+    chains.iter().cloned().map(order::from).collect()
+}
+
+pub fn to_locs(locs: &[Loc]) -> Vec<OrderIndex> {
+    locs.iter().map(|&(chain, index)| OrderIndex(order::from(chain), entry::from(index))).collect()
+}
+
+pub fn from_locs(locs: &[OrderIndex]) -> Vec<Loc> {
+    locs.iter().map(|&OrderIndex(chain, index)| (chain.into(), index.into())).collect()
+}
+
+/// Parse the `chains=1,2,3` parameter of a query string,
+/// returns an empty list if there is none.
+pub fn query_chains(query: &str) -> Result<Vec<u64>, String> {
+    for param in query.split('&') {
+        let mut kv = param.splitn(2, '=');
+        if kv.next() != Some("chains") {
+            continue
+        }
+        return kv.next().unwrap_or("").split(',').filter(|c| !c.is_empty()).map(|c| {
+            c.parse().map_err(|_| format!("bad chain {:?}", c))
+        }).collect()
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads() {
+        assert_eq!(payload(&Some("abc".to_owned()), &None), Ok(b"abc".to_vec()));
+        assert_eq!(payload(&None, &Some("AAH/".to_owned())), Ok(vec![0, 1, 255]));
+        assert!(payload(&None, &None).is_err());
+        assert!(payload(&Some("a".to_owned()), &Some("YQ==".to_owned())).is_err());
+        assert!(payload(&None, &Some("!".to_owned())).is_err());
+    }
+
+    #[test]
+    fn entries_pick_encoding() {
+        let locs = [OrderIndex(order::from(3), entry::from(1))];
+        let text = Entry::new(b"hello", &locs);
+        assert_eq!(text.locs, vec![(3, 1)]);
+        assert_eq!(text.data, Some("hello".to_owned()));
+        assert_eq!(text.data_base64, None);
+        let bytes = Entry::new(&[0, 1, 255], &locs);
+        assert_eq!(bytes.data, None);
+        assert_eq!(bytes.data_base64, Some("AAH/".to_owned()));
+    }
+
+    #[test]
+    fn parse_query_chains() {
+        assert_eq!(query_chains(""), Ok(vec![]));
+        assert_eq!(query_chains("chains=1,2,30"), Ok(vec![1, 2, 30]));
+        assert_eq!(query_chains("poll=5&chains=7"), Ok(vec![7]));
+        assert!(query_chains("chains=1,x").is_err());
+    }
+}
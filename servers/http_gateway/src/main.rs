@@ -0,0 +1,368 @@
+#[macro_use]
+extern crate log;
+
+extern crate base64;
+extern crate env_logger;
+extern crate fuzzy_log_client;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tiny_http;
+
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fuzzy_log_client::fuzzy_log::log_handle::{GetRes, LogBuilder, LogHandle};
+use fuzzy_log_client::packets::order;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use structopt::StructOpt;
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+mod api;
+mod tail;
+
+/// Request bodies larger than this are rejected rather than buffered.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "http_gateway", about = "Serve the FuzzyLog over HTTP/JSON.")]
+struct Args {
+    #[structopt(help = "FuzzyLog servers to run against.")]
+    servers: ServerAddrs,
+
+    #[structopt(short="p", long="port", help = "port to listen on.", default_value="13337")]
+    port: u16,
+
+    #[structopt(short="a", long="all-interfaces", help = "listen on all interfaces instead of localhost.")]
+    all_interfaces: bool,
+
+    #[structopt(short="i", long="poll-interval", help = "milliseconds between tail polls.", default_value="10")]
+    poll_interval: u64,
+
+    #[structopt(short="t", long="session-timeout", help = "seconds a session may go unused before it is ended.", default_value="300")]
+    session_timeout: u64,
+
+    #[structopt(short="m", long="max-sessions", help = "how many sessions may be open at once.", default_value="1024")]
+    max_sessions: usize,
+}
+
+#[derive(Debug)]
+struct ServerAddrs(Vec<(SocketAddr, SocketAddr)>);
+
+impl FromStr for ServerAddrs {
+    type Err = std::string::ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ServerAddrs(
+            s.split('^').map(|t|{
+                let mut addrs = t.split('#').map(|s| {
+                    match SocketAddr::from_str(s) {
+                        Ok(addr) => addr,
+                        Err(e) => panic!("head parse err {} @ {}", e, s),
+                    }
+                });
+                let head = addrs.next().expect("no head");
+                let tail = if let Some(addr) = addrs.next() {
+                    addr
+                } else {
+                    head
+                };
+                assert!(addrs.next().is_none());
+                (head, tail)
+            }).collect()
+        ))
+    }
+}
+
+struct Gateway {
+    servers: ServerAddrs,
+    poll_interval: Duration,
+    session_timeout: Duration,
+    max_sessions: usize,
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    next_session: AtomicUsize,
+}
+
+/// A session is a single client of the log; requests within a session
+/// are run one at a time, in the order the gateway receives them.
+struct Session {
+    chains: Vec<order>,
+    handle: Mutex<LogHandle<[u8]>>,
+    /// When the session's last request arrived, sessions unused for longer
+    /// than the gateway's `session_timeout` are ended.
+    last_used: Mutex<Instant>,
+}
+
+type Failure = (u16, String);
+
+fn main() {
+    let _ = env_logger::init();
+    let args @ Args{..} = StructOpt::from_args();
+
+    let ip = if args.all_interfaces { [0, 0, 0, 0] } else { [127, 0, 0, 1] };
+    let addr = SocketAddr::from((ip, args.port));
+    let server = Server::http(addr).expect("could not listen");
+    println!("serving {:?} at http://{}", &args.servers.0, addr);
+
+    let gateway = Arc::new(Gateway {
+        servers: args.servers,
+        poll_interval: Duration::from_millis(args.poll_interval),
+        session_timeout: Duration::from_secs(args.session_timeout),
+        max_sessions: args.max_sessions,
+        sessions: Default::default(),
+        next_session: AtomicUsize::new(1),
+    });
+    let reaper = gateway.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        reaper.end_idle_sessions();
+    });
+    for request in server.incoming_requests() {
+        let gateway = gateway.clone();
+        thread::spawn(move || {
+            let peer = *request.remote_addr();
+            match gateway.handle(request) {
+                Ok(()) => trace!("{:?} done", peer),
+                Err(e) => error!("{:?} failed with {}", peer, e),
+            }
+        });
+    }
+}
+
+impl Gateway {
+    fn handle(&self, mut request: Request) -> io::Result<()> {
+        let url = request.url().to_owned();
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i+1..]),
+            None => (&url[..], ""),
+        };
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        let route = (segments.next(), segments.next(), segments.next(), segments.next());
+        let method = request.method().clone();
+        let result = match (method, route) {
+            (Method::Post, (Some("sessions"), None, None, None)) =>
+                read_body(&mut request).and_then(|new| self.new_session(new)),
+
+            (Method::Delete, (Some("sessions"), Some(id), None, None)) =>
+                self.end_session(id),
+
+            (Method::Post, (Some("sessions"), Some(id), Some("append"), None)) =>
+                self.session(id).and_then(|session| {
+                    read_body(&mut request).and_then(|append| session.append(append))
+                }),
+
+            (Method::Post, (Some("sessions"), Some(id), Some("multiappend"), None)) =>
+                self.session(id).and_then(|session| {
+                    read_body(&mut request).and_then(|append| session.multiappend(append))
+                }),
+
+            (Method::Post, (Some("sessions"), Some(id), Some("snapshot"), None)) =>
+                self.session(id).and_then(|session| {
+                    read_body(&mut request).and_then(|snapshot| session.snapshot(snapshot))
+                }),
+
+            (Method::Get, (Some("sessions"), Some(id), Some("tail"), None)) => {
+                let chains = self.session(id).and_then(|session| {
+                    let chains = api::query_chains(query).map_err(bad_request)?;
+                    if chains.is_empty() {
+                        Ok(session.chains.clone())
+                    } else {
+                        Ok(api::to_chains(&chains))
+                    }
+                });
+                match chains {
+                    Ok(chains) => {
+                        let handle = self.builder(&chains).build();
+                        let out = request.into_writer();
+                        return tail::tail(handle, &chains, self.poll_interval, out)
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+
+            _ => Err((404, format!("no route for {}", path))),
+        };
+        let (status, body) = match result {
+            Ok(body) => (200, body),
+            Err((status, error)) => (status, to_json(&api::Error { error })),
+        };
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("valid header");
+        request.respond(
+            Response::from_string(body).with_status_code(status).with_header(content_type)
+        )
+    }
+
+    fn builder(&self, chains: &[order]) -> LogBuilder<[u8]> {
+        let servers = &self.servers.0;
+        if servers[0].0 != servers[0].1 {
+            LogHandle::<[u8]>::replicated_with_servers(&servers[..])
+        } else {
+            LogHandle::<[u8]>::unreplicated_with_servers(servers.iter().map(|&(a, _)| a))
+        }.chains(chains)
+    }
+
+    fn new_session(&self, new: api::NewSession) -> Result<String, Failure> {
+        if new.chains.is_empty() {
+            return Err(bad_request("a session must read from at least one chain"))
+        }
+        if self.sessions.lock().unwrap().len() >= self.max_sessions {
+            return Err(too_many_sessions())
+        }
+        let chains = api::to_chains(&new.chains);
+        let handle = self.builder(&chains).build();
+        let session = self.next_session.fetch_add(1, Ordering::Relaxed) as u64;
+        let mut sessions = self.sessions.lock().unwrap();
+        // others may have opened sessions while we were connecting
+        if sessions.len() >= self.max_sessions {
+            return Err(too_many_sessions())
+        }
+        let old = sessions.insert(session, Arc::new(Session {
+            chains,
+            handle: Mutex::new(handle),
+            last_used: Mutex::new(Instant::now()),
+        }));
+        debug_assert!(old.is_none());
+        Ok(to_json(&api::SessionCreated { session }))
+    }
+
+    fn end_idle_sessions(&self) {
+        let now = Instant::now();
+        let timeout = self.session_timeout;
+        self.sessions.lock().unwrap().retain(|&id, session| {
+            let idle = now.duration_since(*session.last_used.lock().unwrap()) > timeout;
+            if idle {
+                debug!("session {} timed out", id);
+            }
+            !idle
+        });
+    }
+
+    fn end_session(&self, id: &str) -> Result<String, Failure> {
+        let id = parse_session(id)?;
+        match self.sessions.lock().unwrap().remove(&id) {
+            Some(..) => Ok("{}".to_owned()),
+            None => Err(no_session(id)),
+        }
+    }
+
+    fn session(&self, id: &str) -> Result<Arc<Session>, Failure> {
+        let id = parse_session(id)?;
+        let session = self.sessions.lock().unwrap().get(&id).cloned().ok_or_else(|| no_session(id))?;
+        *session.last_used.lock().unwrap() = Instant::now();
+        Ok(session)
+    }
+}
+
+impl Session {
+    fn append(&self, append: api::Append) -> Result<String, Failure> {
+        let data = api::payload(&append.data, &append.data_base64).map_err(bad_request)?;
+        let deps = api::to_locs(&append.deps);
+        let mut handle = self.handle.lock().unwrap();
+        let id = handle.async_append(order::from(append.chain), &data[..], &deps);
+        let locs = handle.wait_for_a_specific_append(id).map_err(log_error)?;
+        Ok(to_json(&api::Appended { locs: api::from_locs(&locs) }))
+    }
+
+    fn multiappend(&self, append: api::Multiappend) -> Result<String, Failure> {
+        if append.chains.is_empty() {
+            return Err(bad_request("multiappend to no chains"))
+        }
+        let data = api::payload(&append.data, &append.data_base64).map_err(bad_request)?;
+        let chains = api::to_chains(&append.chains);
+        let deps = api::to_locs(&append.deps);
+        let mut handle = self.handle.lock().unwrap();
+        let id = handle.async_multiappend(&chains, &data[..], &deps);
+        let locs = handle.wait_for_a_specific_append(id).map_err(log_error)?;
+        Ok(to_json(&api::Appended { locs: api::from_locs(&locs) }))
+    }
+
+    fn snapshot(&self, snapshot: api::Snapshot) -> Result<String, Failure> {
+        let chains = if snapshot.chains.is_empty() {
+            self.chains.clone()
+        } else {
+            api::to_chains(&snapshot.chains)
+        };
+        let mut handle = self.handle.lock().unwrap();
+        handle.snapshot_colors(&chains);
+        let mut entries = vec![];
+        loop {
+            match handle.get_next() {
+                Ok((data, locs)) => entries.push(api::Entry::new(data, locs)),
+                Err(GetRes::Done) => break,
+                Err(e) => return Err(log_error(e)),
+            }
+        }
+        Ok(to_json(&api::Entries { entries }))
+    }
+}
+
+fn read_body<T: DeserializeOwned>(request: &mut Request) -> Result<T, Failure> {
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY_SIZE + 1).read_to_string(&mut body)
+        .map_err(|e| bad_request(format!("could not read body: {}", e)))?;
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err((413, "body too large".to_owned()))
+    }
+    serde_json::from_str(&body).map_err(|e| bad_request(format!("bad body: {}", e)))
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("gateway responses are always serializable")
+}
+
+fn parse_session(id: &str) -> Result<u64, Failure> {
+    id.parse().map_err(|_| bad_request(format!("bad session {:?}", id)))
+}
+
+fn no_session(id: u64) -> Failure {
+    (404, format!("no session {}", id))
+}
+
+fn too_many_sessions() -> Failure {
+    (503, "too many open sessions".to_owned())
+}
+
+fn bad_request<S: Into<String>>(error: S) -> Failure {
+    (400, error.into())
+}
+
+fn log_error<E: ::std::fmt::Debug>(error: E) -> Failure {
+    (502, format!("{:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_cap() {
+        let addr = "127.0.0.1:13338".parse().unwrap();
+        let gateway = Gateway {
+            servers: ServerAddrs(vec![(addr, addr)]),
+            poll_interval: Duration::from_millis(10),
+            session_timeout: Duration::from_secs(1),
+            max_sessions: 0,
+            sessions: Default::default(),
+            next_session: AtomicUsize::new(1),
+        };
+        // the cap is checked before we connect to the log
+        let status = gateway.new_session(api::NewSession { chains: vec![1] }).map_err(|(s, _)| s);
+        assert_eq!(status, Err(503));
+        assert!(gateway.sessions.lock().unwrap().is_empty());
+    }
+}
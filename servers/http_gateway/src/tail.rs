@@ -0,0 +1,85 @@
+//! Tailing a set of colors as a stream of server-sent events.
+//!
+//! Each tail owns its own `LogHandle`, so a slow reader does not hold up
+//! the appends and snapshots of the session it was opened from.
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+
+use fuzzy_log_client::fuzzy_log::log_handle::{GetRes, LogHandle};
+use fuzzy_log_client::packets::order;
+
+use api::Entry;
+
+/// Comment lines are sent after this many empty polls, so that we notice
+/// when the client has gone away even if no new entries arrive.
+const KEEP_ALIVE_POLLS: u32 = 100;
+
+pub const HEADER: &'static [u8] = b"HTTP/1.1 200 OK\r\n\
+    Content-Type: text/event-stream\r\n\
+    Cache-Control: no-cache\r\n\
+    Connection: close\r\n\r\n";
+
+/// Send every entry in `chains` to `out`, as it arrives, until the client disconnects.
+pub fn tail<W: Write>(
+    mut handle: LogHandle<[u8]>, chains: &[order], poll_interval: Duration, mut out: W
+) -> io::Result<()> {
+    out.write_all(HEADER)?;
+    out.flush()?;
+    let mut event = vec![];
+    let mut idle_polls = 0;
+    loop {
+        let mut sent = false;
+        handle.snapshot_colors(chains);
+        loop {
+            match handle.get_next() {
+                Ok((data, locs)) => {
+                    event.clear();
+                    write_event(&mut event, &Entry::new(data, locs));
+                    out.write_all(&event)?;
+                    sent = true;
+                },
+                Err(GetRes::Done) => break,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+            }
+        }
+        if sent {
+            idle_polls = 0;
+            out.flush()?;
+            continue
+        }
+        idle_polls += 1;
+        if idle_polls >= KEEP_ALIVE_POLLS {
+            idle_polls = 0;
+            out.write_all(b":\n\n")?;
+            out.flush()?;
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+pub fn write_event(buffer: &mut Vec<u8>, entry: &Entry) {
+    buffer.extend_from_slice(b"event: entry\ndata: ");
+    serde_json::to_writer(&mut *buffer, entry).expect("writes to a vec cannot fail");
+    buffer.extend_from_slice(b"\n\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fuzzy_log_client::packets::{entry, OrderIndex};
+
+    #[test]
+    fn event_format() {
+        let mut buffer = vec![];
+        write_event(&mut buffer, &Entry::new(b"hi", &[OrderIndex(order::from(2), entry::from(5))]));
+        assert_eq!(
+            ::std::str::from_utf8(&buffer).unwrap(),
+            "event: entry\ndata: {\"locs\":[[2,5]],\"data\":\"hi\"}\n\n"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+#[macro_use]
+extern crate log;
+
+extern crate byteorder;
+extern crate env_logger;
+extern crate fuzzy_log_client;
+
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use fuzzy_log_client::fuzzy_log::log_handle::{GetRes, LogHandle, TryWaitRes};
+use fuzzy_log_client::packets::order;
+
+use structopt::StructOpt;
+
+use protocol::{Request, Response};
+
+mod protocol;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "proxy", about = "Serve the FuzzyLog over a language neutral protocol.")]
+struct Args {
+    #[structopt(help = "FuzzyLog servers to run against.")]
+    servers: ServerAddrs,
+
+    #[structopt(short="p", long="port", help = "port to listen on.", default_value="13336")]
+    port: u16,
+
+    #[structopt(short="a", long="all-interfaces", help = "listen on all interfaces instead of localhost.")]
+    all_interfaces: bool,
+}
+
+#[derive(Debug)]
+struct ServerAddrs(Vec<(SocketAddr, SocketAddr)>);
+
+impl FromStr for ServerAddrs {
+    type Err = std::string::ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ServerAddrs(
+            s.split('^').map(|t|{
+                let mut addrs = t.split('#').map(|s| {
+                    match SocketAddr::from_str(s) {
+                        Ok(addr) => addr,
+                        Err(e) => panic!("head parse err {} @ {}", e, s),
+                    }
+                });
+                let head = addrs.next().expect("no head");
+                let tail = if let Some(addr) = addrs.next() {
+                    addr
+                } else {
+                    head
+                };
+                assert!(addrs.next().is_none());
+                (head, tail)
+            }).collect()
+        ))
+    }
+}
+
+fn main() {
+    let _ = env_logger::init();
+    let args @ Args{..} = StructOpt::from_args();
+
+    let ip = if args.all_interfaces { [0, 0, 0, 0] } else { [127, 0, 0, 1] };
+    let addr = SocketAddr::from((ip, args.port));
+    let listener = TcpListener::bind(addr).expect("could not listen");
+    println!("proxying {:?} at {}", &args.servers.0, addr);
+
+    let servers = Arc::new(args.servers);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("accept error {}", e);
+                continue
+            },
+        };
+        let servers = servers.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            match serve(stream, &servers) {
+                Ok(()) => trace!("{:?} disconnected", peer),
+                Err(e) => error!("{:?} disconnected with {}", peer, e),
+            }
+        });
+    }
+}
+
+fn serve(stream: TcpStream, servers: &ServerAddrs) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let (version, chains) = protocol::read_hello(&mut reader)?;
+    if version != protocol::VERSION {
+        protocol::write_hello_reply(&mut writer, protocol::status::ERROR)?;
+        return writer.flush()
+    }
+    protocol::write_hello_reply(&mut writer, protocol::status::OK)?;
+    writer.flush()?;
+
+    let mut handle = if servers.0[0].0 != servers.0[0].1 {
+        LogHandle::<[u8]>::replicated_with_servers(&servers.0[..])
+    } else {
+        LogHandle::<[u8]>::unreplicated_with_servers(servers.0.iter().map(|&(a, _)| a))
+    }.chains(&chains[..])
+        .build();
+
+    let mut buffer = vec![];
+    let mut response = Response::default();
+    loop {
+        response.clear();
+        match protocol::read_request(&mut reader, &mut buffer)? {
+            None => return Ok(()),
+            Some(request) => handle_request(&mut handle, &chains, request, &mut response),
+        }
+        response.send(&mut writer)?;
+        writer.flush()?;
+    }
+}
+
+fn handle_request(
+    handle: &mut LogHandle<[u8]>, interesting: &[order], request: Request, response: &mut Response
+) {
+    match request {
+        Request::Append { chain, deps, data } => {
+            let id = handle.async_append(chain, data, &deps);
+            response.id(&id);
+        },
+
+        Request::Multiappend { chains, deps, data } => {
+            if chains.is_empty() {
+                response.error("multiappend to no chains");
+                return
+            }
+            let id = handle.async_multiappend(&chains, data, &deps);
+            response.id(&id);
+        },
+
+        Request::Snapshot { chains } => {
+            if chains.is_empty() {
+                handle.snapshot_colors(interesting)
+            } else {
+                handle.snapshot_colors(&chains)
+            }
+        },
+
+        Request::GetNext => match handle.get_next() {
+            Ok((data, locs)) => { response.locs(locs).data(data); },
+            Err(GetRes::Done) => { response.done(); },
+            Err(e) => { response.error(&format!("{:?}", e)); },
+        },
+
+        Request::Wait => match handle.wait_for_any_append() {
+            Ok((id, locs)) => { response.id(&id).locs(&locs); },
+            Err(TryWaitRes::NothingReady) => { response.error("no appends in flight"); },
+            Err(e) => { response.error(&format!("{:?}", e)); },
+        },
+    }
+}
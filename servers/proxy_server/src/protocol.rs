@@ -0,0 +1,265 @@
+//! The proxy's wire format, see `Readme.md` for the full description.
+//!
+//! All integers are big-endian. After the hello exchange every request and
+//! response is a frame consisting of a `u32` length, followed by that many bytes,
+//! the first of which is the op code (for requests) or status (for responses).
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use fuzzy_log_client::packets::{order, entry, OrderIndex, Uuid};
+
+pub const MAGIC: &'static [u8; 4] = b"FZLP";
+pub const VERSION: u16 = 1;
+
+/// Frames larger than this are rejected rather than buffered.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+pub mod op {
+    pub const APPEND: u8 = 1;
+    pub const MULTIAPPEND: u8 = 2;
+    pub const SNAPSHOT: u8 = 3;
+    pub const GET_NEXT: u8 = 4;
+    pub const WAIT: u8 = 5;
+}
+
+pub mod status {
+    pub const OK: u8 = 0;
+    pub const DONE: u8 = 1;
+    pub const ERROR: u8 = 2;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Append { chain: order, deps: Vec<OrderIndex>, data: &'a [u8] },
+    Multiappend { chains: Vec<order>, deps: Vec<OrderIndex>, data: &'a [u8] },
+    Snapshot { chains: Vec<order> },
+    GetNext,
+    Wait,
+}
+
+/// Read a client's hello, returning the protocol version it speaks and the
+/// chains it is interested in.
+pub fn read_hello<R: Read>(mut read: R) -> io::Result<(u16, Vec<order>)> {
+    let mut magic = [0; 4];
+    read.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a FuzzyLog proxy client"))
+    }
+    let version = read.read_u16::<BigEndian>()?;
+    let chains = read_chains(&mut read)?;
+    Ok((version, chains))
+}
+
+pub fn write_hello<W: Write>(mut write: W, chains: &[order]) -> io::Result<()> {
+    write.write_all(MAGIC)?;
+    write.write_u16::<BigEndian>(VERSION)?;
+    write_chains(&mut write, chains)
+}
+
+pub fn write_hello_reply<W: Write>(mut write: W, status: u8) -> io::Result<()> {
+    write.write_u16::<BigEndian>(VERSION)?;
+    write.write_u8(status)
+}
+
+/// Read the next request frame into `buffer` and parse it,
+/// `None` if the client closed the connection between requests.
+pub fn read_request<'b, R: Read>(mut read: R, buffer: &'b mut Vec<u8>)
+-> io::Result<Option<Request<'b>>> {
+    let len = match read.read_u32::<BigEndian>() {
+        Ok(len) => len as usize,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len == 0 || len > MAX_FRAME_SIZE {
+        return Err(invalid("bad frame length"))
+    }
+    buffer.clear();
+    buffer.resize(len, 0);
+    read.read_exact(&mut buffer[..])?;
+    parse_request(&buffer[..]).map(Some)
+}
+
+pub fn parse_request(frame: &[u8]) -> io::Result<Request> {
+    let (&op, mut body) = frame.split_first().ok_or_else(|| invalid("empty frame"))?;
+    let request = match op {
+        op::APPEND => {
+            let chain = order::from(body.read_u64::<BigEndian>()?);
+            let deps = read_locs(&mut body)?;
+            Request::Append { chain, deps, data: body }
+        },
+        op::MULTIAPPEND => {
+            let chains = read_chains(&mut body)?;
+            let deps = read_locs(&mut body)?;
+            Request::Multiappend { chains, deps, data: body }
+        },
+        op::SNAPSHOT => Request::Snapshot { chains: read_chains(&mut body)? },
+        op::GET_NEXT => Request::GetNext,
+        op::WAIT => Request::Wait,
+        _ => return Err(invalid("unknown op")),
+    };
+    Ok(request)
+}
+
+pub fn write_request<W: Write>(mut write: W, request: &Request) -> io::Result<()> {
+    let mut frame = vec![];
+    match *request {
+        Request::Append { chain, ref deps, data } => {
+            frame.write_u8(op::APPEND)?;
+            frame.write_u64::<BigEndian>(chain.into())?;
+            write_locs(&mut frame, deps)?;
+            frame.extend_from_slice(data);
+        },
+        Request::Multiappend { ref chains, ref deps, data } => {
+            frame.write_u8(op::MULTIAPPEND)?;
+            write_chains(&mut frame, chains)?;
+            write_locs(&mut frame, deps)?;
+            frame.extend_from_slice(data);
+        },
+        Request::Snapshot { ref chains } => {
+            frame.write_u8(op::SNAPSHOT)?;
+            write_chains(&mut frame, chains)?;
+        },
+        Request::GetNext => frame.write_u8(op::GET_NEXT)?,
+        Request::Wait => frame.write_u8(op::WAIT)?,
+    }
+    write_frame(&mut write, &frame)
+}
+
+/// A response under construction, the status is filled in when it is sent.
+#[derive(Debug, Default)]
+pub struct Response {
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn clear(&mut self) {
+        self.body.clear();
+        self.body.push(status::OK);
+    }
+
+    pub fn id(&mut self, id: &Uuid) -> &mut Self {
+        self.body.extend_from_slice(id.as_bytes());
+        self
+    }
+
+    pub fn locs(&mut self, locs: &[OrderIndex]) -> &mut Self {
+        write_locs(&mut self.body, locs).expect("writes to a vec cannot fail");
+        self
+    }
+
+    pub fn data(&mut self, data: &[u8]) -> &mut Self {
+        self.body.extend_from_slice(data);
+        self
+    }
+
+    pub fn error(&mut self, message: &str) -> &mut Self {
+        self.body.clear();
+        self.body.push(status::ERROR);
+        self.body.extend_from_slice(message.as_bytes());
+        self
+    }
+
+    pub fn done(&mut self) -> &mut Self {
+        self.body.clear();
+        self.body.push(status::DONE);
+        self
+    }
+
+    pub fn send<W: Write>(&self, write: W) -> io::Result<()> {
+        write_frame(write, &self.body)
+    }
+}
+
+fn write_frame<W: Write>(mut write: W, frame: &[u8]) -> io::Result<()> {
+    write.write_u32::<BigEndian>(frame.len() as u32)?;
+    write.write_all(frame)
+}
+
+fn read_chains<R: Read>(mut read: R) -> io::Result<Vec<order>> {
+    let num_chains = read.read_u32::<BigEndian>()? as usize;
+    if num_chains > MAX_FRAME_SIZE / 8 {
+        return Err(invalid("too many chains"))
+    }
+    (0..num_chains).map(|_| read.read_u64::<BigEndian>().map(order::from)).collect()
+}
+
+fn write_chains<W: Write>(mut write: W, chains: &[order]) -> io::Result<()> {
+    write.write_u32::<BigEndian>(chains.len() as u32)?;
+    for &chain in chains {
+        write.write_u64::<BigEndian>(chain.into())?
+    }
+    Ok(())
+}
+
+fn read_locs<R: Read>(mut read: R) -> io::Result<Vec<OrderIndex>> {
+    let num_locs = read.read_u32::<BigEndian>()? as usize;
+    if num_locs > MAX_FRAME_SIZE / 16 {
+        return Err(invalid("too many locations"))
+    }
+    (0..num_locs).map(|_| {
+        let chain = read.read_u64::<BigEndian>()?;
+        let index = read.read_u64::<BigEndian>()?;
+        Ok(OrderIndex(order::from(chain), entry::from(index)))
+    }).collect()
+}
+
+fn write_locs<W: Write>(mut write: W, locs: &[OrderIndex]) -> io::Result<()> {
+    write.write_u32::<BigEndian>(locs.len() as u32)?;
+    for &OrderIndex(chain, index) in locs {
+        write.write_u64::<BigEndian>(chain.into())?;
+        write.write_u64::<BigEndian>(index.into())?;
+    }
+    Ok(())
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        let mut bytes = vec![];
+        write_hello(&mut bytes, &[order::from(3), order::from(7)]).unwrap();
+        assert_eq!(read_hello(&bytes[..]).unwrap(), (VERSION, vec![order::from(3), order::from(7)]));
+        bytes[0] = b'X';
+        assert!(read_hello(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let deps = vec![OrderIndex(order::from(1), entry::from(2))];
+        let requests = [
+            Request::Append { chain: order::from(5), deps: deps.clone(), data: &[1, 2, 3] },
+            Request::Multiappend {
+                chains: vec![order::from(5), order::from(6)], deps: vec![], data: &[],
+            },
+            Request::Snapshot { chains: vec![] },
+            Request::GetNext,
+            Request::Wait,
+        ];
+        let mut bytes = vec![];
+        for request in &requests {
+            write_request(&mut bytes, request).unwrap();
+        }
+        let mut read = &bytes[..];
+        let mut buffer = vec![];
+        for request in &requests {
+            assert_eq!(read_request(&mut read, &mut buffer).unwrap().as_ref(), Some(request));
+        }
+        assert_eq!(read_request(&mut read, &mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let mut buffer = vec![];
+        assert!(read_request(&[0, 0, 0, 0][..], &mut buffer).is_err());
+        assert!(read_request(&[0, 0, 0, 1, 99][..], &mut buffer).is_err());
+        assert!(read_request(&[0, 0, 0, 2, op::APPEND, 0][..], &mut buffer).is_err());
+    }
+}
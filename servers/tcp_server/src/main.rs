@@ -13,7 +13,7 @@ use std::sync::atomic::AtomicUsize;
 
 pub fn main() {
     let _ = env_logger::init();
-    let Args {port_number, group, num_worker_threads, upstream, downstream}
+    let Args {port_number, group, num_worker_threads, num_ordering_threads, upstream, downstream}
         = parse_args();
     let ip_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
     let addr = SocketAddr::new(ip_addr, port_number);
@@ -39,7 +39,15 @@ pub fn main() {
         Ok(accept) => {
             let addr = accept.local_addr().unwrap();
             print_start(addr);
-            if replicated {
+            if num_ordering_threads > 1 {
+                println!("with {} ordering threads", num_ordering_threads);
+                if replicated {
+                    println!("upstream {:?}, downstream {:?}", upstream, downstream);
+                }
+                servers2::tcp::run_sharded(accept, server_num, group_size,
+                    upstream, downstream, num_worker_threads, num_ordering_threads, &a)
+            }
+            else if replicated {
                 println!("upstream {:?}, downstream {:?}", upstream, downstream);
                 servers2::tcp::run_with_replication(accept, server_num, group_size,
                     upstream, downstream, num_worker_threads, &a)
@@ -81,9 +89,9 @@ pub fn main() {
 
 const USAGE: &'static str =
 "Usage:
-\ttcp_server <port number> [-w | --workers <num worker threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
-\ttcp_server (-ls | --lock-server) [-w | --workers <num worker threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
-\ttcp_server (-ig | --in-group <server num>:<num servers in group>) [--workers <num worker threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
+\ttcp_server <port number> [-w | --workers <num worker threads>] [-o | --ordering-threads <num ordering threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
+\ttcp_server (-ls | --lock-server) [-w | --workers <num worker threads>] [-o | --ordering-threads <num ordering threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
+\ttcp_server (-ig | --in-group <server num>:<num servers in group>) [--workers <num worker threads>] [-o | --ordering-threads <num ordering threads>] [-up | --upstream <ip addr>:<port>] [-dwn | --downstream <ip addr>]
 
 can also be run with 'cargo run --release -- <args>...'";
 
@@ -91,6 +99,7 @@ struct Args {
     port_number: u16,
     group: Group,
     num_worker_threads: usize,
+    num_ordering_threads: usize,
     upstream: Option<SocketAddr>,
     downstream: Option<IpAddr>,
 }
@@ -105,6 +114,7 @@ enum Group {
 enum Flag {
     None,
     Workers,
+    OrderingThreads,
     InGroup,
     Upstream,
     Downstream,
@@ -120,6 +130,7 @@ fn parse_args() -> Args {
         port_number: 0,
         group: Group::Singleton,
         num_worker_threads: num_cpus::get() - 2,
+        num_ordering_threads: 1,
         upstream: None,
         downstream: None,
     };
@@ -129,6 +140,7 @@ fn parse_args() -> Args {
             Flag::None => {
                 match &*arg {
                     "-w" | "--workers" => last_flag = Flag::Workers,
+                    "-o" | "--ordering-threads" => last_flag = Flag::OrderingThreads,
                     "-ig" | "--in-group" => {
                         if args.group != Group::Singleton {
                             error!("A server cannot both be in a group and a lock server.");
@@ -179,6 +191,23 @@ fn parse_args() -> Args {
                     }
                 }
             }
+            Flag::OrderingThreads => {
+                match arg.parse() {
+                    Ok(num_threads) if num_threads > 0 && num_threads <= 64 => {
+                        args.num_ordering_threads = num_threads;
+                        last_flag = Flag::None
+                    }
+                    Ok(num_threads) => {
+                        error!("<num ordering threads> must be between 1 and 64, not {}.",
+                            num_threads);
+                        std::process::exit(1)
+                    }
+                    Err(e) => {
+                        error!("Invalid <num ordering threads> at '--ordering-threads': {}.", e);
+                        std::process::exit(1)
+                    }
+                }
+            }
             Flag::Upstream => {
                 match arg.parse() {
                     Ok(addr) => {
@@ -246,6 +275,10 @@ fn parse_args() -> Args {
             error!("Missing <num worker threads> for '--workers'");
             std::process::exit(1)
         }
+        Flag::OrderingThreads => {
+            error!("Missing <num ordering threads> for '--ordering-threads'");
+            std::process::exit(1)
+        }
         Flag::Downstream => {
             error!("Missing <downstream addr> for '--downstream'");
             std::process::exit(1)
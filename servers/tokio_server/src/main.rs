@@ -30,12 +30,15 @@ pub fn main() {
             println!("Starting server {} out of {} at {} with {} worker threads",
                 server_num, group_size, addr, num_worker_threads),
     };
-    if replicated {
-        unimplemented!("no tokio replication yet")
-    } else {
-        tokio_server::run_unreplicated_server(&addr, server_num, group_size, || print_start(addr))
-            .expect("cannot run server");
-    }
+    let num_worker_threads = std::cmp::max(num_worker_threads, 1);
+    tokio_server::run_server(
+        &addr, server_num, group_size, upstream, downstream, num_worker_threads, || {
+            print_start(addr);
+            if replicated {
+                println!("upstream {:?}, downstream {:?}", upstream, downstream);
+            }
+        }
+    ).expect("cannot run server");
 
     // match acceptor {
     //     Ok(accept) => {
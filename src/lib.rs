@@ -18,6 +18,7 @@ extern crate fuzzy_log_util;
 pub extern crate fuzzy_log_packets;
 pub extern crate fuzzy_log_server;
 pub extern crate fuzzy_log_client;
+#[cfg(test)] extern crate tokio_server;
 
 pub use fuzzy_log_packets as packets;
 pub use packets::storeables as storeables;
@@ -866,6 +867,17 @@ pub mod c_binidings {
                 write_id: WriteId::nil(),
                 locs: WriteLocations { num_locs: server, locs: ptr::null_mut() },
             },
+            //TODO report which color was refused
+            Err(TryWaitRes::PermissionDenied(_color))
+            | Err(TryWaitRes::QuotaExceeded(_color))
+            | Err(TryWaitRes::Aborted(_)) => WriteIdAndLocs {
+                write_id: WriteId::nil(),
+                locs: WriteLocations { num_locs: 0, locs: ptr::null_mut() },
+            },
+            Err(TryWaitRes::Corrupted(id)) => WriteIdAndLocs {
+                write_id: WriteId::from_uuid(id),
+                locs: WriteLocations { num_locs: 0, locs: ptr::null_mut() },
+            },
             Ok((id, locs)) => WriteIdAndLocs {
                 write_id: WriteId::from_uuid(id),
                 locs: build_write_locs(locs),
@@ -7,6 +7,9 @@ macro_rules! async_tests {
         async_tests!(rtcp);
         async_tests!(rstcp);
         async_tests!(r3tcp);
+        async_tests!(shtcp);
+        async_tests!(tktcp);
+        async_tests!(tkrtcp);
     };
     (test $new_thread_log:ident, $ntl_with_boring:ident, $ntl_with_simple:ident) => (
         async_tests!(test $new_thread_log, $ntl_with_boring, $ntl_with_simple, false);
@@ -945,6 +948,196 @@ macro_rules! async_tests {
             }
         }
 
+        #[test]
+        #[inline(never)]
+        pub fn test_snapshot_at_least() {
+            use async::fuzzy_log::log_handle::SessionToken;
+
+            let _ = env_logger::init();
+            trace!("TEST snapshot_at_least");
+
+            let token = {
+                let mut writer = $new_thread_log::<u64>(vec![87.into(), 88.into()]);
+                let _ = writer.append(87.into(), &1, &[]);
+                let _ = writer.append(88.into(), &2, &[]);
+                let _ = writer.append(87.into(), &3, &[]);
+                writer.session_token()
+            };
+            assert_eq!(token.horizons(),
+                &[OrderIndex(87.into(), 2.into()), OrderIndex(88.into(), 1.into())][..]);
+            let token = SessionToken::from_bytes(&token.as_bytes().to_vec()).unwrap();
+
+            let mut reader = $new_thread_log::<u64>(vec![87.into(), 88.into()]);
+            reader.snapshot_at_least(&token);
+            let mut got = vec![];
+            loop {
+                match reader.get_next() {
+                    Ok((&v, _)) => got.push(v),
+                    Err(GetRes::Done) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            got.sort();
+            assert_eq!(got, vec![1, 2, 3]);
+            assert_eq!(reader.session_token(), token);
+        }
+
+        #[test]
+        #[inline(never)]
+        pub fn test_transaction_conflict() {
+            use async::fuzzy_log::log_handle::TransactionRes;
+
+            let _ = env_logger::init();
+            trace!("TEST transaction_conflict");
+
+            let columns = vec![89.into(), 90.into()];
+            let mut lh0 = $new_thread_log::<u64>(columns.clone());
+            let mut lh1 = $new_thread_log::<u64>(columns.clone());
+            let _ = lh0.append(89.into(), &1, &[]);
+            for lh in [&mut lh0, &mut lh1].iter_mut() {
+                lh.snapshot(89.into());
+                assert_eq!(lh.get_next(), Ok((&1, &[OrderIndex(89.into(), 1.into())][..])));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+            }
+
+            let committed = {
+                let mut txn = lh0.transaction();
+                txn.read(89.into()).write(89.into()).write(90.into());
+                assert_eq!(txn.read_set(), &[OrderIndex(89.into(), 1.into())][..]);
+                txn.commit(&2)
+            };
+            assert_eq!(committed,
+                Ok(vec![OrderIndex(89.into(), 2.into()), OrderIndex(90.into(), 1.into())]));
+
+            let aborted = {
+                let mut txn = lh1.transaction();
+                txn.read(89.into()).write(90.into());
+                txn.commit(&3)
+            };
+            assert_eq!(aborted, Err(TransactionRes::Aborted(OrderIndex(89.into(), 2.into()))));
+
+            //the aborted transaction was not given a location
+            let mut written = vec![];
+            lh1.sync_events_for_chain(90.into(), |e| written.push((*e.data, e.inhabits.to_vec())))
+                .unwrap();
+            assert_eq!(written,
+                vec![(2, vec![OrderIndex(89.into(), 2.into()), OrderIndex(90.into(), 1.into())])]);
+
+            let committed = {
+                let mut txn = lh1.transaction();
+                txn.read_at(89.into(), 2.into()).write(90.into());
+                txn.commit(&4)
+            };
+            assert_eq!(committed, Ok(vec![OrderIndex(90.into(), 2.into())]));
+        }
+
+        #[test]
+        #[inline(never)]
+        pub fn test_cursors() {
+            use async::fuzzy_log::log_handle::ReadHandle;
+
+            let _ = env_logger::init();
+            trace!("TEST cursors");
+
+            let read_all = |reader: &mut ReadHandle<u64>| {
+                let mut got = vec![];
+                loop {
+                    match reader.get_next() {
+                        Ok((&v, _)) => got.push(v),
+                        Err(GetRes::Done) => return got,
+                        Err(e) => panic!("{:?}", e),
+                    }
+                }
+            };
+
+            let mut lh = $new_thread_log::<u64>(vec![91.into(), 92.into()]);
+            for i in 1..4 {
+                let _ = lh.append(91.into(), &i, &[]);
+            }
+            let _ = lh.append(92.into(), &10, &[]);
+
+            let mut follower = lh.cursor("follower", &[91.into()]);
+            let mut analytics = lh.cursor("analytics", &[91.into(), 92.into()]);
+            assert_eq!(follower.cursor_name(), Some("follower"));
+
+            follower.snapshot(91.into());
+            assert_eq!(read_all(&mut follower), vec![1, 2, 3]);
+            analytics.snapshot(92.into());
+            assert_eq!(read_all(&mut analytics), vec![10]);
+
+            // each cursor carries on from its own horizon
+            let _ = lh.append(91.into(), &4, &[]);
+            follower.snapshot(91.into());
+            assert_eq!(read_all(&mut follower), vec![4]);
+            analytics.snapshot_colors(&[91.into(), 92.into()]);
+            assert_eq!(read_all(&mut analytics), vec![1, 2, 3, 4]);
+
+            // as does the handle itself
+            lh.snapshot(91.into());
+            for i in 1..5 {
+                assert_eq!(lh.get_next(), Ok((&i, &[OrderIndex(91.into(), i.into())][..])));
+            }
+            assert_eq!(lh.get_next(), Err(GetRes::Done));
+
+            drop(analytics);
+            let _ = lh.append(91.into(), &5, &[]);
+            follower.take_snapshot();
+            assert_eq!(read_all(&mut follower), vec![5]);
+        }
+
+        #[test]
+        #[inline(never)]
+        pub fn test_cursors_race() {
+            use async::fuzzy_log::log_handle::ReadHandle;
+
+            let _ = env_logger::init();
+            trace!("TEST cursors race");
+
+            let read_all = |reader: &mut ReadHandle<u64>| {
+                let mut got = vec![];
+                loop {
+                    match reader.get_next() {
+                        Ok((&v, _)) => got.push(v),
+                        Err(GetRes::Done) => return got,
+                        Err(e) => panic!("{:?}", e),
+                    }
+                }
+            };
+
+            let mut lh = $new_thread_log::<u64>(vec![93.into()]);
+            let mut first = lh.cursor("first", &[93.into()]);
+            let mut second = lh.cursor("second", &[93.into()]);
+            for round in 0..3 {
+                for i in 1..11 {
+                    let _ = lh.append(93.into(), &(round * 10 + i), &[]);
+                }
+                // both cursors fetch the same entries at once,
+                // each of which is answered twice
+                first.snapshot(93.into());
+                second.snapshot(93.into());
+                let expected: Vec<_> = (round * 10 + 1..round * 10 + 11).collect();
+                assert_eq!(read_all(&mut second), expected);
+                assert_eq!(read_all(&mut first), expected);
+            }
+
+            // the answers nobody was waiting for don't reach the handle
+            lh.snapshot(93.into());
+            for i in 1..31 {
+                assert_eq!(lh.get_next(), Ok((&i, &[OrderIndex(93.into(), i.into())][..])));
+            }
+            assert_eq!(lh.get_next(), Err(GetRes::Done));
+
+            // nor do the reads of a cursor closed while they're in flight
+            let _ = lh.append(93.into(), &31, &[]);
+            second.snapshot(93.into());
+            drop(second);
+            first.snapshot(93.into());
+            assert_eq!(read_all(&mut first), vec![31]);
+            lh.snapshot(93.into());
+            assert_eq!(lh.get_next(), Ok((&31, &[OrderIndex(93.into(), 31.into())][..])));
+            assert_eq!(lh.get_next(), Err(GetRes::Done));
+        }
+
         //TODO test append after prefetch but before read
     );
     (tcp) => (
@@ -1103,6 +1296,905 @@ macro_rules! async_tests {
                 assert_eq!(lh.get_next(), Err(GetRes::Done));
             }
 
+            #[test]
+            #[inline(never)]
+            pub fn test_recover_crashed_multiappend() {
+                use std::io::{Read, Write};
+                use std::net::{SocketAddr, TcpStream};
+                use std::time::{Duration, Instant};
+                use packets::hello::{self, Hello};
+                let _ = env_logger::init();
+                trace!("TEST recover crashed multiappend");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the chains are on different servers
+                let (c0, c1) = (order::from(1_000_10), order::from(1_000_11));
+
+                // a client which crashes after skeens round 1 on the first server
+                {
+                    let crashed_id = Uuid::new_v4();
+                    let mut server = TcpStream::connect(&addrs[0]).unwrap();
+                    let mut first = [0];
+                    server.read_exact(&mut first).unwrap();
+                    let (hello, _) = hello::peer_hello_bytes(first[0], 2, &Hello::current(0)).unwrap();
+                    server.write_all(&hello).unwrap();
+                    server.write_all(crashed_id.as_bytes()).unwrap();
+                    server.read_exact(&mut [0; hello::HELLO_REPLY_SIZE]).unwrap();
+                    server.read_exact(&mut [0; 16]).unwrap();
+
+                    let mut skeens1 = vec![];
+                    EntryContents::Multi {
+                        id: &Uuid::new_v4(),
+                        flags: &(EntryFlag::NewMultiPut | EntryFlag::TakeLock),
+                        lock: &0,
+                        locs: &[OrderIndex(c0, 0.into()), OrderIndex(c1, 0.into())],
+                        deps: &[],
+                        data: &[1, 2, 3],
+                    }.fill_vec(&mut skeens1);
+                    skeens1.extend_from_slice(crashed_id.as_bytes());
+                    server.write_all(&skeens1).unwrap();
+
+                    let mut reply = vec![];
+                    let mut bytes = [0u8; 128];
+                    while unsafe { EntryContents::try_ref(&reply[..]).is_err() } {
+                        let read = server.read(&mut bytes).unwrap();
+                        assert!(read > 0);
+                        reply.extend_from_slice(&bytes[..read]);
+                    }
+                    assert!(bytes_as_entry(&reply).flag().contains(EntryFlag::Skeens1Queued));
+                }
+
+                let mut lh = LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .recover_stalled_multiappends(Duration::from_millis(100))
+                    .build();
+                // this waits behind the crashed multiappend until it's recovered,
+                // which the store checks for every timeout, whether or not the handle is idle
+                let start = Instant::now();
+                let _ = lh.append(c0, &[4, 5, 6][..], &[]);
+                assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+                lh.snapshot(c0);
+                assert_eq!(lh.get_next(), Ok((&[1, 2, 3][..],
+                    &[OrderIndex(c0, 1.into()), OrderIndex(c1, 1.into())][..])));
+                assert_eq!(lh.get_next(), Ok((&[4, 5, 6][..],
+                    &[OrderIndex(c0, 2.into())][..])));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_recover_crashed_sentinel() {
+                use std::io::{Read, Write};
+                use std::net::{SocketAddr, TcpStream};
+                use std::time::Duration;
+                let _ = env_logger::init();
+                trace!("TEST recover crashed sentinel");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the data chains are on the first server, the sentinel chains on the second
+                let (c0, c1) = (order::from(1_000_12), order::from(1_000_13));
+                let (c2, c3) = (order::from(1_000_14), order::from(1_000_15));
+                let separator = OrderIndex(0.into(), 0.into());
+
+                // a client which crashes after skeens round 1
+                let skeens1 = |server: &SocketAddr, crashed_id: &Uuid, packet: &[u8]| {
+                    let mut server = TcpStream::connect(server).unwrap();
+                    server.read_exact(&mut [0]).unwrap();
+                    server.write_all(&[2]).unwrap();
+                    server.write_all(crashed_id.as_bytes()).unwrap();
+                    server.read_exact(&mut [0; 16]).unwrap();
+                    server.write_all(packet).unwrap();
+                    server.write_all(crashed_id.as_bytes()).unwrap();
+
+                    let mut reply = vec![];
+                    let mut bytes = [0u8; 128];
+                    while unsafe { EntryContents::try_ref(&reply[..]).is_err() } {
+                        let read = server.read(&mut bytes).unwrap();
+                        assert!(read > 0);
+                        reply.extend_from_slice(&bytes[..read]);
+                    }
+                    assert!(bytes_as_entry(&reply).flag().contains(EntryFlag::Skeens1Queued));
+                };
+                let flags = EntryFlag::NewMultiPut | EntryFlag::TakeLock;
+
+                // the sentinel server has to fetch this one's data from the first server
+                {
+                    let (crashed_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+                    let locs = [OrderIndex(c0, 0.into()), separator, OrderIndex(c1, 0.into())];
+                    let (mut multi, mut sentinel) = (vec![], vec![]);
+                    EntryContents::Multi {
+                        id: &id, flags: &flags, lock: &0, locs: &locs, deps: &[], data: &[1, 2, 3],
+                    }.fill_vec(&mut multi);
+                    EntryContents::Senti {
+                        id: &id, flags: &flags, data_bytes: &3, lock: &0, locs: &locs, deps: &[],
+                    }.fill_vec(&mut sentinel);
+                    skeens1(&addrs[0], &crashed_id, &multi);
+                    skeens1(&addrs[1], &crashed_id, &sentinel);
+                }
+                // and this one's data never reached the first server, so it's aborted
+                {
+                    let (crashed_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+                    let locs = [OrderIndex(c2, 0.into()), separator, OrderIndex(c3, 0.into())];
+                    let mut sentinel = vec![];
+                    EntryContents::Senti {
+                        id: &id, flags: &flags, data_bytes: &3, lock: &0, locs: &locs, deps: &[],
+                    }.fill_vec(&mut sentinel);
+                    skeens1(&addrs[1], &crashed_id, &sentinel);
+                }
+
+                let mut lh = LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1, c2, c3])
+                    .recover_stalled_multiappends(Duration::from_millis(100))
+                    .build();
+                // these wait behind the crashed sentinels until they're recovered
+                assert_eq!(lh.append(c1, &[4, 5, 6][..], &[])[0].0, c1);
+                assert_eq!(lh.append(c3, &[7, 8, 9][..], &[])[0].0, c3);
+                lh.snapshot(c0);
+                assert_eq!(lh.get_next().map(|(data, _)| data), Ok(&[1, 2, 3][..]));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+                lh.snapshot(c2);
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_causal_queries() {
+                use std::net::SocketAddr;
+                use async::causal::CausalQueries;
+                let _ = env_logger::init();
+                trace!("TEST causal queries");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the chains are on different servers
+                let (c0, c1) = (order::from(1_000_20), order::from(1_000_21));
+                let mut lh = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .build();
+                let a = lh.append(c0, &1, &[])[0];
+                let b = lh.append(c1, &2, &[a])[0];
+                let c = lh.append(c0, &3, &[b])[0];
+                let m = lh.multiappend(&[c0, c1], &4, &[c, a]);
+                let (m0, m1) = (m[0], m[1]);
+
+                let mut queries = CausalQueries::unreplicated(addrs).unwrap();
+                assert_eq!(queries.dependencies_of(b).unwrap(), Some(vec![a]));
+                assert_eq!(queries.dependencies_of(OrderIndex(c0, 1_000.into())).unwrap(), None);
+
+                assert_eq!(queries.causal_past(m0, 1).unwrap(), vec![c, a]);
+                assert_eq!(queries.causal_past(m1, 3).unwrap(), vec![c, a, b]);
+                assert_eq!(queries.causal_past(a, 3).unwrap(), vec![]);
+
+                assert_eq!(queries.cited_by(a).unwrap(), {
+                    let mut citing = vec![b, m0, m1];
+                    citing.sort();
+                    citing
+                });
+                assert_eq!(queries.cited_by(b).unwrap(), vec![c]);
+                assert_eq!(queries.cited_by(m0).unwrap(), vec![]);
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_hello_negotiation() {
+                use std::io::{Read, Write};
+                use std::net::{SocketAddr, TcpStream};
+                use packets::hello::{self, Hello, Rejection};
+                let _ = env_logger::init();
+                trace!("TEST hello negotiation");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                let connect = |ours: &Hello| {
+                    let mut server = TcpStream::connect(&addrs[0]).unwrap();
+                    let mut first = [0];
+                    server.read_exact(&mut first).unwrap();
+                    let (bytes, versioned) = hello::peer_hello_bytes(first[0], 2, ours).unwrap();
+                    assert!(versioned);
+                    server.write_all(&bytes).unwrap();
+                    server.write_all(Uuid::new_v4().as_bytes()).unwrap();
+                    let mut reply = [0; hello::HELLO_REPLY_SIZE];
+                    server.read_exact(&mut reply).unwrap();
+                    (server, hello::check_reply(ours, &reply))
+                };
+
+                let (mut server, negotiated) = connect(&Hello::current(0));
+                assert_eq!(negotiated.map(|n| n.version), Ok(hello::PROTOCOL_VERSION));
+                server.read_exact(&mut [0; 16]).unwrap();
+
+                // rejected peers are disconnected without an ack
+                let (mut server, negotiated) = connect(&Hello::current(hello::feature::COMPRESSION));
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::MissingFeatures {
+                    ours: hello::feature::COMPRESSION, theirs: 0,
+                }));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+
+                let future = Hello { version: 7, min_version: 5, ..Hello::current(0) };
+                let (mut server, negotiated) = connect(&future);
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::Version {
+                    ours: (5, 7), theirs: (hello::MIN_PROTOCOL_VERSION, hello::PROTOCOL_VERSION),
+                }));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_checksummed_appends() {
+                use std::io::{Read, Write};
+                use std::net::{SocketAddr, TcpStream};
+                use packets::checksum;
+                use packets::hello::{self, Hello};
+                let _ = env_logger::init();
+                trace!("TEST checksummed appends");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the chains are on different servers
+                let (c0, c1) = (order::from(1_000_30), order::from(1_000_31));
+                let mut lh = LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .checksum_appends()
+                    .build();
+                let a = lh.append(c0, &[1, 2, 3][..], &[])[0];
+                let m = lh.multiappend(&[c0, c1], &[4, 5][..], &[a]);
+                lh.snapshot_colors(&[c0, c1]);
+                assert_eq!(lh.get_next(), Ok((&[1, 2, 3][..], &[a][..])));
+                assert_eq!(lh.get_next(), Ok((&[4, 5][..], &m[..])));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+
+                // an append whose checksum does not match is bounced back rather than stored
+                let mut server = TcpStream::connect(&addrs[0]).unwrap();
+                let mut first = [0];
+                server.read_exact(&mut first).unwrap();
+                let (hello, _) = hello::peer_hello_bytes(first[0], 2, &Hello::current(0)).unwrap();
+                server.write_all(&hello).unwrap();
+                let client_id = Uuid::new_v4();
+                server.write_all(client_id.as_bytes()).unwrap();
+                server.read_exact(&mut [0; hello::HELLO_REPLY_SIZE]).unwrap();
+                server.read_exact(&mut [0; 16]).unwrap();
+
+                let id = Uuid::new_v4();
+                let mut data = checksum::with_checksum(&id, &[], &[7, 8, 9]);
+                data[0] ^= 0xff;
+                let mut corrupt = vec![];
+                EntryContents::Single {
+                    id: &id,
+                    flags: &EntryFlag::Checksummed,
+                    loc: &OrderIndex(c0, 0.into()),
+                    deps: &[],
+                    data: &data,
+                    timestamp: &0,
+                }.fill_vec(&mut corrupt);
+                corrupt.extend_from_slice(client_id.as_bytes());
+                server.write_all(&corrupt).unwrap();
+
+                let mut reply = vec![];
+                let mut bytes = [0u8; 128];
+                while unsafe { EntryContents::try_ref(&reply[..]).is_err() } {
+                    let read = server.read(&mut bytes).unwrap();
+                    assert!(read > 0);
+                    reply.extend_from_slice(&bytes[..read]);
+                }
+                let reply = bytes_as_entry(&reply);
+                assert_eq!(reply.id(), &id);
+                assert_eq!(reply.rejection_status(), Some(reject::CORRUPT));
+                assert_eq!(reply.rejected_request(), EntryKind::Data);
+
+                lh.snapshot(c0);
+                assert_eq!(lh.get_next(), Ok((&[1, 2, 3][..], &[a][..])));
+                assert_eq!(lh.get_next(), Ok((&[4, 5][..], &m[..])));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_shared_entry_cache() {
+                use std::net::SocketAddr;
+                use std::sync::Arc;
+                use async::fuzzy_log::log_handle::EntryCache;
+                let _ = env_logger::init();
+                trace!("TEST shared entry cache");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                let (c0, c1) = (order::from(1_000_80), order::from(1_000_81));
+                let cache = Arc::new(EntryCache::new(1 << 20));
+                let handle = || LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .entry_cache(cache.clone())
+                    .build();
+                let mut lh = handle();
+                let a = lh.append(c0, &[1][..], &[])[0];
+                let b = lh.append(c1, &[2][..], &[])[0];
+                let m = lh.multiappend(&[c0, c1], &[3][..], &[]);
+                let read_all = |lh: &mut LogHandle<[u8]>| {
+                    lh.snapshot_colors(&[c0, c1]);
+                    let mut read = vec![];
+                    while let Ok((data, locs)) = lh.get_next() {
+                        read.push((data.to_vec(), locs.to_vec()))
+                    }
+                    read.sort();
+                    read
+                };
+                let expected = {
+                    let mut expected = vec![(vec![1u8], vec![a]), (vec![2], vec![b]), (vec![3], m)];
+                    expected.sort();
+                    expected
+                };
+                assert_eq!(read_all(&mut lh), expected);
+                let first = cache.stats();
+                assert!(first.inserts >= 3);
+
+                // the second handle reads everything from the cache
+                let mut other = handle();
+                assert_eq!(read_all(&mut other), expected);
+                let second = cache.stats();
+                assert!(second.hits >= 3);
+                assert_eq!(second.inserts, first.inserts);
+                assert_eq!(second.evictions, 0);
+
+                cache.trim(c0, 3.into());
+                assert_eq!(cache.stats().trimmed, 2);
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_entry_cache_follows_trims() {
+                use std::net::SocketAddr;
+                use std::sync::Arc;
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+                use std::time::Duration;
+                use mio;
+                use async::fuzzy_log::log_handle::EntryCache;
+                use servers2::retention::{Retention, RetentionPolicy};
+                use servers2::tcp::ServerConfig;
+                let _ = env_logger::init();
+                trace!("TEST entry cache follows trims");
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                const TRIM_ADDRS: &'static [&'static str] = &["0.0.0.0:14398", "0.0.0.0:14399"];
+
+                let c0 = order::from(1_000_90);
+                for (i, &addr_str) in TRIM_ADDRS.iter().enumerate() {
+                    let acceptor = mio::tcp::TcpListener::bind(&addr_str.parse().unwrap());
+                    if let Ok(acceptor) = acceptor {
+                        let retention = Retention::none()
+                            .color(c0, RetentionPolicy::forever().max_entries(1))
+                            .check_every(Duration::from_millis(50));
+                        thread::spawn(move || {
+                            ::servers2::tcp::run_with_config(
+                                acceptor, i as u32, TRIM_ADDRS.len() as u32,
+                                None, None, 1,
+                                ServerConfig::new().retention(retention),
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                }
+                while SERVERS_READY.load(Ordering::Acquire) < TRIM_ADDRS.len() {}
+
+                let addrs: Vec<SocketAddr> =
+                    TRIM_ADDRS.into_iter().map(|s| s.parse().unwrap()).collect();
+                let cache = Arc::new(EntryCache::new(1 << 20));
+                let handle = || LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0])
+                    .entry_cache(cache.clone())
+                    .build();
+                let mut writer = handle();
+                for i in 1..4 {
+                    writer.append(c0, &i, &[]);
+                }
+                let mut reader = handle();
+                reader.snapshot(c0);
+                for i in 1..4 {
+                    assert_eq!(reader.get_next().map(|(&v, _)| v), Ok(i));
+                }
+                assert_eq!(reader.get_next(), Err(GetRes::Done));
+                assert_eq!(cache.stats().entries, 3);
+
+                // the server keeps only the last entry
+                thread::sleep(Duration::from_millis(500));
+                assert!(cache.get(OrderIndex(c0, 1.into())).is_some());
+
+                // the next snapshot through any of the handles tells the cache
+                reader.snapshot(c0);
+                assert_eq!(reader.get_next(), Err(GetRes::Done));
+                assert_eq!(cache.stats().trimmed, 2);
+                assert!(cache.get(OrderIndex(c0, 1.into())).is_none());
+                assert!(cache.get(OrderIndex(c0, 2.into())).is_none());
+                assert!(cache.get(OrderIndex(c0, 3.into())).is_some());
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_state_machine_replica() {
+                use std::net::SocketAddr;
+                use async::state_machine::{Replica, StateMachine};
+                let _ = env_logger::init();
+                trace!("TEST state machine replica");
+
+                // the sum of the ops, and how many this replica applied itself
+                #[derive(Default)]
+                struct Sum(u64, u64);
+
+                impl StateMachine for Sum {
+                    type Op = u64;
+
+                    fn encode(op: &u64, buffer: &mut Vec<u8>) {
+                        write_u64(*op, buffer)
+                    }
+
+                    fn decode(bytes: &[u8]) -> Option<u64> {
+                        read_u64(bytes)
+                    }
+
+                    fn apply(&mut self, op: u64, _locs: &[OrderIndex], _id: &Uuid) {
+                        self.0 += op;
+                        self.1 += 1;
+                    }
+
+                    fn snapshot(&self, buffer: &mut Vec<u8>) {
+                        write_u64(self.0, buffer)
+                    }
+
+                    fn restore(&mut self, bytes: &[u8]) {
+                        self.0 = read_u64(bytes).unwrap()
+                    }
+                }
+
+                fn write_u64(n: u64, buffer: &mut Vec<u8>) {
+                    buffer.extend((0..8).map(|i| (n >> (8 * i)) as u8))
+                }
+
+                fn read_u64(bytes: &[u8]) -> Option<u64> {
+                    if bytes.len() != 8 { return None }
+                    Some(bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u64))
+                }
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                let color = order::from(1_000_90);
+                let handle = || LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![color])
+                    .build();
+
+                let mut a = Replica::new(handle(), Sum::default(), vec![color])
+                    .batch_appends(2);
+                let mut b = Replica::new(handle(), Sum::default(), vec![color]);
+                a.append(&5).unwrap();
+                a.append(&7).unwrap();
+                a.barrier().unwrap();
+                assert_eq!(a.state().0, 12);
+
+                // observations include appends from other replicas
+                b.append(&1).unwrap();
+                assert_eq!(a.observe(|s| s.0), Ok(13));
+                assert_eq!(a.applied().horizon_for(color), Some(3.into()));
+
+                // a replica started from a checkpoint does not replay what it includes
+                let checkpoint = a.checkpoint();
+                let mut c = Replica::from_checkpoint(
+                    handle(), Sum::default(), vec![color], &checkpoint);
+                assert_eq!(c.state().0, 13);
+                b.append(&10).unwrap();
+                c.barrier().unwrap();
+                assert_eq!(c.state().0, 23);
+                assert_eq!(c.state().1, 1);
+                assert_eq!(c.applied().horizon_for(color), Some(4.into()));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_catalog() {
+                use std::net::SocketAddr;
+                use async::catalog::{
+                    catalog_color, Catalog, CatalogError, ColorMeta, ColorName, FIRST_NAMED_COLOR,
+                };
+                let _ = env_logger::init();
+                trace!("TEST catalog");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                let catalog = || Catalog::new(
+                    LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                        .chains(&[catalog_color()])
+                        .build()
+                );
+                let (mut mine, mut other) = (catalog(), catalog());
+
+                let info = mine.create("test/events", ColorMeta::default()).unwrap();
+                assert!(u64::from(info.color) >= FIRST_NAMED_COLOR);
+                assert_eq!(other.lookup_fresh("test/events"), Ok(info.clone()));
+                assert_eq!(other.create("test/events", ColorMeta::default()),
+                    Err(CatalogError::Exists(info.clone())));
+                assert_eq!(other.create("test//events", ColorMeta::default()),
+                    Err(CatalogError::InvalidName));
+
+                // handles can read named colors
+                let mut lh = LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![ColorName::from("test/events")])
+                    .try_build(&mut other)
+                    .unwrap();
+                let loc = lh.append(info.color, &[1, 2][..], &[])[0];
+                lh.snapshot(info.color);
+                assert_eq!(lh.get_next(), Ok((&[1, 2][..], &[loc][..])));
+                let missing = LogHandle::<[u8]>::unreplicated_with_servers(&addrs)
+                    .chains(vec![ColorName::from("test/missing")])
+                    .try_build(&mut other);
+                assert_eq!(missing.err(), Some(CatalogError::NotFound));
+
+                // a deleted name's color is not reused
+                mine.delete("test/events").unwrap();
+                assert_eq!(other.lookup_fresh("test/events"), Err(CatalogError::NotFound));
+                let recreated = other.create("test/events", ColorMeta::default()).unwrap();
+                assert!(recreated.color != info.color);
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_access_control() {
+                use std::io::{Read, Write};
+                use std::net::{SocketAddr, TcpStream};
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+                use mio;
+                use packets::hello::{self, Credentials, Hello, Rejection};
+                use servers2::access::{permission, AccessControl};
+                use servers2::tcp::ServerConfig;
+                use async::fuzzy_log::log_handle::TryWaitRes;
+                let _ = env_logger::init();
+                trace!("TEST access control");
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                const ACL_ADDRS: &'static [&'static str] = &["0.0.0.0:14390", "0.0.0.0:14391"];
+
+                // the chains are on different servers
+                let (c0, c1) = (order::from(1_000_50), order::from(1_000_51));
+                for (i, &addr_str) in ACL_ADDRS.iter().enumerate() {
+                    let acceptor = mio::tcp::TcpListener::bind(&addr_str.parse().unwrap());
+                    if let Ok(acceptor) = acceptor {
+                        let access = AccessControl::open()
+                            .user("alice", b"secret")
+                            .user("bob", b"hunter2")
+                            .grant(c0, "alice", permission::READ | permission::APPEND)
+                            .grant(c0, "bob", permission::READ)
+                            .grant(c1, "alice", permission::ADMIN);
+                        thread::spawn(move || {
+                            ::servers2::tcp::run_with_config(
+                                acceptor, i as u32, ACL_ADDRS.len() as u32,
+                                None, None, 1,
+                                ServerConfig::new().access(access),
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                }
+                while SERVERS_READY.load(Ordering::Acquire) < ACL_ADDRS.len() {}
+
+                let addrs: Vec<SocketAddr> =
+                    ACL_ADDRS.into_iter().map(|s| s.parse().unwrap()).collect();
+                let mut alice = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .credentials("alice", b"secret")
+                    .build();
+                let a = alice.append(c0, &1, &[])[0];
+                let m = alice.multiappend(&[c0, c1], &2, &[a]);
+
+                let mut bob = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .credentials("bob", b"hunter2")
+                    .build();
+                bob.snapshot(c0);
+                assert_eq!(bob.get_next().map(|(&v, _)| v), Ok(1));
+                assert_eq!(bob.get_next().map(|(&v, l)| (v, l.to_vec())), Ok((2, m.to_vec())));
+                assert_eq!(bob.get_next(), Err(GetRes::Done));
+                bob.snapshot(c1);
+                assert_eq!(bob.get_next(), Err(GetRes::PermissionDenied(c1)));
+
+                // a multiappend is refused if any of its colors is
+                let mut bob = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .credentials("bob", b"hunter2")
+                    .build();
+                let id = bob.async_multiappend(&[c0, c1], &3, &[]);
+                assert_eq!(bob.wait_for_a_specific_append(id), Err(TryWaitRes::PermissionDenied(c0)));
+
+                // colors without grants are open to everyone
+                let mut anonymous = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, order::from(1_000_52)])
+                    .build();
+                anonymous.append(order::from(1_000_52), &4, &[]);
+                let id = anonymous.async_append(c0, &5, &[]);
+                assert_eq!(
+                    anonymous.wait_for_a_specific_append(id), Err(TryWaitRes::PermissionDenied(c0))
+                );
+
+                // bad credentials are refused in the handshake
+                let connect = |credentials: Credentials| {
+                    let ours = Hello::current(hello::feature::AUTH);
+                    let mut server = TcpStream::connect(&addrs[0]).unwrap();
+                    let mut first = [0];
+                    server.read_exact(&mut first).unwrap();
+                    let (bytes, _) = hello::peer_hello_bytes(first[0], 2, &ours).unwrap();
+                    server.write_all(&bytes).unwrap();
+                    server.write_all(&credentials.to_bytes()).unwrap();
+                    server.write_all(Uuid::new_v4().as_bytes()).unwrap();
+                    let mut reply = [0; hello::HELLO_REPLY_SIZE];
+                    server.read_exact(&mut reply).unwrap();
+                    (server, hello::check_reply(&ours, &reply))
+                };
+                let (mut server, negotiated) = connect(Credentials::new("alice", b"secret"));
+                assert!(negotiated.is_ok());
+                server.read_exact(&mut [0; 16]).unwrap();
+                let (mut server, negotiated) = connect(Credentials::new("alice", b"hunter2"));
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::BadCredentials));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_replicated_authentication() {
+                use std::io::{Read, Write};
+                use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+                use mio;
+                use packets::hello::{self, Credentials, Hello, Rejection};
+                use servers2::access::AccessControl;
+                use servers2::tcp::ServerConfig;
+                let _ = env_logger::init();
+                trace!("TEST replicated authentication");
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                // a head and its tail
+                const AUTH_ADDRS: &'static [&'static str] = &["127.0.0.1:14396", "127.0.0.1:14397"];
+
+                let local_host = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+                for (i, &addr_str) in AUTH_ADDRS.iter().enumerate() {
+                    let acceptor = mio::tcp::TcpListener::bind(&addr_str.parse().unwrap());
+                    if let Ok(acceptor) = acceptor {
+                        let prev_server: Option<SocketAddr> =
+                            if i > 0 { Some(AUTH_ADDRS[i - 1].parse().unwrap()) } else { None };
+                        let next_server = if i + 1 < AUTH_ADDRS.len() { Some(local_host) } else { None };
+                        let access = AccessControl::open()
+                            .user("alice", b"secret")
+                            .require_authentication();
+                        thread::spawn(move || {
+                            ::servers2::tcp::run_with_config(
+                                acceptor, 0, 1,
+                                prev_server, next_server, 1,
+                                ServerConfig::new().access(access),
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                }
+                while SERVERS_READY.load(Ordering::Acquire) < AUTH_ADDRS.len() {}
+
+                let addrs: Vec<SocketAddr> =
+                    AUTH_ADDRS.into_iter().map(|s| s.parse().unwrap()).collect();
+                let c0 = order::from(1_000_53);
+                // the tail authenticates the client to the head with its credentials
+                let mut alice = LogHandle::<u64>::replicated_with_servers(&[(addrs[0], addrs[1])])
+                    .chains(vec![c0])
+                    .credentials("alice", b"secret")
+                    .build();
+                alice.append(c0, &1, &[]);
+
+                let connect = |addr: &SocketAddr, peer_type: u8, credentials: Option<Credentials>| {
+                    let required = if credentials.is_some() { hello::feature::AUTH } else { 0 };
+                    let ours = Hello::current(required);
+                    let mut server = TcpStream::connect(addr).unwrap();
+                    let mut first = [0];
+                    server.read_exact(&mut first).unwrap();
+                    let (bytes, _) = hello::peer_hello_bytes(first[0], peer_type, &ours).unwrap();
+                    server.write_all(&bytes).unwrap();
+                    if let Some(credentials) = credentials {
+                        server.write_all(&credentials.to_bytes()).unwrap();
+                    }
+                    server.write_all(Uuid::new_v4().as_bytes()).unwrap();
+                    let mut reply = [0; hello::HELLO_REPLY_SIZE];
+                    server.read_exact(&mut reply).unwrap();
+                    (server, hello::check_reply(&ours, &reply))
+                };
+                // peers claiming to be servers are authenticated like clients
+                let (mut server, negotiated) = connect(&addrs[0], 1, None);
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::BadCredentials));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+                // and only taken where a server sits downstream
+                let credentials = Credentials::new("alice", b"secret");
+                let (mut server, negotiated) = connect(&addrs[1], 1, Some(credentials));
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::UnexpectedPeer));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+
+                // unknown peer types are refused
+                for addr in &addrs {
+                    let mut server = TcpStream::connect(addr).unwrap();
+                    server.read_exact(&mut [0]).unwrap();
+                    server.write_all(&[7]).unwrap();
+                    assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+                }
+
+                // without bringing down the servers
+                alice.append(c0, &2, &[]);
+                alice.snapshot(c0);
+                assert_eq!(alice.get_next().map(|(&v, _)| v), Ok(1));
+                assert_eq!(alice.get_next().map(|(&v, _)| v), Ok(2));
+                assert_eq!(alice.get_next(), Err(GetRes::Done));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_admission_control() {
+                use std::net::SocketAddr;
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+                use std::time::Duration;
+                use mio;
+                use servers2::admission::AdmissionControl;
+                use servers2::tcp::ServerConfig;
+                let _ = env_logger::init();
+                trace!("TEST admission control");
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                const ADMISSION_ADDRS: &'static [&'static str] = &["0.0.0.0:14392", "0.0.0.0:14393"];
+
+                let (c0, c1) = (order::from(1_000_60), order::from(1_000_61));
+                for (i, &addr_str) in ADMISSION_ADDRS.iter().enumerate() {
+                    let acceptor = mio::tcp::TcpListener::bind(&addr_str.parse().unwrap());
+                    if let Ok(acceptor) = acceptor {
+                        let admission = AdmissionControl::unlimited()
+                            .max_appends_per_client(1)
+                            .max_appends(2);
+                        thread::spawn(move || {
+                            ::servers2::tcp::run_with_config(
+                                acceptor, i as u32, ADMISSION_ADDRS.len() as u32,
+                                None, None, 1,
+                                ServerConfig::new().admission(admission),
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                }
+                while SERVERS_READY.load(Ordering::Acquire) < ADMISSION_ADDRS.len() {}
+
+                let addrs: Vec<SocketAddr> =
+                    ADMISSION_ADDRS.into_iter().map(|s| s.parse().unwrap()).collect();
+                let mut writers: Vec<_> = (0..3).map(|_|
+                    LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                        .chains(vec![c0, c1])
+                        .retry_backoff(Duration::from_millis(1), Duration::from_millis(10))
+                        .build()
+                ).collect();
+                // appends a busy server refused are sent again until they get in
+                for (w, writer) in writers.iter_mut().enumerate() {
+                    for i in 0..50u64 {
+                        writer.async_append(c0, &(w as u64 * 100 + i), &[]);
+                        writer.async_multiappend(&[c0, c1], &(w as u64 * 100 + 50 + i), &[]);
+                    }
+                }
+                for writer in &mut writers {
+                    assert_eq!(writer.wait_for_all_appends(), Ok(()));
+                }
+
+                let mut reader = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .build();
+                reader.snapshot(c0);
+                let mut seen = vec![vec![]; 3];
+                while let Ok((&v, _)) = reader.get_next() {
+                    seen[(v / 100) as usize].push(v % 100);
+                }
+                // a retried append may land after ones sent later, but each lands once
+                for mut appends in seen {
+                    appends.sort();
+                    assert_eq!(appends, (0..100).collect::<Vec<_>>());
+                }
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_quotas() {
+                use std::net::SocketAddr;
+                use std::sync::Arc;
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+                use std::time::Duration;
+                use mio;
+                use servers2::access::AccessControl;
+                use servers2::quota::{Quota, QuotaEnforcer, Quotas};
+                use servers2::tcp::ServerConfig;
+                use async::fuzzy_log::log_handle::TryWaitRes;
+                let _ = env_logger::init();
+                trace!("TEST quotas");
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                const QUOTA_ADDRS: &'static [&'static str] = &["0.0.0.0:14394", "0.0.0.0:14395"];
+
+                // both chains are on the first server
+                let (c0, c1) = (order::from(1_000_70), order::from(1_000_72));
+                for (i, &addr_str) in QUOTA_ADDRS.iter().enumerate() {
+                    let acceptor = mio::tcp::TcpListener::bind(&addr_str.parse().unwrap());
+                    if let Ok(acceptor) = acceptor {
+                        let access = AccessControl::open()
+                            .user("alice", b"secret")
+                            .user("bob", b"hunter2");
+                        let quotas = Quotas::none()
+                            .principal("alice", Quota::unlimited().appends_per_sec(1))
+                            .colors(u64::from(c1)..u64::from(c1) + 1,
+                                Quota::unlimited().appends_per_sec(1));
+                        thread::spawn(move || {
+                            ::servers2::tcp::run_with_config(
+                                acceptor, i as u32, QUOTA_ADDRS.len() as u32,
+                                None, None, 1,
+                                ServerConfig::new()
+                                    .access(access)
+                                    .quotas(Arc::new(QuotaEnforcer::new(quotas))),
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                }
+                while SERVERS_READY.load(Ordering::Acquire) < QUOTA_ADDRS.len() {}
+
+                let addrs: Vec<SocketAddr> =
+                    QUOTA_ADDRS.into_iter().map(|s| s.parse().unwrap()).collect();
+                let mut alice = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .credentials("alice", b"secret")
+                    .build();
+                alice.append(c0, &1, &[]);
+                let id = alice.async_append(c0, &2, &[]);
+                assert_eq!(alice.wait_for_a_specific_append(id), Err(TryWaitRes::QuotaExceeded(c0)));
+
+                // alice's quota does not limit anyone else
+                let mut bob = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .credentials("bob", b"hunter2")
+                    .build();
+                bob.append(c0, &3, &[]);
+                bob.append(c0, &4, &[]);
+
+                // but the quota of a range of colors limits everyone appending to it
+                let id = bob.async_append(c1, &5, &[]);
+                assert!(bob.wait_for_a_specific_append(id).is_ok());
+                let id = bob.async_append(c1, &6, &[]);
+                assert_eq!(bob.wait_for_a_specific_append(id), Err(TryWaitRes::QuotaExceeded(c1)));
+
+                // once the bucket refills appends get through again
+                thread::sleep(Duration::from_millis(1100));
+                alice.append(c0, &7, &[]);
+
+                let mut reader = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1])
+                    .build();
+                reader.snapshot(c0);
+                let mut seen = vec![];
+                while let Ok((&v, _)) = reader.get_next() {
+                    seen.push(v);
+                }
+                assert_eq!(seen, vec![1, 3, 4, 7]);
+            }
+
             fn new_thread_log<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
                 start_tcp_servers();
 
@@ -1398,6 +2490,328 @@ macro_rules! async_tests {
             }
         }
     };
+    (shtcp) => {
+        mod shtcp {
+            async_tests!(test new_thread_log, ntl_with_boring, ntl_with_simple);
+
+            #[allow(non_upper_case_globals)]
+            const addr_strs: &'static [&'static str] = &["0.0.0.0:14090", "0.0.0.0:14091"];
+
+            const NUM_ORDERING_THREADS: usize = 3;
+
+            #[test]
+            #[inline(never)]
+            pub fn test_cross_shard_multiappend() {
+                use std::net::SocketAddr;
+                let _ = env_logger::init();
+                trace!("TEST cross shard multiappend");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the chains are on the same server but in different shards
+                let (c0, c1, c2) =
+                    (order::from(1_000_40), order::from(1_000_42), order::from(1_000_44));
+                let mut lh = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![c0, c1, c2])
+                    .build();
+                let a = lh.append(c1, &1, &[])[0];
+                let m = lh.multiappend(&[c0, c1, c2], &2, &[a]);
+                let b = lh.append(c2, &3, &[])[0];
+                assert_eq!(a, OrderIndex(c1, 1.into()));
+                assert_eq!(&m[..], &[
+                    OrderIndex(c0, 1.into()), OrderIndex(c1, 2.into()), OrderIndex(c2, 1.into())
+                ]);
+                assert_eq!(b, OrderIndex(c2, 2.into()));
+
+                lh.snapshot(c1);
+                assert_eq!(lh.get_next().map(|(&v, _)| v), Ok(1));
+                assert_eq!(lh.get_next().map(|(&v, l)| (v, l.to_vec())), Ok((2, m.to_vec())));
+                assert_eq!(lh.get_next(), Err(GetRes::Done));
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_cross_shard_transaction() {
+                use std::net::SocketAddr;
+                use async::fuzzy_log::log_handle::TransactionRes;
+                let _ = env_logger::init();
+                trace!("TEST cross shard transaction");
+
+                start_tcp_servers();
+
+                let addrs: Vec<SocketAddr> =
+                    addr_strs.into_iter().map(|s| s.parse().unwrap()).collect();
+                // the read is in a different shard than the write on the same server,
+                // and the remote write is on the other server
+                let (read, write, remote) =
+                    (order::from(1_000_84), order::from(1_000_82), order::from(1_000_81));
+                let mut lh = LogHandle::<u64>::unreplicated_with_servers(&addrs)
+                    .chains(vec![read, write, remote])
+                    .build();
+                let r = lh.append(read, &1, &[])[0];
+                assert_eq!(r, OrderIndex(read, 1.into()));
+
+                let committed = {
+                    let mut txn = lh.transaction();
+                    txn.read_at(read, 1.into()).write(write);
+                    txn.commit(&2)
+                };
+                assert_eq!(committed, Ok(vec![OrderIndex(write, 1.into())]));
+
+                let committed = {
+                    let mut txn = lh.transaction();
+                    txn.read_at(read, 1.into()).write(write).write(remote);
+                    txn.commit(&3)
+                };
+                assert_eq!(committed,
+                    Ok(vec![OrderIndex(remote, 1.into()), OrderIndex(write, 2.into())]));
+
+                // the read is unlocked once the transaction is done
+                let r = lh.append(read, &4, &[])[0];
+                assert_eq!(r, OrderIndex(read, 2.into()));
+
+                let aborted = {
+                    let mut txn = lh.transaction();
+                    txn.read_at(read, 1.into()).write(write).write(remote);
+                    txn.commit(&5)
+                };
+                assert_eq!(aborted, Err(TransactionRes::Aborted(OrderIndex(read, 2.into()))));
+            }
+
+            fn new_thread_log<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, ::std::net::SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).chains(interesting_chains.into_iter())
+                    .build()
+            }
+
+            fn ntl_with_boring<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, ::std::net::SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).chains(interesting_chains.into_iter())
+                    .fetch_boring_multis()
+                    .build()
+            }
+
+            fn ntl_with_simple<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, ::std::net::SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).my_colors_chains(interesting_chains)
+                    .build()
+            }
+
+            fn start_tcp_servers()
+            {
+                use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+                use std::thread;
+
+                use mio;
+
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+                for (i, &addr_str) in addr_strs.iter().enumerate() {
+                    let addr = addr_str.parse().expect("invalid inet address");
+                    let acceptor = mio::tcp::TcpListener::bind(&addr);
+                    if let Ok(acceptor) = acceptor {
+                        thread::spawn(move || {
+                            trace!("starting sharded server");
+                            ::servers2::tcp::run_sharded(
+                                acceptor,
+                                i as u32,
+                                addr_strs.len() as u32,
+                                None,
+                                None,
+                                2,
+                                NUM_ORDERING_THREADS,
+                                &SERVERS_READY,
+                            )
+                        });
+                    }
+                    else {
+                        trace!("server already started");
+                    }
+                }
+
+                while SERVERS_READY.load(Ordering::Acquire) < addr_strs.len() {}
+            }
+        }
+    };
+    (tktcp) => {
+        mod tktcp {
+            use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+            use std::thread;
+            use std::net::SocketAddr;
+
+            async_tests!(test new_thread_log, ntl_with_boring, ntl_with_simple);
+
+            #[allow(non_upper_case_globals)]
+            const addr_strs: &'static [&'static str] = &["127.0.0.1:14190", "127.0.0.1:14191"];
+
+            fn new_thread_log<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).chains(interesting_chains.into_iter())
+                    .build()
+            }
+
+            fn ntl_with_boring<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).chains(interesting_chains.into_iter())
+                    .fetch_boring_multis()
+                    .build()
+            }
+
+            fn ntl_with_simple<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::unreplicated_with_servers::<_, SocketAddr>(
+                    addr_strs.into_iter().map(|s| s.parse().unwrap())
+                ).my_colors_chains(interesting_chains)
+                    .build()
+            }
+
+            #[test]
+            #[inline(never)]
+            pub fn test_tokio_hello_negotiation() {
+                use std::io::{Read, Write};
+                use std::net::TcpStream;
+                use packets::hello::{self, Credentials, Hello, Rejection};
+                let _ = env_logger::init();
+                trace!("TEST tokio hello negotiation");
+
+                start_tcp_servers();
+
+                let addr: SocketAddr = addr_strs[0].parse().unwrap();
+                let connect = |ours: &Hello| {
+                    let mut server = TcpStream::connect(&addr).unwrap();
+                    let mut first = [0];
+                    server.read_exact(&mut first).unwrap();
+                    let (bytes, versioned) = hello::peer_hello_bytes(first[0], 2, ours).unwrap();
+                    assert!(versioned);
+                    server.write_all(&bytes).unwrap();
+                    if ours.required_features & hello::feature::AUTH != 0 {
+                        server.write_all(&Credentials::new("alice", b"secret").to_bytes()).unwrap();
+                    }
+                    server.write_all(Uuid::new_v4().as_bytes()).unwrap();
+                    let mut reply = [0; hello::HELLO_REPLY_SIZE];
+                    server.read_exact(&mut reply).unwrap();
+                    (server, hello::check_reply(ours, &reply))
+                };
+
+                let (mut server, negotiated) = connect(&Hello::current(0));
+                assert_eq!(negotiated.map(|n| n.version), Ok(hello::PROTOCOL_VERSION));
+                server.read_exact(&mut [0; 16]).unwrap();
+
+                // there is no access control here, so credentials cannot be honored
+                let (mut server, negotiated) = connect(&Hello::current(hello::feature::AUTH));
+                assert_eq!(negotiated.map(|n| n.version), Err(Rejection::MissingFeatures {
+                    ours: hello::feature::AUTH, theirs: 0,
+                }));
+                assert_eq!(server.read(&mut [0; 16]).unwrap_or(0), 0);
+            }
+
+            fn start_tcp_servers() {
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                static SERVER_STARTING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+                if SERVER_STARTING.swap(1, Ordering::SeqCst) == 0 {
+                    for (i, &addr_str) in addr_strs.iter().enumerate() {
+                        let addr: SocketAddr = addr_str.parse().unwrap();
+                        thread::spawn(move || {
+                            trace!("starting tokio server {}", i);
+                            ::tokio_server::run_server(
+                                &addr, i as u32, addr_strs.len() as u32, None, None, 2,
+                                || { SERVERS_READY.fetch_add(1, Ordering::SeqCst); },
+                            ).expect("cannot run server")
+                        });
+                    }
+                }
+
+                while SERVERS_READY.load(Ordering::Acquire) < addr_strs.len() {}
+            }
+        }
+    };
+    (tkrtcp) => {
+        mod tkrtcp {
+            use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+            use std::thread;
+            use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+            async_tests!(test new_thread_log, ntl_with_boring, ntl_with_simple);
+
+            const HEAD_ADDR: &'static str = "127.0.0.1:14290";
+            const TAIL_ADDR: &'static str = "127.0.0.1:14291";
+
+            fn servers() -> (SocketAddr, SocketAddr) {
+                (HEAD_ADDR.parse().unwrap(), TAIL_ADDR.parse().unwrap())
+            }
+
+            fn new_thread_log<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::replicated_with_servers(Some(servers()))
+                    .chains(interesting_chains)
+                    .build()
+            }
+
+            fn ntl_with_boring<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::replicated_with_servers(Some(servers()))
+                    .chains(interesting_chains)
+                    .fetch_boring_multis()
+                    .build()
+            }
+
+            fn ntl_with_simple<V>(interesting_chains: Vec<order>) -> LogHandle<V> {
+                start_tcp_servers();
+
+                LogHandle::replicated_with_servers(Some(servers()))
+                    .my_colors_chains(interesting_chains)
+                    .build()
+            }
+
+            fn start_tcp_servers() {
+                static SERVERS_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+                static SERVER_STARTING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+                if SERVER_STARTING.swap(1, Ordering::SeqCst) == 0 {
+                    let local_host = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+                    let (head, tail) = servers();
+                    thread::spawn(move || {
+                        trace!("starting tokio replica server head");
+                        ::tokio_server::run_server(
+                            &head, 0, 1, None, Some(local_host), 2,
+                            || { SERVERS_READY.fetch_add(1, Ordering::SeqCst); },
+                        ).expect("cannot run server")
+                    });
+                    while SERVERS_READY.load(Ordering::Acquire) < 1 {}
+                    thread::spawn(move || {
+                        trace!("starting tokio replica server tail");
+                        ::tokio_server::run_server(
+                            &tail, 0, 1, Some(head), None, 2,
+                            || { SERVERS_READY.fetch_add(1, Ordering::SeqCst); },
+                        ).expect("cannot run server")
+                    });
+                }
+
+                while SERVERS_READY.load(Ordering::Acquire) < 2 {}
+            }
+        }
+    };
     (rtcp) => {
         mod rtcp {
             use std::sync::{Arc, Mutex};
@@ -1,4 +1,14 @@
+//! An alternative server built on tokio, sharing `fuzzy_log_server`'s log threads.
+//!
+//! It speaks the same protocol and handles replication, but it only implements
+//! the core of `fuzzy_log_server::tcp`: it has no credentials or access control,
+//! admission control, quotas, retention or tiered storage.
+//! Peers which require credentials are refused during the hello.
+//! `fuzzy_log_server::tcp` is the supported server, use it in deployments.
+
+extern crate byteorder;
 extern crate futures;
+#[macro_use] extern crate log;
 extern crate tokio;
 extern crate tokio_io;
 
@@ -8,9 +18,11 @@ extern crate fuzzy_log_util;
 
 use std::io::{Error as IoError, ErrorKind};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
 
+use byteorder::{ByteOrder, LittleEndian};
+
 use futures::{future, stream, Future, Sink, Stream};
 use futures::sync::{mpsc, oneshot};
 
@@ -30,7 +42,9 @@ use fuzzy_log_util::socket_addr::Ipv4SocketAddr as ClientId;
 use fuzzy_log_util::hash::IdHashMap;
 
 use buffer_stream::BufferStream;
+use negotiate::{NewClient, Negotiator};
 
+pub use negotiate::Position;
 pub use read_buffer::ReadBuffer;
 pub use batch_read_buffer::BatchReadBuffer;
 
@@ -38,6 +52,7 @@ pub use batch_read_buffer::BatchReadBuffer;
 // mod doorbell;
 mod batch_read_buffer;
 mod buffer_stream;
+mod negotiate;
 mod read_buffer;
 mod vec_stream;
 mod worker;
@@ -49,20 +64,43 @@ pub enum ToServer {
 
 pub fn run_unreplicated_server<CallBack: FnOnce()>(
     addr: &SocketAddr, this_server_num: u32, total_chain_servers: u32, callback: CallBack
+) -> Result<(), IoError> {
+    run_server(addr, this_server_num, total_chain_servers, None, None, 1, callback)
+}
+
+/// Start a server, which may be part of a replication chain,
+/// serving its clients from a pool of `num_workers` threads.
+pub fn run_server<CallBack: FnOnce()>(
+    addr: &SocketAddr,
+    this_server_num: u32,
+    total_chain_servers: u32,
+    prev_server: Option<SocketAddr>,
+    next_server: Option<IpAddr>,
+    num_workers: usize,
+    callback: CallBack,
 ) -> Result<(), IoError> {
     let (store, log_reader) = fuzzy_log_server::new_chain_store_and_reader();
     let to_log = start_log_thread(this_server_num, total_chain_servers, store);
     let listener = TcpListener::bind(&addr)?;
+    let position = Position::new(prev_server, next_server.is_some());
     callback();
-    Ok(run(listener, to_log, log_reader, Some(false)))
+    Ok(run(listener, to_log, log_reader, Some(position), num_workers))
 }
 
+/// Accept clients on `listener`, negotiating with them according to `position`,
+/// or not at all if it is `None`.
 pub fn run(
     listener: TcpListener,
     sender: mpsc::Sender<ToLog<u64>>,
     log_reader: ChainReader<u64>,
-    replication: Option<bool>,
+    position: Option<Position>,
+    num_workers: usize,
 ) {
+    let workers = start_workers(num_workers, log_reader);
+    let negotiator = position.map(Negotiator::new);
+    let (is_replica, continues_replication) = position
+        .map(|p| (p.is_replica(), p.continues_replication()))
+        .unwrap_or((false, false));
     let mut next = 0;
     let server = listener
         .incoming()
@@ -70,129 +108,42 @@ pub fn run(
             use ::std::time::Duration;
             let _ = tcp.set_nodelay(true);
             let _ = tcp.set_keepalive(Some(Duration::from_secs(1)));
+            let negotiation: negotiate::Negotiation = match negotiator {
+                Some(ref negotiator) => negotiator.got_connection(tcp),
+                None => Box::new(future::ok(Some(NewClient {
+                    id: ClientId::nil(), upstream: tcp, downstream: None,
+                }))),
+            };
+            let sender = sender.clone();
+            let workers = workers.clone();
             let client = next;
             next += 1;
-            let (to_client, receiver) = mpsc::channel(100);
-            //FIXME no wait?
-            let sender = sender.clone();
-            let sender = sender
-                .send(ToLog::NewClient(client, to_client))
-                .wait()
-                .unwrap();
-            let log_reader = log_reader.clone();
-            thread::spawn(move || {
-
-                let tcp = match replication {
-                    None => tcp,
-                    Some(true) => unimplemented!(),
-                    Some(false) => {
-                        let mut ack = [0; 16];
-                        io::read_exact(tcp, ack)
-                        .and_then(|(tcp, ack)|
-                            io::write_all(tcp, ack)
-                        ).wait().unwrap().0
-                    },
-                };
-
-                let (reader, writer) = tcp.split();
-
-                //FIXME read client_id
-                //FIXME send up/downstream
-
-                let batch = worker::Batcher::new(client, log_reader);
-                let write_buffer = BufferStream::default();
-                let needs_writing = write_buffer.clone();
-                let read = stream::repeat(())
-                    .fold(
-                        (
-                            sender,
-                            reader,
-                            BatchReadBuffer::with_capacity(8192),
-                            batch,
-                            needs_writing,
-                        ),
-                        move |(sender, reader, buffer, mut batch, mut needs_writing), _| {
-                            io::read(reader, buffer).and_then(move |(reader, mut buffer, len)| {
-                                buffer.freeze_additional(len);
-                                let needed;
-                                //TODO do read_fixed batch, send, future instead?
-                                loop {
-                                    let len = match unsafe { Packet::Ref::try_ref(&buffer[..]) } {
-                                        Ok((packet, bytes)) if bytes.len() >= client_id_size() => {
-                                            Ok(packet.len() + client_id_size())
-                                        }
-                                        Ok((packet, _)) => Err(packet.len() + client_id_size()),
-                                        Err(WrapErr::NotEnoughBytes(len)) => {
-                                            Err(len + client_id_size())
-                                        }
-                                        Err(e) => panic!("{:?}", e), //panic!("{:?} @ {:?}", e, buffer),
-                                    };
-                                    match len {
-                                        Ok(len) => {
-                                            //TODO cache Vecs?
-                                            let buff = Buffer::wrap_vec(buffer.split_off(len));
-                                            batch.add_client_msg(buff);
-                                        }
-                                        Err(len) => {
-                                            needed = Some(len);
-                                            break;
-                                        }
-                                    }
-                                }
-                                buffer.shift_back();
-                                if let Some(len) = needed {
-                                    buffer.ensure_fits(len)
-                                }
-                                sender
-                                    .send_all(batch.log_batch())
-                                    .map_err(|_| IoError::from(ErrorKind::BrokenPipe))
-                                    .map(move |(sender, batch)| {
-                                        let mut batcher = batch.batcher();
-                                        batcher.handle_buffered_reads(|res| match res {
-                                            Ok(bytes) => needs_writing.add_slice(bytes),
-                                            Err(contents) => needs_writing.add_contents(contents),
-                                        });
-                                        (sender, reader, buffer, batcher, needs_writing)
-                                    })
-                            })
-                        },
-                    )
-                    .map(|_| ())
-                    .map_err(|err: IoError| println!("Recv error {:?}", err));
-
-                let needs_writing = write_buffer.clone();
-                let buffer_cache = write_buffer.clone();
-
-                //TODO backpressure
-                let add_buffer = receiver
-                    .fold(write_buffer, move |mut write_buffer, new_send| {
-                        //TODO replication
-                        worker::add_response(new_send, &mut write_buffer, client);
-                        future::ok(write_buffer)
-                    })
-                    .map(|_| ())
-                    .map_err(|_| ());
-
-                let write = needs_writing
-                    .map_err(|_| IoError::from(ErrorKind::BrokenPipe))
-                    .fold(
-                        (writer, buffer_cache),
-                        |(writer, buffer_cache), write_buffer| {
-                            io::write_all(writer, write_buffer).map(|(writer, buffer)| {
-                                buffer_cache.return_buffer(buffer);
-                                (writer, buffer_cache)
-                            })
-                        },
-                    )
-                    .map(|_| ())
-                    .map_err(|err: IoError| println!("Send error {:?}", err));
-
-                current_thread::run(|_| {
-                    current_thread::spawn(read);
-                    current_thread::spawn(add_buffer);
-                    current_thread::spawn(write);
-                });
-            });
+            let start = negotiation
+                .and_then(move |new_client| {
+                    let new_client = match new_client {
+                        None => return future::Either::A(future::ok(())),
+                        Some(new_client) => new_client,
+                    };
+                    let (to_client, receiver) = mpsc::channel(100);
+                    let new_client = sender
+                        .send(ToLog::NewClient(client, to_client))
+                        .map_err(|_| IoError::from(ErrorKind::BrokenPipe))
+                        .map(move |to_log| {
+                            //FIXME use hash distribution
+                            let worker = (client % workers.len() as u64) as usize;
+                            let _ = workers[worker].unbounded_send(Connection {
+                                client: new_client,
+                                client_num: client,
+                                is_replica,
+                                continues_replication,
+                                to_log,
+                                from_log: receiver,
+                            });
+                        });
+                    future::Either::B(new_client)
+                })
+                .map_err(|err| println!("negotiation error {:?}", err));
+            current_thread::spawn(start);
             future::ok(())
         })
         .map_err(|err| {
@@ -204,6 +155,178 @@ pub fn run(
     });
 }
 
+struct Connection {
+    client: NewClient,
+    client_num: u64,
+    is_replica: bool,
+    continues_replication: bool,
+    to_log: mpsc::Sender<ToLog<u64>>,
+    from_log: mpsc::Receiver<ToWorker<u64>>,
+}
+
+fn start_workers(num_workers: usize, log_reader: ChainReader<u64>)
+-> ::std::sync::Arc<Vec<mpsc::UnboundedSender<Connection>>> {
+    assert!(num_workers > 0, "a server needs at least one worker");
+    let workers = (0..num_workers).map(|_| {
+        let (to_worker, from_acceptor) = mpsc::unbounded();
+        let log_reader = log_reader.clone();
+        thread::spawn(move || {
+            let serve = from_acceptor.for_each(move |connection| {
+                serve_client(connection, &log_reader);
+                future::ok(())
+            });
+            current_thread::run(|_| {
+                current_thread::spawn(serve);
+            });
+        });
+        to_worker
+    }).collect();
+    ::std::sync::Arc::new(workers)
+}
+
+fn serve_client(connection: Connection, log_reader: &ChainReader<u64>) {
+    let Connection {
+        client: NewClient { id, upstream, downstream },
+        client_num,
+        is_replica,
+        continues_replication,
+        to_log,
+        from_log,
+    } = connection;
+
+    let (reader, writer) = upstream.split();
+    let write_buffer = BufferStream::default();
+    let batch = worker::Batcher::new(client_num, id, log_reader.clone());
+    current_thread::spawn(read_ops(reader, is_replica, batch, to_log.clone(), write_buffer.clone()));
+    current_thread::spawn(write_buffers(writer, write_buffer.clone()));
+
+    let mut responses = match downstream {
+        None => write_buffer,
+        Some(downstream) => {
+            //at the tail of a chain clients read from the downstream connection
+            let (reader, writer) = downstream.split();
+            let down_buffer = BufferStream::default();
+            let batch = worker::Batcher::new(client_num, id, log_reader.clone());
+            current_thread::spawn(read_ops(reader, false, batch, to_log, down_buffer.clone()));
+            current_thread::spawn(write_buffers(writer, down_buffer.clone()));
+            down_buffer
+        },
+    };
+
+    let replicate_to = if continues_replication { Some(id) } else { None };
+    //TODO backpressure
+    let add_buffer = from_log
+        .for_each(move |new_send| {
+            worker::add_response(new_send, &mut responses, client_num, replicate_to);
+            future::ok(())
+        });
+    current_thread::spawn(add_buffer);
+}
+
+fn read_ops<R: AsyncRead + 'static>(
+    reader: R,
+    is_replica: bool,
+    batch: worker::Batcher,
+    sender: mpsc::Sender<ToLog<u64>>,
+    needs_writing: BufferStream,
+) -> Box<Future<Item=(), Error=()>> {
+    //ops from another server also carry the location of their storage there
+    let trailer_size = client_id_size() + if is_replica { mem::size_of::<u64>() } else { 0 };
+    let read = stream::repeat(())
+        .fold(
+            (
+                sender,
+                reader,
+                BatchReadBuffer::with_capacity(8192),
+                batch,
+                needs_writing,
+            ),
+            move |(sender, reader, buffer, mut batch, mut needs_writing), _| {
+                io::read(reader, buffer).and_then(move |(reader, mut buffer, len)| {
+                    if len == 0 {
+                        return future::Either::A(
+                            future::err(IoError::from(ErrorKind::UnexpectedEof))
+                        )
+                    }
+                    buffer.freeze_additional(len);
+                    let needed;
+                    //TODO do read_fixed batch, send, future instead?
+                    loop {
+                        let len = match unsafe { Packet::Ref::try_ref(&buffer[..]) } {
+                            Ok((packet, bytes)) if bytes.len() >= trailer_size => {
+                                Ok(packet.len())
+                            }
+                            Ok((packet, _)) => Err(packet.len() + trailer_size),
+                            Err(WrapErr::NotEnoughBytes(len)) => {
+                                Err(len + trailer_size)
+                            }
+                            Err(e) => panic!("{:?}", e), //panic!("{:?} @ {:?}", e, buffer),
+                        };
+                        match len {
+                            Ok(len) => {
+                                //TODO cache Vecs?
+                                let mut msg = buffer.split_off(len + trailer_size);
+                                if is_replica {
+                                    let storage_loc =
+                                        LittleEndian::read_u64(&msg[len..len + mem::size_of::<u64>()]);
+                                    msg.truncate(len);
+                                    batch.add_replica_msg(Buffer::wrap_vec(msg), storage_loc);
+                                } else {
+                                    msg.truncate(len);
+                                    batch.add_client_msg(Buffer::wrap_vec(msg));
+                                }
+                            }
+                            Err(len) => {
+                                needed = Some(len);
+                                break;
+                            }
+                        }
+                    }
+                    buffer.shift_back();
+                    if let Some(len) = needed {
+                        buffer.ensure_fits(len)
+                    }
+                    let sent = sender
+                        .send_all(batch.log_batch())
+                        .map_err(|_| IoError::from(ErrorKind::BrokenPipe))
+                        .map(move |(sender, batch)| {
+                            let mut batcher = batch.batcher();
+                            batcher.handle_buffered_reads(|res| match res {
+                                Ok(bytes) => needs_writing.add_slice(bytes),
+                                Err(contents) => needs_writing.add_contents(contents),
+                            });
+                            (sender, reader, buffer, batcher, needs_writing)
+                        });
+                    future::Either::B(sent)
+                })
+            },
+        )
+        .map(|_| ())
+        .map_err(|err: IoError| if err.kind() != ErrorKind::UnexpectedEof {
+            println!("Recv error {:?}", err)
+        });
+    Box::new(read)
+}
+
+fn write_buffers<W: ::tokio_io::AsyncWrite + 'static>(writer: W, write_buffer: BufferStream)
+-> Box<Future<Item=(), Error=()>> {
+    let buffer_cache = write_buffer.clone();
+    let write = write_buffer
+        .map_err(|_| IoError::from(ErrorKind::BrokenPipe))
+        .fold(
+            (writer, buffer_cache),
+            |(writer, buffer_cache), write_buffer| {
+                io::write_all(writer, write_buffer).map(|(writer, buffer)| {
+                    buffer_cache.return_buffer(buffer);
+                    (writer, buffer_cache)
+                })
+            },
+        )
+        .map(|_| ())
+        .map_err(|err: IoError| println!("Send error {:?}", err));
+    Box::new(write)
+}
+
 pub enum ToLog<T> {
     New(
         Buffer,
@@ -212,9 +335,10 @@ pub enum ToLog<T> {
     ),
     Replication(ToReplicate, T),
 
-    #[allow(dead_code)]
     Recovery(Recovery, T),
 
+    CitedBy(Buffer, T),
+
     NewClient(u64, mpsc::Sender<ToWorker<u64>>),
 }
 
@@ -236,6 +360,7 @@ pub fn start_log_thread(
                     ToLog::New(buffer, storage, st) => log.handle_op(buffer, storage, st),
                     ToLog::Replication(tr, st) => log.handle_replication(tr, st),
                     ToLog::Recovery(r, st) => log.handle_recovery(r, st),
+                    ToLog::CitedBy(buffer, st) => log.handle_cited_by(buffer, st),
                     ToLog::NewClient(client, channel) => {
                         log.to_workers.0.insert(client, channel);
                     }
@@ -567,7 +692,7 @@ mod tests {
         let listener = TcpListener::bind(&"0.0.0.0:13288".parse().unwrap()).unwrap();
         let (to_log, from_client) = mpsc::channel(10);
         let (store, reader) = fuzzy_log_server::new_chain_store_and_reader();
-        thread::spawn(move || run(listener, to_log, reader, None));
+        thread::spawn(move || run(listener, to_log, reader, None, 2));
 
         thread::spawn(move || {
             let handle = from_client
@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use futures::{future, Future};
+
+use tokio::net::TcpStream;
+
+use tokio_io::io;
+
+use fuzzy_log_packets::hello::{self, Hello, Rejection};
+use fuzzy_log_util::hash::IdHashMap;
+use fuzzy_log_util::socket_addr::Ipv4SocketAddr as ClientId;
+
+// the same handshake as fuzzy_log_server::tcp (see packets::hello)
+// 1. server writes HANDSHAKE_VERSIONED
+// 2. down sends 1 | VERSIONED, client sends 2 | VERSIONED
+// 3. down/client sends its hello
+// 4. down/client sends id
+// 5. server sends its hello reply
+// 6. server sends id
+//
+// this server does not implement credentials, so peers which require
+// feature::AUTH have their credentials skipped, are sent a MISSING_FEATURES
+// reply, and are disconnected.
+//
+// at the head a client is only acked once the downstream server has connected
+// with the same id, everywhere else the upstream connection is made first
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Position {
+    Solo, Head, Tail(SocketAddr), Mid(SocketAddr),
+}
+
+impl Position {
+    pub fn new(prev_server: Option<SocketAddr>, has_next_server: bool) -> Self {
+        match (prev_server, has_next_server) {
+            (None,           false) => Position::Solo,
+            (Some(upstream), false) => Position::Tail(upstream),
+            (None,           true) => Position::Head,
+            (Some(upstream), true) => Position::Mid(upstream),
+        }
+    }
+
+    /// The ops this server reads come from another server.
+    pub fn is_replica(&self) -> bool {
+        match *self {
+            Position::Tail(..) | Position::Mid(..) => true,
+            Position::Solo | Position::Head => false,
+        }
+    }
+
+    /// The results of ops are sent to another server instead of the client.
+    pub fn continues_replication(&self) -> bool {
+        match *self {
+            Position::Head | Position::Mid(..) => true,
+            Position::Solo | Position::Tail(..) => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ClientType {
+    Client,
+    Server,
+}
+
+/// A negotiated connection: ops are read from `upstream`,
+/// and their results are sent on `downstream` if it exists.
+pub struct NewClient {
+    pub id: ClientId,
+    pub upstream: TcpStream,
+    pub downstream: Option<TcpStream>,
+}
+
+pub type Negotiation = Box<Future<Item=Option<NewClient>, Error=IoError>>;
+
+#[derive(Clone)]
+pub struct Negotiator {
+    position: Position,
+    hello: Hello,
+    // at the head, the connections waiting for their partner
+    waiting: Rc<RefCell<IdHashMap<ClientId, (ClientType, TcpStream)>>>,
+}
+
+impl Negotiator {
+    pub fn new(position: Position) -> Self {
+        Negotiator { position, hello: our_hello(), waiting: Default::default() }
+    }
+
+    /// Negotiate a newly accepted connection, resolves to `None` if the
+    /// connection is waiting on another one to complete.
+    pub fn got_connection(&self, socket: TcpStream) -> Negotiation {
+        let this = self.clone();
+        let ours = self.hello;
+        let greeted = io::write_all(socket, [hello::HANDSHAKE_VERSIONED])
+            .and_then(|(socket, _)| io::read_exact(socket, [0]))
+            .and_then(|(socket, kind)| {
+                let versioned = kind[0] & hello::VERSIONED != 0;
+                let kind = match kind[0] & !hello::VERSIONED {
+                    1 => ClientType::Server,
+                    2 => ClientType::Client,
+                    _ => return Err(IoError::new(ErrorKind::InvalidData, "bad client type")),
+                };
+                Ok((socket, kind, versioned))
+            })
+            .and_then(move |(socket, kind, versioned)| {
+                // peers without hellos get no reply
+                let theirs: Box<Future<Item=_, Error=IoError>> = if versioned {
+                    Box::new(io::read_exact(socket, vec![0; hello::HELLO_SIZE])
+                        .and_then(|(socket, theirs)| {
+                            let theirs = Hello::from_bytes(&theirs)
+                                .map_err(|r| IoError::new(ErrorKind::InvalidData, r.to_string()))?;
+                            Ok((socket, Some(theirs)))
+                        }))
+                } else {
+                    Box::new(future::ok((socket, None)))
+                };
+                theirs.map(move |(socket, theirs)| (socket, kind, theirs))
+            })
+            .and_then(|(socket, kind, theirs)| {
+                // credentials are read and ignored, see above
+                let skip = match theirs {
+                    Some(ref theirs) if theirs.required_features & hello::feature::AUTH != 0 =>
+                        hello::CREDENTIALS_SIZE,
+                    _ => 0,
+                };
+                io::read_exact(socket, vec![0; skip])
+                    .map(move |(socket, _)| (socket, kind, theirs))
+            })
+            .and_then(|(socket, kind, theirs)| {
+                io::read_exact(socket, [0; 16])
+                    .map(move |(socket, id)| (socket, kind, theirs, ClientId::from_bytes(id)))
+            })
+            .and_then(move |(socket, kind, theirs, id)| {
+                let negotiated = ours.negotiate(theirs.as_ref().unwrap_or(&Hello::legacy()));
+                let replied: Box<Future<Item=_, Error=IoError>> = match (theirs, negotiated) {
+                    (None, Ok(..)) => Box::new(future::ok((socket, kind, id))),
+                    (None, Err(rejection)) => Box::new(future::err(rejected(rejection))),
+                    (Some(..), negotiated) => Box::new(
+                        io::write_all(socket, hello::reply_bytes(&ours, &negotiated).to_vec())
+                            .and_then(move |(socket, _)| match negotiated {
+                                Ok(..) => Ok((socket, kind, id)),
+                                Err(rejection) => Err(rejected(rejection)),
+                            })
+                    ),
+                };
+                replied
+            });
+        Box::new(greeted.and_then(move |(socket, kind, id)| this.finish(socket, kind, id)))
+    }
+
+    fn finish(self, socket: TcpStream, kind: ClientType, id: ClientId) -> Negotiation {
+        match self.position {
+            Position::Solo => Box::new(io::write_all(socket, id).map(move |(socket, _)| {
+                Some(NewClient { id, upstream: socket, downstream: None })
+            })),
+
+            Position::Tail(upstream_addr) | Position::Mid(upstream_addr) => {
+                let expected = match self.position {
+                    Position::Tail(..) => ClientType::Client,
+                    _ => ClientType::Server,
+                };
+                if kind != expected {
+                    return Box::new(future::err(
+                        IoError::new(ErrorKind::InvalidData, "wrong client type for position")
+                    ))
+                }
+                let ours = self.hello;
+                let up = TcpStream::connect(&upstream_addr)
+                    .and_then(|up| {
+                        let _ = up.set_nodelay(true);
+                        io::read_exact(up, [0])
+                    })
+                    .and_then(move |(up, server_first)| {
+                        let (mut bytes, versioned) =
+                            hello::peer_hello_bytes(server_first[0], 1, &ours)
+                            .map_err(rejected)?;
+                        bytes.extend_from_slice(id.bytes());
+                        Ok(io::write_all(up, bytes).map(move |(up, _)| (up, versioned)))
+                    })
+                    .flatten()
+                    .and_then(move |(up, versioned)| {
+                        let reply: Box<Future<Item=_, Error=IoError>> = if versioned {
+                            Box::new(io::read_exact(up, vec![0; hello::HELLO_REPLY_SIZE])
+                                .and_then(move |(up, reply)| {
+                                    hello::check_reply(&ours, &reply).map_err(rejected)?;
+                                    Ok(up)
+                                }))
+                        } else {
+                            Box::new(future::ok(up))
+                        };
+                        reply
+                    })
+                    .and_then(|up| io::read_exact(up, [0; 16]))
+                    .and_then(move |(up, id2)| {
+                        if ClientId::from_bytes(id2) != id {
+                            return Err(IoError::new(ErrorKind::InvalidData, "upstream ack mismatch"))
+                        }
+                        Ok(up)
+                    });
+                Box::new(up.and_then(move |up| {
+                    io::write_all(socket, id).map(move |(down, _)| {
+                        Some(NewClient { id, upstream: up, downstream: Some(down) })
+                    })
+                }))
+            },
+
+            Position::Head => {
+                let socket: Box<Future<Item=TcpStream, Error=IoError>> =
+                    if kind == ClientType::Server {
+                        Box::new(io::write_all(socket, id).map(|(socket, _)| socket))
+                    } else {
+                        Box::new(future::ok(socket))
+                    };
+                let waiting = self.waiting.clone();
+                Box::new(socket.and_then(move |socket| {
+                    let other = waiting.borrow_mut().remove(&id);
+                    let (client, server) = match (other, kind) {
+                        (None, _) => {
+                            waiting.borrow_mut().insert(id, (kind, socket));
+                            return Box::new(future::ok(None)) as Negotiation
+                        },
+                        (Some((ClientType::Client, client)), ClientType::Server) =>
+                            (client, socket),
+                        (Some((ClientType::Server, server)), ClientType::Client) =>
+                            (socket, server),
+                        (Some(..), _) => return Box::new(future::err(
+                            IoError::new(ErrorKind::InvalidData, "duplicate client id")
+                        )),
+                    };
+                    Box::new(io::write_all(client, id).map(move |(client, _)| {
+                        Some(NewClient { id, upstream: client, downstream: Some(server) })
+                    }))
+                }))
+            },
+        }
+    }
+}
+
+/// The hello of this server: everything this build supports except `AUTH`.
+fn our_hello() -> Hello {
+    Hello { features: hello::feature::SUPPORTED & !hello::feature::AUTH, ..Hello::current(0) }
+}
+
+fn rejected(rejection: Rejection) -> IoError {
+    IoError::new(ErrorKind::ConnectionRefused, rejection.to_string())
+}
@@ -6,9 +6,13 @@ use ToWorker;
 
 use buffer_stream::BufferStream;
 
-use fuzzy_log_packets::{EntryContents, EntryFlag, EntryLayout, OrderIndex};
+use byteorder::{ByteOrder, LittleEndian};
+
+use fuzzy_log_packets::{reject, EntryContents, EntryFlag, EntryKind, EntryLayout, OrderIndex};
 use fuzzy_log_packets::buffer::Buffer;
-use fuzzy_log_server::{worker_thread, ChainReader, SkeensMultiStorage, Troption};
+use fuzzy_log_server::{worker_thread, ChainReader, Recovery, SkeensMultiStorage, ToReplicate, Troption};
+use fuzzy_log_server::shared_slice::RcSlice;
+use fuzzy_log_util::socket_addr::Ipv4SocketAddr as ClientId;
 
 use futures::{Async, Poll};
 use stream::Stream;
@@ -18,14 +22,16 @@ pub struct Batcher {
     read_batch: VecDeque<Buffer>,
     reader: ChainReader<u64>,
     client: u64,
+    id: ClientId,
 }
 
 impl Batcher {
-    pub fn new(client: u64, reader: ChainReader<u64>) -> Self {
+    pub fn new(client: u64, id: ClientId, reader: ChainReader<u64>) -> Self {
         Batcher {
             log_batch: VecDeque::new(),
             read_batch: VecDeque::new(),
             client,
+            id,
             reader,
         }
     }
@@ -35,13 +41,46 @@ impl Batcher {
     }
 
     pub fn add_client_msg(&mut self, mut msg: Buffer) {
+        let (kind, flag) = {
+            let e = msg.contents();
+            (e.kind(), e.flag().clone())
+        };
+        // recovery packets have no layout, so they need to be routed before we look for one
+        match kind {
+            EntryKind::UpdateRecovery => {
+                let recoverer = {
+                    let locs = msg.contents().locs().to_vec().into_boxed_slice();
+                    Box::new((self.id.to_uuid(), locs))
+                };
+                let to_send = ToLog::Recovery(Recovery::TasRecoverer(msg, recoverer), self.client);
+                return self.log_batch.push_back(to_send)
+            },
+            EntryKind::CheckSkeens1 => {
+                let to_send = ToLog::Recovery(Recovery::CheckSkeens1(msg), self.client);
+                return self.log_batch.push_back(to_send)
+            },
+            EntryKind::CitedBy => {
+                return self.log_batch.push_back(ToLog::CitedBy(msg, self.client))
+            },
+            EntryKind::FenceClient => {
+                //we do not fence off clients, so the fence is acked along with the reads
+                return self.read_batch.push_back(msg)
+            },
+            _ => (),
+        }
+        // corrupt appends are bounced back to the client along with the reads
+        if let Err(e) = msg.contents().verify_checksum() {
+            error!("rejecting append from {:?}: {}", self.id, e);
+            msg.reject(reject::CORRUPT, None);
+            return self.read_batch.push_back(msg)
+        }
         let storage = match msg.contents().layout() {
             EntryLayout::Read => {
                 self.read_batch.push_back(msg);
                 return;
             }
             EntryLayout::Multiput | EntryLayout::Sentinel => {
-                let (size, senti_size, num_locs, has_senti, is_unlock, flag) = {
+                let (size, senti_size, num_locs, has_senti, is_unlock) = {
                     let e = msg.contents();
                     let locs = e.locs();
                     let num_locs = locs.len();
@@ -54,26 +93,26 @@ impl Batcher {
                         num_locs,
                         has_senti,
                         e.flag().contains(EntryFlag::Unlock),
-                        e.flag().clone(),
                     )
                 };
                 if is_unlock {
                     Troption::None
-                } else {
-                    assert!(flag.contains(EntryFlag::NewMultiPut) || !flag.contains(EntryFlag::TakeLock));
+                } else if flag.contains(EntryFlag::DirectWrite) {
+                    let storage = Box::new((RcSlice::with_len(size), RcSlice::with_len(senti_size)));
+                    let to_send = ToLog::Replication(ToReplicate::Multi(msg, storage), self.client);
+                    return self.log_batch.push_back(to_send)
+                } else if flag.contains(EntryFlag::NewMultiPut) || !flag.contains(EntryFlag::TakeLock) {
                     let senti_size = if has_senti { Some(senti_size) } else { None };
                     let mut storage = SkeensMultiStorage::new(num_locs, size, senti_size);
                     if !flag.contains(EntryFlag::TakeLock) {
                         storage.fill_from(&mut msg)
                     }
                     Troption::Left(storage)
+                } else {
+                    let m = RcSlice::with_len(size);
+                    let s = RcSlice::with_len(senti_size);
+                    Troption::Right(Box::new((m, s)))
                 }
-                // } else {
-                //     use fuzzy_log_server::shared_slice::RcSlice;
-                //     let m = RcSlice::with_len(size);
-                //     let s = RcSlice::with_len(senti_size);
-                //     Troption::Right(Box::new((m, s)))
-                // }
             }
             EntryLayout::Snapshot => {
                 let (size, num_locs, is_unlock) = {
@@ -89,6 +128,10 @@ impl Batcher {
                     Troption::Left(storage)
                 }
             }
+            EntryLayout::Data if flag.contains(EntryFlag::DirectWrite) => {
+                let tr = ToReplicate::Data(msg, ::std::u64::MAX);
+                return self.log_batch.push_back(ToLog::Replication(tr, self.client))
+            },
             EntryLayout::Data => Troption::None,
             EntryLayout::GC => Troption::None,
             EntryLayout::Lock => unreachable!("No Locks"),
@@ -97,6 +140,61 @@ impl Batcher {
         self.log_batch.push_back(to_send)
     }
 
+    /// Add an op forwarded by the previous server in the replication chain.
+    pub fn add_replica_msg(&mut self, mut msg: Buffer, storage_loc: u64) {
+        let kind = msg.contents().kind();
+        // the head already stored this entry, so we must as well,
+        // but it is flagged so the tail's ack tells the client the append failed
+        if let Err(e) = msg.contents().verify_checksum() {
+            error!("replicating corrupt entry {:?}: {}", msg.contents().locs(), e);
+            msg.contents_mut().flag_mut().insert(EntryFlag::Corrupted);
+        }
+        let to_send = match kind {
+            EntryKind::CitedBy => {
+                //already answered by the head of the chain, pass it along
+                return self.log_batch.push_back(ToLog::CitedBy(msg, self.client))
+            },
+            EntryKind::Data => ToReplicate::Data(msg, storage_loc),
+            EntryKind::Lock => ToReplicate::UnLock(msg),
+            EntryKind::Multiput | EntryKind::Sentinel => {
+                let (size, senti_size) = {
+                    let e = msg.contents();
+                    (e.len(), e.sentinel_entry_size())
+                };
+                let storage = Box::new((RcSlice::with_len(size), RcSlice::with_len(senti_size)));
+                ToReplicate::Multi(msg, storage)
+            },
+            EntryKind::SingleToReplica => ToReplicate::SingleSkeens1(msg, storage_loc),
+            EntryKind::MultiputToReplica | EntryKind::SentinelToReplica => {
+                let (size, senti_size, num_locs, has_senti) = {
+                    let e = msg.contents();
+                    let locs = e.locs();
+                    //FIXME
+                    let has_senti = locs.contains(&OrderIndex(0.into(), 0.into()))
+                        || !e.flag().contains(EntryFlag::TakeLock);
+                    (e.non_replicated_len(), e.sentinel_entry_size(), locs.len(), has_senti)
+                };
+                let senti_size = if has_senti { Some(senti_size) } else { None };
+                let storage = SkeensMultiStorage::new(num_locs, size, senti_size);
+                ToReplicate::Skeens1(msg, storage)
+            },
+            EntryKind::Skeens2ToReplica => ToReplicate::Skeens2(msg),
+            EntryKind::SnapshotToReplica => {
+                let (size, num_locs) = {
+                    let e = msg.contents();
+                    (e.len(), e.locs().len())
+                };
+                let mut storage = SkeensMultiStorage::new(num_locs, size, None);
+                let mut msg = msg;
+                storage.fill_from(&mut msg);
+                ToReplicate::SnapshotSkeens1(msg, storage)
+            },
+            EntryKind::GC => ToReplicate::GC(msg),
+            e => unreachable!("{:?}", e),
+        };
+        self.log_batch.push_back(ToLog::Replication(to_send, self.client))
+    }
+
     pub fn log_batch<E>(self) -> LogBatch<E> {
         LogBatch(self, PhantomData)
     }
@@ -106,6 +204,14 @@ impl Batcher {
     pub fn handle_buffered_reads<SendFn>(&mut self, mut send: SendFn)
     where SendFn: for<'a> FnMut(Result<&'a [u8], EntryContents<'a>>) {
         for buffer in self.read_batch.drain(..) {
+            let reply = {
+                let e = buffer.contents();
+                e.kind() == EntryKind::FenceClient || e.kind() == EntryKind::Rejected
+            };
+            if reply {
+                send(Ok(buffer.entry_slice()));
+                continue
+            }
             worker_thread::handle_read(&self.reader, &buffer, self.client as usize, &mut send);
         }
     }
@@ -140,20 +246,55 @@ impl<E> Stream for LogBatch<E> {
 //     }
 // }
 
-pub fn add_response(msg: ToWorker<u64>, write_buffer: &mut BufferStream, worker_num: u64)
--> Option<Buffer> {
-    //TODO downstream?
-    worker_thread::handle_to_worker2(msg, worker_num as usize, /*continue_replication*/ false, |to_send, _, _| {
+/// Send the result of an op to its client,
+/// or if `downstream` is set, to the next server in the replication chain
+/// tagged with the op's client.
+pub fn add_response(
+    msg: ToWorker<u64>,
+    write_buffer: &mut BufferStream,
+    worker_num: u64,
+    downstream: Option<ClientId>,
+) -> Option<Buffer> {
+    let continue_replication = downstream.is_some();
+    worker_thread::handle_to_worker2(msg, worker_num as usize, continue_replication, |to_send, _, _| {
         use worker_thread::ToSend;
-        match to_send {
-            ToSend::Nothing => return,
-            ToSend::OldReplication(..) => unreachable!(),
+        match downstream {
+            None => match to_send {
+                ToSend::Nothing => return,
+                ToSend::OldReplication(..) => unreachable!(),
 
-            ToSend::Contents(to_send) | ToSend::OldContents(to_send, _) => write_buffer.add_contents(to_send),
+                ToSend::Contents(to_send) | ToSend::OldContents(to_send, _) => write_buffer.add_contents(to_send),
 
-            ToSend::Slice(to_send) => write_buffer.add_slice(to_send),
+                ToSend::Slice(to_send) => write_buffer.add_slice(to_send),
 
-            ToSend::StaticSlice(to_send) | ToSend::Read(to_send) => write_buffer.add_slice(to_send),
+                ToSend::StaticSlice(to_send) | ToSend::Read(to_send) => write_buffer.add_slice(to_send),
+            },
+
+            Some(src_addr) => {
+                let mut storage_loc_bytes: [u8; 8] = [0; 8];
+                match to_send {
+                    ToSend::Nothing => return,
+                    ToSend::Read(..) => unreachable!(),
+
+                    ToSend::OldReplication(to_send, storage_loc) => {
+                        LittleEndian::write_u64(&mut storage_loc_bytes, storage_loc);
+                        write_buffer.add_slice(to_send)
+                    },
+
+                    ToSend::Contents(to_send) => write_buffer.add_contents(to_send),
+
+                    ToSend::OldContents(to_send, storage_loc) => {
+                        LittleEndian::write_u64(&mut storage_loc_bytes, storage_loc);
+                        write_buffer.add_contents(to_send)
+                    },
+
+                    ToSend::Slice(to_send) => write_buffer.add_slice(to_send),
+
+                    ToSend::StaticSlice(to_send) => write_buffer.add_slice(to_send),
+                }
+                write_buffer.add_slice(&storage_loc_bytes);
+                write_buffer.add_slice(src_addr.bytes());
+            },
         }
     }).0
 }
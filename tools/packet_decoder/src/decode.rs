@@ -0,0 +1,428 @@
+//! Turning the bytes of one direction of a FuzzyLog connection into packets.
+//!
+//! A connection starts with the handshake described in `packets::hello`,
+//! after which the peer sends packets each followed by its id
+//! (and, on replication links, the storage location of the entry),
+//! and the server sends bare packets.
+//! If we did not see the start of a connection we skip bytes until we find
+//! something which parses as a packet, and guess at what follows it.
+
+use std::fmt::Write;
+
+use fuzzy_log_packets::{EntryContents, OrderIndex, Uuid};
+use fuzzy_log_packets::Packet::WrapErr;
+use fuzzy_log_packets::hello::{self, Credentials, Hello};
+
+/// Anything claiming to be larger than this is garbage we're trying to resync past.
+const MAX_PACKET_SIZE: usize = 64 * 1024 * 1024;
+
+const PEER_ID_SIZE: usize = 16;
+const STORAGE_LOC_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    FromServer,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Peer {
+    /// The previous server in a replication chain.
+    Replica,
+    Client,
+}
+
+/// What the two directions of a connection learn about each other.
+#[derive(Debug, Default)]
+pub struct Handshake {
+    pub peer: Option<Peer>,
+    pub peer_versioned: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Handshake(String),
+    Packet(String),
+    Skipped(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    ServerFirst,
+    HelloReply,
+    ServerId,
+    PeerType,
+    PeerHello,
+    PeerCredentials,
+    PeerId,
+    Packets,
+    Lost,
+}
+
+#[derive(Debug)]
+pub struct StreamDecoder {
+    direction: Direction,
+    state: State,
+    buffer: Vec<u8>,
+}
+
+impl StreamDecoder {
+    /// `from_start` is whether the stream will start with the handshake.
+    pub fn new(direction: Direction, from_start: bool) -> Self {
+        let state = match (direction, from_start) {
+            (Direction::ToServer, true) => State::PeerType,
+            (Direction::FromServer, true) => State::ServerFirst,
+            (_, false) => State::Lost,
+        };
+        StreamDecoder { direction, state, buffer: vec![] }
+    }
+
+    /// Decode as much of `bytes`, along with anything left over from earlier, as we can.
+    pub fn feed(&mut self, bytes: &[u8], handshake: &mut Handshake) -> Vec<Event> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = vec![];
+        let mut read = 0;
+        while let Some((event, used)) =
+            step(self.direction, &mut self.state, &self.buffer[read..], handshake) {
+            read += used;
+            events.extend(event);
+        }
+        self.buffer.drain(..read);
+        events
+    }
+
+    /// The bytes we could not make sense of before the stream ended.
+    pub fn leftover(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// Decode one thing from the start of `bytes`,
+/// `None` if we need more bytes to decode anything.
+fn step(direction: Direction, state: &mut State, bytes: &[u8], handshake: &mut Handshake)
+-> Option<(Option<Event>, usize)> {
+    use self::State::*;
+    match *state {
+        ServerFirst => {
+            let first = *bytes.first()?;
+            let versioned = first == hello::HANDSHAKE_VERSIONED;
+            *state = HelloReply;
+            let event = format!("server handshake {}", if versioned { "versioned" } else { "legacy" });
+            Some((Some(Event::Handshake(event)), 1))
+        },
+
+        HelloReply => match handshake.peer_versioned? {
+            false => {
+                *state = ServerId;
+                Some((None, 0))
+            },
+            true => {
+                let reply = bytes.get(..hello::HELLO_REPLY_SIZE)?;
+                let theirs = Hello::from_bytes(&reply[1..]).ok()?;
+                *state = ServerId;
+                let event = format!("hello reply status {}, {}", reply[0], describe_hello(&theirs));
+                Some((Some(Event::Handshake(event)), reply.len()))
+            },
+        },
+
+        PeerType => {
+            let peer_type = *bytes.first()?;
+            let versioned = peer_type & hello::VERSIONED != 0;
+            let peer = match peer_type & !hello::VERSIONED {
+                1 => Peer::Replica,
+                2 => Peer::Client,
+                other => {
+                    *state = Lost;
+                    let event = format!("unknown peer type {}", other);
+                    return Some((Some(Event::Handshake(event)), 1))
+                },
+            };
+            handshake.peer = Some(peer);
+            handshake.peer_versioned = Some(versioned);
+            *state = if versioned { PeerHello } else { PeerId };
+            Some((Some(Event::Handshake(format!("{:?} connected", peer))), 1))
+        },
+
+        PeerHello => {
+            let ours = Hello::from_bytes(bytes.get(..hello::HELLO_SIZE)?).ok()?;
+            let authenticated = ours.required_features & hello::feature::AUTH != 0;
+            *state = if authenticated { PeerCredentials } else { PeerId };
+            let event = format!("hello {}", describe_hello(&ours));
+            Some((Some(Event::Handshake(event)), hello::HELLO_SIZE))
+        },
+
+        PeerCredentials => {
+            let credentials = Credentials::from_bytes(bytes.get(..hello::CREDENTIALS_SIZE)?).ok()?;
+            *state = PeerId;
+            // never print the secret
+            let event = format!("credentials for {:?}", credentials.principal());
+            Some((Some(Event::Handshake(event)), hello::CREDENTIALS_SIZE))
+        },
+
+        ServerId | PeerId => {
+            let id = Uuid::from_bytes(bytes.get(..PEER_ID_SIZE)?).unwrap();
+            *state = Packets;
+            Some((Some(Event::Handshake(format!("id {}", id))), PEER_ID_SIZE))
+        },
+
+        Packets => {
+            let size = match packet_size(bytes) {
+                Ok(size) => size,
+                Err(true) => return None,
+                Err(false) => {
+                    *state = Lost;
+                    return Some((None, 0))
+                },
+            };
+            let trailer = trailer_size(direction, &bytes[size..], handshake)?;
+            let (packet, trailer) = bytes.get(..size + trailer)?.split_at(size);
+            let contents = unsafe { EntryContents::try_ref(packet).unwrap().0 };
+            let mut description = describe(contents);
+            if trailer.len() > PEER_ID_SIZE {
+                let (loc, _) = trailer.split_at(STORAGE_LOC_SIZE);
+                let loc = loc.iter().rev().fold(0u64, |l, &b| (l << 8) | b as u64);
+                let _ = write!(description, " storage={:#x}", loc);
+            }
+            if trailer.len() >= PEER_ID_SIZE {
+                let from = Uuid::from_bytes(&trailer[trailer.len() - PEER_ID_SIZE..]).unwrap();
+                let _ = write!(description, " from={}", from);
+            }
+            Some((Some(Event::Packet(description)), size + trailer.len()))
+        },
+
+        Lost => {
+            for skip in 0..bytes.len() {
+                match packet_size(&bytes[skip..]) {
+                    Ok(..) => {
+                        *state = Packets;
+                        let event = if skip > 0 { Some(Event::Skipped(skip)) } else { None };
+                        return Some((event, skip))
+                    },
+                    // this might be a packet, we'll know once we have more bytes
+                    Err(true) if skip > 0 => return Some((Some(Event::Skipped(skip)), skip)),
+                    Err(true) => return None,
+                    Err(false) => continue,
+                }
+            }
+            // nothing here could start a packet
+            match bytes.len() {
+                0 => None,
+                len => Some((Some(Event::Skipped(len)), len)),
+            }
+        },
+    }
+}
+
+/// The size of what follows a packet, `None` if we need more bytes to decide.
+fn trailer_size(direction: Direction, after: &[u8], handshake: &mut Handshake) -> Option<usize> {
+    match (direction, handshake.peer) {
+        (Direction::FromServer, _) => Some(0),
+        (Direction::ToServer, Some(Peer::Client)) => Some(PEER_ID_SIZE),
+        (Direction::ToServer, Some(Peer::Replica)) => Some(STORAGE_LOC_SIZE + PEER_ID_SIZE),
+        // we missed the handshake, guess based on what comes next
+        (Direction::ToServer, None) => {
+            let replica_size = STORAGE_LOC_SIZE + PEER_ID_SIZE;
+            if after.len() < replica_size + 1 {
+                return None
+            }
+            match (packet_size(&after[PEER_ID_SIZE..]), packet_size(&after[replica_size..])) {
+                (Err(false), Ok(..)) | (Err(false), Err(true)) => {
+                    handshake.peer = Some(Peer::Replica);
+                    Some(replica_size)
+                },
+                _ => {
+                    handshake.peer = Some(Peer::Client);
+                    Some(PEER_ID_SIZE)
+                },
+            }
+        },
+    }
+}
+
+/// The size of the packet at the start of `bytes`,
+/// `Err(true)` if we need more bytes to tell, `Err(false)` if it isn't a packet.
+fn packet_size(bytes: &[u8]) -> Result<usize, bool> {
+    match unsafe { EntryContents::try_ref(bytes) } {
+        Ok((contents, _)) => Ok(contents.len()),
+        Err(WrapErr::NotEnoughBytes(needed)) => Err(needed <= MAX_PACKET_SIZE),
+        Err(WrapErr::BadTag) => Err(false),
+    }
+}
+
+fn describe_hello(hello: &Hello) -> String {
+    format!("version {} (min {}) features {:#x} required {:#x}",
+        hello.version, hello.min_version, hello.features, hello.required_features)
+}
+
+/// A one line description of a packet.
+pub fn describe(contents: EntryContents) -> String {
+    use fuzzy_log_packets::Packet::Ref::*;
+    let mut out = String::new();
+    let _ = match contents {
+        Read{id, flags, loc, horizon, min, ..} => write!(out,
+            "Read {} flags={:?} loc={} horizon={} min={}",
+            id, flags, fmt_loc(loc), fmt_loc(horizon), fmt_loc(min)),
+
+        Single{id, flags, loc, deps, data, timestamp} => write!(out,
+            "Single {} flags={:?} loc={} deps={} data={}B timestamp={}",
+            id, flags, fmt_loc(loc), fmt_locs(deps), data.len(), timestamp),
+
+        Multi{id, flags, lock, locs, deps, data} => write!(out,
+            "Multi {} flags={:?} lock={} locs={} deps={} data={}B",
+            id, flags, lock, fmt_locs(locs), fmt_locs(deps), data.len()),
+
+        Senti{id, flags, lock, locs, deps} => write!(out,
+            "Senti {} flags={:?} lock={} locs={} deps={}",
+            id, flags, lock, fmt_locs(locs), fmt_locs(deps)),
+
+        SingleToReplica{id, flags, loc, deps, data, timestamp, queue_num} => write!(out,
+            "SingleToReplica {} flags={:?} loc={} deps={} data={}B timestamp={} queue_num={}",
+            id, flags, fmt_loc(loc), fmt_locs(deps), data.len(), timestamp, queue_num),
+
+        MultiToReplica{id, flags, lock, locs, deps, data, queue_nums} => write!(out,
+            "MultiToReplica {} flags={:?} lock={} locs={} deps={} data={}B queue_nums={:?}",
+            id, flags, lock, fmt_locs(locs), fmt_locs(deps), data.len(), queue_nums),
+
+        SentiToReplica{id, flags, lock, locs, deps, queue_nums} => write!(out,
+            "SentiToReplica {} flags={:?} lock={} locs={} deps={} queue_nums={:?}",
+            id, flags, lock, fmt_locs(locs), fmt_locs(deps), queue_nums),
+
+        Skeens2ToReplica{id, flags, lock, loc} => write!(out,
+            "Skeens2ToReplica {} flags={:?} lock={} loc={}", id, flags, lock, fmt_loc(loc)),
+
+        GC{id, flags, locs} => write!(out,
+            "GC {} flags={:?} locs={}", id, flags, fmt_locs(locs)),
+
+        FenceClient{fencing_write, client_to_fence, fencing_client} => write!(out,
+            "FenceClient {} fencing={} by={}", fencing_write, client_to_fence, fencing_client),
+
+        UpdateRecovery{old_recoverer, write_id, flags, lock, locs} => write!(out,
+            "UpdateRecovery {} flags={:?} lock={} locs={} old_recoverer={}",
+            write_id, flags, lock, fmt_locs(locs), old_recoverer),
+
+        CheckSkeens1{id, flags, loc, ..} => write!(out,
+            "CheckSkeens1 {} flags={:?} loc={}", id, flags, fmt_loc(loc)),
+
+        Snapshot{id, flags, lock, locs, ..} => write!(out,
+            "Snapshot {} flags={:?} lock={} locs={}", id, flags, lock, fmt_locs(locs)),
+
+        SnapshotToReplica{id, flags, lock, locs, queue_nums, ..} => write!(out,
+            "SnapshotToReplica {} flags={:?} lock={} locs={} queue_nums={:?}",
+            id, flags, lock, fmt_locs(locs), queue_nums),
+
+        CitedBy{id, flags, loc, citations} => write!(out,
+            "CitedBy {} flags={:?} loc={} citations={}",
+            id, flags, fmt_loc(loc), fmt_locs(citations)),
+
+        Rejected{id, flags, status, request, locs} => write!(out,
+            "Rejected {} flags={:?} status={} request={:?} locs={}",
+            id, flags, status, request, fmt_locs(locs)),
+    };
+    out
+}
+
+fn fmt_loc(&OrderIndex(chain, index): &OrderIndex) -> String {
+    format!("({}, {})", u64::from(chain), u64::from(index))
+}
+
+fn fmt_locs(locs: &[OrderIndex]) -> String {
+    let locs: Vec<_> = locs.iter().map(fmt_loc).collect();
+    format!("[{}]", locs.join(", "))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use fuzzy_log_packets::EntryFlag;
+
+    pub fn single(id: &Uuid) -> Vec<u8> {
+        let mut bytes = vec![];
+        EntryContents::Single {
+            id: id,
+            flags: &EntryFlag::Nothing,
+            loc: &OrderIndex(3.into(), 0.into()),
+            deps: &[OrderIndex(1.into(), 2.into())],
+            data: &[1, 2, 3],
+            timestamp: &0,
+        }.fill_vec(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn handshake_then_packets() {
+        let id = Uuid::new_v4();
+        let mut handshake = Handshake::default();
+
+        let mut to_server = vec![2 | hello::VERSIONED];
+        to_server.extend_from_slice(&Hello::current(0).to_bytes());
+        to_server.extend_from_slice(id.as_bytes());
+        let packet = single(&id);
+        to_server.extend_from_slice(&packet);
+        to_server.extend_from_slice(id.as_bytes());
+
+        let mut decoder = StreamDecoder::new(Direction::ToServer, true);
+        let (start, rest) = to_server.split_at(50);
+        let events = decoder.feed(start, &mut handshake);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], Event::Handshake("Client connected".to_owned()));
+        assert_eq!(handshake.peer_versioned, Some(true));
+        let events = decoder.feed(rest, &mut handshake);
+        assert_eq!(events, vec![Event::Packet(format!(
+            "Single {} flags={:?} loc=(3, 0) deps=[(1, 2)] data=3B timestamp=0 from={}",
+            id, EntryFlag::Nothing, id
+        ))]);
+        assert_eq!(decoder.leftover(), 0);
+
+        let mut from_server = vec![hello::HANDSHAKE_VERSIONED];
+        from_server.extend_from_slice(&[0; hello::HELLO_REPLY_SIZE]);
+        from_server.extend_from_slice(id.as_bytes());
+        from_server.extend_from_slice(&packet);
+        let mut decoder = StreamDecoder::new(Direction::FromServer, true);
+        let events = decoder.feed(&from_server, &mut handshake);
+        assert_eq!(events.len(), 4);
+        assert!(match events[3] { Event::Packet(ref p) => p.starts_with("Single"), _ => false });
+    }
+
+    #[test]
+    fn handshake_with_credentials() {
+        let id = Uuid::new_v4();
+        let mut handshake = Handshake::default();
+
+        let mut to_server = vec![2 | hello::VERSIONED];
+        to_server.extend_from_slice(&Hello::current(hello::feature::AUTH).to_bytes());
+        to_server.extend_from_slice(&Credentials::new("alice", b"hunter2").to_bytes());
+        to_server.extend_from_slice(id.as_bytes());
+        to_server.extend_from_slice(&single(&id));
+        to_server.extend_from_slice(id.as_bytes());
+
+        let mut decoder = StreamDecoder::new(Direction::ToServer, true);
+        let events = decoder.feed(&to_server, &mut handshake);
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2], Event::Handshake("credentials for \"alice\"".to_owned()));
+        assert_eq!(events[3], Event::Handshake(format!("id {}", id)));
+        assert!(match events[4] { Event::Packet(ref p) => p.starts_with("Single"), _ => false });
+        assert!(events.iter().all(|e| !format!("{:?}", e).contains("hunter2")));
+        assert_eq!(decoder.leftover(), 0);
+    }
+
+    #[test]
+    fn resync_mid_stream() {
+        // an id which can't be mistaken for the start of a packet
+        let id = Uuid::from_bytes(&[0xff; 16]).unwrap();
+        let mut handshake = Handshake::default();
+        let mut bytes = vec![0xff; 5];
+        for _ in 0..2 {
+            bytes.extend_from_slice(&single(&id));
+            bytes.extend_from_slice(&[0; STORAGE_LOC_SIZE]);
+            bytes.extend_from_slice(id.as_bytes());
+        }
+        let mut decoder = StreamDecoder::new(Direction::ToServer, false);
+        let events = decoder.feed(&bytes, &mut handshake);
+        assert_eq!(events[0], Event::Skipped(5));
+        assert_eq!(events.len(), 3);
+        assert!(match events[1] { Event::Packet(ref p) => p.contains("storage=0x0"), _ => false });
+        assert_eq!(handshake.peer, Some(Peer::Replica));
+        assert_eq!(decoder.leftover(), 0);
+    }
+}
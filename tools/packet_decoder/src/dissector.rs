@@ -0,0 +1,194 @@
+//! A Wireshark dissector generated from the packet schema.
+//!
+//! The dissector understands the packets themselves and the sender id which
+//! follows every packet a client sends to a server. It does not understand the
+//! connection handshake, nor the storage locations servers send down
+//! replication chains; use the decoder on a capture for those.
+
+use std::fmt::Write;
+
+use fuzzy_log_packets::schema::{self, Body, Field, Packet, Type};
+
+/// The port the servers in our tests and benchmarks listen on.
+const DEFAULT_PORT: u16 = 13490;
+
+/// A Lua dissector for `packet`, load it with `wireshark -X lua_script:<file>`.
+pub fn lua_dissector(packet: &Packet) -> String {
+    let proto = packet.name.to_lowercase();
+    let mut out = String::new();
+    let _ = writeln!(out, "-- Generated from the definition of `{}`, do not edit.\n", packet.name);
+    let _ = writeln!(out, "local proto = Proto({:?}, \"FuzzyLog {}\")", proto, packet.name);
+    let _ = writeln!(out,
+        "proto.prefs.port = Pref.uint(\"Server port\", {}, \"The port FuzzyLog servers listen on\")\n",
+        DEFAULT_PORT);
+    let _ = writeln!(out, "local LITTLE = {}", schema::byte_order() == "little");
+    let _ = writeln!(out, "local SENDER_SIZE = 16");
+    let _ = writeln!(out, "local f_sender = ProtoField.bytes(\"{}.sender\", \"sender\")", proto);
+    out.push_str("local fields = { f_sender }\n\n");
+
+    // every field gets a ProtoField, declared once per variant
+    let (tag_size, variants) = match packet.body {
+        Body::Struct(ref fields) => (0, vec![(0, packet.name, fields)]),
+        Body::Enum { ref tag, ref variants } => {
+            let size = tag.ty.size().expect("packet tags have a fixed size");
+            (size, variants.iter().map(|v| (v.tag, v.name, &v.fields)).collect())
+        },
+    };
+    let _ = writeln!(out, "local TAG_SIZE = {}\n", tag_size);
+    out.push_str("local LAYOUTS = {\n");
+    for (tag, name, fields) in variants {
+        let _ = writeln!(out, "    [{:#x}] = {{ {:?}, {{", tag, name);
+        for field in fields {
+            write_field(&proto, name, field, &mut out);
+        }
+        out.push_str("    }},\n");
+    }
+    out.push_str("}\n");
+    out.push_str(LUA_DISSECTOR);
+    out
+}
+
+fn write_field(proto: &str, variant: &str, field: &Field, out: &mut String) {
+    let abbrev = format!("{}.{}.{}", proto, variant.to_lowercase(), field.name);
+    let (size, count) = match field.ty {
+        Type::VarArray { ref elem, count } =>
+            (elem.size().expect("nested variable length arrays"), Some(count)),
+        ref ty => (ty.size().unwrap(), None),
+    };
+    let int = match (&field.ty, count) {
+        (&Type::Fixed { name, size }, None) => match (name, size) {
+            ("u8", 1) | ("u16", 2) | ("u32", 4) | ("u64", 8) => Some(format!("uint{}", size * 8)),
+            ("i8", 1) | ("i16", 2) | ("i32", 4) | ("i64", 8) => Some(format!("int{}", size * 8)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let proto_field = format!("ProtoField.{}({:?}, {:?})",
+        int.as_ref().map(|s| &**s).unwrap_or("bytes"), abbrev, field.name);
+    let _ = writeln!(out, "        {{ name = {:?}, size = {}, count = {}, int = {}, field = {} }},",
+        field.name, size, count.map(|c| format!("{:?}", c)).unwrap_or("nil".to_owned()),
+        int.is_some(), proto_field);
+}
+
+const LUA_DISSECTOR: &'static str = r#"
+for _, layout in pairs(LAYOUTS) do
+    for _, f in ipairs(layout[2]) do
+        table.insert(fields, f.field)
+    end
+end
+proto.fields = fields
+
+local function read_int(tvb, offset, size)
+    if size == 0 then
+        return 0
+    end
+    local range = tvb(offset, size)
+    if size > 4 then
+        return (LITTLE and range:le_uint64() or range:uint64()):tonumber()
+    end
+    return LITTLE and range:le_uint() or range:uint()
+end
+
+-- The length of the packet at offset, its layout and the values of its integer fields,
+-- nil if the packet is incomplete, or false if this isn't a packet.
+local function packet_len(tvb, offset)
+    local available = tvb:len() - offset
+    if available < TAG_SIZE then
+        return nil
+    end
+    local layout = LAYOUTS[read_int(tvb, offset, TAG_SIZE)]
+    if layout == nil then
+        return false
+    end
+    local len = TAG_SIZE
+    local values = {}
+    for _, f in ipairs(layout[2]) do
+        local size = f.size
+        if f.count then
+            size = size * values[f.count]
+        end
+        if available < len + size then
+            return nil
+        end
+        if f.int then
+            values[f.name] = read_int(tvb, offset + len, size)
+        end
+        len = len + size
+    end
+    return len, layout, values
+end
+
+function proto.dissector(tvb, pinfo, tree)
+    pinfo.cols.protocol = "FuzzyLog"
+    pinfo.cols.info:set("")
+    -- everything sent to a server is followed by the id of its sender
+    local trailer = 0
+    if pinfo.dst_port == proto.prefs.port then
+        trailer = SENDER_SIZE
+    end
+    local offset = 0
+    while offset < tvb:len() do
+        local len, layout, values = packet_len(tvb, offset)
+        if len == false then
+            -- the handshake, or a capture which started mid-packet
+            return
+        end
+        if len == nil or tvb:len() < offset + len + trailer then
+            pinfo.desegment_offset = offset
+            pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+            return
+        end
+        local subtree = tree:add(proto, tvb(offset, len + trailer), layout[1])
+        local pos = offset + TAG_SIZE
+        for _, f in ipairs(layout[2]) do
+            local size = f.size
+            if f.count then
+                size = size * values[f.count]
+            end
+            if size > 0 then
+                if LITTLE and f.int then
+                    subtree:add_le(f.field, tvb(pos, size))
+                else
+                    subtree:add(f.field, tvb(pos, size))
+                end
+            end
+            pos = pos + size
+        end
+        if trailer > 0 then
+            subtree:add(f_sender, tvb(pos, trailer))
+        end
+        pinfo.cols.info:append(layout[1] .. " ")
+        offset = pos + trailer
+    end
+end
+
+local registered_port = proto.prefs.port
+DissectorTable.get("tcp.port"):add(registered_port, proto)
+
+function proto.prefs_changed()
+    local tcp = DissectorTable.get("tcp.port")
+    tcp:remove(registered_port, proto)
+    registered_port = proto.prefs.port
+    tcp:add(registered_port, proto)
+end
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_has_a_layout() {
+        let packet = ::fuzzy_log_packets::Packet::schema();
+        let lua = lua_dissector(&packet);
+        if let Body::Enum { ref variants, .. } = packet.body {
+            for v in variants {
+                assert!(lua.contains(&format!("[{:#x}] = {{ {:?}, {{", v.tag, v.name)), "{}", v.name);
+            }
+        } else {
+            panic!("packets are an enum")
+        }
+        assert!(lua.contains("{ name = \"data_bytes\", size = 2, count = nil, int = true, \
+            field = ProtoField.uint16(\"packet.read.data_bytes\", \"data_bytes\") },"));
+    }
+}
@@ -0,0 +1,155 @@
+//! Decode FuzzyLog traffic offline, from a pcap capture or a dump of one side
+//! of a connection, and generate a Wireshark dissector for the packets.
+
+extern crate fuzzy_log_packets;
+
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddrV4;
+use std::process;
+
+use structopt::StructOpt;
+
+use decode::{Direction, Event, Handshake, StreamDecoder};
+use stream::Reassembler;
+
+mod decode;
+mod dissector;
+mod pcap;
+mod stream;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "packet_decoder", about = "Decode captured FuzzyLog traffic.")]
+struct Args {
+    #[structopt(help = "pcap capture, or raw bytes with --raw.")]
+    input: Option<String>,
+
+    #[structopt(long = "raw",
+        help = "the input is the raw bytes of one direction of a connection, to-server or from-server.")]
+    raw: Option<String>,
+
+    #[structopt(long = "handshake", help = "the raw input starts at the start of the connection.")]
+    handshake: bool,
+
+    #[structopt(long = "replica", help = "the raw input was sent down a replication chain.")]
+    replica: bool,
+
+    #[structopt(short = "p", long = "server-port",
+        help = "port the servers listen on, used for connections whose start was not captured.")]
+    server_port: Option<u16>,
+
+    #[structopt(long = "dissector", help = "print a Wireshark dissector for the packets and exit.")]
+    dissector: bool,
+}
+
+fn main() {
+    let args @ Args{..} = StructOpt::from_args();
+
+    if args.dissector {
+        print!("{}", dissector::lua_dissector(&fuzzy_log_packets::Packet::schema()));
+        return
+    }
+
+    let input = args.input.as_ref().unwrap_or_else(|| fail("no input file"));
+    let mut bytes = vec![];
+    File::open(input).and_then(|mut f| f.read_to_end(&mut bytes))
+        .unwrap_or_else(|e| fail(&format!("cannot read {}: {}", input, e)));
+
+    match args.raw {
+        Some(ref direction) => {
+            let direction = match &**direction {
+                "to-server" => Direction::ToServer,
+                "from-server" => Direction::FromServer,
+                other => fail(&format!("unknown direction {}", other)),
+            };
+            decode_raw(&bytes, direction, &args)
+        },
+        None => decode_capture(&bytes, &args),
+    }
+}
+
+fn decode_raw(bytes: &[u8], direction: Direction, args: &Args) {
+    let mut handshake = Handshake::default();
+    if !args.handshake {
+        handshake.peer = Some(if args.replica { decode::Peer::Replica } else { decode::Peer::Client });
+    }
+    let mut decoder = StreamDecoder::new(direction, args.handshake);
+    for event in decoder.feed(bytes, &mut handshake) {
+        println!("{}", fmt_event(&event));
+    }
+    if decoder.leftover() > 0 {
+        println!("{} bytes left over", decoder.leftover());
+    }
+}
+
+/// One direction of a connection.
+struct Flow {
+    stream: Reassembler,
+    decoder: Option<StreamDecoder>,
+}
+
+fn decode_capture(bytes: &[u8], args: &Args) {
+    let segments = pcap::segments(bytes).unwrap_or_else(|e| fail(&e));
+    let mut flows: HashMap<(SocketAddrV4, SocketAddrV4), Flow> = HashMap::new();
+    // the direction each connection was opened in, if we saw it opened
+    let mut openers: HashMap<(SocketAddrV4, SocketAddrV4), bool> = HashMap::new();
+    let mut handshakes: HashMap<(SocketAddrV4, SocketAddrV4), Handshake> = HashMap::new();
+
+    for segment in segments {
+        let (src, dst) = (segment.src, segment.dst);
+        let connection = if (src.ip(), src.port()) < (dst.ip(), dst.port()) { (src, dst) } else { (dst, src) };
+        if segment.syn && !segment.ack {
+            openers.insert(connection, src == connection.0);
+        }
+
+        let flow = flows.entry((src, dst))
+            .or_insert_with(|| Flow { stream: Reassembler::new(), decoder: None });
+        let ready = flow.stream.push(segment.seq, segment.syn, segment.payload);
+        if ready.is_empty() {
+            continue
+        }
+        let from_start = flow.stream.from_start();
+        let decoder = flow.decoder.get_or_insert_with(|| {
+            let to_server = match (openers.get(&connection), args.server_port) {
+                (Some(&first_opened), _) => first_opened == (src == connection.0),
+                (None, Some(port)) => dst.port() == port,
+                // servers usually have the lower port
+                (None, None) => dst.port() < src.port(),
+            };
+            let direction = if to_server { Direction::ToServer } else { Direction::FromServer };
+            StreamDecoder::new(direction, from_start)
+        });
+        let handshake = handshakes.entry(connection).or_insert_with(Handshake::default);
+        for event in decoder.feed(&ready, handshake) {
+            println!("{:.6} {} -> {} {}", segment.time, src, dst, fmt_event(&event));
+        }
+    }
+
+    for (&(src, dst), flow) in &flows {
+        let leftover = flow.decoder.as_ref().map(|d| d.leftover()).unwrap_or(0);
+        if leftover > 0 {
+            println!("{} -> {} {} bytes left over", src, dst, leftover);
+        }
+        if let Some(gap) = flow.stream.gap() {
+            println!("{} -> {} missing {} bytes of the stream", src, dst, gap);
+        }
+    }
+}
+
+fn fmt_event(event: &Event) -> String {
+    match *event {
+        Event::Handshake(ref h) => format!("handshake: {}", h),
+        Event::Packet(ref p) => p.clone(),
+        Event::Skipped(n) => format!("skipped {} bytes", n),
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1)
+}
@@ -0,0 +1,187 @@
+//! Just enough of the classic pcap format to get at TCP over IPv4.
+//!
+//! We understand captures from ethernet, linux cooked, loopback and raw IP links,
+//! which covers what tcpdump produces on the machines we run on.
+//! pcapng files should be converted with `editcap -F pcap` first.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+const ETHERNET: u32 = 1;
+const NULL: u32 = 0;
+const RAW: u32 = 101;
+const LINUX_SLL: u32 = 113;
+const IPV4: u32 = 228;
+
+const TCP: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// Seconds since the epoch.
+    pub time: f64,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub seq: u32,
+    pub syn: bool,
+    pub ack: bool,
+    pub payload: &'a [u8],
+}
+
+/// Every TCP segment in the capture, in capture order.
+pub fn segments(capture: &[u8]) -> Result<Vec<Segment>, String> {
+    if capture.len() < 24 {
+        return Err("capture too short for a pcap header".to_owned())
+    }
+    let magic = read_u32(&capture[..4], false);
+    let (big_endian, nanos) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        0x0a0d0d0a => return Err("pcapng is not supported, convert with `editcap -F pcap`".to_owned()),
+        magic => return Err(format!("not a pcap file, magic {:#x}", magic)),
+    };
+    let link = read_u32(&capture[20..24], big_endian);
+    let mut segments = vec![];
+    let mut records = &capture[24..];
+    while records.len() >= 16 {
+        let secs = read_u32(&records[..4], big_endian);
+        let frac = read_u32(&records[4..8], big_endian);
+        let len = read_u32(&records[8..12], big_endian) as usize;
+        if records.len() < 16 + len {
+            // the capture was cut off mid-record
+            break
+        }
+        let frame = &records[16..16 + len];
+        records = &records[16 + len..];
+        let time = secs as f64 + frac as f64 / if nanos { 1e9 } else { 1e6 };
+        if let Some(ip) = ip_packet(link, frame, big_endian) {
+            if let Some(segment) = tcp_segment(time, ip) {
+                segments.push(segment)
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// The IPv4 packet in a frame, if there is one.
+fn ip_packet(link: u32, frame: &[u8], big_endian: bool) -> Option<&[u8]> {
+    let ip = match link {
+        ETHERNET => {
+            let mut header = 14;
+            let mut ether_type = read_u16(frame.get(12..14)?);
+            // 802.1Q tags
+            while ether_type == 0x8100 {
+                ether_type = read_u16(frame.get(header + 2..header + 4)?);
+                header += 4;
+            }
+            if ether_type != 0x0800 {
+                return None
+            }
+            frame.get(header..)?
+        },
+        LINUX_SLL => {
+            if read_u16(frame.get(14..16)?) != 0x0800 {
+                return None
+            }
+            frame.get(16..)?
+        },
+        // the address family is in the byte order of the capturing machine
+        NULL => {
+            if read_u32(frame.get(..4)?, big_endian) != 2 {
+                return None
+            }
+            frame.get(4..)?
+        },
+        RAW | IPV4 => frame,
+        _ => return None,
+    };
+    if ip.first()? >> 4 != 4 {
+        return None
+    }
+    Some(ip)
+}
+
+fn tcp_segment(time: f64, ip: &[u8]) -> Option<Segment> {
+    let header_len = ((ip.first()? & 0xf) as usize) * 4;
+    let total_len = read_u16(ip.get(2..4)?) as usize;
+    let fragment = read_u16(ip.get(6..8)?);
+    // we do not reassemble ip fragments, and neither do our servers send them
+    if ip[9] != TCP || fragment & 0x3fff != 0 {
+        return None
+    }
+    let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    // captures may be truncated, or padded past the end of the packet
+    let tcp = ip.get(header_len..::std::cmp::min(total_len, ip.len()))?;
+    let data_offset = ((tcp.get(12)? >> 4) as usize) * 4;
+    let flags = tcp[13];
+    Some(Segment {
+        time,
+        src: SocketAddrV4::new(src, read_u16(&tcp[0..2])),
+        dst: SocketAddrV4::new(dst, read_u16(&tcp[2..4])),
+        seq: read_u32(&tcp[4..8], true),
+        syn: flags & 0x02 != 0,
+        ack: flags & 0x10 != 0,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let b = |i: usize| bytes[i] as u32;
+    if big_endian {
+        b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3)
+    } else {
+        b(3) << 24 | b(2) << 16 | b(1) << 8 | b(0)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A little-endian, microsecond, raw IP capture of `segments`.
+    pub fn capture(segments: &[(SocketAddrV4, SocketAddrV4, u32, u8, &[u8])]) -> Vec<u8> {
+        let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        capture.extend_from_slice(&[0; 8]);
+        capture.extend_from_slice(&[0xff, 0xff, 0, 0, RAW as u8, 0, 0, 0]);
+        for (i, &(src, dst, seq, flags, payload)) in segments.iter().enumerate() {
+            let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, TCP, 0, 0];
+            ip.extend_from_slice(&src.ip().octets());
+            ip.extend_from_slice(&dst.ip().octets());
+            ip.extend_from_slice(&[(src.port() >> 8) as u8, src.port() as u8]);
+            ip.extend_from_slice(&[(dst.port() >> 8) as u8, dst.port() as u8]);
+            ip.extend_from_slice(&[(seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8]);
+            ip.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+            ip.extend_from_slice(payload);
+            let len = ip.len();
+            ip[2] = (len >> 8) as u8;
+            ip[3] = len as u8;
+
+            capture.extend_from_slice(&[i as u8, 0, 0, 0, 0, 0, 0, 0]);
+            capture.extend_from_slice(&[len as u8, (len >> 8) as u8, 0, 0]);
+            capture.extend_from_slice(&[len as u8, (len >> 8) as u8, 0, 0]);
+            capture.extend_from_slice(&ip);
+        }
+        capture
+    }
+
+    #[test]
+    fn read_segments() {
+        let a = "10.0.0.1:4000".parse().unwrap();
+        let b = "10.0.0.2:13490".parse().unwrap();
+        let capture = capture(&[(a, b, 7, 0x02, &[]), (b, a, 100, 0x12, &[1]), (a, b, 8, 0x10, &[2, 3])]);
+        let segments = segments(&capture).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!((segments[0].src, segments[0].dst), (a, b));
+        assert!(segments[0].syn && !segments[0].ack);
+        assert!(segments[1].syn && segments[1].ack);
+        assert_eq!(segments[1].payload, &[1]);
+        assert_eq!(segments[2].seq, 8);
+        assert_eq!(segments[2].payload, &[2, 3]);
+        assert_eq!(segments[2].time, 2.0);
+    }
+}
@@ -0,0 +1,122 @@
+//! Reassembling one direction of a TCP connection from its segments.
+
+use std::collections::BTreeMap;
+
+/// Segments more than this far behind what we've delivered are retransmissions
+/// of data from before we started listening.
+const OLD: u32 = 1 << 31;
+
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    /// The sequence number of the first byte of the stream.
+    base: Option<u32>,
+    /// Whether we saw the SYN, and so have the stream from its first byte.
+    from_start: bool,
+    /// Bytes delivered so far.
+    delivered: u32,
+    /// Out of order segments, by offset from `base`.
+    pending: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn from_start(&self) -> bool {
+        self.from_start
+    }
+
+    /// Add a segment, and return any bytes which are now in order.
+    pub fn push(&mut self, seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        if syn {
+            if self.base.is_none() {
+                self.base = Some(seq.wrapping_add(1));
+                self.from_start = true;
+            }
+            return vec![]
+        }
+        if payload.is_empty() {
+            return vec![]
+        }
+        let base = *self.base.get_or_insert(seq);
+        let offset = seq.wrapping_sub(base);
+        let end = offset.wrapping_add(payload.len() as u32);
+        if self.delivered.wrapping_sub(end) < OLD {
+            // entirely a retransmission
+            return vec![]
+        }
+        if self.delivered.wrapping_sub(offset) < OLD {
+            // partly a retransmission
+            let skip = self.delivered.wrapping_sub(offset) as usize;
+            self.pending.entry(self.delivered).or_insert_with(Vec::new);
+            let pending = self.pending.get_mut(&self.delivered).unwrap();
+            if pending.len() < payload.len() - skip {
+                *pending = payload[skip..].to_vec()
+            }
+        } else {
+            let pending = self.pending.entry(offset).or_insert_with(Vec::new);
+            if pending.len() < payload.len() {
+                *pending = payload.to_vec()
+            }
+        }
+        self.drain()
+    }
+
+    /// The number of bytes we're missing before the next out of order segment.
+    pub fn gap(&self) -> Option<u32> {
+        self.pending.keys().next().map(|&offset| offset - self.delivered)
+    }
+
+    fn drain(&mut self) -> Vec<u8> {
+        let mut ready = vec![];
+        loop {
+            let offset = match self.pending.keys().next() {
+                Some(&offset) if offset <= self.delivered => offset,
+                _ => break,
+            };
+            let data = self.pending.remove(&offset).unwrap();
+            let skip = (self.delivered - offset) as usize;
+            if skip < data.len() {
+                ready.extend_from_slice(&data[skip..]);
+                self.delivered += (data.len() - skip) as u32;
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut stream = Reassembler::new();
+        assert_eq!(stream.push(99, true, &[]), vec![]);
+        assert!(stream.from_start());
+        assert_eq!(stream.push(100, false, &[1, 2]), vec![1, 2]);
+        assert_eq!(stream.push(102, false, &[3]), vec![3]);
+    }
+
+    #[test]
+    fn out_of_order_and_retransmitted() {
+        let mut stream = Reassembler::new();
+        stream.push(0xffff_fffe, true, &[]);
+        assert_eq!(stream.push(1, false, &[4, 5]), vec![]);
+        assert_eq!(stream.gap(), Some(2));
+        assert_eq!(stream.push(0xffff_ffff, false, &[1, 2]), vec![1, 2, 4, 5]);
+        assert_eq!(stream.push(0xffff_ffff, false, &[1, 2]), vec![]);
+        assert_eq!(stream.push(2, false, &[5, 6, 7]), vec![6, 7]);
+        assert_eq!(stream.gap(), None);
+    }
+
+    #[test]
+    fn mid_stream() {
+        let mut stream = Reassembler::new();
+        assert_eq!(stream.push(5000, false, &[1]), vec![1]);
+        assert!(!stream.from_start());
+        assert_eq!(stream.push(4000, false, &[0; 10]), vec![]);
+        assert_eq!(stream.push(5001, false, &[2]), vec![2]);
+    }
+}